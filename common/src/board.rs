@@ -0,0 +1,139 @@
+// hello-ra4m1
+// ボードの端子割り当て
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::pfs::{PfsConfig, pfs_configure};
+use crate::port::{port_reset, port_set};
+use ra4m1_fsp_pac as pac;
+
+// ボードはどちらか1つを選ぶ
+// 違うボード用のサンプルを書き込んで黙って動かない、ということが無いようにコンパイル時に決める
+#[cfg(all(feature = "board-uno-r4-minima", feature = "board-ra4m1-zero"))]
+compile_error!("features `board-uno-r4-minima` and `board-ra4m1-zero` are mutually exclusive");
+#[cfg(not(any(feature = "board-uno-r4-minima", feature = "board-ra4m1-zero")))]
+compile_error!("select a board with feature `board-uno-r4-minima` or `board-ra4m1-zero`");
+
+// LEDをつないだ端子
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Led {
+    pub port: u8,         // PORT番号
+    pub pin: u8,          // 端子番号
+    pub active_low: bool, // Lowで点灯する
+}
+
+impl Led {
+    pub const fn new(port: u8, pin: u8, active_low: bool) -> Self {
+        assert!(port <= 9 && pin <= 15);
+        Led {
+            port,
+            pin,
+            active_low,
+        }
+    }
+
+    // 端子を出力にして消灯する
+    pub fn init(self, p: &pac::Peripherals) {
+        pfs_configure(p, self.port, self.pin, PfsConfig::output(self.active_low));
+    }
+
+    // 点灯(true)か消灯(false)にする
+    // POSR/PORRに書くので、割り込みハンドラから呼んでも同じPORTの他の端子を壊さない
+    pub fn set(self, on: bool) {
+        if on != self.active_low {
+            port_set(self.port, 1 << self.pin);
+        } else {
+            port_reset(self.port, 1 << self.pin);
+        }
+    }
+
+    pub fn on(self) {
+        self.set(true);
+    }
+
+    pub fn off(self) {
+        self.set(false);
+    }
+}
+
+// ボードのLED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardLeds {
+    pub onboard: Led,    // ユーザーLED
+    pub tx: Option<Led>, // シリアル通信送信LED
+    pub rx: Option<Led>, // シリアル通信受信LED
+}
+
+// 端子
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pin {
+    pub port: u8, // PORT番号
+    pub pin: u8,  // 端子番号
+}
+
+impl Pin {
+    pub const fn new(port: u8, pin: u8) -> Self {
+        assert!(port <= 9 && pin <= 15);
+        Pin { port, pin }
+    }
+}
+
+// 送信と受信の端子の組
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxRxPins {
+    pub tx: Pin, // 送信データ
+    pub rx: Pin, // 受信データ
+}
+
+// ボードの端子
+// 割り当てを確かめていない周辺機能はNoneにして、使う側では無いものとして扱う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardPins {
+    pub ws2812b: Pin,           // WS2812Bのデータ入力
+    pub sci1: Option<TxRxPins>, // SCI1の送信データ(TXD1)と受信データ(RXD1)
+    pub can0: Option<TxRxPins>, // CAN0の送信データ(CTX0)と受信データ(CRX0)
+}
+
+// Arduino UNO R4 MINIMA (board-uno-r4-minima)
+// PORT 111 = D13(LED), PORT 012 = TX_LED, PORT 013 = RX_LED
+#[cfg(feature = "board-uno-r4-minima")]
+pub const BOARD_LEDS: BoardLeds = BoardLeds {
+    onboard: Led::new(1, 11, false),
+    tx: Some(Led::new(0, 12, true)),
+    rx: Some(Led::new(0, 13, true)),
+};
+
+// PORT 106 = D6(WS2812B), PORT 501 = SCI1_TXD, PORT 502 = SCI1_RXD
+// PORT 103 = D4(CTX0), PORT 102 = D5(CRX0)
+#[cfg(feature = "board-uno-r4-minima")]
+pub const BOARD_PINS: BoardPins = BoardPins {
+    ws2812b: Pin::new(1, 6),
+    sci1: Some(TxRxPins {
+        tx: Pin::new(5, 1),
+        rx: Pin::new(5, 2),
+    }),
+    can0: Some(TxRxPins {
+        tx: Pin::new(1, 3),
+        rx: Pin::new(1, 2),
+    }),
+};
+
+// Waveshare RA4M1-Zero (board-ra4m1-zero)
+// TX/RX LEDは無い
+// PORT 111 = ユーザーLED(最初のRA4M1-Zeroのサンプル(pac, gpt_timer)がWS2812Bと一緒に駆動していた端子)
+#[cfg(feature = "board-ra4m1-zero")]
+pub const BOARD_LEDS: BoardLeds = BoardLeds {
+    onboard: Led::new(1, 11, false),
+    tx: None,
+    rx: None,
+};
+
+// PORT 106 = ボードに乗っているWS2812B(最初のRA4M1-Zeroのサンプルで点灯を確かめた端子)
+// SCI1とCAN0はどの端子がピンヘッダに出ているか確かめていないので割り当てない
+#[cfg(feature = "board-ra4m1-zero")]
+pub const BOARD_PINS: BoardPins = BoardPins {
+    ws2812b: Pin::new(1, 6),
+    sci1: None,
+    can0: None,
+};
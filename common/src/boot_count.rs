@@ -0,0 +1,55 @@
+// hello-ra4m1
+// データフラッシュに記録する起動回数
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::clock::ClockConfig;
+use crate::dataflash::{BOOT_COUNT_BLOCKS, DataFlashError};
+use crate::dataflash_ring::DataFlashRing;
+
+// 1つの記録枠の大きさ(バイト)
+// 回数(u32)とそのビット反転(u32)を書く
+// データフラッシュは消去後の値が不定なので、回数と反転が合っている枠だけを書き込み済みとみなす
+const SLOT_SIZE: usize = 8;
+
+// 起動回数の記録
+// 同じ場所に書き続けないように、BOOT_COUNT_BLOCKS の記録枠(スロット)に順に書いていく
+type BootCountRing = DataFlashRing<SLOT_SIZE>;
+
+// 1ブロックを消去するのは128回の起動に1回
+const _: () = assert!(BootCountRing::RECORDS_PER_BLOCK == 128);
+
+// 記録枠の回数, 書き込まれていなければNone
+fn decode(buf: &[u8; SLOT_SIZE]) -> Option<u32> {
+    let count = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let inverted = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    (count == !inverted).then_some(count)
+}
+
+fn encode(count: u32) -> [u8; SLOT_SIZE] {
+    let mut buf = [0u8; SLOT_SIZE];
+    buf[..4].copy_from_slice(&count.to_le_bytes());
+    buf[4..].copy_from_slice(&(!count).to_le_bytes());
+    buf
+}
+
+// 記録されている起動回数, 一度も記録していなければ0
+// 事前にdataflash_init()を呼ぶこと
+pub fn boot_count_read() -> u32 {
+    BootCountRing::open(BOOT_COUNT_BLOCKS, decode)
+        .1
+        .unwrap_or(0)
+}
+
+// 起動回数を1増やして記録し、増やした後の回数を返す
+// 事前にdataflash_init()を呼ぶこと
+//
+// 最後に書いた記録枠の次の枠に書く
+// 消去はDataFlashRingが書いた後で次のブロックに対して行うので、書き込みの途中で電源が切れても前の回数は残る
+pub fn boot_count_increment(clocks: &ClockConfig) -> Result<u32, DataFlashError> {
+    let (mut ring, latest) = BootCountRing::open(BOOT_COUNT_BLOCKS, decode);
+    let count = latest.map_or(1, |count| count.wrapping_add(1));
+    ring.append(clocks, &encode(count))?;
+    Ok(count)
+}
@@ -0,0 +1,291 @@
+// hello-ra4m1
+// CANモジュール(CAN0)
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::board::BOARD_PINS;
+use crate::clock::ClockConfig;
+use crate::pfs::{PfsConfig, PwprGuard, pfs_configure};
+use ra4m1_fsp_pac as pac;
+
+// 通信速度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanBitrate {
+    Kbps125, // 125kbps
+    Kbps250, // 250kbps
+    Kbps500, // 500kbps
+}
+
+impl CanBitrate {
+    pub const fn bps(self) -> u32 {
+        match self {
+            CanBitrate::Kbps125 => 125_000,
+            CanBitrate::Kbps250 => 250_000,
+            CanBitrate::Kbps500 => 500_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanError {
+    BitrateNotAchievable, // PCLKBを割り切れる分周比とビットタイミングが無い
+    ModeSwitchTimeout,    // 動作モードの切り替えが終わらない
+    InvalidId,            // 標準ID(11ビット)の範囲外
+    TooLong,              // データが8バイトを超える
+    TxBusy,               // 前の送信が終わっていない
+    NoPins,               // ボードにCAN0の端子が割り当てられていない
+}
+
+// 標準ID(11ビット)のデータフレーム
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanFrame {
+    pub id: u16,       // 標準ID
+    pub len: u8,       // データ長(0～8)
+    pub data: [u8; 8], // データ(len バイトだけ有効)
+}
+
+impl CanFrame {
+    pub fn new(id: u16, data: &[u8]) -> Result<Self, CanError> {
+        if id > CAN_STANDARD_ID_MAX {
+            return Err(CanError::InvalidId);
+        }
+        if data.len() > 8 {
+            return Err(CanError::TooLong);
+        }
+        let mut frame = CanFrame {
+            id,
+            len: data.len() as u8,
+            data: [0; 8],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+        Ok(frame)
+    }
+
+    // 有効なデータ
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+// 標準IDの最大値
+pub const CAN_STANDARD_ID_MAX: u16 = 0x7ff;
+
+// ビットタイミング
+// 1ビットを 1(SS) + tseg1 + tseg2 個のTq(タイムクオンタ)で数える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanBitTiming {
+    pub prescaler: u16, // CANクロックの分周比(1～1024)
+    pub tseg1: u8,      // タイムセグメント1(4～16Tq)
+    pub tseg2: u8,      // タイムセグメント2(2～8Tq)
+    pub sjw: u8,        // 再同期ジャンプ幅(1～4Tq)
+}
+
+// can_clock_hz のクロックで bitrate を出すビットタイミングを求める
+// 1ビットのTq数は大きい方から試して、サンプリング点を75%付近にする
+// 割り切れる組み合わせが無ければNone
+pub const fn can_bit_timing(can_clock_hz: u32, bitrate: CanBitrate) -> Option<CanBitTiming> {
+    let bps = bitrate.bps();
+    // 1ビットのTq数は8～25だが、tseg1の上限(16Tq)からサンプリング点75%で取れるのは23まで
+    let mut tq = 23;
+    while tq >= 8 {
+        if can_clock_hz.is_multiple_of(bps * tq) {
+            let prescaler = can_clock_hz / (bps * tq);
+            let tseg1 = tq * 3 / 4 - 1;
+            let tseg2 = tq - 1 - tseg1;
+            if prescaler >= 1 && prescaler <= 1024 && tseg2 >= 2 && tseg2 <= 8 && tseg1 > tseg2 {
+                return Some(CanBitTiming {
+                    prescaler: prescaler as u16,
+                    tseg1: tseg1 as u8,
+                    tseg2: tseg2 as u8,
+                    sjw: 1,
+                });
+            }
+        }
+        tq -= 1;
+    }
+    None
+}
+
+// clock_init_hoco48のPCLKB(24MHz)とclock_init_pll48のPCLKB(16MHz)で全ての通信速度を出せる
+const _: () = assert!(can_bit_timing(24_000_000, CanBitrate::Kbps125).is_some());
+const _: () = assert!(can_bit_timing(24_000_000, CanBitrate::Kbps500).is_some());
+const _: () = assert!(can_bit_timing(16_000_000, CanBitrate::Kbps125).is_some());
+const _: () = assert!(can_bit_timing(16_000_000, CanBitrate::Kbps500).is_some());
+// 24MHz, 500kbpsは 3分周 * 16Tq, サンプリング点 (1 + 11) / 16 = 75%
+const _: () = assert!(matches!(
+    can_bit_timing(24_000_000, CanBitrate::Kbps500),
+    Some(CanBitTiming {
+        prescaler: 3,
+        tseg1: 11,
+        tseg2: 4,
+        sjw: 1
+    })
+));
+
+// 送信に使うメールボックス
+pub const CAN_TX_MAILBOX: usize = 0;
+
+// 受信に使うメールボックス
+// マスクレジスタはメールボックス4つごとに1つなので、MKR1を使うメールボックス4～7にする
+pub const CAN_RX_MAILBOXES: core::ops::Range<usize> = 4..8;
+
+// MB_D0 ～ MB_D7 のアドレス
+// PACのmb_d0()～mb_d7()は1バイトごとに別の型なので、ユーザーズマニュアルのアドレスを使う
+const fn mb_data_addr(mailbox: usize) -> usize {
+    0x4005_0206 + 16 * mailbox
+}
+
+const _: () = assert!(mb_data_addr(1) == 0x4005_0216);
+
+// 状態の確認を何回繰り返したら諦めるか
+const CAN_SPIN_LIMIT: u32 = 1_000_000;
+
+fn spin_until(mut cond: impl FnMut() -> bool) -> Result<(), CanError> {
+    for _ in 0..CAN_SPIN_LIMIT {
+        if cond() {
+            return Ok(());
+        }
+    }
+    Err(CanError::ModeSwitchTimeout)
+}
+
+// CANモジュールの設定
+// PCLKBをCANクロックにして bitrate のビットタイミングを設定し、標準IDのデータフレームを全て受信する
+// 受信したフレームは can_receive_mailbox() で読み出す
+pub fn can_init(
+    p: &pac::Peripherals,
+    clocks: &ClockConfig,
+    bitrate: CanBitrate,
+) -> Result<CanBitTiming, CanError> {
+    let timing = can_bit_timing(clocks.pclkb, bitrate).ok_or(CanError::BitrateNotAchievable)?;
+    let pins = BOARD_PINS.can0.ok_or(CanError::NoPins)?;
+
+    // CAN0モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrb().modify(|_r, w| w.mstpb2()._0());
+
+    // I/Oポートの設定
+    // 端子機能選択(CAN)
+    const CAN0_PSEL: u8 = 0b10000;
+    let pwpr = PwprGuard::new(p);
+    let (ctx, crx) = (pins.tx, pins.rx);
+    pfs_configure(p, ctx.port, ctx.pin, PfsConfig::peripheral(CAN0_PSEL, true));
+    pfs_configure(
+        p,
+        crx.port,
+        crx.pin,
+        PfsConfig::peripheral(CAN0_PSEL, false),
+    );
+    drop(pwpr);
+
+    // リセット直後はCANスリープモードなので、CANリセットモードにしてからスリープを解除する
+    p.CAN0.ctlr().modify(|_r, w| w.canm()._01());
+    p.CAN0.ctlr().modify(|_r, w| w.slpm()._0());
+    spin_until(|| {
+        let str = p.CAN0.str().read();
+        str.slpst().is_0() && str.rstst().is_1()
+    })?;
+
+    // ビットタイミング
+    // 各フィールドには Tq数 - 1 を書く
+    p.CAN0.bcr().write(|w| {
+        w.cclks()._0(); // PCLKB
+        w.tseg1().set(timing.tseg1 - 1);
+        w.tseg2().set(timing.tseg2 - 1);
+        w.sjw().set(timing.sjw - 1);
+        unsafe { w.brp().bits(timing.prescaler - 1) }
+    });
+
+    p.CAN0.ctlr().modify(|_r, w| {
+        w.mbm()._0(); // ノーマルメールボックスモード(32メールボックス)
+        w.idfm()._00(); // 標準IDモード
+        w.mlm()._0(); // メッセージロストの時は上書きする
+        w.tpm()._0() // IDの優先順で送信する
+    });
+
+    // 受信メールボックスは全てのIDを受け取る
+    // マスク有効にして、マスクの全ビットを比較しない(0)にする
+    p.CAN0.mkr(1).write(|w| unsafe { w.bits(0) });
+    p.CAN0.mkivlr().write(|w| unsafe { w.bits(0) });
+
+    // 受信メールボックスの割り込みを許可
+    let rx_bits = CAN_RX_MAILBOXES.fold(0u32, |acc, mb| acc | (1 << mb));
+    p.CAN0.mier().write(|w| unsafe { w.bits(rx_bits) });
+
+    // CANオペレーションモードにする
+    p.CAN0.ctlr().modify(|_r, w| w.canm()._00());
+    spin_until(|| {
+        let str = p.CAN0.str().read();
+        str.rstst().is_0() && str.hltst().is_0()
+    })?;
+
+    // 受信メールボックスの設定
+    for mb in CAN_RX_MAILBOXES {
+        // メールボックスを未設定にしてから書き換える
+        p.CAN0.mctl_rx(mb).write(|w| unsafe { w.bits(0) });
+        spin_until(|| p.CAN0.mctl_rx(mb).read().bits() == 0)?;
+        p.CAN0.mb_id(mb).write(|w| {
+            w.ide()._0(); // 標準ID
+            w.rtr()._0() // データフレーム
+        });
+        p.CAN0.mctl_rx(mb).write(|w| w.recreq()._1());
+    }
+
+    Ok(timing)
+}
+
+// 標準ID id のデータフレームを送信メールボックスに入れて送信を始める
+// 前の送信が終わっていなければCanError::TxBusy
+pub fn can_send(p: &pac::Peripherals, id: u16, data: &[u8]) -> Result<(), CanError> {
+    let frame = CanFrame::new(id, data)?;
+    let mb = CAN_TX_MAILBOX;
+
+    let mctl = p.CAN0.mctl_tx(mb).read();
+    if mctl.trmreq().is_1() && mctl.sentdata().is_0() {
+        return Err(CanError::TxBusy);
+    }
+
+    // 送信完了フラグを下ろしてメールボックスを未設定にする
+    p.CAN0.mctl_tx(mb).write(|w| unsafe { w.bits(0) });
+    spin_until(|| p.CAN0.mctl_tx(mb).read().bits() == 0)?;
+
+    p.CAN0.mb_id(mb).write(|w| {
+        w.ide()._0(); // 標準ID
+        w.rtr()._0(); // データフレーム
+        unsafe { w.sid().bits(frame.id) }
+    });
+    p.CAN0.mb_dl(mb).write(|w| w.dlc().set(frame.len));
+    let addr = mb_data_addr(mb) as *mut u8;
+    for (i, &byte) in frame.data().iter().enumerate() {
+        unsafe { core::ptr::write_volatile(addr.add(i), byte) };
+    }
+
+    // 送信要求
+    p.CAN0.mctl_tx(mb).write(|w| w.trmreq()._1());
+    Ok(())
+}
+
+// 受信メールボックス mb に新しいフレームがあれば読み出す
+// 読み出している間に次のフレームで上書きされたら読み直す
+pub fn can_receive_mailbox(p: &pac::Peripherals, mb: usize) -> Option<CanFrame> {
+    if p.CAN0.mctl_rx(mb).read().newdata().is_0() {
+        return None;
+    }
+    loop {
+        // 受信完了フラグを下ろす(受信要求はそのまま)
+        p.CAN0.mctl_rx(mb).write(|w| w.recreq()._1().newdata()._0());
+
+        let id = p.CAN0.mb_id(mb).read().sid().bits();
+        let len = p.CAN0.mb_dl(mb).read().dlc().bits().min(8);
+        let mut data = [0u8; 8];
+        let addr = mb_data_addr(mb) as *const u8;
+        for (i, byte) in data[..len as usize].iter_mut().enumerate() {
+            *byte = unsafe { core::ptr::read_volatile(addr.add(i)) };
+        }
+
+        let mctl = p.CAN0.mctl_rx(mb).read();
+        if mctl.newdata().is_0() && mctl.invaldata().is_0() {
+            return Some(CanFrame { id, len, data });
+        }
+    }
+}
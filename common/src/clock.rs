@@ -0,0 +1,514 @@
+// hello-ra4m1
+// クロック設定
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use ra4m1_fsp_pac as pac;
+use scopeguard::defer;
+
+// システムクロックのクロックソース
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Hoco, // 高速オンチップオシレータ(HOCO)
+    Moco, // 中速オンチップオシレータ(MOCO)
+    Loco, // 低速オンチップオシレータ(LOCO)
+    Mosc, // メインクロック発振器(MOSC)
+    Sosc, // サブクロック発振器(SOSC)
+    Pll,  // PLL
+}
+
+// クロック設定の失敗
+// どの発振器やクロック切り替えが待っても安定しなかったか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    MoscNotStable,      // メインクロック発振器(MOSC)が発振安定しない
+    MoscStopTimeout,    // メインクロック発振器(MOSC)の停止が確認できない
+    MoscStartTimeout,   // メインクロック発振器(MOSC)の動作開始が確認できない
+    PllNotLocked,       // PLLが発振安定しない
+    HocoNotStable,      // 高速オンチップオシレータ(HOCO)が発振安定しない
+    ClockSwitchTimeout, // 動作モードやシステムクロックの切り替えが終わらない
+    FlashCacheTimeout,  // フラッシュキャッシュのインバリデートが終わらない
+}
+
+// 状態の確認を何回繰り返したら諦めるか
+// リセット直後のMOCO(8MHz)で動いていても1秒足らずで諦める回数
+const CLOCK_SPIN_LIMIT: u32 = 1_000_000;
+
+// cond() がtrueになるまで待つ
+// CLOCK_SPIN_LIMIT回確認してもtrueにならなければ err を返す
+fn spin_until(err: ClockError, mut cond: impl FnMut() -> bool) -> Result<(), ClockError> {
+    for _ in 0..CLOCK_SPIN_LIMIT {
+        if cond() {
+            return Ok(());
+        }
+    }
+    Err(err)
+}
+
+// フラッシュキャッシュをインバリデートしてから許可する
+fn flash_cache_enable(p: &pac::Peripherals) -> Result<(), ClockError> {
+    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
+    spin_until(ClockError::FlashCacheTimeout, || {
+        p.FCACHE.fcacheiv().read().fcacheiv().bit_is_clear()
+    })?; // インバリデート完了待ち
+    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
+    Ok(())
+}
+
+// クロック設定の結果
+// 各クロックの周波数(Hz)
+#[derive(Debug, Clone, Copy)]
+pub struct ClockConfig {
+    pub source: ClockSource, // システムクロックのクロックソース
+    pub iclk: u32,           // システムクロック
+    pub pclka: u32,          // 周辺モジュールクロックA
+    pub pclkb: u32,          // 周辺モジュールクロックB
+    pub pclkc: u32,          // 周辺モジュールクロックC
+    pub pclkd: u32,          // 周辺モジュールクロックD
+    pub fclk: u32,           // Flashインターフェースクロック
+}
+
+// 高速オンチップオシレータ(HOCO)の周波数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HocoFreq {
+    Mhz24,
+    Mhz32,
+    Mhz48,
+    Mhz64,
+}
+
+impl HocoFreq {
+    // HOCOCR2レジスタのHCFRQ1ビット(b5-b3)に書き込む値
+    const fn hococr2(self) -> u8 {
+        match self {
+            HocoFreq::Mhz24 => 0b0000_0000,
+            HocoFreq::Mhz32 => 0b0001_0000,
+            HocoFreq::Mhz48 => 0b0010_0000,
+            HocoFreq::Mhz64 => 0b0010_1000,
+        }
+    }
+
+    // 周波数(Hz)
+    pub const fn hz(self) -> u32 {
+        match self {
+            HocoFreq::Mhz24 => 24_000_000,
+            HocoFreq::Mhz32 => 32_000_000,
+            HocoFreq::Mhz48 => 48_000_000,
+            HocoFreq::Mhz64 => 64_000_000,
+        }
+    }
+
+    // 分周比(2のべき乗の指数)
+    // (ICLK, PCLKA, PCLKB, PCLKC, PCLKD, FCLK)
+    // ICLK, PCLKAは48MHz以下, PCLKB, FCLKは32MHz以下, PCLKC, PCLKDは64MHz以下にする
+    const fn dividers(self) -> (u8, u8, u8, u8, u8, u8) {
+        match self {
+            HocoFreq::Mhz24 => (0, 0, 0, 0, 0, 0),
+            HocoFreq::Mhz32 => (0, 0, 0, 0, 0, 0),
+            HocoFreq::Mhz48 => (0, 0, 1, 0, 0, 1),
+            HocoFreq::Mhz64 => (1, 1, 1, 0, 0, 1),
+        }
+    }
+}
+
+// HOCOCR2レジスタのアドレス
+// HOCOCR2レジスタはPACに無いのでユーザーズマニュアルの値を使う
+const HOCOCR2_ADDR: usize = 0x4001_e037;
+
+// 16MHz水晶発振子(メインクロック発振器の入力)の周波数
+const MOSC_HZ: u32 = 16_000_000;
+// 中速オンチップオシレータ(MOCO)の周波数
+const MOCO_HZ: u32 = 8_000_000;
+// 低速オンチップオシレータ(LOCO)とサブクロック発振器の周波数
+const LOCO_HZ: u32 = 32_768;
+const SOSC_HZ: u32 = 32_768;
+
+// 高速オンチップオシレータ(HOCO)周波数指定
+// HOCOCR2レジスタはPACに無いのでアドレスを直接指定して書き込む
+// HOCOCR2は8ビットレジスタなので、隣のレジスタを壊さないように必ず8ビットで書き込むこと
+// 保護レジスタ(PRCR)で書込み許可を与えてから呼ぶこと
+fn set_hoco_freq(freq: HocoFreq) {
+    unsafe { core::ptr::write_volatile(HOCOCR2_ADDR as *mut u8, freq.hococr2()) };
+}
+
+// 高速オンチップオシレータ(HOCO)の周波数(Hz)をHOCOCR2レジスタから読み出す
+fn hoco_hz() -> u32 {
+    let hococr2 = unsafe { core::ptr::read_volatile(HOCOCR2_ADDR as *const u8) };
+    match hococr2 & 0b0011_1000 {
+        0b0000_0000 => HocoFreq::Mhz24.hz(),
+        0b0001_0000 => HocoFreq::Mhz32.hz(),
+        0b0010_0000 => HocoFreq::Mhz48.hz(),
+        _ => HocoFreq::Mhz64.hz(),
+    }
+}
+
+// SCKSCRレジスタで選ばれているシステムクロックのクロックソース
+fn system_clock_source(p: &pac::Peripherals) -> ClockSource {
+    match p.SYSTEM.sckscr().read().cksel().bits() {
+        0b000 => ClockSource::Hoco,
+        0b001 => ClockSource::Moco,
+        0b010 => ClockSource::Loco,
+        0b011 => ClockSource::Mosc,
+        0b100 => ClockSource::Sosc,
+        _ => ClockSource::Pll,
+    }
+}
+
+// システムクロックのクロックソースの周波数(Hz)
+fn system_clock_source_hz(p: &pac::Peripherals, source: ClockSource) -> u32 {
+    match source {
+        ClockSource::Hoco => hoco_hz(),
+        ClockSource::Moco => MOCO_HZ,
+        ClockSource::Loco => LOCO_HZ,
+        ClockSource::Mosc => MOSC_HZ,
+        ClockSource::Sosc => SOSC_HZ,
+        ClockSource::Pll => {
+            // PLL = MOSC * 逓倍率 / 分周比
+            let pllccr2 = p.SYSTEM.pllccr2().read();
+            let mul = pllccr2.pllmul().bits() as u32 + 1;
+            let div = 1 << pllccr2.plodiv().bits(); // 00: /1, 01: /2, 10: /4
+            MOSC_HZ * mul / div
+        }
+    }
+}
+
+// 現在のクロック設定をレジスタから読み出す
+// clock_init_*()以外でクロックを設定した場合にも正しい周波数が分かる
+pub fn current_clock_config(p: &pac::Peripherals) -> ClockConfig {
+    let source = system_clock_source(p);
+    let hz = system_clock_source_hz(p, source);
+    // 分周比は2のべき乗の指数
+    let sckdivcr = p.SYSTEM.sckdivcr().read();
+    ClockConfig {
+        source,
+        iclk: hz >> sckdivcr.ick().bits(),
+        pclka: hz >> sckdivcr.pcka().bits(),
+        pclkb: hz >> sckdivcr.pckb().bits(),
+        pclkc: hz >> sckdivcr.pckc().bits(),
+        pclkd: hz >> sckdivcr.pckd().bits(),
+        fclk: hz >> sckdivcr.fck().bits(),
+    }
+}
+
+// 現在のシステムクロック(ICLK)の周波数(Hz)
+pub fn current_iclk_hz(p: &pac::Peripherals) -> u32 {
+    current_clock_config(p).iclk
+}
+
+// メインクロック発振器(MOSC)の発振安定待ちの上限(ミリ秒)
+// 発振安定待ち時間(MOSCWTCR)は32768usなので、その3倍待っても安定しなければ水晶発振子が無いとみなす
+const MOSC_STABILIZATION_TIMEOUT_MS: u32 = 100;
+
+// メインクロック発振器(MOSC)の発振安定待ち
+// 時間切れならメインクロック発振器を止めてfalseを返す
+fn wait_mosc_stable(p: &pac::Peripherals) -> bool {
+    // まだクロックを切り替える前なので、今のシステムクロックで時間を数える
+    let cycles_per_ms = current_iclk_hz(p) / 1000;
+    for _ in 0..MOSC_STABILIZATION_TIMEOUT_MS {
+        if p.SYSTEM.oscsf().read().moscsf().bit_is_set() {
+            return true;
+        }
+        cortex_m::asm::delay(cycles_per_ms);
+    }
+    if p.SYSTEM.oscsf().read().moscsf().bit_is_set() {
+        return true;
+    }
+    // メインクロック発振器(MOSC)の停止
+    p.SYSTEM.mosccr().write(|w| w.mostp()._1());
+    false
+}
+
+// クロック設定
+// 16MHz水晶発振子をメインクロックに設定する
+// 水晶発振子が発振しなければErr(ClockError::MoscNotStable)
+pub fn clock_init_xtal(p: &pac::Peripherals) -> Result<ClockConfig, ClockError> {
+    // 保護レジスタを操作して書込み許可を与える
+    p.SYSTEM.prcr().write(|w| {
+        w.prkey().set(0xa5);
+        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
+        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
+    });
+    // 関数脱出時に保護レジスタを元通りに復帰する
+    defer! {
+        p.SYSTEM.prcr().write(|w| {
+            w.prkey().set(0xa5);
+            w.prc0().clear_bit();
+            w.prc1().clear_bit()
+        });
+    }
+
+    // 消費電力モードはハイスピードモードに設定
+    p.SYSTEM.opccr().write(|w| w.opcm()._00());
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.opccr().read().opcmtsf().bit_is_clear()
+    })?; // 確認
+
+    // サブクロックの停止
+    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.sosccr().read().sostp().bit_is_set()
+    })?; // サブクロック停止確認
+
+    // メインクロック発振器(MOSC)の停止
+    p.SYSTEM.mosccr().write(|w| w.mostp()._1());
+    spin_until(ClockError::MoscStopTimeout, || {
+        p.SYSTEM.mosccr().read().mostp().is_1()
+    })?; // 確認
+
+    // メインクロック発振器(MOSC)モードコントロールレジスタ
+    p.SYSTEM.momcr().write(|w| {
+        w.modrv1()._0(); // 10MHz ～ 20MHz
+        w.mosel()._0() // 外部水晶発振子
+    });
+
+    // メインクロック発振器(MOSC)待機時間
+    p.SYSTEM.moscwtcr().write(|w| w.msts()._1001()); // 32768us
+
+    // メインクロック発振器(MOSC)動作
+    p.SYSTEM.mosccr().write(|w| w.mostp()._0());
+    spin_until(ClockError::MoscStartTimeout, || {
+        p.SYSTEM.mosccr().read().mostp().is_0()
+    })?; // 確認
+
+    // メインクロック発振器(MOSC)発振安定待ち
+    if !wait_mosc_stable(p) {
+        return Err(ClockError::MoscNotStable);
+    }
+
+    // 分周器設定
+    p.SYSTEM.sckdivcr().write(|w| {
+        w.ick()._000(); // システムクロック(ICLK Div /1)
+        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
+        w.pckb()._000(); // 周辺モジュールクロックB(PCLKA Div /1)
+        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
+        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
+        w.fck()._000() // Flashインターフェースクロック(FCLK Div /1)
+    });
+
+    // システムクロックをメインクロックに切り替え
+    p.SYSTEM.sckscr().write(|w| w.cksel()._011()); // メインクロック発振器(MOSC)
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.sckscr().read().cksel().is_011()
+    })?; // 確認
+
+    // フラッシュキャッシュ
+    flash_cache_enable(p)?;
+
+    Ok(ClockConfig {
+        source: ClockSource::Mosc,
+        iclk: 16_000_000,
+        pclka: 16_000_000,
+        pclkb: 16_000_000,
+        pclkc: 16_000_000,
+        pclkd: 16_000_000,
+        fclk: 16_000_000,
+    })
+}
+
+// クロック設定
+// 16MHz水晶発振子を12逓倍のち4分周した48MHzをクロックに設定する
+// 水晶発振子が発振しなければErr(ClockError::MoscNotStable)
+pub fn clock_init_pll48(p: &pac::Peripherals) -> Result<ClockConfig, ClockError> {
+    // 保護レジスタを操作して書込み許可を与える
+    p.SYSTEM.prcr().write(|w| {
+        w.prkey().set(0xa5);
+        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
+        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
+    });
+    // 関数脱出時に保護レジスタを元通りに復帰する
+    defer! {
+        p.SYSTEM.prcr().write(|w| {
+            w.prkey().set(0xa5);
+            w.prc0().clear_bit();
+            w.prc1().clear_bit()
+        });
+    }
+
+    // 消費電力モードはハイスピードモードに設定
+    p.SYSTEM.opccr().write(|w| w.opcm()._00());
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.opccr().read().opcmtsf().bit_is_clear()
+    })?; // 確認
+
+    // サブクロックの停止
+    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.sosccr().read().sostp().bit_is_set()
+    })?; // サブクロック停止確認
+
+    // メインクロック発振器(MOSC)の停止
+    p.SYSTEM.mosccr().write(|w| w.mostp()._1());
+    spin_until(ClockError::MoscStopTimeout, || {
+        p.SYSTEM.mosccr().read().mostp().is_1()
+    })?; // 確認
+
+    //
+    // メインクロック発振器(MOSC)の入力は16MHz水晶発振子
+    //
+
+    // メインクロック発振器(MOSC)モードコントロールレジスタ
+    p.SYSTEM.momcr().write(|w| {
+        w.modrv1()._0(); // 10MHz ～ 20MHz
+        w.mosel()._0() // 外部水晶発振子
+    });
+
+    // メインクロック発振器(MOSC)待機時間
+    p.SYSTEM.moscwtcr().write(|w| w.msts()._1001()); // 32768us
+
+    // メインクロック発振器(MOSC)動作
+    p.SYSTEM.mosccr().write(|w| w.mostp()._0());
+    spin_until(ClockError::MoscStartTimeout, || {
+        p.SYSTEM.mosccr().read().mostp().is_0()
+    })?; // 確認
+
+    // メインクロック発振器(MOSC)発振安定待ち
+    if !wait_mosc_stable(p) {
+        return Err(ClockError::MoscNotStable);
+    }
+
+    // メインクロック発振器(MOSC)をPLLで逓倍する
+    // 逓倍率および分周比の設定
+    p.SYSTEM.pllccr2().write(|w| {
+        w.pllmul().set(12 - 1); // PLL Mul x12
+        w.plodiv()._10() // PLL Div /4
+    });
+
+    // PLL動作
+    p.SYSTEM.pllcr().write(|w| w.pllstp()._0());
+    spin_until(ClockError::PllNotLocked, || {
+        p.SYSTEM.pllcr().read().pllstp().is_0()
+    })?; // 確認
+
+    // PLL発振安定待ち
+    spin_until(ClockError::PllNotLocked, || {
+        p.SYSTEM.oscsf().read().pllsf().bit_is_set()
+    })?;
+
+    // 分周器設定
+    p.SYSTEM.sckdivcr().write(|w| {
+        w.ick()._000(); // システムクロック(ICLK Div /1)
+        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
+        w.pckb()._001(); // 周辺モジュールクロックB(PCLKA Div /2)
+        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
+        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
+        w.fck()._001() // Flashインターフェースクロック(FCLK Div /2)
+    });
+
+    // システムクロックをPLLに切り替え
+    p.SYSTEM.sckscr().write(|w| w.cksel()._101()); // PLL
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.sckscr().read().cksel().is_101()
+    })?; // 確認
+
+    // フラッシュキャッシュ
+    flash_cache_enable(p)?;
+
+    Ok(ClockConfig {
+        source: ClockSource::Pll,
+        iclk: 48_000_000,
+        pclka: 48_000_000,
+        pclkb: 24_000_000,
+        pclkc: 48_000_000,
+        pclkd: 48_000_000,
+        fclk: 24_000_000,
+    })
+}
+
+// クロック設定
+// 高速オンチップオシレータ(HOCO)を指定の周波数でメインクロックに設定する
+pub fn clock_init_hoco(p: &pac::Peripherals, freq: HocoFreq) -> Result<ClockConfig, ClockError> {
+    // 保護レジスタを操作して書込み許可を与える
+    p.SYSTEM.prcr().write(|w| {
+        w.prkey().set(0xa5);
+        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
+        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
+    });
+    // 関数脱出時に保護レジスタを元通りに復帰する
+    defer! {
+        p.SYSTEM.prcr().write(|w| {
+            w.prkey().set(0xa5);
+            w.prc0().clear_bit();
+            w.prc1().clear_bit()
+        });
+    }
+
+    // 消費電力モードはハイスピードモードに設定
+    p.SYSTEM.opccr().write(|w| w.opcm()._00());
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.opccr().read().opcmtsf().bit_is_clear()
+    })?; // 確認
+
+    // サブクロックの停止
+    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.sosccr().read().sostp().bit_is_set()
+    })?; // サブクロック停止確認
+
+    // 高速オンチップオシレータ(HOCO)周波数指定
+    set_hoco_freq(freq);
+
+    // 高速オンチップオシレータ(HOCO)クロック動作
+    p.SYSTEM.hococr().write(|w| w.hcstp()._0());
+    spin_until(ClockError::HocoNotStable, || {
+        p.SYSTEM.hococr().read().hcstp().is_0()
+    })?; // 確認
+
+    // 高速オンチップオシレータ(HOCO)クロック発振安定待ち
+    spin_until(ClockError::HocoNotStable, || {
+        p.SYSTEM.oscsf().read().hocosf().bit_is_set()
+    })?;
+
+    // 分周器設定
+    let (ick, pcka, pckb, pckc, pckd, fck) = freq.dividers();
+    p.SYSTEM.sckdivcr().write(|w| {
+        w.ick().set(ick); // システムクロック(ICLK)
+        w.pcka().set(pcka); // 周辺モジュールクロックA(PCLKA)
+        w.pckb().set(pckb); // 周辺モジュールクロックB(PCLKB)
+        w.pckc().set(pckc); // 周辺モジュールクロックC(PCLKC)
+        w.pckd().set(pckd); // 周辺モジュールクロックD(PCLKD)
+        w.fck().set(fck) // Flashインターフェースクロック(FCLK)
+    });
+
+    // システムクロックを高速オンチップオシレータ(HOCO)クロックに切り替え
+    p.SYSTEM.sckscr().write(|w| w.cksel()._000()); // HOCOクロック
+    spin_until(ClockError::ClockSwitchTimeout, || {
+        p.SYSTEM.sckscr().read().cksel().is_000()
+    })?; // 確認
+
+    // フラッシュキャッシュ
+    flash_cache_enable(p)?;
+
+    let hz = freq.hz();
+    Ok(ClockConfig {
+        source: ClockSource::Hoco,
+        iclk: hz >> ick,
+        pclka: hz >> pcka,
+        pclkb: hz >> pckb,
+        pclkc: hz >> pckc,
+        pclkd: hz >> pckd,
+        fclk: hz >> fck,
+    })
+}
+
+// クロック設定
+// 高速オンチップオシレータ(HOCO)を48MHzでメインクロックに設定する
+pub fn clock_init_hoco48(p: &pac::Peripherals) -> Result<ClockConfig, ClockError> {
+    clock_init_hoco(p, HocoFreq::Mhz48)
+}
+
+// クロック設定
+// init(clock_init_xtalかclock_init_pll48)で設定して、
+// 水晶発振子が発振しないかPLLが発振安定しなければ高速オンチップオシレータ(HOCO)48MHzにする
+// どちらになったかは戻り値のsourceで分かる
+pub fn clock_init_or_hoco48(
+    p: &pac::Peripherals,
+    init: fn(&pac::Peripherals) -> Result<ClockConfig, ClockError>,
+) -> Result<ClockConfig, ClockError> {
+    match init(p) {
+        Err(
+            ClockError::MoscNotStable | ClockError::MoscStartTimeout | ClockError::PllNotLocked,
+        ) => clock_init_hoco48(p),
+        result => result,
+    }
+}
@@ -0,0 +1,133 @@
+// hello-ra4m1
+// COBS(Consistent Overhead Byte Stuffing)符号化
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+// COBSの符号化, 復号のエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CobsError {
+    BufferTooSmall, // 出力先が足りない
+    InvalidFrame,   // 符号化されたデータとして正しくない
+}
+
+// n バイトを符号化した時の最大の長さ(区切りの0x00は含まない)
+pub const fn cobs_max_encoded_len(n: usize) -> usize {
+    n + n / 254 + 1
+}
+
+// input を符号化して out に書き込み、書き込んだバイト数を返す
+// 符号化したデータには0x00が含まれないので、フレームの区切りに0x00を使える
+pub const fn cobs_encode(input: &[u8], out: &mut [u8]) -> Result<usize, CobsError> {
+    if out.is_empty() {
+        return Err(CobsError::BufferTooSmall);
+    }
+    let mut code_index = 0; // 次の0x00までの距離を書き込む位置
+    let mut out_index = 1;
+    let mut code: u8 = 1;
+    let mut i = 0;
+    while i < input.len() {
+        let byte = input[i];
+        i += 1;
+        if byte != 0 {
+            if out_index >= out.len() {
+                return Err(CobsError::BufferTooSmall);
+            }
+            out[out_index] = byte;
+            out_index += 1;
+            code += 1;
+            if code != 0xff {
+                continue;
+            }
+        }
+        // 0x00 または 254バイト続いたところでブロックを閉じる
+        if out_index >= out.len() {
+            return Err(CobsError::BufferTooSmall);
+        }
+        out[code_index] = code;
+        code_index = out_index;
+        out_index += 1;
+        code = 1;
+    }
+    out[code_index] = code;
+    Ok(out_index)
+}
+
+// 符号化された input (区切りの0x00は含まない) を復号して out に書き込み、書き込んだバイト数を返す
+pub const fn cobs_decode(input: &[u8], out: &mut [u8]) -> Result<usize, CobsError> {
+    let mut i = 0;
+    let mut out_index = 0;
+    while i < input.len() {
+        let code = input[i];
+        if code == 0 {
+            return Err(CobsError::InvalidFrame);
+        }
+        i += 1;
+        let mut n = 1;
+        while n < code {
+            if i >= input.len() || input[i] == 0 {
+                return Err(CobsError::InvalidFrame);
+            }
+            if out_index >= out.len() {
+                return Err(CobsError::BufferTooSmall);
+            }
+            out[out_index] = input[i];
+            out_index += 1;
+            i += 1;
+            n += 1;
+        }
+        // 254バイトのブロックと最後のブロックの後には0x00が無い
+        if code != 0xff && i < input.len() {
+            if out_index >= out.len() {
+                return Err(CobsError::BufferTooSmall);
+            }
+            out[out_index] = 0;
+            out_index += 1;
+        }
+    }
+    Ok(out_index)
+}
+
+// 符号化と復号の結果が expected と一致すればtrue
+const fn check<const N: usize>(input: &[u8], expected: &[u8]) -> bool {
+    const fn eq(a: &[u8], b: &[u8]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < a.len() {
+            if a[i] != b[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+    let mut encoded = [0u8; N];
+    let Ok(len) = cobs_encode(input, &mut encoded) else {
+        return false;
+    };
+    let (encoded, _) = encoded.split_at(len);
+    let mut decoded = [0u8; N];
+    let Ok(len) = cobs_decode(encoded, &mut decoded) else {
+        return false;
+    };
+    let (decoded, _) = decoded.split_at(len);
+    eq(encoded, expected) && eq(decoded, input)
+}
+
+// 符号化と復号をよく知られた例で確かめる
+const _: () = assert!(check::<8>(&[0x00], &[0x01, 0x01]));
+const _: () = assert!(check::<8>(&[0x00, 0x00], &[0x01, 0x01, 0x01]));
+const _: () = assert!(check::<8>(
+    &[0x11, 0x22, 0x00, 0x33],
+    &[0x03, 0x11, 0x22, 0x02, 0x33]
+));
+const _: () = assert!(check::<8>(
+    &[0x11, 0x22, 0x33, 0x44],
+    &[0x05, 0x11, 0x22, 0x33, 0x44]
+));
+const _: () = assert!(check::<8>(
+    &[0x11, 0x00, 0x00, 0x00],
+    &[0x02, 0x11, 0x01, 0x01, 0x01]
+));
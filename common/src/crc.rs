@@ -0,0 +1,99 @@
+// hello-ra4m1
+// CRC演算器
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use ra4m1_fsp_pac as pac;
+
+// CRCの生成多項式
+// どれもMSBファーストで初期値0, 出力XORなし
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrcPolynomial {
+    Crc8,       // X^8 + X^2 + X + 1 (CRC-8/SMBUS)
+    Crc16,      // X^16 + X^15 + X^2 + 1 (CRC-16/UMTS)
+    Crc16Ccitt, // X^16 + X^12 + X^5 + 1 (CRC-16/XMODEM)
+}
+
+impl CrcPolynomial {
+    // 生成多項式(最上位の項を除く)
+    const fn poly(self) -> u32 {
+        match self {
+            CrcPolynomial::Crc8 => 0x07,
+            CrcPolynomial::Crc16 => 0x8005,
+            CrcPolynomial::Crc16Ccitt => 0x1021,
+        }
+    }
+
+    // CRCのビット幅
+    const fn width(self) -> u32 {
+        match self {
+            CrcPolynomial::Crc8 => 8,
+            CrcPolynomial::Crc16 | CrcPolynomial::Crc16Ccitt => 16,
+        }
+    }
+}
+
+// ソフトウェアで計算するCRC
+// CRC演算器の結果を確かめるための参照実装
+pub const fn crc_software(poly: CrcPolynomial, data: &[u8]) -> u32 {
+    let width = poly.width();
+    let top = 1 << (width - 1);
+    let mask = (1 << width) - 1;
+    let mut crc: u32 = 0;
+    let mut i = 0;
+    while i < data.len() {
+        crc ^= (data[i] as u32) << (width - 8);
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & top != 0 {
+                (crc << 1) ^ poly.poly()
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        crc &= mask;
+        i += 1;
+    }
+    crc
+}
+
+// 参照実装を "123456789" のチェック値で確かめる
+const _: () = assert!(crc_software(CrcPolynomial::Crc8, b"123456789") == 0xf4);
+const _: () = assert!(crc_software(CrcPolynomial::Crc16, b"123456789") == 0xfee8);
+const _: () = assert!(crc_software(CrcPolynomial::Crc16Ccitt, b"123456789") == 0x31c3);
+
+// CRC演算器の初期化
+pub fn crc_init(p: &pac::Peripherals, poly: CrcPolynomial) {
+    // CRC演算器のモジュールストップ状態を解除
+    p.MSTP.mstpcrc().modify(|_r, w| w.mstpc1()._0());
+
+    p.CRC.crccr0().write(|w| {
+        match poly {
+            CrcPolynomial::Crc8 => w.gps()._001(),
+            CrcPolynomial::Crc16 => w.gps()._010(),
+            CrcPolynomial::Crc16Ccitt => w.gps()._011(),
+        };
+        w.lms()._1(); // MSBファースト
+        w.dorclr()._1() // CRCDORレジスタをクリア
+    });
+}
+
+// CRC演算器でデーターのCRCを計算する
+// crc_init()で設定した生成多項式を使う
+pub fn crc_compute(p: &pac::Peripherals, data: &[u8]) -> u32 {
+    // CRCDORレジスタをクリアして初期値を0にする
+    p.CRC.crccr0().modify(|_r, w| w.dorclr()._1());
+
+    for &b in data {
+        p.CRC.crcdir_by().write(|w| unsafe { w.bits(b) });
+    }
+
+    // 8ビットCRCは CRCDOR_BY に, 16ビットCRCは CRCDOR_HA に結果が入る
+    if p.CRC.crccr0().read().gps().is_001() {
+        p.CRC.crcdor_by().read().bits() as u32
+    } else {
+        p.CRC.crcdor_ha().read().bits() as u32
+    }
+}
@@ -0,0 +1,267 @@
+// hello-ra4m1
+// データフラッシュの読み書き
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::clock::ClockConfig;
+use core::ops::Range;
+
+// データフラッシュの読み出しアドレス
+const DATAFLASH_READ_BASE: usize = 0x4010_0000;
+// プログラム/イレーズ時に指定するデータフラッシュのアドレス
+const DATAFLASH_PE_BASE: u32 = 0xfe00_0000;
+// データフラッシュの容量(バイト)
+pub const DATAFLASH_SIZE: usize = 8 * 1024;
+// イレーズ単位のブロックの大きさ(バイト)
+pub const DATAFLASH_BLOCK_SIZE: usize = 1024;
+// ブロックの数
+pub const DATAFLASH_BLOCKS: usize = DATAFLASH_SIZE / DATAFLASH_BLOCK_SIZE;
+
+// データフラッシュのブロックの割り当て
+// ブロックを使う所はここの値を使って、割り当てが重ならないようにする
+pub const TEMP_LOG_BLOCKS: Range<usize> = 0..5; // 温度の記録(temp_log)
+pub const TEMP_UNIT_BLOCK: usize = 5; // 温度の表示単位(uart)
+pub const BOOT_COUNT_BLOCKS: Range<usize> = 6..8; // 起動回数(boot_count)
+
+const _: () = assert!(TEMP_LOG_BLOCKS.end <= TEMP_UNIT_BLOCK);
+const _: () = assert!(TEMP_UNIT_BLOCK < BOOT_COUNT_BLOCKS.start);
+const _: () = assert!(BOOT_COUNT_BLOCKS.end <= DATAFLASH_BLOCKS);
+
+// フラッシュ制御レジスタ
+// PACに無いのでユーザーズマニュアルの値を使う
+const FLCN_BASE: usize = 0x407e_c000;
+const DFLCTL: usize = FLCN_BASE + 0x090; // データフラッシュコントロールレジスタ
+const FPMCR: usize = FLCN_BASE + 0x100; // フラッシュP/Eモード制御レジスタ
+const FASR: usize = FLCN_BASE + 0x104; // フラッシュ領域選択レジスタ
+const FSARL: usize = FLCN_BASE + 0x108; // フラッシュ処理開始アドレスレジスタL
+const FSARH: usize = FLCN_BASE + 0x110; // フラッシュ処理開始アドレスレジスタH
+const FCR: usize = FLCN_BASE + 0x114; // フラッシュ制御レジスタ
+const FEARL: usize = FLCN_BASE + 0x118; // フラッシュ処理終了アドレスレジスタL
+const FEARH: usize = FLCN_BASE + 0x120; // フラッシュ処理終了アドレスレジスタH
+const FRESETR: usize = FLCN_BASE + 0x124; // フラッシュリセットレジスタ
+const FSTATR00: usize = FLCN_BASE + 0x128; // フラッシュステータスレジスタ00
+const FSTATR1: usize = FLCN_BASE + 0x12c; // フラッシュステータスレジスタ1
+const FWBL0: usize = FLCN_BASE + 0x130; // フラッシュ書き込みバッファレジスタL0
+const FPR: usize = FLCN_BASE + 0x180; // 保護解除レジスタ
+const FISR: usize = FLCN_BASE + 0x1d8; // フラッシュ初期設定レジスタ
+const FENTRYR: usize = FLCN_BASE + 0x3fb0; // フラッシュP/Eモードエントリレジスタ
+
+// FPMCRレジスタの値
+const FPMCR_READ_MODE: u8 = 0x08; // リードモード
+const FPMCR_DATAFLASH_PE_MODE: u8 = 0x10; // データフラッシュP/Eモード
+
+// FENTRYRレジスタの値(上位バイトはキーコード)
+const FENTRYR_READ_MODE: u16 = 0xaa00;
+const FENTRYR_DATAFLASH_PE_MODE: u16 = 0xaa80;
+
+// FCRレジスタの値
+const FCR_OPST: u8 = 1 << 7; // 処理開始
+const FCR_CMD_PROGRAM: u8 = 0x01; // プログラム
+const FCR_CMD_BLOCK_ERASE: u8 = 0x04; // ブロックイレーズ
+
+// FSTATR1レジスタのFRDYビット(処理完了)
+const FSTATR1_FRDY: u8 = 1 << 6;
+
+// FSTATR00レジスタのエラービット
+const FSTATR00_ERERR: u8 = 1 << 0; // イレーズエラー
+const FSTATR00_PRGERR: u8 = (1 << 1) | (1 << 2); // プログラムエラー
+const FSTATR00_ILGLERR: u8 = 1 << 4; // 不正コマンドエラー
+
+// データフラッシュ操作のエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFlashError {
+    OutOfRange,           // データフラッシュの範囲外
+    CrossesBlockBoundary, // 書き込みがブロックの境界をまたぐ
+    EraseFailed,          // イレーズ失敗
+    ProgramFailed,        // プログラム失敗
+    Timeout,              // フラッシュシーケンサの処理やモード遷移が終わらない
+}
+
+fn read8(addr: usize) -> u8 {
+    unsafe { core::ptr::read_volatile(addr as *const u8) }
+}
+
+fn write8(addr: usize, value: u8) {
+    unsafe { core::ptr::write_volatile(addr as *mut u8, value) };
+}
+
+fn read16(addr: usize) -> u16 {
+    unsafe { core::ptr::read_volatile(addr as *const u16) }
+}
+
+fn write16(addr: usize, value: u16) {
+    unsafe { core::ptr::write_volatile(addr as *mut u16, value) };
+}
+
+// マイクロ秒待つ
+fn delay_us(clocks: &ClockConfig, us: u32) {
+    cortex_m::asm::delay(clocks.iclk / 1_000_000 * us);
+}
+
+// 状態の確認を諦めるまでの時間(マイクロ秒)
+// ブロックイレーズの最大時間より十分長くする
+const DATAFLASH_TIMEOUT_US: u32 = 1_000_000;
+
+// cond()が成り立つまで1マイクロ秒ごとに確かめる
+// DATAFLASH_TIMEOUT_US経っても成り立たなければErr(DataFlashError::Timeout)
+fn spin_until(clocks: &ClockConfig, mut cond: impl FnMut() -> bool) -> Result<(), DataFlashError> {
+    for _ in 0..DATAFLASH_TIMEOUT_US {
+        if cond() {
+            return Ok(());
+        }
+        delay_us(clocks, 1);
+    }
+    Err(DataFlashError::Timeout)
+}
+
+// FPMCRレジスタに書き込む
+// 保護解除レジスタに0xa5を書いた直後に 値, 反転値, 値 の順に書く
+fn fpmcr_write(value: u8) {
+    write8(FPR, 0xa5);
+    write8(FPMCR, value);
+    write8(FPMCR, !value);
+    write8(FPMCR, value);
+}
+
+// データフラッシュP/Eモードにする
+fn pe_mode_enter(clocks: &ClockConfig) -> Result<(), DataFlashError> {
+    write16(FENTRYR, FENTRYR_DATAFLASH_PE_MODE);
+    spin_until(clocks, || read16(FENTRYR) == FENTRYR_DATAFLASH_PE_MODE)?; // 確認
+
+    fpmcr_write(FPMCR_DATAFLASH_PE_MODE);
+    // フラッシュ処理にはFCLKの周波数(MHz) - 1 を設定する
+    write8(FISR, ((clocks.fclk / 1_000_000 - 1) & 0x1f) as u8);
+    // データフラッシュの領域を選ぶ
+    write8(FASR, 0);
+    // モード遷移待ち(tDSTOP)
+    delay_us(clocks, 1);
+    Ok(())
+}
+
+// リードモードに戻す
+fn pe_mode_exit(clocks: &ClockConfig) -> Result<(), DataFlashError> {
+    fpmcr_write(FPMCR_READ_MODE);
+    // モード遷移待ち(tMS)
+    delay_us(clocks, 15);
+
+    write16(FENTRYR, FENTRYR_READ_MODE);
+    spin_until(clocks, || read16(FENTRYR) == 0) // 確認
+}
+
+// フラッシュシーケンサをリセットしてエラー状態から復帰する
+fn sequencer_reset() {
+    write8(FRESETR, 1);
+    write8(FRESETR, 0);
+}
+
+// FCRレジスタにコマンドを書いて完了を待つ
+// 戻り値はFSTATR00レジスタの値
+fn execute(clocks: &ClockConfig, cmd: u8) -> Result<u8, DataFlashError> {
+    write8(FCR, FCR_OPST | cmd);
+    let done = spin_until(clocks, || read8(FSTATR1) & FSTATR1_FRDY != 0); // 処理完了待ち
+    write8(FCR, 0);
+    if done.is_err() {
+        // 終わらない処理は打ち切る
+        sequencer_reset();
+        return Err(DataFlashError::Timeout);
+    }
+    spin_until(clocks, || read8(FSTATR1) & FSTATR1_FRDY == 0)?; // FRDYビットが落ちるのを待つ
+
+    let status = read8(FSTATR00);
+    if status & (FSTATR00_ERERR | FSTATR00_PRGERR | FSTATR00_ILGLERR) != 0 {
+        // エラー状態からの復帰
+        sequencer_reset();
+    }
+    Ok(status)
+}
+
+// 処理開始アドレスを設定する
+fn set_start_address(offset: usize) {
+    let addr = DATAFLASH_PE_BASE + offset as u32;
+    write16(FSARH, (addr >> 16) as u16);
+    write16(FSARL, addr as u16);
+}
+
+// 処理終了アドレスを設定する
+fn set_end_address(offset: usize) {
+    let addr = DATAFLASH_PE_BASE + offset as u32;
+    write16(FEARH, (addr >> 16) as u16);
+    write16(FEARL, addr as u16);
+}
+
+// データフラッシュの読み出しを許可する
+pub fn dataflash_init(clocks: &ClockConfig) {
+    write8(DFLCTL, 1); // DFLENビット
+    // データフラッシュ停止解除待ち(tDSTOP)
+    delay_us(clocks, 1);
+}
+
+// データフラッシュの先頭から offset バイトの位置を buf に読み出す
+pub fn dataflash_read(offset: usize, buf: &mut [u8]) -> Result<(), DataFlashError> {
+    if offset + buf.len() > DATAFLASH_SIZE {
+        return Err(DataFlashError::OutOfRange);
+    }
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = read8(DATAFLASH_READ_BASE + offset + i);
+    }
+    Ok(())
+}
+
+// データフラッシュの先頭から offset バイトの位置に data を書き込む
+// 書き込み先はdataflash_erase()で消去しておくこと
+// 1回の書き込みは1つのブロックの中に収めること
+pub fn dataflash_write(
+    clocks: &ClockConfig,
+    offset: usize,
+    data: &[u8],
+) -> Result<(), DataFlashError> {
+    if offset + data.len() > DATAFLASH_SIZE {
+        return Err(DataFlashError::OutOfRange);
+    }
+    if data.is_empty() {
+        return Ok(());
+    }
+    if offset / DATAFLASH_BLOCK_SIZE != (offset + data.len() - 1) / DATAFLASH_BLOCK_SIZE {
+        return Err(DataFlashError::CrossesBlockBoundary);
+    }
+
+    // データフラッシュは1バイト単位で書き込む
+    let result = pe_mode_enter(clocks).and_then(|()| {
+        data.iter().enumerate().try_for_each(|(i, &b)| {
+            set_start_address(offset + i);
+            write16(FWBL0, b as u16);
+            let status = execute(clocks, FCR_CMD_PROGRAM)?;
+            if status & (FSTATR00_PRGERR | FSTATR00_ILGLERR) != 0 {
+                Err(DataFlashError::ProgramFailed)
+            } else {
+                Ok(())
+            }
+        })
+    });
+    // 失敗してもリードモードに戻す
+    let exited = pe_mode_exit(clocks);
+    result.and(exited)
+}
+
+// データフラッシュの block 番目のブロックを消去する
+pub fn dataflash_erase(clocks: &ClockConfig, block: usize) -> Result<(), DataFlashError> {
+    if block >= DATAFLASH_BLOCKS {
+        return Err(DataFlashError::OutOfRange);
+    }
+
+    let start = block * DATAFLASH_BLOCK_SIZE;
+    let result = pe_mode_enter(clocks).and_then(|()| {
+        set_start_address(start);
+        set_end_address(start + DATAFLASH_BLOCK_SIZE - 1);
+        let status = execute(clocks, FCR_CMD_BLOCK_ERASE)?;
+        if status & (FSTATR00_ERERR | FSTATR00_ILGLERR) != 0 {
+            Err(DataFlashError::EraseFailed)
+        } else {
+            Ok(())
+        }
+    });
+    // 失敗してもリードモードに戻す
+    let exited = pe_mode_exit(clocks);
+    result.and(exited)
+}
@@ -0,0 +1,157 @@
+// hello-ra4m1
+// データフラッシュのブロックを順に使って、固定長の記録を書き足していくリングバッファ
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::clock::ClockConfig;
+use crate::dataflash::{
+    DATAFLASH_BLOCK_SIZE, DATAFLASH_BLOCKS, DataFlashError, dataflash_erase, dataflash_read,
+    dataflash_write,
+};
+use core::ops::Range;
+
+// N バイトの記録を blocks のブロックに順に書いていくリングバッファ
+// 記録の中身(通し番号, 壊れていないか)は使う側が決める
+//
+// 消去は1ブロック先に済ませておく(erase-ahead)
+// ブロックの先頭に書いたら、その次のブロックを消去する(一番古い記録が消える)
+// 書く位置は常に消去済みなので、書き込みの途中で電源が切れても消えるのは書きかけの1件だけになる
+// 消去済みのブロックを1つ残すので、読み出せる記録は (ブロック数 - 1) × ブロックの記録の数 以上になる
+//
+// 事前にdataflash_init()を呼ぶこと
+pub struct DataFlashRing<const N: usize> {
+    first_block: usize, // 先頭のブロック
+    blocks: usize,      // ブロックの数
+    head: usize,        // 次に書く位置
+    fresh: bool,        // open()の後でまだ書いていない
+    ahead_dirty: bool,  // 次のブロックに記録が残っている(消去が済んでいない)
+}
+
+impl<const N: usize> DataFlashRing<N> {
+    // 1ブロックの記録の数
+    pub const RECORDS_PER_BLOCK: usize = {
+        assert!(N > 0 && DATAFLASH_BLOCK_SIZE.is_multiple_of(N));
+        DATAFLASH_BLOCK_SIZE / N
+    };
+
+    // blocks のブロックの記録を調べて、続きから書けるようにする
+    // seq_of は書き込み済みの記録なら通し番号を返し、消去後の不定な値や書きかけならNoneを返す
+    // 通し番号が最大の記録を最新として、その次の位置から書く
+    // 戻り値の2つ目は最新の記録の通し番号
+    pub fn open(
+        blocks: Range<usize>,
+        seq_of: impl Fn(&[u8; N]) -> Option<u32>,
+    ) -> (Self, Option<u32>) {
+        assert!(blocks.len() >= 2 && blocks.end <= DATAFLASH_BLOCKS);
+        let mut ring = DataFlashRing {
+            first_block: blocks.start,
+            blocks: blocks.len(),
+            head: 0,
+            fresh: true,
+            ahead_dirty: false,
+        };
+        let latest = (0..ring.capacity())
+            .filter_map(|slot| {
+                ring.read(slot)
+                    .and_then(|buf| seq_of(&buf))
+                    .map(|seq| (slot, seq))
+            })
+            .max_by_key(|&(_slot, seq)| seq);
+        if let Some((slot, _seq)) = latest {
+            ring.head = (slot + 1) % ring.capacity();
+        }
+        // 前回の消去が済んでいるか確かめる
+        let ahead = ring.next_block_start(ring.head);
+        ring.ahead_dirty = (ahead..ahead + Self::RECORDS_PER_BLOCK)
+            .any(|slot| ring.read(slot).and_then(|buf| seq_of(&buf)).is_some());
+        (ring, latest.map(|(_slot, seq)| seq))
+    }
+
+    // 記録できる数
+    pub const fn capacity(&self) -> usize {
+        self.blocks * Self::RECORDS_PER_BLOCK
+    }
+
+    // slot 番目の記録のデータフラッシュの先頭からの位置
+    const fn offset(&self, slot: usize) -> usize {
+        self.first_block * DATAFLASH_BLOCK_SIZE + slot * N
+    }
+
+    // slot 番目の記録がある次のブロックの先頭の位置
+    const fn next_block_start(&self, slot: usize) -> usize {
+        (slot / Self::RECORDS_PER_BLOCK + 1) % self.blocks * Self::RECORDS_PER_BLOCK
+    }
+
+    // slot 番目の記録があるブロックを消去する
+    fn erase_block_of(&self, clocks: &ClockConfig, slot: usize) -> Result<(), DataFlashError> {
+        dataflash_erase(clocks, self.first_block + slot / Self::RECORDS_PER_BLOCK)
+    }
+
+    // slot 番目の記録(中身は確かめない)
+    pub fn read(&self, slot: usize) -> Option<[u8; N]> {
+        let mut buf = [0u8; N];
+        dataflash_read(self.offset(slot), &mut buf).ok()?;
+        Some(buf)
+    }
+
+    // slot 番目に記録を書いて、読み直して確かめる
+    fn write(
+        &self,
+        clocks: &ClockConfig,
+        slot: usize,
+        record: &[u8; N],
+    ) -> Result<(), DataFlashError> {
+        dataflash_write(clocks, self.offset(slot), record)?;
+        if self.read(slot).as_ref() == Some(record) {
+            Ok(())
+        } else {
+            Err(DataFlashError::ProgramFailed)
+        }
+    }
+
+    // 記録を書き足して、書いた位置を返す
+    pub fn append(
+        &mut self,
+        clocks: &ClockConfig,
+        record: &[u8; N],
+    ) -> Result<usize, DataFlashError> {
+        let mut slot = self.head;
+        let block_start = slot.is_multiple_of(Self::RECORDS_PER_BLOCK);
+        if self.fresh && block_start {
+            // 起動して最初にブロックの先頭に書く時は、消去が済んでいるか分からないので消去しておく
+            // このブロックに最新の記録は無い
+            self.erase_block_of(clocks, slot)?;
+        }
+        if block_start {
+            // 消去済みのブロックの先頭に書けなければ諦める
+            self.write(clocks, slot, record)?;
+        } else {
+            match self.write(clocks, slot, record) {
+                Err(DataFlashError::ProgramFailed) => {
+                    // 消去されていない位置だった(前回の書きかけなど)
+                    // 次のブロックを消去してその先頭に書く
+                    slot = self.next_block_start(slot);
+                    self.erase_block_of(clocks, slot)?;
+                    self.write(clocks, slot, record)?;
+                }
+                result => result?,
+            }
+        }
+        self.head = (slot + 1) % self.capacity();
+        self.fresh = false;
+
+        // 書いた後で次のブロックを消去するので、最新の記録は消えない
+        if slot.is_multiple_of(Self::RECORDS_PER_BLOCK) || self.ahead_dirty {
+            self.erase_block_of(clocks, self.next_block_start(slot))?;
+            self.ahead_dirty = false;
+        }
+        Ok(slot)
+    }
+
+    // 全ての位置の記録を古い順に(中身は確かめない)
+    pub fn oldest_first(&self) -> impl Iterator<Item = [u8; N]> + '_ {
+        let head = self.head;
+        (0..self.capacity()).filter_map(move |i| self.read((head + i) % self.capacity()))
+    }
+}
@@ -0,0 +1,75 @@
+// hello-ra4m1
+// イベントリンクコントローラ(ELC)
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use ra4m1_fsp_pac as pac;
+
+// イベントを受け取る周辺機能
+// 値はイベントリンク設定レジスタ(ELSRn)の番号
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElcPeripheral {
+    GptA = 0,         // GPT (ELC_GPTA)
+    GptB = 1,         // GPT (ELC_GPTB)
+    GptC = 2,         // GPT (ELC_GPTC)
+    GptD = 3,         // GPT (ELC_GPTD)
+    Adc140 = 8,       // ADC140 A/D変換開始(ELC_AD00)
+    Adc140GroupB = 9, // ADC140 グループBのA/D変換開始(ELC_AD01)
+    Dac12 = 12,       // DAC12 D/A変換開始
+}
+
+// GPTのイベント番号(ICUのIELSRn, ELCのELSRnに書く値)
+// GPTは1チャネルあたり8個のイベントを持っていて、GPT0から順に並んでいる
+// GPTn_CAPTURE_COMPARE_A, B, COMPARE_C, D, E, F, COUNTER_OVERFLOW, COUNTER_UNDERFLOW
+const GPT_EVENT_BASE: u8 = 0x057;
+const GPT_EVENTS_PER_CHANNEL: u8 = 8;
+
+// GPTn_CAPTURE_COMPARE_A のイベント番号
+pub const fn gpt_capture_compare_a_event(channel: u8) -> u8 {
+    GPT_EVENT_BASE + GPT_EVENTS_PER_CHANNEL * channel
+}
+
+// GPTn_CAPTURE_COMPARE_B のイベント番号
+pub const fn gpt_capture_compare_b_event(channel: u8) -> u8 {
+    gpt_capture_compare_a_event(channel) + 1
+}
+
+// GPTn_COUNTER_OVERFLOW のイベント番号
+pub const fn gpt_counter_overflow_event(channel: u8) -> u8 {
+    gpt_capture_compare_a_event(channel) + 6
+}
+
+// GPT320(GPT0), GPT321(GPT1) のカウンタオーバーフロー
+pub const GPT0_COUNTER_OVERFLOW_EVENT: u8 = gpt_counter_overflow_event(0);
+pub const GPT1_COUNTER_OVERFLOW_EVENT: u8 = gpt_counter_overflow_event(1);
+// GPT321(GPT1) のインプットキャプチャB
+pub const GPT1_CAPTURE_COMPARE_B_EVENT: u8 = gpt_capture_compare_b_event(1);
+
+const _: () = assert!(GPT0_COUNTER_OVERFLOW_EVENT == 0x05d);
+const _: () = assert!(GPT1_CAPTURE_COMPARE_B_EVENT == 0x060);
+const _: () = assert!(GPT1_COUNTER_OVERFLOW_EVENT == 0x065);
+
+// イベントリンクコントローラの動作を開始する
+fn elc_enable(p: &pac::Peripherals) {
+    // ELCモジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrc().modify(|_r, w| w.mstpc14()._0());
+    // すべてのイベントリンクを有効にする
+    p.ELC.elcr().write(|w| w.elcon()._1());
+}
+
+// イベント番号 source_event のイベントが発生したら dest の周辺機能を動作させる
+// イベント番号はICUのIELSRnレジスタに書く値と同じ
+pub fn elc_link(p: &pac::Peripherals, source_event: u8, dest: ElcPeripheral) {
+    elc_enable(p);
+    p.ELC
+        .elsr(dest as usize)
+        .write(|w| unsafe { w.els().bits(source_event) });
+}
+
+// dest の周辺機能へのイベントリンクを解除する
+pub fn elc_unlink(p: &pac::Peripherals, dest: ElcPeripheral) {
+    p.ELC
+        .elsr(dest as usize)
+        .write(|w| unsafe { w.els().bits(0) });
+}
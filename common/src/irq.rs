@@ -0,0 +1,53 @@
+// hello-ra4m1
+// 割り込み(IELn)の優先度
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use cortex_m::peripheral::NVIC;
+use ra4m1_fsp_pac as pac;
+
+// 割り込み優先度
+// RA4M1のNVICは優先度レジスタの上位4ビットだけが有効で、0(最高)から15(最低)までの16段階
+// 優先度の高い割り込みは低い割り込みハンドラの実行中でも割り込む
+//
+// 既定の並び
+// シリアル受信は次の文字が来るまでに読まないとオーバーランエラー(ORER)になるので最も高くする
+// タイマーは1回ぐらい遅れても周期が崩れないので最も低くする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IrqPriority {
+    SciReceive = 1,  // SCI 受信(RXI), 受信エラー(ERI)
+    SciTransmit = 3, // SCI 送信(TXI), 送信終了(TEI)
+    Adc = 4,         // A/D変換終了
+    Timer = 5,       // GPT オーバーフロー, インプットキャプチャ
+}
+
+impl IrqPriority {
+    // NVICの優先度レジスタに書く値
+    pub const fn level(self) -> u8 {
+        (self as u8) << (8 - pac::NVIC_PRIO_BITS)
+    }
+}
+
+// 優先度レジスタには4ビットの段階が上位に寄せて入る
+const _: () = assert!(IrqPriority::SciReceive.level() == 0x10);
+const _: () = assert!(IrqPriority::Timer.level() == 0x50);
+
+// 割り込みの優先度を設定する
+pub fn set_irq_priority(iel: pac::Interrupt, priority: IrqPriority) {
+    // 優先度レジスタに書くだけなので、NVICの所有権は取らない
+    let mut nvic = unsafe { cortex_m::Peripherals::steal() }.NVIC;
+    unsafe { nvic.set_priority(iel, priority.level()) };
+}
+
+// 割り込みの優先度を設定してから許可する
+pub fn unmask_with_priority(iel: pac::Interrupt, priority: IrqPriority) {
+    set_irq_priority(iel, priority);
+    unsafe { NVIC::unmask(iel) };
+}
+
+// 今の割り込み優先度(0から15)
+pub fn irq_priority(iel: pac::Interrupt) -> u8 {
+    NVIC::get_priority(iel) >> (8 - pac::NVIC_PRIO_BITS)
+}
@@ -0,0 +1,32 @@
+// hello-ra4m1
+// サンプルプログラムで共通に使う部品
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+#![no_std]
+
+pub mod board;
+pub mod boot_count;
+pub mod can;
+pub mod clock;
+pub mod cobs;
+pub mod crc;
+pub mod dataflash;
+pub mod dataflash_ring;
+pub mod elc;
+pub mod irq;
+pub mod lvd;
+pub mod mstp;
+#[cfg(feature = "panic-sos")]
+mod panic_sos;
+pub mod pfs;
+pub mod port;
+pub mod reset;
+pub mod rgb;
+pub mod rtc;
+pub mod signal;
+pub mod temp_log;
+pub mod time;
+pub mod unique_id;
+pub mod ws2812b;
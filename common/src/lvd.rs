@@ -0,0 +1,181 @@
+// hello-ra4m1
+// 電圧検出回路(LVD)
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::clock::ClockConfig;
+use ra4m1_fsp_pac as pac;
+use scopeguard::defer;
+
+// 電圧検出1の検出電圧(Vdet1, VCCが下降する時の値)
+// VCCが5VのボードでUSB給電の電圧降下を見るならV4_29 ～ V3_84を使う(Arduino UNO R4 MINIMA)
+// VCCが3.3Vのボードなら、それより低いV3_10以下を使う(RA4M1-Zero)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LvdThreshold {
+    V4_29, // 4.29V
+    V4_14, // 4.14V
+    V4_02, // 4.02V
+    V3_84, // 3.84V
+    V3_10, // 3.10V
+    V3_00, // 3.00V
+    V2_90, // 2.90V
+    V2_79, // 2.79V
+    V2_68, // 2.68V
+    V2_58, // 2.58V
+    V2_48, // 2.48V
+    V2_20, // 2.20V
+    V1_96, // 1.96V
+    V1_86, // 1.86V
+    V1_75, // 1.75V
+    V1_65, // 1.65V
+}
+
+impl LvdThreshold {
+    // LVDLVLRレジスタのLVD1LVLビットの値
+    const fn bits(self) -> u8 {
+        self as u8
+    }
+
+    // 検出電圧(mV)
+    pub const fn millivolts(self) -> u16 {
+        match self {
+            LvdThreshold::V4_29 => 4290,
+            LvdThreshold::V4_14 => 4140,
+            LvdThreshold::V4_02 => 4020,
+            LvdThreshold::V3_84 => 3840,
+            LvdThreshold::V3_10 => 3100,
+            LvdThreshold::V3_00 => 3000,
+            LvdThreshold::V2_90 => 2900,
+            LvdThreshold::V2_79 => 2790,
+            LvdThreshold::V2_68 => 2680,
+            LvdThreshold::V2_58 => 2580,
+            LvdThreshold::V2_48 => 2480,
+            LvdThreshold::V2_20 => 2200,
+            LvdThreshold::V1_96 => 1960,
+            LvdThreshold::V1_86 => 1860,
+            LvdThreshold::V1_75 => 1750,
+            LvdThreshold::V1_65 => 1650,
+        }
+    }
+}
+
+// VCCが検出電圧を下回った時の動作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LvdAction {
+    Reset,     // 電圧監視1リセット(VCCが検出電圧を上回ってから解除される)
+    Interrupt, // ノンマスカブル割り込み(NMI)
+}
+
+// 電圧検出1の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LvdStatus {
+    pub below_threshold: bool, // VCCが検出電圧より低い
+    pub detected: bool,        // lvd_clear()してからVCCが検出電圧を下回ったことがある
+}
+
+// 電圧検出回路の動作開始待ち時間(td(E-A), マイクロ秒)
+const LVD_ENABLE_WAIT_US: u32 = 300;
+
+// 電圧検出1の設定
+// VCCが threshold を下回ると action の動作をする
+// Interrupt の場合はNMIハンドラでlvd_nmi_clear()を呼ぶこと
+pub fn lvd_init(
+    p: &pac::Peripherals,
+    clocks: &ClockConfig,
+    threshold: LvdThreshold,
+    action: LvdAction,
+) {
+    // 保護レジスタを操作して書込み許可を与える
+    p.SYSTEM.prcr().write(|w| {
+        w.prkey().set(0xa5);
+        w.prc3().set_bit() // 電圧検出回路関連レジスタに書込み許可を与える
+    });
+    // 関数脱出時に保護レジスタを元通りに復帰する
+    defer! {
+        p.SYSTEM.prcr().write(|w| {
+            w.prkey().set(0xa5);
+            w.prc3().clear_bit()
+        });
+    }
+
+    // 設定中は電圧検出1を止める
+    p.SYSTEM.lvd1cr0().modify(|_r, w| {
+        w.rie()._0(); // 割り込み/リセット禁止
+        w.cmpe()._0() // 比較結果出力禁止
+    });
+    p.SYSTEM.lvcmpcr().modify(|_r, w| w.lvd1e()._0());
+
+    // 検出電圧
+    p.SYSTEM
+        .lvdlvlr()
+        .modify(|_r, w| w.lvd1lvl().set(threshold.bits()));
+
+    // VCCが下降して検出電圧を下回った時に検出する
+    // 割り込みはノンマスカブル割り込み(NMI)にする
+    p.SYSTEM.lvd1cr1().write(|w| {
+        w.idtsel()._01();
+        w.irqsel()._0()
+    });
+
+    // 割り込みかリセットか
+    p.SYSTEM.lvd1cr0().modify(|_r, w| match action {
+        LvdAction::Reset => {
+            w.ri()._1();
+            w.rn()._0() // VCCが検出電圧を上回ってからリセットを解除する
+        }
+        LvdAction::Interrupt => w.ri()._0(),
+    });
+
+    if action == LvdAction::Interrupt {
+        // NMIの要因として電圧監視1割り込みを許可する(一度書いたら変更できない)
+        p.ICU.nmier().modify(|_r, w| w.lvd1en()._1());
+    }
+
+    // 電圧検出1を動作させる
+    p.SYSTEM.lvcmpcr().modify(|_r, w| w.lvd1e()._1());
+    cortex_m::asm::delay(clocks.iclk / 1_000_000 * LVD_ENABLE_WAIT_US);
+    p.SYSTEM.lvd1cr0().modify(|_r, w| w.cmpe()._1()); // 比較結果出力許可
+
+    // 検出フラグを下ろしてから割り込み/リセットを許可する
+    p.SYSTEM.lvd1sr().modify(|_r, w| w.det()._0());
+    p.SYSTEM.lvd1cr0().modify(|_r, w| w.rie()._1());
+}
+
+// 電圧検出1の状態
+pub fn lvd_status(p: &pac::Peripherals) -> LvdStatus {
+    let r = p.SYSTEM.lvd1sr().read();
+    LvdStatus {
+        below_threshold: r.mon().is_0(),
+        detected: r.det().is_1(),
+    }
+}
+
+// 電圧検出1の検出フラグを下ろす
+pub fn lvd_clear(p: &pac::Peripherals) {
+    // 保護レジスタを操作して書込み許可を与える
+    p.SYSTEM.prcr().write(|w| {
+        w.prkey().set(0xa5);
+        w.prc3().set_bit() // 電圧検出回路関連レジスタに書込み許可を与える
+    });
+    // 関数脱出時に保護レジスタを元通りに復帰する
+    defer! {
+        p.SYSTEM.prcr().write(|w| {
+            w.prkey().set(0xa5);
+            w.prc3().clear_bit()
+        });
+    }
+
+    p.SYSTEM.lvd1sr().modify(|_r, w| w.det()._0());
+}
+
+// NMIの要因が電圧監視1割り込みならフラグを下ろしてtrueを返す
+// NMIハンドラから呼ぶ
+pub fn lvd_nmi_clear(p: &pac::Peripherals) -> bool {
+    if p.ICU.nmisr().read().lvd1st().bit_is_clear() {
+        return false;
+    }
+    lvd_clear(p);
+    p.ICU.nmiclr().write(|w| w.lvd1clr()._1());
+    true
+}
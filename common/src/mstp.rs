@@ -0,0 +1,63 @@
+// hello-ra4m1
+// モジュールストップ(周辺機能へのクロックの供給と停止)
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use ra4m1_fsp_pac as pac;
+
+// モジュールストップで止められる周辺機能
+// 1つのビットで複数のチャネルを止めるものは、まとめて止まる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peripheral {
+    Sci0,  // SCI0 (MSTPCRB.MSTPB31)
+    Sci1,  // SCI1 (MSTPCRB.MSTPB30)
+    Can0,  // CAN0 (MSTPCRB.MSTPB2)
+    Crc,   // CRC演算器 (MSTPCRC.MSTPC1)
+    Elc,   // イベントリンクコントローラ (MSTPCRC.MSTPC14)
+    Adc,   // 14ビットA/Dコンバーター ADC140 (MSTPCRD.MSTPD16)
+    Dac,   // 12ビットD/Aコンバーター DAC12 (MSTPCRD.MSTPD20)
+    Gpt32, // 32ビットGPT GPT320, GPT321 (MSTPCRD.MSTPD5)
+    Gpt16, // 16ビットGPT GPT162～GPT167 (MSTPCRD.MSTPD6)
+}
+
+// 周辺機能をモジュールストップ状態にしてクロックの供給を止める
+// 止めている間はその周辺機能のレジスタを読み書きできないので、
+// 割り込みや動作を止めてから呼び、再び使う時は初期化からやり直すこと
+pub fn module_stop(p: &pac::Peripherals, peripheral: Peripheral) {
+    set_module_stop(p, peripheral, true);
+}
+
+// 周辺機能のモジュールストップ状態を解除してクロックを供給する
+pub fn module_start(p: &pac::Peripherals, peripheral: Peripheral) {
+    set_module_stop(p, peripheral, false);
+}
+
+fn set_module_stop(p: &pac::Peripherals, peripheral: Peripheral, stop: bool) {
+    match peripheral {
+        Peripheral::Sci0 => p.MSTP.mstpcrb().modify(|_r, w| w.mstpb31().bit(stop)),
+        Peripheral::Sci1 => p.MSTP.mstpcrb().modify(|_r, w| w.mstpb30().bit(stop)),
+        Peripheral::Can0 => p.MSTP.mstpcrb().modify(|_r, w| w.mstpb2().bit(stop)),
+        Peripheral::Crc => p.MSTP.mstpcrc().modify(|_r, w| w.mstpc1().bit(stop)),
+        Peripheral::Elc => p.MSTP.mstpcrc().modify(|_r, w| w.mstpc14().bit(stop)),
+        Peripheral::Adc => p.MSTP.mstpcrd().modify(|_r, w| w.mstpd16().bit(stop)),
+        Peripheral::Dac => p.MSTP.mstpcrd().modify(|_r, w| w.mstpd20().bit(stop)),
+        Peripheral::Gpt32 => p.MSTP.mstpcrd().modify(|_r, w| w.mstpd5().bit(stop)),
+        Peripheral::Gpt16 => p.MSTP.mstpcrd().modify(|_r, w| w.mstpd6().bit(stop)),
+    };
+}
+
+// 周辺機能がモジュールストップ状態ならtrue
+pub fn is_module_stopped(p: &pac::Peripherals, peripheral: Peripheral) -> bool {
+    match peripheral {
+        Peripheral::Sci0 => p.MSTP.mstpcrb().read().mstpb31().bit(),
+        Peripheral::Sci1 => p.MSTP.mstpcrb().read().mstpb30().bit(),
+        Peripheral::Can0 => p.MSTP.mstpcrb().read().mstpb2().bit(),
+        Peripheral::Crc => p.MSTP.mstpcrc().read().mstpc1().bit(),
+        Peripheral::Elc => p.MSTP.mstpcrc().read().mstpc14().bit(),
+        Peripheral::Adc => p.MSTP.mstpcrd().read().mstpd16().bit(),
+        Peripheral::Dac => p.MSTP.mstpcrd().read().mstpd20().bit(),
+        Peripheral::Gpt32 => p.MSTP.mstpcrd().read().mstpd5().bit(),
+        Peripheral::Gpt16 => p.MSTP.mstpcrd().read().mstpd6().bit(),
+    }
+}
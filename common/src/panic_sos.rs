@@ -0,0 +1,47 @@
+// hello-ra4m1
+// パニック時にLEDでSOSを点滅させるパニックハンドラ
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::board::BOARD_LEDS;
+use core::panic::PanicInfo;
+use ra4m1_fsp_pac as pac;
+
+// 点滅の1単位時間(CPUクロックのサイクル数)
+// パニック時のクロック設定は分からないので、48MHzとして約200ミリ秒
+const UNIT_CYCLES: u32 = 48_000_000 / 5;
+
+// SOSのモールス符号
+// (点灯時間, 消灯時間) を単位時間で表す
+// 短点は1, 長点は3, 符号の間は1, 文字の間は3, 語の間は7
+const SOS: [(u32, u32); 9] = [
+    (1, 1),
+    (1, 1),
+    (1, 3), // S
+    (3, 1),
+    (3, 1),
+    (3, 3), // O
+    (1, 1),
+    (1, 1),
+    (1, 7), // S
+];
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    let p = unsafe { pac::Peripherals::steal() };
+
+    // ユーザーLEDを出力に設定する
+    let led = BOARD_LEDS.onboard;
+    led.init(&p);
+
+    loop {
+        for (on, off) in SOS {
+            led.on();
+            cortex_m::asm::delay(on * UNIT_CYCLES);
+            led.off();
+            cortex_m::asm::delay(off * UNIT_CYCLES);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// hello-ra4m1
+// 端子機能選択(PmnPFSレジスタ)の設定
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use ra4m1_fsp_pac as pac;
+
+// PmnPFSレジスタのベースアドレス(P000PFS)
+const PFS_BASE: usize = 0x4004_0800;
+
+// PmnPFSレジスタのアドレス
+// PmnPFS = 0x4004_0800 + 0x40 * m + 4 * n
+//
+// PACのp002pfs() ～ p008pfs()はP001PFSと、p011pfs() ～ p014pfs()はP010PFSと同じアドレスを指す
+// (SVDで端子をまとめて定義した部分が同じオフセットに展開されている)
+// なのでPACのアクセサは使わず、ユーザーズマニュアルの式でアドレスを求める
+pub const fn pfs_addr(port: u8, pin: u8) -> usize {
+    assert!(port <= 9 && pin <= 15);
+    PFS_BASE + 0x40 * port as usize + 4 * pin as usize
+}
+
+// ユーザーズマニュアルのアドレスと一致するか確かめる
+const _: () = assert!(pfs_addr(0, 0) == 0x4004_0800);
+const _: () = assert!(pfs_addr(0, 10) == 0x4004_0828);
+const _: () = assert!(pfs_addr(0, 12) == 0x4004_0830);
+const _: () = assert!(pfs_addr(0, 13) == 0x4004_0834);
+const _: () = assert!(pfs_addr(1, 11) == 0x4004_086c);
+const _: () = assert!(pfs_addr(5, 1) == 0x4004_0944);
+const _: () = assert!(pfs_addr(5, 2) == 0x4004_0948);
+
+// PmnPFSレジスタ
+// どの端子のPmnPFSレジスタもビット配置は同じなので、PACのP000PFSの型で読み書きする
+fn pfs_register(port: u8, pin: u8) -> &'static pac::pfs::P000PFS {
+    unsafe { &*(pfs_addr(port, pin) as *const pac::pfs::P000PFS) }
+}
+
+// PwprGuardが入れ子になっている数
+static PWPR_GUARD_DEPTH: AtomicU8 = AtomicU8::new(0);
+
+// 書き込みプロテクトレジスタ(PWPR)の保護解除
+// 作ると PmnPFS レジスタに書き込み許可を与え、捨てると(早期リターンでも)元通りに保護する
+// 入れ子にした場合は一番外側のガードを捨てた時に保護する
+pub struct PwprGuard<'a> {
+    p: &'a pac::Peripherals,
+}
+
+impl<'a> PwprGuard<'a> {
+    pub fn new(p: &'a pac::Peripherals) -> Self {
+        cortex_m::interrupt::free(|_cs| {
+            if PWPR_GUARD_DEPTH.load(Ordering::Relaxed) == 0 {
+                // 書き込みプロテクトレジスタを操作してPmnPFS レジスタに書き込み許可を与える
+                p.PMISC.pwpr().write(|w| w.b0wi()._0());
+                p.PMISC.pwpr().write(|w| w.pfswe()._1());
+            }
+            PWPR_GUARD_DEPTH.store(
+                PWPR_GUARD_DEPTH.load(Ordering::Relaxed) + 1,
+                Ordering::Relaxed,
+            );
+        });
+        PwprGuard { p }
+    }
+}
+
+impl Drop for PwprGuard<'_> {
+    fn drop(&mut self) {
+        cortex_m::interrupt::free(|_cs| {
+            let depth = PWPR_GUARD_DEPTH.load(Ordering::Relaxed) - 1;
+            PWPR_GUARD_DEPTH.store(depth, Ordering::Relaxed);
+            if depth == 0 {
+                // 書き込みプロテクトレジスタを元通りに復帰する
+                self.p.PMISC.pwpr().write(|w| w.pfswe()._0());
+                self.p.PMISC.pwpr().write(|w| w.b0wi()._1());
+            }
+        });
+    }
+}
+
+// 端子の設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PfsConfig {
+    pub psel: Option<u8>,  // 周辺機能(PSELビットの値), Noneなら汎用入出力
+    pub output: bool,      // 出力にする
+    pub output_high: bool, // 出力の初期値をHighにする
+    pub pull_up: bool,     // 入力プルアップを有効にする
+    pub open_drain: bool,  // Nチャネルオープンドレイン出力にする
+    pub irq: bool,         // IRQ入力端子にする
+    pub analog: bool,      // アナログ端子にする
+}
+
+impl PfsConfig {
+    // 汎用入力
+    pub const fn input(pull_up: bool) -> Self {
+        PfsConfig {
+            psel: None,
+            output: false,
+            output_high: false,
+            pull_up,
+            open_drain: false,
+            irq: false,
+            analog: false,
+        }
+    }
+
+    // 汎用出力
+    pub const fn output(high: bool) -> Self {
+        PfsConfig {
+            psel: None,
+            output: true,
+            output_high: high,
+            pull_up: false,
+            open_drain: false,
+            irq: false,
+            analog: false,
+        }
+    }
+
+    // 周辺機能
+    // output: 出力端子ならtrue
+    pub const fn peripheral(psel: u8, output: bool) -> Self {
+        assert!(psel <= 0x1f);
+        PfsConfig {
+            psel: Some(psel),
+            output,
+            output_high: false,
+            pull_up: false,
+            open_drain: false,
+            irq: false,
+            analog: false,
+        }
+    }
+}
+
+// PORT port の pin 番端子を config の通りに設定する
+pub fn pfs_configure(p: &pac::Peripherals, port: u8, pin: u8, config: PfsConfig) {
+    let pfs = pfs_register(port, pin);
+    let _pwpr = PwprGuard::new(p);
+
+    // 周辺機能はPMRビットが0の時にPSELビットを設定してからPMRビットを1にする
+    pfs.write(|w| {
+        unsafe { w.psel().bits(config.psel.unwrap_or(0)) };
+        w.podr().bit(config.output_high);
+        w.pdr().bit(config.output);
+        w.pcr().bit(config.pull_up);
+        w.ncodr().bit(config.open_drain);
+        w.isel().bit(config.irq);
+        w.asel().bit(config.analog)
+    });
+    if config.psel.is_some() {
+        pfs.modify(|_r, w| w.pmr()._1());
+    }
+}
@@ -0,0 +1,58 @@
+// hello-ra4m1
+// 入出力ポート(PORTm)のレジスタをポート番号で読み書きする
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use ra4m1_fsp_pac as pac;
+
+// PORT0のレジスタのベースアドレス
+const PORT_BASE: usize = 0x4004_0000;
+
+// PORTmのレジスタのアドレス
+// PORTm = 0x4004_0000 + 0x20 * m
+const fn port_addr(port: u8) -> usize {
+    assert!(port <= 9);
+    PORT_BASE + 0x20 * port as usize
+}
+
+// ユーザーズマニュアルのアドレスと一致するか確かめる
+const _: () = assert!(port_addr(0) == 0x4004_0000);
+const _: () = assert!(port_addr(1) == 0x4004_0020);
+const _: () = assert!(port_addr(9) == 0x4004_0120);
+
+// PORTmのレジスタ
+// PACではPORT1～PORT4とそれ以外で型が分かれているが、PCNTR1～PCNTR3の配置は同じなので
+// PACのPORT0の型で読み書きする
+fn port_register(port: u8) -> &'static pac::port0::RegisterBlock {
+    unsafe { &*(port_addr(port) as *const pac::port0::RegisterBlock) }
+}
+
+// PORT port の端子の入力(ビットnがn番端子)
+// ポート制御レジスタ2(PCNTR2)の下位16ビットがPIDR
+pub fn port_input(port: u8) -> u16 {
+    port_register(port).pcntr2().read().pidr().bits()
+}
+
+// PORT port の端子の出力データ(ビットnがn番端子)
+// ポート制御レジスタ1(PCNTR1)の上位16ビットがPODR
+pub fn port_output(port: u8) -> u16 {
+    port_register(port).pcntr1().read().podr().bits()
+}
+
+// PORT port の bits の端子をHighにする
+// ポート制御レジスタ3(PCNTR3)の下位16ビットがPOSR(1を書いたビットだけHighになる)
+// 他の端子を読み書きしないので、割り込みハンドラから呼んでも同じPORTの他の端子を壊さない
+pub fn port_set(port: u8, bits: u16) {
+    port_register(port)
+        .pcntr3()
+        .write(|w| unsafe { w.posr().bits(bits) });
+}
+
+// PORT port の bits の端子をLowにする
+// ポート制御レジスタ3(PCNTR3)の上位16ビットがPORR(1を書いたビットだけLowになる)
+pub fn port_reset(port: u8, bits: u16) {
+    port_register(port)
+        .pcntr3()
+        .write(|w| unsafe { w.porr().bits(bits) });
+}
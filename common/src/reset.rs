@@ -0,0 +1,88 @@
+// hello-ra4m1
+// リセット要因の読み出し
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use ra4m1_fsp_pac as pac;
+
+// リセット要因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    PowerOn,             // パワーオンリセット
+    VoltageMonitor0,     // 電圧監視0リセット
+    VoltageMonitor1,     // 電圧監視1リセット
+    VoltageMonitor2,     // 電圧監視2リセット
+    IndependentWatchdog, // 独立ウォッチドッグタイマ(IWDT)リセット
+    Watchdog,            // ウォッチドッグタイマ(WDT)リセット
+    Software,            // ソフトウェアリセット
+    RamParityError,      // SRAMパリティエラーリセット
+    BusSlaveMpu,         // バススレーブMPUエラーリセット
+    BusMasterMpu,        // バスマスタMPUエラーリセット
+    StackPointerError,   // CPUスタックポインタエラーリセット
+    Pin,                 // RES端子リセット
+}
+
+impl ResetCause {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power-on",
+            ResetCause::VoltageMonitor0 => "voltage monitor 0",
+            ResetCause::VoltageMonitor1 => "voltage monitor 1",
+            ResetCause::VoltageMonitor2 => "voltage monitor 2",
+            ResetCause::IndependentWatchdog => "IWDT",
+            ResetCause::Watchdog => "WDT",
+            ResetCause::Software => "software",
+            ResetCause::RamParityError => "RAM parity error",
+            ResetCause::BusSlaveMpu => "bus slave MPU",
+            ResetCause::BusMasterMpu => "bus master MPU",
+            ResetCause::StackPointerError => "SP error",
+            ResetCause::Pin => "RES pin",
+        }
+    }
+}
+
+// リセットステータスレジスタ(RSTSR0/1/2)から前回のリセット要因を読み出して、検出フラグを下ろす
+// 次のリセットの要因と区別できるように、起動後に一度だけ呼ぶ
+// RES端子リセットには検出フラグが無いので、
+// ウォームスタート(RSTSR2.CWSF = 1)でどの検出フラグも立っていなければRES端子リセットとする
+// 複数の検出フラグが立っていたら上に書いた順の要因を返す
+pub fn read_reset_cause(p: &pac::Peripherals) -> ResetCause {
+    let rstsr0 = p.SYSTEM.rstsr0().read();
+    let rstsr1 = p.SYSTEM.rstsr1().read();
+    let cold_start = p.SYSTEM.rstsr2().read().cwsf().is_0();
+
+    let cause = if cold_start || rstsr0.porf().is_1() {
+        ResetCause::PowerOn
+    } else if rstsr0.lvd0rf().is_1() {
+        ResetCause::VoltageMonitor0
+    } else if rstsr0.lvd1rf().is_1() {
+        ResetCause::VoltageMonitor1
+    } else if rstsr0.lvd2rf().is_1() {
+        ResetCause::VoltageMonitor2
+    } else if rstsr1.iwdtrf().is_1() {
+        ResetCause::IndependentWatchdog
+    } else if rstsr1.wdtrf().is_1() {
+        ResetCause::Watchdog
+    } else if rstsr1.swrf().is_1() {
+        ResetCause::Software
+    } else if rstsr1.rperf().is_1() {
+        ResetCause::RamParityError
+    } else if rstsr1.bussrf().is_1() {
+        ResetCause::BusSlaveMpu
+    } else if rstsr1.busmrf().is_1() {
+        ResetCause::BusMasterMpu
+    } else if rstsr1.sperf().is_1() {
+        ResetCause::StackPointerError
+    } else {
+        ResetCause::Pin
+    };
+
+    // 検出フラグは1を読んだ後に0を書いて下ろす
+    p.SYSTEM.rstsr0().write(|w| unsafe { w.bits(0) });
+    p.SYSTEM.rstsr1().write(|w| unsafe { w.bits(0) });
+    // 次にパワーオンリセット以外でリセットされたらウォームスタートになる
+    p.SYSTEM.rstsr2().write(|w| w.cwsf()._1());
+
+    cause
+}
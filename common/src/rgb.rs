@@ -0,0 +1,159 @@
+// hello-ra4m1
+// RGB色
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use core::ops::{Add, Mul, Sub};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rgb<T> {
+    pub r: T,
+    pub g: T,
+    pub b: T,
+}
+
+// ガンマ補正テーブル (ガンマ値 2.2)
+// 255 * (i / 255)^2.2 をコンパイル時に計算する
+pub const GAMMA8: [u8; 256] = {
+    // a^0.2 (五乗根) をニュートン法で求める
+    const fn fifth_root(a: f64) -> f64 {
+        let mut y = 1.0;
+        let mut n = 0;
+        while n < 32 {
+            y = (4.0 * y + a / (y * y * y * y)) / 5.0;
+            n += 1;
+        }
+        y
+    }
+    let mut table = [0u8; 256];
+    let mut i = 1;
+    while i < 256 {
+        let x = i as f64 / 255.0;
+        // x^2.2 = x^2 * x^0.2
+        let y = x * x * fifth_root(x);
+        table[i] = (y * 255.0 + 0.5) as u8;
+        i += 1;
+    }
+    table
+};
+
+impl Rgb<u8> {
+    // 各色をガンマ補正する
+    pub const fn gamma_correct(self) -> Rgb<u8> {
+        Rgb {
+            r: GAMMA8[self.r as usize],
+            g: GAMMA8[self.g as usize],
+            b: GAMMA8[self.b as usize],
+        }
+    }
+
+    // selfからotherへ t/255 の割合で補間する
+    // t = 0 で self, t = 255 で other
+    pub const fn lerp(self, other: Rgb<u8>, t: u8) -> Rgb<u8> {
+        const fn mix(a: u8, b: u8, t: u8) -> u8 {
+            ((a as u16 * (255 - t as u16) + b as u16 * t as u16) / 255) as u8
+        }
+        Rgb {
+            r: mix(self.r, other.r, t),
+            g: mix(self.g, other.g, t),
+            b: mix(self.b, other.b, t),
+        }
+    }
+}
+
+// 各色の飽和加算
+impl Add for Rgb<u8> {
+    type Output = Rgb<u8>;
+
+    fn add(self, rhs: Rgb<u8>) -> Rgb<u8> {
+        Rgb {
+            r: self.r.saturating_add(rhs.r),
+            g: self.g.saturating_add(rhs.g),
+            b: self.b.saturating_add(rhs.b),
+        }
+    }
+}
+
+// 各色の飽和減算
+impl Sub for Rgb<u8> {
+    type Output = Rgb<u8>;
+
+    fn sub(self, rhs: Rgb<u8>) -> Rgb<u8> {
+        Rgb {
+            r: self.r.saturating_sub(rhs.r),
+            g: self.g.saturating_sub(rhs.g),
+            b: self.b.saturating_sub(rhs.b),
+        }
+    }
+}
+
+// 各色の飽和乗算
+impl Mul<u8> for Rgb<u8> {
+    type Output = Rgb<u8>;
+
+    fn mul(self, rhs: u8) -> Rgb<u8> {
+        Rgb {
+            r: self.r.saturating_mul(rhs),
+            g: self.g.saturating_mul(rhs),
+            b: self.b.saturating_mul(rhs),
+        }
+    }
+}
+
+// HSV色空間からRGB色空間に変換する
+// h: 色相 0~359度, s: 彩度 0~255, v: 明度 0~255
+pub fn hsv_to_rgb(h: u16, s: u8, v: u8) -> Rgb<u8> {
+    if s == 0 {
+        // 無彩色
+        return Rgb { r: v, g: v, b: v };
+    }
+    let h = (h % 360) as u32;
+    let s = s as u32;
+    let v = v as u32;
+    // 60度ごとの領域と、領域内の位置(0~255)
+    let region = h / 60;
+    let remainder = (h % 60) * 255 / 60;
+    let p = v * (255 - s) / 255;
+    let q = v * (255 - s * remainder / 255) / 255;
+    let t = v * (255 - s * (255 - remainder) / 255) / 255;
+    let (r, g, b) = match region {
+        0 => (v, t, p),
+        1 => (q, v, p),
+        2 => (p, v, t),
+        3 => (p, q, v),
+        4 => (t, p, v),
+        _ => (v, p, q),
+    };
+    Rgb {
+        r: r as u8,
+        g: g as u8,
+        b: b as u8,
+    }
+}
+
+// 帯全体に虹色を1周分並べる
+// i番目の画素の色相は phase + i*360/len 度 (360度を超えたら折り返す)
+// phaseを少しずつ増やして呼ぶと虹色が流れる
+// 360画素より長い帯では隣り合う画素が同じ色相になることがある
+pub fn rainbow_fill(buf: &mut [Rgb<u8>], phase: u16) {
+    let len = buf.len() as u32;
+    let phase = phase as u32 % 360;
+    for (i, pixel) in buf.iter_mut().enumerate() {
+        let hue = (phase + i as u32 * 360 / len) % 360;
+        *pixel = hsv_to_rgb(hue as u16, 255, 255);
+    }
+}
+
+// 各色を brightness/255 倍に減光する
+// brightnessが0なら完全に消灯(全て0)になる
+pub const fn rgb_dimmed(value: Rgb<u8>, brightness: u8) -> Rgb<u8> {
+    const fn scale(c: u8, brightness: u8) -> u8 {
+        ((c as u16 * brightness as u16) / 255) as u8
+    }
+    Rgb {
+        r: scale(value.r, brightness),
+        g: scale(value.g, brightness),
+        b: scale(value.b, brightness),
+    }
+}
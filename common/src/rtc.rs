@@ -0,0 +1,281 @@
+// hello-ra4m1
+// リアルタイムクロック(RTC)
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::clock::ClockConfig;
+use ra4m1_fsp_pac as pac;
+use scopeguard::defer;
+
+// サブクロック発振器の発振安定待ち時間(ms)
+const SOSC_STABILIZATION_MS: u32 = 1000;
+
+// RTCのカウントソース
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcClockSource {
+    SubClock, // サブクロック発振器(32.768kHz水晶振動子)
+    Loco,     // 低速オンチップオシレータ(32.768kHz, 精度は低い)
+}
+
+// RTC操作のエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    InvalidDateTime, // 日時が範囲外(2000年から2099年までの存在する日時でない)
+    Timeout,         // レジスタの設定が待っても反映されない(カウントソースが発振していないなど)
+}
+
+// 状態の確認を何回繰り返したら諦めるか
+// 設定の反映はカウントソースの数クロック(32.768kHzで数百マイクロ秒)で終わるので、それより十分長くする
+const RTC_SPIN_LIMIT: u32 = 1_000_000;
+
+// cond() がtrueになるまで待つ
+// RTC_SPIN_LIMIT回確認してもtrueにならなければErr(RtcError::Timeout)を返す
+fn spin_until(mut cond: impl FnMut() -> bool) -> Result<(), RtcError> {
+    for _ in 0..RTC_SPIN_LIMIT {
+        if cond() {
+            return Ok(());
+        }
+    }
+    Err(RtcError::Timeout)
+}
+
+// 日時
+// 年は2000年から2099年まで
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // 曜日(0 = 日曜日 ～ 6 = 土曜日)
+    pub fn weekday(&self) -> u8 {
+        // Sakamotoの方法
+        const T: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let y = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let d = y + y / 4 - y / 100 + y / 400 + T[self.month as usize - 1] + self.day as u16;
+        (d % 7) as u8
+    }
+
+    // 月の日数
+    const fn days_in_month(&self) -> u8 {
+        match self.month {
+            2 if self.year.is_multiple_of(4) => 29, // 2000年から2099年までは4で割り切れる年がうるう年
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    // RTCのカウンタに書ける日時か
+    pub const fn is_valid(&self) -> bool {
+        2000 <= self.year
+            && self.year <= 2099
+            && 1 <= self.month
+            && self.month <= 12
+            && 1 <= self.day
+            && self.day <= self.days_in_month()
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// 2進数をBCDにする
+const fn to_bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+// BCDを2進数にする
+const fn from_bcd(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0x0f)
+}
+
+// RTCのカウントを停止する
+fn rtc_stop(p: &pac::Peripherals) -> Result<(), RtcError> {
+    p.RTC.rcr2().modify(|_, w| w.start()._0());
+    spin_until(|| p.RTC.rcr2().read().start().bit_is_clear()) // 停止確認
+}
+
+// RTCのカウントを開始する
+fn rtc_start(p: &pac::Peripherals) -> Result<(), RtcError> {
+    p.RTC.rcr2().modify(|_, w| w.start()._1());
+    spin_until(|| p.RTC.rcr2().read().start().bit_is_set()) // 開始確認
+}
+
+// カウンタに日時を書き込む
+// RTCのカウントを停止した状態で呼ぶこと
+fn rtc_write_counters(p: &pac::Peripherals, dt: &DateTime) -> Result<(), RtcError> {
+    if !dt.is_valid() {
+        return Err(RtcError::InvalidDateTime);
+    }
+    unsafe {
+        p.RTC.rseccnt().write(|w| w.bits(to_bcd(dt.second)));
+        p.RTC.rmincnt().write(|w| w.bits(to_bcd(dt.minute)));
+        p.RTC.rhrcnt().write(|w| w.bits(to_bcd(dt.hour)));
+        p.RTC.rwkcnt().write(|w| w.bits(dt.weekday()));
+        p.RTC.rdaycnt().write(|w| w.bits(to_bcd(dt.day)));
+        p.RTC.rmoncnt().write(|w| w.bits(to_bcd(dt.month)));
+        p.RTC
+            .ryrcnt()
+            .write(|w| w.bits(to_bcd((dt.year - 2000) as u8) as u16));
+    }
+    Ok(())
+}
+
+// カウンタから日時を読み出す
+fn rtc_read_counters(p: &pac::Peripherals) -> DateTime {
+    DateTime {
+        year: 2000 + from_bcd(p.RTC.ryrcnt().read().bits() as u8) as u16,
+        month: from_bcd(p.RTC.rmoncnt().read().bits()),
+        day: from_bcd(p.RTC.rdaycnt().read().bits()),
+        // PMビット(b6)は24時間モードでは使わない
+        hour: from_bcd(p.RTC.rhrcnt().read().bits() & 0x3f),
+        minute: from_bcd(p.RTC.rmincnt().read().bits()),
+        second: from_bcd(p.RTC.rseccnt().read().bits()),
+    }
+}
+
+// RTCの初期化
+// カウントソースの発振を開始してカレンダーカウントモード(24時間制)でカウントを開始する
+// 日時は2000-01-01 00:00:00から始まる
+// リセット前から同じカウントソースでカウントしていて日時が正しければ、その日時のまま続ける
+// サブクロックが止まっていた時は、その間カウントも止まっていたので続けない
+// clock_init_*()はサブクロックを停止するので、その後に呼ぶこと
+// レジスタの設定が反映されなければErr(RtcError::Timeout)を返す
+pub fn rtc_init(
+    p: &pac::Peripherals,
+    clocks: &ClockConfig,
+    source: RtcClockSource,
+) -> Result<(), RtcError> {
+    // ミリ秒待つ
+    let delay_ms = |ms: u32| cortex_m::asm::delay(clocks.iclk / 1000 * ms);
+
+    // カウントソースがリセット前から発振していたか
+    // LOCOはリセットで止まらない
+    let source_running = match source {
+        RtcClockSource::SubClock => p.SYSTEM.sosccr().read().sostp().bit_is_clear(),
+        RtcClockSource::Loco => true,
+    };
+
+    if source == RtcClockSource::SubClock {
+        // 保護レジスタを操作して書込み許可を与える
+        p.SYSTEM.prcr().write(|w| {
+            w.prkey().set(0xa5);
+            w.prc0().set_bit() // クロック発生回路関連レジスタに書込み許可を与える
+        });
+        // 関数脱出時に保護レジスタを元通りに復帰する
+        defer! {
+            p.SYSTEM.prcr().write(|w| {
+                w.prkey().set(0xa5);
+                w.prc0().clear_bit()
+            });
+        }
+
+        // サブクロック発振器の動作
+        if !source_running {
+            p.SYSTEM.sosccr().write(|w| w.sostp().clear_bit());
+            spin_until(|| p.SYSTEM.sosccr().read().sostp().bit_is_clear())?; // サブクロック動作確認
+            // サブクロックには発振安定フラグが無いので時間で待つ
+            delay_ms(SOSC_STABILIZATION_MS);
+        }
+    }
+
+    // カウント中のRTCはそのまま使う
+    let same_source = match source {
+        RtcClockSource::SubClock => p.RTC.rcr4().read().rcksel().is_0(),
+        RtcClockSource::Loco => p.RTC.rcr4().read().rcksel().is_1(),
+    };
+    if source_running
+        && same_source
+        && p.RTC.rcr2().read().start().bit_is_set()
+        && p.RTC.rcr2().read().hr24().bit_is_set()
+        && rtc_now(p).is_valid()
+    {
+        return Ok(());
+    }
+
+    // カウントソースの選択
+    p.RTC.rcr4().write(|w| match source {
+        RtcClockSource::SubClock => w.rcksel()._0(),
+        RtcClockSource::Loco => w.rcksel()._1(),
+    });
+    // カウントソースの6クロック分待つ
+    delay_ms(1);
+
+    // カウント停止
+    rtc_stop(p)?;
+
+    // RTCソフトウェアリセット
+    p.RTC.rcr2().modify(|_, w| w.reset()._1());
+    spin_until(|| p.RTC.rcr2().read().reset().bit_is_clear())?; // リセット完了確認
+
+    // カレンダーカウントモード, 24時間制
+    p.RTC.rcr2().modify(|_, w| {
+        w.cntmd()._0();
+        w.hr24()._1()
+    });
+    spin_until(|| p.RTC.rcr2().read().hr24().bit_is_set())?; // 設定反映確認
+
+    // 割り込みは使わない
+    p.RTC.rcr1().write(|w| unsafe { w.bits(0) });
+
+    // 日時の初期値
+    const EPOCH: DateTime = DateTime {
+        year: 2000,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    const _: () = assert!(EPOCH.is_valid());
+    rtc_write_counters(p, &EPOCH)?; // 範囲内なので失敗しない
+
+    // カウント開始
+    rtc_start(p)
+}
+
+// 日時を設定する
+// 範囲外の日時ならカウントを止めずにErr(RtcError::InvalidDateTime)を返す
+// カウントの停止か開始が反映されなければErr(RtcError::Timeout)を返す
+pub fn rtc_set(p: &pac::Peripherals, dt: &DateTime) -> Result<(), RtcError> {
+    if !dt.is_valid() {
+        return Err(RtcError::InvalidDateTime);
+    }
+    rtc_stop(p)?;
+    let result = rtc_write_counters(p, dt);
+    rtc_start(p)?;
+    result
+}
+
+// 現在の日時
+pub fn rtc_now(p: &pac::Peripherals) -> DateTime {
+    // 読み出し中に桁上がりがあると値が不正になるので、2回続けて同じ値になるまで読み直す
+    loop {
+        let a = rtc_read_counters(p);
+        let b = rtc_read_counters(p);
+        if a == b {
+            return a;
+        }
+    }
+}
@@ -0,0 +1,43 @@
+// hello-ra4m1
+// 割り込みハンドラからメインループへの通知
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// 割り込みハンドラがset()で立てて、メインループがtake()で受け取って下ろすフラグ
+// アトミック命令だけで読み書きするので、クリティカルセクション(割り込み禁止)は要らない
+// 受け取るまでに何回set()しても1回の通知になる
+pub struct Signal {
+    flag: AtomicBool,
+}
+
+impl Signal {
+    pub const fn new() -> Self {
+        Signal {
+            flag: AtomicBool::new(false),
+        }
+    }
+
+    // 通知する
+    pub fn set(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    // 通知があればフラグを下ろしてtrueを返す
+    pub fn take(&self) -> bool {
+        self.flag.swap(false, Ordering::SeqCst)
+    }
+
+    // 受け取っていない通知を捨てる
+    pub fn clear(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Default for Signal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
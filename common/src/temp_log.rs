@@ -0,0 +1,114 @@
+// hello-ra4m1
+// データフラッシュに記録する温度の履歴(リングバッファ)
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::clock::ClockConfig;
+use crate::crc::{CrcPolynomial, crc_software};
+use crate::dataflash::{DataFlashError, TEMP_LOG_BLOCKS};
+use crate::dataflash_ring::DataFlashRing;
+
+// 1つの記録の大きさ(バイト)
+// 通し番号(u32), 起動回数(u32), 起動してからの秒数(u32), 温度(i16, 0.01℃単位), CRC-16-CCITT(u16)
+// データフラッシュは消去後の値が不定なので、CRCが合っている記録だけを書き込み済みとみなす
+const RECORD_SIZE: usize = 16;
+
+type TempLogRing = DataFlashRing<RECORD_SIZE>;
+
+// 記録できる数
+// 1ブロックは消去済みにしておくので、読み出せるのは256件から320件
+// 1分に1回なら4時間16分以上
+pub const TEMP_LOG_CAPACITY: usize =
+    TempLogRing::RECORDS_PER_BLOCK * (TEMP_LOG_BLOCKS.end - TEMP_LOG_BLOCKS.start);
+
+const _: () = assert!(TempLogRing::RECORDS_PER_BLOCK == 64);
+const _: () = assert!(TEMP_LOG_CAPACITY == 320);
+
+// 温度の記録
+// RTCは電源を入れるたびに2000-01-01から数え直すので、日時の代わりに
+// 起動回数(boot_count)と起動してからの秒数で記録した時を表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TempRecord {
+    pub seq: u32,           // 通し番号(書いた順)
+    pub boot: u32,          // 記録した時の起動回数
+    pub uptime_s: u32,      // 起動してから記録するまでの秒数
+    pub centi_celsius: i16, // 温度(0.01℃単位)
+}
+
+impl TempRecord {
+    // 温度(℃)
+    pub fn celsius(&self) -> f32 {
+        self.centi_celsius as f32 / 100.0
+    }
+
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0u8; RECORD_SIZE];
+        buf[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.boot.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.uptime_s.to_le_bytes());
+        buf[12..14].copy_from_slice(&self.centi_celsius.to_le_bytes());
+        let crc = crc_software(CrcPolynomial::Crc16Ccitt, &buf[..RECORD_SIZE - 2]) as u16;
+        buf[RECORD_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let crc = crc_software(CrcPolynomial::Crc16Ccitt, &buf[..RECORD_SIZE - 2]) as u16;
+        if crc.to_le_bytes() != buf[RECORD_SIZE - 2..] {
+            return None;
+        }
+        Some(TempRecord {
+            seq: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            boot: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            uptime_s: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            centi_celsius: i16::from_le_bytes([buf[12], buf[13]]),
+        })
+    }
+}
+
+// データフラッシュの TEMP_LOG_BLOCKS に温度を書き足していくリングバッファ
+// 事前にdataflash_init()を呼ぶこと
+pub struct TempLogger {
+    ring: TempLogRing,
+    next_seq: u32, // 次に書く記録の通し番号
+}
+
+impl TempLogger {
+    // データフラッシュの記録を調べて、続きから書けるようにする
+    pub fn open() -> Self {
+        let (ring, latest) = TempLogRing::open(TEMP_LOG_BLOCKS, |buf| {
+            TempRecord::decode(buf).map(|r| r.seq)
+        });
+        TempLogger {
+            ring,
+            next_seq: latest.map_or(0, |seq| seq.wrapping_add(1)),
+        }
+    }
+
+    // 起動回数 boot, 起動してからの秒数 uptime_s と温度 celsius を記録して、書いた記録を返す
+    pub fn append(
+        &mut self,
+        clocks: &ClockConfig,
+        boot: u32,
+        uptime_s: u32,
+        celsius: f32,
+    ) -> Result<TempRecord, DataFlashError> {
+        let record = TempRecord {
+            seq: self.next_seq,
+            boot,
+            uptime_s,
+            centi_celsius: (celsius * 100.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16,
+        };
+        self.ring.append(clocks, &record.encode())?;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        Ok(record)
+    }
+
+    // 記録されている全ての記録を古い順に
+    pub fn records(&self) -> impl Iterator<Item = TempRecord> + '_ {
+        self.ring
+            .oldest_first()
+            .filter_map(|buf| TempRecord::decode(&buf))
+    }
+}
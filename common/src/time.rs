@@ -0,0 +1,170 @@
+// hello-ra4m1
+// SysTickタイマーによる経過時間(ミリ秒)とタイムアウト, GPT166によるマイクロ秒の待ち, サイクル数の待ち
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::clock::ClockConfig;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::SYST;
+use cortex_m::peripheral::syst::SystClkSource;
+use ra4m1_fsp_pac as pac;
+
+// 起動からの経過時間(ミリ秒)
+// Cortex-M4には64ビットのアトミック命令が無いのでMutex<Cell<u64>>で持つ
+static MILLIS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+// SysTickタイマーで1ミリ秒ごとに割り込みを発生させる
+// 使う側のSysTick割り込みハンドラから millis_tick() を呼ぶこと
+//
+// #[cortex_m_rt::exception]
+// fn SysTick() {
+//     millis_tick();
+// }
+pub fn systick_init(syst: &mut SYST, iclk_hz: u32) {
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(iclk_hz / 1000 - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+// 経過時間を1ミリ秒進める
+// SysTick割り込みハンドラから呼ぶ
+pub fn millis_tick() {
+    cortex_m::interrupt::free(|cs| {
+        let millis = MILLIS.borrow(cs);
+        millis.set(millis.get().wrapping_add(1));
+    });
+}
+
+// 起動からの経過時間(ミリ秒)
+// u64のミリ秒が一周するのは約5億8千万年後なので実用上オーバーフローしない
+// (一周した場合は0に戻る)
+pub fn millis() -> u64 {
+    cortex_m::interrupt::free(|cs| MILLIS.borrow(cs).get())
+}
+
+// 期限付きの待ち
+// delay_ms()のように止まって待たずに、メインループで is_expired() を見て次の処理に進む
+// 止まらないので、待っている間もシリアル通信などの他の処理ができる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    deadline: u64, // 期限(millis()の値)
+}
+
+impl Timeout {
+    // 今から ms ミリ秒後に期限切れになる
+    pub fn after(ms: u32) -> Self {
+        Timeout {
+            deadline: millis().wrapping_add(ms as u64),
+        }
+    }
+
+    // 期限を過ぎていればtrue
+    pub fn is_expired(&self) -> bool {
+        millis() >= self.deadline
+    }
+
+    // 期限までの残り時間(ミリ秒), 過ぎていれば0
+    pub fn remaining_ms(&self) -> u64 {
+        self.deadline.saturating_sub(millis())
+    }
+
+    // 期限を ms ミリ秒延ばす
+    // 期限を過ぎてから呼んでも前の期限から数えるので、周期的な処理の間隔がずれない
+    pub fn advance(&mut self, ms: u32) {
+        self.deadline = self.deadline.wrapping_add(ms as u64);
+    }
+}
+
+// GPT166のカウントクロック(PCLKD)の周波数(Hz)
+// gpt_delay_init()で設定するまでは0
+static GPT166_CLOCK_HZ: AtomicU32 = AtomicU32::new(0);
+
+// delay_us_gpt()で使うGPT166を、PCLKD/1で0から0xFFFFまで回し続ける
+// SysTickを使わないので、SysTickをRTOSのティックなどの別の用途に使っていても待てる
+// GPT166は他の用途に使わないこと
+pub fn gpt_delay_init(p: &pac::Peripherals, clocks: &ClockConfig) {
+    // GPT167~GPT162モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd6()._0());
+
+    // GPT166タイマーカウント動作を停止
+    p.GPT166.gtcr().modify(|_r, w| w.cst()._0());
+
+    // UPカウント設定
+    p.GPT166.gtuddtyc().modify(|_r, w| w.ud()._1());
+
+    // カウンタ最大値設定
+    // 16ビットなので48MHzなら約1.37ミリ秒で一周する
+    p.GPT166.gtpr().write(|w| unsafe { w.bits(0xffff) });
+
+    // カウンタ初期値設定
+    p.GPT166.gtcnt().reset();
+
+    GPT166_CLOCK_HZ.store(clocks.pclkd, Ordering::Relaxed);
+
+    // GPT166タイマーカウント動作を開始
+    p.GPT166.gtcr().modify(|_r, w| {
+        w.cst()._1();
+        w.md()._000(); // のこぎり波形PWMモード
+        w.tpcs()._000() // プリスケーラ― (PCLKD/1)
+    });
+}
+
+// us マイクロ秒待つ
+// GPT166のカウンタ(GTCNT)を見て止まって待つ。事前にgpt_delay_init()を呼ぶこと(呼んでいなければ待たない)
+// カウンタの一周より長い時は、半周ずつに分けてオーバーフローをまたいで待つ
+// 半周より長く割り込みに止められると、その半周は一周分(約1.37ミリ秒)長く待つことがある
+pub fn delay_us_gpt(p: &pac::Peripherals, us: u32) {
+    let hz = GPT166_CLOCK_HZ.load(Ordering::Relaxed) as u64;
+    gpt166_wait_ticks(p, us as u64 * hz / 1_000_000);
+}
+
+// ns ナノ秒以上待つ(カウントクロックの1周期単位で切り上げる)
+// 待ち方はdelay_us_gpt()と同じ。事前にgpt_delay_init()を呼ぶこと(呼んでいなければ待たない)
+pub fn delay_ns_gpt(p: &pac::Peripherals, ns: u32) {
+    let hz = GPT166_CLOCK_HZ.load(Ordering::Relaxed) as u64;
+    gpt166_wait_ticks(p, (ns as u64 * hz).div_ceil(1_000_000_000));
+}
+
+// GPT166のカウンタが ticks 進むまで待つ
+fn gpt166_wait_ticks(p: &pac::Peripherals, ticks: u64) {
+    let mut remaining = ticks;
+    const CHUNK: u64 = 0x8000;
+    while remaining > 0 {
+        let ticks = remaining.min(CHUNK) as u16;
+        let start = p.GPT166.gtcnt().read().bits() as u16;
+        while (p.GPT166.gtcnt().read().bits() as u16).wrapping_sub(start) < ticks {}
+        remaining -= ticks as u64;
+    }
+}
+
+// busy_wait_ns()がサイクル数を求めるのに使うCPUクロック(ICLK)の周波数(Hz)
+// RA4M1の最大動作周波数にしておくと、それより遅いクロックで動かした時は長めに待つだけで短くはならない
+pub const BUSY_WAIT_ICLK_HZ: u32 = 48_000_000;
+
+// ns ナノ秒をBUSY_WAIT_ICLK_HZのサイクル数にする(切り上げ)
+pub const fn ns_to_cycles(ns: u32) -> u32 {
+    (BUSY_WAIT_ICLK_HZ as u64 * ns as u64).div_ceil(1_000_000_000) as u32
+}
+
+const _: () = assert!(ns_to_cycles(1_000) == 48);
+const _: () = assert!(ns_to_cycles(1) == 1);
+
+// CPUクロックで n サイクル以上止まって待つ
+// 割り込みで止められるとその分だけ長くなる
+#[inline(always)]
+pub fn busy_wait_cycles(n: u32) {
+    cortex_m::asm::delay(n);
+}
+
+// ns ナノ秒以上止まって待つ
+// タイマーを使わないので、周辺機能を設定する前やレジスタを書き換えた直後の短い待ちに使う
+// ICLKがBUSY_WAIT_ICLK_HZ(48MHz)の時に最も正確で、それより遅いクロックでは長く待つ
+#[inline(always)]
+pub fn busy_wait_ns(ns: u32) {
+    busy_wait_cycles(ns_to_cycles(ns));
+}
@@ -0,0 +1,41 @@
+// hello-ra4m1
+// ユニークIDと型名の読み出し
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use heapless::{String, Vec};
+
+// ファクトリ MCU インフォメーションフラッシュルートテーブル (FMIFRT)
+const FMIFRT: *const u32 = 0x407f_b19c as *const u32;
+
+// ユニークIDのベースアドレスから4つのレジスタを読み出す
+fn read_registers(offsets: [usize; 4]) -> [u8; 16] {
+    // ユニークIDのベースアドレス
+    let unique_id_base_address = unsafe { core::ptr::read_volatile(FMIFRT) } as *const u32;
+
+    let mut buf = [0u8; 16];
+    for (chunk, offset) in buf.chunks_exact_mut(4).zip(offsets) {
+        let reg: u32 =
+            unsafe { core::ptr::read_volatile(unique_id_base_address.wrapping_byte_add(offset)) };
+        // バイトオーダーを変換
+        chunk.copy_from_slice(&reg.to_ne_bytes());
+    }
+    buf
+}
+
+// 128ビットのユニークID
+// ユニークIDレジスタ n（UIDRn）（n = 0 ～ 3）
+// ユニークIDのベースアドレスに対するオフセットは 14h, 18h, 1ch, 20h
+pub fn read_unique_id() -> [u8; 16] {
+    read_registers([0x14, 0x18, 0x1c, 0x20])
+}
+
+// 型名
+// 型名レジスタ n（PNRn）（n = 0 ～ 3）
+// ユニークIDのベースアドレスに対するオフセットは 24h, 28h, 2ch, 30h
+pub fn read_product_part_number() -> String<16> {
+    let buf: Vec<u8, 16> = Vec::from_slice(&read_registers([0x24, 0x28, 0x2c, 0x30])).unwrap();
+    // heapless::Stringに変換
+    String::from_utf8(buf).unwrap()
+}
@@ -0,0 +1,399 @@
+// hello-ra4m1
+// WS2812B(シリアル接続フルカラーLED)
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2025 Akihiro Yamamoto <github.com/ak1211>
+
+use crate::board::BOARD_PINS;
+use crate::port::{port_reset, port_set};
+use crate::rgb::{Rgb, rgb_dimmed};
+use crate::time::delay_us_gpt;
+use cortex_m::delay::Delay;
+use cortex_m::peripheral::DWT;
+use ra4m1_fsp_pac as pac;
+
+// led_pin_bit はWS2812Bをつないだ PORT(BOARD_PINS.ws2812b.port) の端子のビット
+// 同じPORTの端子ならまとめて駆動できる
+// POSR/PORRに書くので、割り込みハンドラが同じPORTの他の端子を操作していても壊さない
+
+// OUTPUT HIGH LEVEL
+fn output_high(led_pin_bit: u16) {
+    port_set(BOARD_PINS.ws2812b.port, led_pin_bit);
+}
+
+// OUTPUT LOW LEVEL
+fn output_low(led_pin_bit: u16) {
+    port_reset(BOARD_PINS.ws2812b.port, led_pin_bit);
+}
+
+// リセット(ラッチ)する
+// timing のリセット期間(初期値280us)だけLOWにして待つ
+pub fn ws2812b_reset(delay: &mut Delay, timing: &Ws2812bTiming, led_pin_bit: u16) {
+    output_low(led_pin_bit);
+    delay.delay_us(timing.reset_us);
+}
+
+// SysTick(Delay)の代わりにGPT166で待ってリセット(ラッチ)する
+// 事前にgpt_delay_init()を呼ぶこと
+pub fn ws2812b_reset_gpt(p: &pac::Peripherals, timing: &Ws2812bTiming, led_pin_bit: u16) {
+    output_low(led_pin_bit);
+    delay_us_gpt(p, timing.reset_us);
+}
+
+// 色データを送る順番
+// WS2812BはGRB, SK6812(RGBW)はGRBWなど、LEDの種類によって違う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorOrder {
+    Grb,  // 緑, 赤, 青の24ビット
+    Rgb,  // 赤, 緑, 青の24ビット
+    Grbw, // 緑, 赤, 青, 白の32ビット
+    Rgbw, // 赤, 緑, 青, 白の32ビット
+}
+
+impl ColorOrder {
+    // 1画素のビット数
+    pub const fn bits(self) -> u8 {
+        match self {
+            ColorOrder::Grb | ColorOrder::Rgb => 24,
+            ColorOrder::Grbw | ColorOrder::Rgbw => 32,
+        }
+    }
+
+    // 送る順に上位ビットから詰めた1画素のデータ
+    // 白のないLEDではwhiteは使わない
+    const fn pack(self, value: Rgb<u8>, white: u8) -> u32 {
+        let (r, g, b, w) = (value.r as u32, value.g as u32, value.b as u32, white as u32);
+        match self {
+            ColorOrder::Grb => g << 16 | r << 8 | b,
+            ColorOrder::Rgb => r << 16 | g << 8 | b,
+            ColorOrder::Grbw => g << 24 | r << 16 | b << 8 | w,
+            ColorOrder::Rgbw => r << 24 | g << 16 | b << 8 | w,
+        }
+    }
+}
+
+// WS2812Bの1ビットのタイミング(CPUクロックのサイクル数)と色データの順番
+#[derive(Debug, Clone, Copy)]
+pub struct Ws2812bTiming {
+    t0h: u32,          // '0'のHIGH期間 0.4us
+    t1h: u32,          // '1'のHIGH期間 0.8us
+    bit: u32,          // 1ビットの周期 1.25us
+    max_gap: u32,      // 画素と画素の間の最長のLOW期間
+    order: ColorOrder, // 色データの順番
+    reset_us: u32,     // リセット(ラッチ)のLOW期間(マイクロ秒)
+}
+
+// リセット(ラッチ)のLOW期間(マイクロ秒)
+// LEDの種類によって必要な長さが違うので、Ws2812bTiming::with_reset_us()で選ぶ
+pub const WS2812B_RESET_US: u32 = 280; // WS2812B-V5など新しいWS2812B(280us以上)
+pub const WS2812B_LEGACY_RESET_US: u32 = 50; // 古いWS2812B(50us以上)
+pub const SK6812_RESET_US: u32 = 80; // SK6812(80us以上)
+
+// 画素と画素の間に空けてよい最長のLOW期間(ナノ秒)
+// データシートのリセット期間は50us以上だが、それより短いLOW期間でラッチしてしまう互換品もあるので
+// 確実に1フレームとして受け取れる長さにする
+pub const WS2812B_MAX_GAP_NS: u32 = 5_000;
+
+// ポート出力の切り替えとサイクルカウンタの読み出しにかかるサイクル数を考えると
+// 1ビットの周期にこれだけのサイクル数が必要
+const WS2812B_MIN_CYCLES_PER_BIT: u32 = 20;
+
+impl Ws2812bTiming {
+    // CPUクロック(ICLK)の周波数からタイミングを求める
+    pub const fn new(iclk: u32) -> Self {
+        const fn cycles(iclk: u32, ns: u64) -> u32 {
+            (iclk as u64 * ns / 1_000_000_000) as u32
+        }
+        let timing = Ws2812bTiming {
+            t0h: cycles(iclk, 400),
+            t1h: cycles(iclk, 800),
+            bit: cycles(iclk, 1250),
+            max_gap: cycles(iclk, WS2812B_MAX_GAP_NS as u64),
+            order: ColorOrder::Grb,
+            reset_us: WS2812B_RESET_US,
+        };
+        assert!(
+            timing.bit >= WS2812B_MIN_CYCLES_PER_BIT,
+            "ICLK is too slow for WS2812B timing"
+        );
+        timing
+    }
+
+    // 色データの順番を変える(初期値はWS2812BのGRB)
+    pub const fn with_color_order(self, order: ColorOrder) -> Self {
+        Ws2812bTiming { order, ..self }
+    }
+
+    // リセット(ラッチ)のLOW期間を変える(初期値はWS2812B_RESET_USの280us)
+    // 画素と画素の間の最長のLOW期間(WS2812B_MAX_GAP_NS)より長くすること
+    pub const fn with_reset_us(self, reset_us: u32) -> Self {
+        assert!(
+            reset_us as u64 * 1_000 > WS2812B_MAX_GAP_NS as u64,
+            "WS2812B reset must be longer than the maximum gap"
+        );
+        Ws2812bTiming { reset_us, ..self }
+    }
+}
+
+// DWTのサイクルカウンタでビットのタイミングを計る
+// 事前にDWTのサイクルカウンタを有効にしておくこと
+// 白のあるLEDでは白を0にして送る
+pub fn ws2812b_write(timing: &Ws2812bTiming, led_pin_bit: u16, value: Rgb<u8>) {
+    ws2812b_write_rgbw(timing, led_pin_bit, value, 0);
+}
+
+// 白を指定して1画素を書き込む
+// 白のないLEDではwhiteは使わない
+pub fn ws2812b_write_rgbw(timing: &Ws2812bTiming, led_pin_bit: u16, value: Rgb<u8>, white: u8) {
+    write_bits(timing, led_pin_bit, timing.order.pack(value, white));
+}
+
+// 1画素のデータを上位ビットから送る
+// 最後のビットを送り終えた時のサイクルカウンタの値を返す
+fn write_bits(timing: &Ws2812bTiming, led_pin_bit: u16, data: u32) -> u32 {
+    let mut end = DWT::cycle_count();
+    for bit_digit in (0..timing.order.bits()).rev() {
+        let high = if data >> bit_digit & 1 == 0 {
+            timing.t0h
+        } else {
+            timing.t1h
+        };
+        let start = DWT::cycle_count();
+        output_high(led_pin_bit);
+        while DWT::cycle_count().wrapping_sub(start) < high {}
+        output_low(led_pin_bit);
+        while DWT::cycle_count().wrapping_sub(start) < timing.bit {}
+        end = start.wrapping_add(timing.bit);
+    }
+    end
+}
+
+// WS2812Bのエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ws2812bError {
+    GapTooLong, // 画素と画素の間がWS2812B_MAX_GAP_NSを超えたので、そこでラッチされたかもしれない
+    IndexOutOfRange, // 画素の番号が連結したLEDの数を超えている
+}
+
+// 画素を1つずつ計算しながら連結したWS2812Bに送る
+// ws2812b_begin()で始めてpixel()で1画素ずつ送り、end()でリセット(ラッチ)する
+// 始めてから終わるまで割り込み禁止にするので、画素と画素の間が空くのは呼び出し側の処理時間だけになる
+// 画素と画素の間はWS2812B_MAX_GAP_NS(5us)以内にすること
+// end()を呼ばずに捨てた場合は割り込み禁止を元に戻すが、リセットは送らない
+pub struct Ws2812bStream<'a> {
+    timing: &'a Ws2812bTiming,
+    led_pin_bit: u16,
+    last_end: Option<u32>, // 前の画素を送り終えた時のサイクルカウンタの値
+    gap_too_long: bool,    // 画素と画素の間が長すぎたことがある
+    interrupts_were_enabled: bool, // 始める前に割り込みが許可されていた
+}
+
+// 連結したWS2812Bに画素を送り始める
+// 事前にDWTのサイクルカウンタを有効にしておくこと
+pub fn ws2812b_begin<'a>(timing: &'a Ws2812bTiming, led_pin_bit: u16) -> Ws2812bStream<'a> {
+    let interrupts_were_enabled = cortex_m::register::primask::read().is_inactive();
+    cortex_m::interrupt::disable();
+    Ws2812bStream {
+        timing,
+        led_pin_bit,
+        last_end: None,
+        gap_too_long: false,
+        interrupts_were_enabled,
+    }
+}
+
+impl Ws2812bStream<'_> {
+    // 1画素を送る
+    // 前の画素との間がWS2812B_MAX_GAP_NSを超えていたらErr(GapTooLong)
+    // (それでも画素は送るが、ここから次のフレームとして受け取られているかもしれない)
+    pub fn pixel(&mut self, value: Rgb<u8>) -> Result<(), Ws2812bError> {
+        self.pixel_rgbw(value, 0)
+    }
+
+    // 白を指定して1画素を送る
+    // 白のないLEDではwhiteは使わない
+    pub fn pixel_rgbw(&mut self, value: Rgb<u8>, white: u8) -> Result<(), Ws2812bError> {
+        let gap_ok = self
+            .last_end
+            .is_none_or(|end| DWT::cycle_count().wrapping_sub(end) <= self.timing.max_gap);
+        let data = self.timing.order.pack(value, white);
+        self.last_end = Some(write_bits(self.timing, self.led_pin_bit, data));
+        if gap_ok {
+            Ok(())
+        } else {
+            self.gap_too_long = true;
+            Err(Ws2812bError::GapTooLong)
+        }
+    }
+
+    // 割り込み禁止を元に戻してからリセット(ラッチ)する
+    // 途中で画素と画素の間が長すぎたことがあればErr(GapTooLong)
+    pub fn end(self, delay: &mut Delay) -> Result<(), Ws2812bError> {
+        let (timing, led_pin_bit, gap_too_long) =
+            (self.timing, self.led_pin_bit, self.gap_too_long);
+        drop(self);
+        ws2812b_reset(delay, timing, led_pin_bit);
+        if gap_too_long {
+            Err(Ws2812bError::GapTooLong)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Ws2812bStream<'_> {
+    fn drop(&mut self) {
+        if self.interrupts_were_enabled {
+            unsafe { cortex_m::interrupt::enable() };
+        }
+    }
+}
+
+// 連結したWS2812Bに全画素を送って、最後にリセット(ラッチ)する
+// 画素の途中で割り込みが入るとタイミングが崩れるので、送信中は割り込み禁止にする
+// 空のスライスならリセットだけ送る
+pub fn ws2812b_write_all(
+    delay: &mut Delay,
+    timing: &Ws2812bTiming,
+    led_pin_bit: u16,
+    pixels: &[Rgb<u8>],
+) {
+    cortex_m::interrupt::free(|_cs| {
+        for pixel in pixels {
+            ws2812b_write(timing, led_pin_bit, *pixel);
+        }
+    });
+    ws2812b_reset(delay, timing, led_pin_bit);
+}
+
+// 連結したWS2812B用のフレームバッファ
+// 描画面(back)にフレームを作ってからpresent()で送信面(front)に写して全画素を送る
+// 描画の途中の画素が送られることはない
+pub struct FrameBuffer<const N: usize> {
+    front: [Rgb<u8>; N],
+    back: [Rgb<u8>; N],
+}
+
+impl<const N: usize> FrameBuffer<N> {
+    pub const fn new() -> Self {
+        FrameBuffer {
+            front: [Rgb { r: 0, g: 0, b: 0 }; N],
+            back: [Rgb { r: 0, g: 0, b: 0 }; N],
+        }
+    }
+
+    // 描画面
+    // present()の後も描画面の内容はそのまま残る
+    pub fn back_mut(&mut self) -> &mut [Rgb<u8>; N] {
+        &mut self.back
+    }
+
+    // 最後にpresent()で送った画素
+    pub const fn front(&self) -> &[Rgb<u8>; N] {
+        &self.front
+    }
+
+    // 描画面を送信面に写してWS2812Bに全画素を送る
+    // 送信中は割り込み禁止になる
+    pub fn present(&mut self, delay: &mut Delay, timing: &Ws2812bTiming, led_pin_bit: u16) {
+        self.front = self.back;
+        ws2812b_write_all(delay, timing, led_pin_bit, &self.front);
+    }
+}
+
+impl<const N: usize> Default for FrameBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// N個連結したWS2812Bの画素
+// set()で画素を書き換えてから、show()で全画素をまとめて送る
+pub struct Strip<const N: usize> {
+    pixels: [Rgb<u8>; N],
+}
+
+impl<const N: usize> Strip<N> {
+    // 全画素消灯で始める
+    pub const fn new() -> Self {
+        Strip {
+            pixels: [Rgb { r: 0, g: 0, b: 0 }; N],
+        }
+    }
+
+    // 連結したLEDの数
+    pub const fn len(&self) -> usize {
+        N
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    // index番目(0から)の画素を color にする
+    // show()を呼ぶまでLEDには送らない
+    pub fn set(&mut self, index: usize, color: Rgb<u8>) -> Result<(), Ws2812bError> {
+        let pixel = self
+            .pixels
+            .get_mut(index)
+            .ok_or(Ws2812bError::IndexOutOfRange)?;
+        *pixel = color;
+        Ok(())
+    }
+
+    // index番目(0から)の画素
+    pub fn get(&self, index: usize) -> Option<Rgb<u8>> {
+        self.pixels.get(index).copied()
+    }
+
+    // 全画素を消灯にする
+    // show()を呼ぶまでLEDには送らない
+    pub fn clear(&mut self) {
+        self.pixels = [Rgb { r: 0, g: 0, b: 0 }; N];
+    }
+
+    pub const fn pixels(&self) -> &[Rgb<u8>; N] {
+        &self.pixels
+    }
+
+    // 全画素をWS2812Bに送ってリセット(ラッチ)する
+    // 送信中は割り込み禁止になる
+    pub fn show(&self, delay: &mut Delay, timing: &Ws2812bTiming, led_pin_bit: u16) {
+        ws2812b_write_all(delay, timing, led_pin_bit, &self.pixels);
+    }
+}
+
+impl<const N: usize> Default for Strip<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 明るさを指定してWS2812Bに書き込む
+// 明るさは線形(brightness/255倍)のまま書く。ガンマ補正するならws2812b_write_dimmed_gamma()
+pub fn ws2812b_write_dimmed(
+    delay: &mut Delay,
+    timing: &Ws2812bTiming,
+    led_pin_bit: u16,
+    value: Rgb<u8>,
+    brightness: u8,
+) {
+    ws2812b_write_all(delay, timing, led_pin_bit, &[rgb_dimmed(value, brightness)]);
+}
+
+// 明るさを指定して、減光後にガンマ補正してWS2812Bに書き込む
+// 明るさを少しずつ変える時に、暗い側の変化が目に自然に見える
+pub fn ws2812b_write_dimmed_gamma(
+    delay: &mut Delay,
+    timing: &Ws2812bTiming,
+    led_pin_bit: u16,
+    value: Rgb<u8>,
+    brightness: u8,
+) {
+    ws2812b_write_all(
+        delay,
+        timing,
+        led_pin_bit,
+        &[rgb_dimmed(value, brightness).gamma_correct()],
+    );
+}
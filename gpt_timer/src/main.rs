@@ -7,337 +7,826 @@
 #![no_std]
 #![no_main]
 
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicU32, Ordering};
 use cortex_m::delay::Delay;
 use cortex_m::interrupt::InterruptNumber;
+use embedded_hal::delay::DelayNs;
+use hello_ra4m1_common::board::{BOARD_LEDS, BOARD_PINS};
+use hello_ra4m1_common::clock::{ClockConfig, clock_init_hoco48};
+use hello_ra4m1_common::elc::{
+    GPT0_COUNTER_OVERFLOW_EVENT, GPT1_CAPTURE_COMPARE_B_EVENT, gpt_counter_overflow_event,
+};
+use hello_ra4m1_common::irq::{IrqPriority, unmask_with_priority};
+use hello_ra4m1_common::pfs::{PfsConfig, pfs_configure};
+use hello_ra4m1_common::port::port_input;
+use hello_ra4m1_common::rgb::{Rgb, hsv_to_rgb, rainbow_fill, rgb_dimmed};
+use hello_ra4m1_common::signal::Signal;
+use hello_ra4m1_common::time::{delay_ns_gpt, gpt_delay_init};
+use hello_ra4m1_common::ws2812b::{Strip, Ws2812bTiming, ws2812b_reset};
 use panic_halt as _;
 use ra4m1_fsp_pac as pac;
 use ra4m1_fsp_pac::interrupt;
-use scopeguard::defer;
 
-// クロック設定
-// 16MHz水晶発振子をメインクロックに設定する
-#[allow(dead_code)]
-fn clock_init_xtal(p: &pac::Peripherals) {
-    // 保護レジスタを操作して書込み許可を与える
-    p.SYSTEM.prcr().write(|w| {
-        w.prkey().set(0xa5);
-        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
-        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
-    });
-    // 関数脱出時に保護レジスタを元通りに復帰する
-    defer! {
-        p.SYSTEM.prcr().write(|w| {
-            w.prkey().set(0xa5);
-            w.prc0().clear_bit();
-            w.prc1().clear_bit()
-        });
+// タイマー割り込みの周波数
+const TICK_HZ: u32 = 50;
+
+// WS2812Bの明るさ(0~255)
+const WS2812B_BRIGHTNESS: u8 = 128;
+
+// 連結したWS2812Bの数
+const WS2812B_NUM_LEDS: usize = 16;
+
+// GPT321で出すPWM (端子, 周波数Hz, デューティ比 0～10000 = 0.00～100.00%)
+// Noneなら出さない
+const PWM_OUTPUT: Option<(Gpt321Pin, u32, u16)> = None;
+
+// 起動した時に圧電ブザーで鳴らすメロディ (ブザーをつないだGPT321の端子, (周波数Hz, 長さミリ秒)の並び)
+// 周波数0は休符, Noneなら鳴らさない
+const STARTUP_MELODY: Option<(Gpt321Pin, &[(u32, u32)])> = None;
+
+// 起動してからアニメーションを始めるまで待つ時間(ミリ秒)
+// SysTickはWS2812BのDelayで使っているので、GPT166(GptDelay)で待つ
+// Noneなら待たない
+const STARTUP_DELAY_MS: Option<u32> = None;
+
+// ボタンで切り替えた後、アニメーションを止めておく時間(マイクロ秒)
+// GPT164のワンショットタイマーで測る(PCLKD 48MHzなら約1.39秒まで)
+// Noneなら止めない
+const BUTTON_PAUSE_US: Option<u32> = None;
+
+// サーボモーターを往復させる端子
+// タイマー割り込みごとにパルス幅を SERVO_SWEEP_STEP_US ずつ変えて、500～2500usを行き来する
+// Noneなら動かさない
+const SERVO_SWEEP: Option<ServoPin> = None;
+const SERVO_SWEEP_STEP_US: u32 = 20;
+
+// Highパルス幅を測ってWS2812Bの明るさにする端子(ラジコン受信機のPWM出力など)
+// 1000usで消灯, 2000usで最大(255)の明るさになる
+// Noneなら測らずにWS2812B_BRIGHTNESSの明るさにする
+const PULSE_CAPTURE: Option<Gpt321Pin> = None;
+
+// PWM出力とインプットキャプチャはどちらもGPT321を使う
+const _: () = assert!(!(PWM_OUTPUT.is_some() && PULSE_CAPTURE.is_some()));
+
+// パルス幅(マイクロ秒)を明るさにする
+const fn pulse_to_brightness(width_us: u32) -> u8 {
+    let width_us = if width_us < 1000 {
+        1000
+    } else if width_us > 2000 {
+        2000
+    } else {
+        width_us
+    };
+    ((width_us - 1000) * 255 / 1000) as u8
+}
+
+const _: () = assert!(pulse_to_brightness(500) == 0);
+const _: () = assert!(pulse_to_brightness(1500) == 127);
+const _: () = assert!(pulse_to_brightness(2500) == 255);
+
+// アニメーション効果
+// frameは1タイマー割り込みごとに1進むフレーム番号
+pub type Effect = fn(strip: &mut [Rgb<u8>], frame: u32);
+
+// タイマー割り込みごとに1フレームずつ進めるアニメーション
+pub struct Animator<const N: usize> {
+    pixels: [Rgb<u8>; N],
+    effect: Effect,
+    frame: u32,
+}
+
+impl<const N: usize> Animator<N> {
+    pub const fn new(effect: Effect) -> Self {
+        Animator {
+            pixels: [Rgb { r: 0, g: 0, b: 0 }; N],
+            effect,
+            frame: 0,
+        }
     }
 
-    // 消費電力モードはハイスピードモードに設定
-    p.SYSTEM.opccr().write(|w| w.opcm()._00());
-    while !p.SYSTEM.opccr().read().opcmtsf().bit_is_clear() {} // 確認
+    // アニメーション効果を切り替える
+    pub fn set_effect(&mut self, effect: Effect) {
+        self.effect = effect;
+        self.frame = 0;
+    }
 
-    // サブクロックの停止
-    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
-    while !p.SYSTEM.sosccr().read().sostp().bit_is_set() {} // サブクロック停止確認
+    // 次のフレームを描画する
+    pub fn next_frame(&mut self) {
+        (self.effect)(&mut self.pixels, self.frame);
+        self.frame = self.frame.wrapping_add(1);
+    }
 
-    // メインクロック発振器(MOSC)の停止
-    p.SYSTEM.mosccr().write(|w| w.mostp()._1());
-    while !p.SYSTEM.mosccr().read().mostp().is_1() {} // 確認
+    pub const fn pixels(&self) -> &[Rgb<u8>; N] {
+        &self.pixels
+    }
+}
 
-    // メインクロック発振器(MOSC)モードコントロールレジスタ
-    p.SYSTEM.momcr().write(|w| {
-        w.modrv1()._0(); // 10MHz ～ 20MHz
-        w.mosel()._0() // 外部水晶発振子
-    });
+// 虹色が流れる
+pub fn rainbow_cycle(strip: &mut [Rgb<u8>], frame: u32) {
+    rainbow_fill(strip, (frame % 360) as u16);
+}
 
-    // メインクロック発振器(MOSC)待機時間
-    p.SYSTEM.moscwtcr().write(|w| w.msts()._1001()); // 32768us
+// 全体がゆっくり明滅する
+pub fn breathing(strip: &mut [Rgb<u8>], frame: u32) {
+    // 0 → 255 → 0 の三角波
+    let level = frame.wrapping_mul(4) % 510;
+    let level = if level < 256 { level } else { 510 - level };
+    let white = Rgb {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+    strip.fill(rgb_dimmed(white, level as u8));
+}
 
-    // メインクロック発振器(MOSC)動作
-    p.SYSTEM.mosccr().write(|w| w.mostp()._0());
-    while !p.SYSTEM.mosccr().read().mostp().is_0() {} // 確認
+// 3つおきに点灯した画素が流れる
+pub fn theater_chase(strip: &mut [Rgb<u8>], frame: u32) {
+    let offset = (frame / 10 % 3) as usize;
+    let color = hsv_to_rgb((frame % 360) as u16, 255, 255);
+    for (i, pixel) in strip.iter_mut().enumerate() {
+        *pixel = if i % 3 == offset {
+            color
+        } else {
+            Rgb { r: 0, g: 0, b: 0 }
+        };
+    }
+}
 
-    // メインクロック発振器(MOSC)発振安定待ち
-    while !p.SYSTEM.oscsf().read().moscsf().bit_is_set() {}
+// GPT320タイマオーバーフロー検出フラグ
+static GPT320_TIMER_OVERFLOW_FLAG: Signal = Signal::new();
 
-    // 分周器設定
-    p.SYSTEM.sckdivcr().write(|w| {
-        w.ick()._000(); // システムクロック(ICLK Div /1)
-        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
-        w.pckb()._000(); // 周辺モジュールクロックB(PCLKA Div /1)
-        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
-        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
-        w.fck()._000() // Flashインターフェースクロック(FCLK Div /1)
-    });
+// 次のタイマー割り込みまで眠って待つ
+// WFIで眠っている間はタイマー割り込みで起こされる
+fn wait_for_tick() {
+    loop {
+        // フラグを確認してからWFIに入るまでの間に割り込みが入ると
+        // 次の割り込みまで起きられないので、割り込み禁止の状態で確認する
+        cortex_m::interrupt::disable();
+        if GPT320_TIMER_OVERFLOW_FLAG.take() {
+            unsafe { cortex_m::interrupt::enable() };
+            return;
+        }
+        // 割り込み禁止中でも割り込み要求があればWFIから復帰する
+        cortex_m::asm::wfi();
+        // ここで割り込みハンドラが動く
+        unsafe { cortex_m::interrupt::enable() };
+    }
+}
 
-    // システムクロックをメインクロックに切り替え
-    p.SYSTEM.sckscr().write(|w| w.cksel()._011()); // メインクロック発振器(MOSC)
-    while !p.SYSTEM.sckscr().read().cksel().is_011() {} // 確認
+// 押しボタンの入力をタイマー割り込みごとに読んでチャタリングを取り除く
+// ボタンは端子とGNDの間につなぎ、内部プルアップを使う(押すとLow)
+pub struct DebouncedInput {
+    port: u8,
+    pin: u8,
+    stable_samples: u8, // 何回続けて同じ値なら確定するか
+    count: u8,          // 確定値と違う値が続いた回数
+    pressed: bool,      // 確定した状態(押されていればtrue)
+    pressed_edge: bool, // 押された瞬間を一度だけ知らせるフラグ
+}
 
-    // フラッシュキャッシュ
-    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
+impl DebouncedInput {
+    // PORT port の pin 番端子をプルアップ付きの入力にする
+    pub fn new(p: &pac::Peripherals, port: u8, pin: u8, stable_samples: u8) -> Self {
+        assert!(port <= 9 && pin <= 15 && stable_samples > 0);
+
+        // 入力プルアップ
+        pfs_configure(p, port, pin, PfsConfig::input(true));
+
+        DebouncedInput {
+            port,
+            pin,
+            stable_samples,
+            count: 0,
+            pressed: false,
+            pressed_edge: false,
+        }
+    }
 
-    while p.FCACHE.fcacheiv().read().fcacheiv().bit_is_set() {}
-    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
+    // 端子がLow(押されている)ならtrue
+    fn sample(&self) -> bool {
+        port_input(self.port) & (1 << self.pin) == 0
+    }
+
+    // タイマー割り込みごとに呼ぶ
+    // 確定値と違う値がstable_samples回続いたら状態を切り替える
+    pub fn update(&mut self) {
+        if self.sample() == self.pressed {
+            self.count = 0;
+            return;
+        }
+        self.count += 1;
+        if self.count >= self.stable_samples {
+            self.count = 0;
+            self.pressed = !self.pressed;
+            if self.pressed {
+                self.pressed_edge = true;
+            }
+        }
+    }
+
+    // 押されている間true
+    #[allow(dead_code)]
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    // 前回呼んでから押されていればtrue
+    // 1回押すごとに1回だけtrueを返す
+    pub fn was_pressed(&mut self) -> bool {
+        core::mem::replace(&mut self.pressed_edge, false)
+    }
 }
 
-// クロック設定
-// 16MHz水晶発振子を12逓倍のち4分周した48MHzをクロックに設定する
-#[allow(dead_code)]
-fn clock_init_pll48(p: &pac::Peripherals) {
-    // 保護レジスタを操作して書込み許可を与える
-    p.SYSTEM.prcr().write(|w| {
-        w.prkey().set(0xa5);
-        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
-        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
-    });
-    // 関数脱出時に保護レジスタを元通りに復帰する
-    defer! {
-        p.SYSTEM.prcr().write(|w| {
-            w.prkey().set(0xa5);
-            w.prc0().clear_bit();
-            w.prc1().clear_bit()
-        });
+// タイマオーバーフロー割り込み番号
+const GPT320_OVERFLOW_IEL: pac::Interrupt = pac::Interrupt::IEL10;
+
+// タイマオーバーフロー割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL10() {
+    cortex_m::interrupt::free(|_cs| {
+        let p = unsafe { pac::Peripherals::steal() };
+        // タイマオーバーフロー割り込み
+        if p.GPT320.gtst().read().tcfpo().is_1() {
+            //
+            GPT320_TIMER_OVERFLOW_FLAG.set();
+            // 音の長さを数え終わったら止める
+            let remaining = TONE_REMAINING_TICKS.load(Ordering::SeqCst);
+            if remaining > 0 {
+                TONE_REMAINING_TICKS.store(remaining - 1, Ordering::SeqCst);
+                if remaining == 1 {
+                    p.GPT321.gtcr().modify(|_r, w| w.cst()._0());
+                }
+            }
+            // タイマオーバーフロー割り込みフラグクリア
+            p.GPT320.gtst().modify(|_r, w| w.tcfpo().clear_bit());
+            // 割り込みステータスフラグクリア
+            p.ICU
+                .ielsr(GPT320_OVERFLOW_IEL.number() as usize)
+                .modify(|_r, w| w.ir().clear_bit());
+        }
+    })
+}
+
+// GPT166のカウンタを見て待つ遅延(embedded-halのDelayNs)
+// SysTickを他で使うときのためのもの
+// 作る時にgpt_delay_init()でGPT166を設定するので、GPT166は他の用途に使わないこと
+// タイマー割り込み(GPT320)のプリスケーラーや動作に関係なく待てる
+pub struct GptDelay<'a> {
+    p: &'a pac::Peripherals,
+}
+
+impl<'a> GptDelay<'a> {
+    pub fn new(p: &'a pac::Peripherals, clocks: &ClockConfig) -> Self {
+        gpt_delay_init(p, clocks);
+        GptDelay { p }
     }
+}
 
-    // 消費電力モードはハイスピードモードに設定
-    p.SYSTEM.opccr().write(|w| w.opcm()._00());
-    while !p.SYSTEM.opccr().read().opcmtsf().bit_is_clear() {} // 確認
+impl DelayNs for GptDelay<'_> {
+    fn delay_ns(&mut self, ns: u32) {
+        delay_ns_gpt(self.p, ns);
+    }
+}
 
-    // サブクロックの停止
-    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
-    while !p.SYSTEM.sosccr().read().sostp().bit_is_set() {} // サブクロック停止確認
+// GPT164 ワンショットタイマーの時間切れフラグ
+static GPT164_ONESHOT_FLAG: Signal = Signal::new();
 
-    // メインクロック発振器(MOSC)の停止
-    p.SYSTEM.mosccr().write(|w| w.mostp()._1());
-    while !p.SYSTEM.mosccr().read().mostp().is_1() {} // 確認
+// GPT164 タイマオーバーフロー割り込み番号
+const GPT164_OVERFLOW_IEL: pac::Interrupt = pac::Interrupt::IEL12;
 
-    //
-    // メインクロック発振器(MOSC)の入力は16MHz水晶発振子
-    //
+// GPT164 カウンタオーバーフロー(GPT4_COUNTER_OVERFLOW)のイベント番号
+const GPT164_OVERFLOW_EVENT: u8 = gpt_counter_overflow_event(4);
+
+// GPT164 タイマオーバーフロー割り込みハンドラ
+// ワンショットなのでカウント動作を停止して時間切れフラグを立てる
+#[cortex_m_rt::interrupt]
+fn IEL12() {
+    let p = unsafe { pac::Peripherals::steal() };
+    p.GPT164.gtcr().modify(|_r, w| w.cst()._0());
+    GPT164_ONESHOT_FLAG.set();
+    // タイマオーバーフロー割り込みフラグクリア
+    p.GPT164.gtst().modify(|_r, w| w.tcfpo().clear_bit());
+    // 割り込みステータスフラグクリア
+    p.ICU
+        .ielsr(GPT164_OVERFLOW_IEL.number() as usize)
+        .modify(|_r, w| w.ir().clear_bit());
+}
+
+// GPT164で測れるワンショットの最長時間(マイクロ秒)
+// 16ビットのカウンタをPCLKD/1024で数えた一周分
+const fn gpt_oneshot_max_us(clocks: &ClockConfig) -> u32 {
+    (0x1_0000u64 * 1024 * 1_000_000 / clocks.pclkd as u64) as u32
+}
 
-    // メインクロック発振器(MOSC)モードコントロールレジスタ
-    p.SYSTEM.momcr().write(|w| {
-        w.modrv1()._0(); // 10MHz ～ 20MHz
-        w.mosel()._0() // 外部水晶発振子
+// GPT164をワンショットタイマーとして動かす
+// micros マイクロ秒後に一度だけオーバーフロー割り込みが入り、カウント動作を停止する
+// 時間切れは gpt_oneshot_expired() で調べる
+// タイマー割り込み(GPT320)とは別のチャネルなので、アニメーションや押しボタンの読み取りは止まらない
+// GPT164は他の用途に使わないこと
+// micros は gpt_oneshot_max_us() 以下にすること(PCLKD 48MHzなら約1.39秒)
+fn gpt_oneshot_us(p: &pac::Peripherals, clocks: &ClockConfig, micros: u32) {
+    assert!(micros <= gpt_oneshot_max_us(clocks));
+
+    // 16ビットに収まる最小のプリスケーラーを選ぶ
+    // PCLKD/1, /4, /16, /64, /256, /1024
+    let counts = (micros as u64 * clocks.pclkd as u64 / 1_000_000).max(1);
+    let (tpcs, period_count) = (0..=5u8)
+        .map(|tpcs| (tpcs, counts.div_ceil(1 << (2 * tpcs))))
+        .find(|&(_, count)| count <= 0x1_0000)
+        .unwrap_or((5, 0x1_0000));
+
+    // GPT167~GPT162モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd6()._0());
+
+    // GPT164タイマーカウント動作を停止
+    p.GPT164.gtcr().modify(|_r, w| w.cst()._0());
+
+    // UPカウント設定
+    p.GPT164.gtuddtyc().modify(|_r, w| w.ud()._1());
+
+    // カウンタ最大値設定
+    p.GPT164
+        .gtpr()
+        .write(|w| unsafe { w.bits(period_count as u32 - 1) });
+
+    // カウンタ初期値設定
+    p.GPT164.gtcnt().reset();
+
+    // 残っているオーバーフローフラグをクリア
+    p.GPT164.gtst().modify(|_r, w| w.tcfpo().clear_bit());
+    GPT164_ONESHOT_FLAG.clear();
+
+    // GPT164 オーバーフロー割り込み設定
+    p.ICU
+        .ielsr(GPT164_OVERFLOW_IEL.number() as usize)
+        .modify(|_r, w| w.iels().set(GPT164_OVERFLOW_EVENT));
+
+    // 割り込み有効
+    unmask_with_priority(GPT164_OVERFLOW_IEL, IrqPriority::Timer);
+
+    // GPT164タイマーカウント動作を開始
+    p.GPT164.gtcr().modify(|_r, w| {
+        w.cst()._1();
+        w.md()._000(); // のこぎり波形PWMモード
+        w.tpcs().set(tpcs) // プリスケーラ―
     });
+}
+
+// gpt_oneshot_us() の時間が過ぎていればtrue(一度だけ)
+fn gpt_oneshot_expired() -> bool {
+    GPT164_ONESHOT_FLAG.take()
+}
+
+// GPT321の入出力端子
+// GTIOC1A / GTIOC1B に割り当てられる端子
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gpt321Pin {
+    P105, // D2  (GTIOC1A)
+    P104, // D3  (GTIOC1B)
+    P109, // D11 (GTIOC1A)
+    P110, // D12 (GTIOC1B)
+}
+
+impl Gpt321Pin {
+    // ポート番号と端子番号
+    const fn port_pin(self) -> (u8, u8) {
+        match self {
+            Gpt321Pin::P105 => (1, 5),
+            Gpt321Pin::P104 => (1, 4),
+            Gpt321Pin::P109 => (1, 9),
+            Gpt321Pin::P110 => (1, 10),
+        }
+    }
+
+    // GTIOCnA端子ならtrue, GTIOCnB端子ならfalse
+    const fn is_gtioca(self) -> bool {
+        matches!(self, Gpt321Pin::P105 | Gpt321Pin::P109)
+    }
+}
+
+// GPT321をのこぎり波PWMモードで動かして、指定の端子にPWM波形を出力する
+// 周期はfreq_hz, デューティ比は0%で開始する
+fn gpt_pwm_init(p: &pac::Peripherals, clocks: &ClockConfig, pin: Gpt321Pin, freq_hz: u32) {
+    assert!(freq_hz > 0 && freq_hz <= clocks.pclkd / 2);
+
+    // GPT321~GPT320モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd5()._0());
+
+    // GPT321タイマーカウント動作を停止
+    p.GPT321.gtcr().modify(|_r, w| w.cst()._0());
 
-    // メインクロック発振器(MOSC)待機時間
-    p.SYSTEM.moscwtcr().write(|w| w.msts()._1001()); // 32768us
+    // UPカウント設定
+    p.GPT321.gtuddtyc().modify(|_r, w| w.ud()._1());
 
-    // メインクロック発振器(MOSC)動作
-    p.SYSTEM.mosccr().write(|w| w.mostp()._0());
-    while !p.SYSTEM.mosccr().read().mostp().is_0() {} // 確認
+    // カウンタ最大値設定
+    let period_count: u32 = clocks.pclkd / freq_hz;
+    p.GPT321
+        .gtpr()
+        .write(|w| unsafe { w.bits(period_count - 1) });
 
-    // メインクロック発振器(MOSC)発振安定待ち
-    while !p.SYSTEM.oscsf().read().moscsf().bit_is_set() {}
+    // カウンタ初期値設定
+    p.GPT321.gtcnt().reset();
 
-    // メインクロック発振器(MOSC)をPLLで逓倍する
-    // 逓倍率および分周比の設定
-    p.SYSTEM.pllccr2().write(|w| {
-        w.pllmul().set(12 - 1); // PLL Mul x12
-        w.plodiv()._10() // PLL Div /4
+    // コンペアマッチ値はGTCCRC/GTCCREに書いて、周期の終わりにGTCCRA/GTCCRBへ転送する
+    p.GPT321.gtber().modify(|_r, w| {
+        w.ccra()._01(); // シングルバッファ動作 (GTCCRA <--> GTCCRC)
+        w.ccrb()._01() // シングルバッファ動作 (GTCCRB <--> GTCCRE)
     });
 
-    // PLL動作
-    p.SYSTEM.pllcr().write(|w| w.pllstp()._0());
-    while !p.SYSTEM.pllcr().read().pllstp().is_0() {} // 確認
-
-    // PLL発振安定待ち
-    while !p.SYSTEM.oscsf().read().pllsf().bit_is_set() {}
-
-    // 分周器設定
-    p.SYSTEM.sckdivcr().write(|w| {
-        w.ick()._000(); // システムクロック(ICLK Div /1)
-        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
-        w.pckb()._001(); // 周辺モジュールクロックB(PCLKA Div /2)
-        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
-        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
-        w.fck()._001() // Flashインターフェースクロック(FCLK Div /2)
+    // 出力設定
+    // 周期の始まりでHigh, コンペアマッチでLow
+    p.GPT321.gtior().modify(|_r, w| {
+        if pin.is_gtioca() {
+            w.gtioa().set(0b01001);
+            w.oadflt()._0(); // カウント停止時はLow出力
+            w.oae()._1() // GTIOCA端子出力許可
+        } else {
+            w.gtiob().set(0b01001);
+            w.obdflt()._0(); // カウント停止時はLow出力
+            w.obe()._1() // GTIOCB端子出力許可
+        }
     });
 
-    // システムクロックをPLLに切り替え
-    p.SYSTEM.sckscr().write(|w| w.cksel()._101()); // PLL
-    while !p.SYSTEM.sckscr().read().cksel().is_101() {} // 確認
+    // デューティ比0%で開始する
+    gpt_pwm_set_duty(p, 0);
+    p.GPT321.gtccra().write(|w| unsafe { w.bits(0) });
+    p.GPT321.gtccrb().write(|w| unsafe { w.bits(0) });
 
-    // フラッシュキャッシュ
-    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
+    // I/Oポートの設定
+    // 端子機能選択(GPT)
+    const GPT_PSEL: u8 = 0b00011;
+    let (port, pin_number) = pin.port_pin();
+    pfs_configure(p, port, pin_number, PfsConfig::peripheral(GPT_PSEL, true));
 
-    while p.FCACHE.fcacheiv().read().fcacheiv().bit_is_set() {}
-    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
+    // GPT321タイマーカウント動作を開始
+    p.GPT321.gtcr().modify(|_r, w| {
+        w.cst()._1();
+        w.md()._000(); // のこぎり波形PWMモード
+        w.tpcs()._000() // プリスケーラ― (PCLKD/1)
+    });
 }
 
-// クロック設定
-// 高速オンチップオシレータ(HOCO)を48MHzでメインクロックに設定する
-#[allow(dead_code)]
-fn clock_init_hoco48(p: &pac::Peripherals) {
-    // 保護レジスタを操作して書込み許可を与える
-    p.SYSTEM.prcr().write(|w| {
-        w.prkey().set(0xa5);
-        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
-        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
+// GPT321のPWMデューティ比を設定する
+// duty_0_to_10000: 0 = 0.00%, 10000 = 100.00%
+// コンペアマッチ値が0(周期の始まり)か周期と同じになる時は、コンペアマッチを使わずに固定出力にするので、
+// 1カウント幅のひげが出ない(0%に近い小さなデューティ比は0%になる)
+fn gpt_pwm_set_duty(p: &pac::Peripherals, duty_0_to_10000: u16) {
+    let duty = duty_0_to_10000.min(10000) as u64;
+    let period_count = p.GPT321.gtpr().read().bits() as u64 + 1;
+    let compare = (period_count * duty / 10000) as u32;
+
+    // 次の周期から有効になるバッファレジスタに書き込む
+    p.GPT321.gtccrc().write(|w| unsafe { w.bits(compare) });
+    p.GPT321.gtccre().write(|w| unsafe { w.bits(compare) });
+
+    // 出力デューティ設定
+    p.GPT321.gtuddtyc().modify(|_r, w| {
+        if compare == 0 {
+            w.oadty()._10().obdty()._10() // 0%デューティ
+        } else if compare as u64 >= period_count {
+            w.oadty()._11().obdty()._11() // 100%デューティ
+        } else {
+            w.oadty()._00().obdty()._00() // コンペアマッチに従う
+        }
     });
-    // 関数脱出時に保護レジスタを元通りに復帰する
-    defer! {
-        p.SYSTEM.prcr().write(|w| {
-            w.prkey().set(0xa5);
-            w.prc0().clear_bit();
-            w.prc1().clear_bit()
-        });
-    }
+}
 
-    // 消費電力モードはハイスピードモードに設定
-    p.SYSTEM.opccr().write(|w| w.opcm()._00());
-    while !p.SYSTEM.opccr().read().opcmtsf().bit_is_clear() {} // 確認
-
-    // サブクロックの停止
-    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
-    while !p.SYSTEM.sosccr().read().sostp().bit_is_set() {} // サブクロック停止確認
-
-    // 高速オンチップオシレータ(HOCO)48MHz指定
-    // HOCOCR2レジスタのアドレス: 0x4001_e037
-    // HOCO48MHz指定: 0b0010_0000
-    unsafe { core::ptr::write_volatile(0x4001_e037 as *mut u8, 0b0010_0000) };
-
-    // 高速オンチップオシレータ(HOCO)クロック動作
-    p.SYSTEM.hococr().write(|w| w.hcstp()._0());
-    while !p.SYSTEM.hococr().read().hcstp().is_0() {} // 確認
-
-    // 高速オンチップオシレータ(HOCO)クロック発振安定待ち
-    while !p.SYSTEM.oscsf().read().hocosf().bit_is_set() {}
-
-    // 分周器設定
-    p.SYSTEM.sckdivcr().write(|w| {
-        w.ick()._000(); // システムクロック(ICLK Div /1)
-        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
-        w.pckb()._001(); // 周辺モジュールクロックB(PCLKA Div /2)
-        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
-        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
-        w.fck()._001() // Flashインターフェースクロック(FCLK Div /2)
-    });
+// 音を止めるまでの残りタイマー割り込み回数
+// 0なら数えていない(no_tone()を呼ぶまで鳴らし続ける)
+static TONE_REMAINING_TICKS: AtomicU32 = AtomicU32::new(0);
 
-    // システムクロックを高速オンチップオシレータ(HOCO)クロックに切り替え
-    p.SYSTEM.sckscr().write(|w| w.cksel()._000()); // HOCOクロック
-    while !p.SYSTEM.sckscr().read().cksel().is_000() {} // 確認
+// ミリ秒をタイマー割り込み回数に直す(切り上げ, 最短1回)
+const fn ms_to_ticks(ms: u32) -> u32 {
+    let ticks = (ms as u64 * TICK_HZ as u64).div_ceil(1000);
+    if ticks == 0 { 1 } else { ticks as u32 }
+}
 
-    // フラッシュキャッシュ
-    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
+// 20ミリ秒単位に切り上げる
+const _: () = assert!(ms_to_ticks(1) == 1);
+const _: () = assert!(ms_to_ticks(20) == 1);
+const _: () = assert!(ms_to_ticks(21) == 2);
+const _: () = assert!(ms_to_ticks(1000) == TICK_HZ);
+
+// 圧電ブザー(パッシブブザー)を鳴らす
+// GPT321で freq_hz のデューティ比50%の矩形波を pin に出力して、
+// duration_ms ミリ秒後(タイマー割り込みの20ミリ秒単位)にGPT320の割り込みハンドラで止める
+// duration_ms が0ならno_tone()を呼ぶまで鳴らし続ける
+// GPT321を使うので、インプットキャプチャ(gpt_capture_init)とは同時に使えない
+fn tone(
+    p: &pac::Peripherals,
+    clocks: &ClockConfig,
+    pin: Gpt321Pin,
+    freq_hz: u32,
+    duration_ms: u32,
+) {
+    // 鳴らしている途中で割り込みハンドラに止められないように、先に数えるのをやめる
+    TONE_REMAINING_TICKS.store(0, Ordering::SeqCst);
+    gpt_pwm_init(p, clocks, pin, freq_hz);
+    gpt_pwm_set_duty(p, 5000);
+    if duration_ms > 0 {
+        TONE_REMAINING_TICKS.store(ms_to_ticks(duration_ms), Ordering::SeqCst);
+    }
+}
 
-    while p.FCACHE.fcacheiv().read().fcacheiv().bit_is_set() {}
-    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
+// 圧電ブザーを止める
+// カウント停止中の端子はLow出力になる
+fn no_tone(p: &pac::Peripherals) {
+    TONE_REMAINING_TICKS.store(0, Ordering::SeqCst);
+    p.GPT321.gtcr().modify(|_r, w| w.cst()._0());
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct Rgb<T> {
-    pub r: T,
-    pub g: T,
-    pub b: T,
-}
-
-const RAINBOW_TABLE: [Rgb<u8>; 7] = {
-    let red = Rgb { r: 128, g: 0, b: 0 };
-    let orange = Rgb {
-        r: 128,
-        g: 82,
-        b: 0,
-    };
-    let yellow = Rgb {
-        r: 128,
-        g: 128,
-        b: 0,
-    };
-    let green = Rgb { r: 0, g: 128, b: 0 };
-    let cyan = Rgb {
-        r: 0,
-        g: 128,
-        b: 128,
-    };
-    let blue = Rgb { r: 0, g: 0, b: 128 };
-    let purple = Rgb {
-        r: 128,
-        g: 0,
-        b: 128,
-    };
-    [red, orange, yellow, green, cyan, blue, purple]
-};
+// tone()で指定した長さを鳴らし終えるまで眠って待つ
+fn tone_wait() {
+    while TONE_REMAINING_TICKS.load(Ordering::SeqCst) > 0 {
+        wait_for_tick();
+    }
+}
 
-fn ws2812b_reset(p: &pac::Peripherals, delay: &mut Delay, led_pin_bit: u16) {
-    // OUTPUT LOW LEVEL
-    p.PORT1
-        .podr()
-        .modify(|r, w| unsafe { w.bits(r.bits() & !led_pin_bit) });
-    delay.delay_us(280);
-}
-
-fn ws2812b_write(p: &pac::Peripherals, led_pin_bit: u16, value: Rgb<u8>) {
-    let grb = (value.g as u32) << 16 | (value.r as u32) << 8 | value.b as u32;
-    for bit_digit in (0..=23u8).rev() {
-        let flag = grb >> bit_digit & 1;
-        // OUTPUT HIGH LEVEL
-        p.PORT1
-            .podr()
-            .modify(|r, w| unsafe { w.bits(r.bits() | led_pin_bit) });
-        if flag == 0 {
-            cortex_m::asm::nop();
+// (周波数Hz, 長さミリ秒)の並びを順に鳴らす
+// 周波数0は休符
+// 鳴らし終えるまで戻らない
+fn play_melody(p: &pac::Peripherals, clocks: &ClockConfig, pin: Gpt321Pin, notes: &[(u32, u32)]) {
+    for &(freq_hz, duration_ms) in notes {
+        if freq_hz == 0 {
+            no_tone(p);
+            TONE_REMAINING_TICKS.store(ms_to_ticks(duration_ms), Ordering::SeqCst);
         } else {
-            cortex_m::asm::nop();
-            cortex_m::asm::nop();
-            cortex_m::asm::nop();
+            tone(p, clocks, pin, freq_hz, duration_ms.max(1));
         }
-        // OUTPUT LOW LEVEL
-        p.PORT1
-            .podr()
-            .modify(|r, w| unsafe { w.bits(r.bits() & !led_pin_bit) });
-        cortex_m::asm::nop();
-        cortex_m::asm::nop();
-        cortex_m::asm::nop();
+        tone_wait();
     }
+    no_tone(p);
 }
 
-// GPT320タイマオーバーフロー検出フラグ
-static GPT320_TIMER_OVERFLOW_FLAG: AtomicBool = AtomicBool::new(false);
+// サーボモーター(ラジコン用)を動かすGPT167の端子
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum ServoPin {
+    P304, // D8 (GTIOC7A)
+    P303, // D9 (GTIOC7B)
+}
 
-// タイマオーバーフロー割り込み番号
-const GPT320_OVERFLOW_IEL: pac::Interrupt = pac::Interrupt::IEL10;
+impl ServoPin {
+    // ポート番号と端子番号
+    const fn port_pin(self) -> (u8, u8) {
+        match self {
+            ServoPin::P304 => (3, 4),
+            ServoPin::P303 => (3, 3),
+        }
+    }
 
-// タイマオーバーフロー割り込みハンドラ
-#[cortex_m_rt::interrupt]
-fn IEL10() {
-    cortex_m::interrupt::free(|_cs| {
-        let p = unsafe { pac::Peripherals::steal() };
-        // タイマオーバーフロー割り込み
-        if p.GPT320.gtst().read().tcfpo().is_1() {
-            //
-            GPT320_TIMER_OVERFLOW_FLAG.store(true, Ordering::SeqCst);
-            // タイマオーバーフロー割り込みフラグクリア
-            p.GPT320.gtst().modify(|_r, w| w.tcfpo().clear_bit());
-            // 割り込みステータスフラグクリア
-            p.ICU
-                .ielsr(GPT320_OVERFLOW_IEL.number() as usize)
-                .modify(|_r, w| w.ir().clear_bit());
+    // GTIOCnA端子ならtrue, GTIOCnB端子ならfalse
+    const fn is_gtioca(self) -> bool {
+        matches!(self, ServoPin::P304)
+    }
+}
+
+// サーボモーターのパルス周期(20ミリ秒 = 50Hz)
+const SERVO_FRAME_HZ: u32 = 50;
+
+// サーボモーターに出すパルス幅の範囲(マイクロ秒)
+const SERVO_PULSE_MIN_US: u32 = 500;
+const SERVO_PULSE_MAX_US: u32 = 2500;
+
+// GPT167をのこぎり波PWMモードで動かして、50Hzのパルスを pin に出力する
+// 16ビットのカウンタに20ミリ秒が収まるように、PCLKDを分周する
+// パルス幅は1500us(中立)で開始する
+fn servo_init(p: &pac::Peripherals, clocks: &ClockConfig, pin: ServoPin) {
+    // 16ビットに収まる一番小さい分周比を選ぶ (PCLKD = 48MHzなら16分周で60000カウント)
+    let (tpcs, divider) = [(0b000, 1), (0b001, 4), (0b010, 16), (0b011, 64)]
+        .into_iter()
+        .find(|&(_, div)| clocks.pclkd / div / SERVO_FRAME_HZ <= 0x1_0000)
+        .unwrap();
+    let period_count = clocks.pclkd / divider / SERVO_FRAME_HZ;
+
+    // GPT167~GPT162モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd6()._0());
+
+    // GPT167タイマーカウント動作を停止
+    p.GPT167.gtcr().modify(|_r, w| w.cst()._0());
+
+    // UPカウント設定
+    p.GPT167.gtuddtyc().modify(|_r, w| w.ud()._1());
+
+    // カウンタ最大値設定
+    p.GPT167
+        .gtpr()
+        .write(|w| unsafe { w.bits(period_count - 1) });
+
+    // カウンタ初期値設定
+    p.GPT167.gtcnt().reset();
+
+    // コンペアマッチ値はGTCCRC/GTCCREに書いて、周期の終わりにGTCCRA/GTCCRBへ転送する
+    // パルスの途中で幅を変えても、その周期のパルスが崩れない
+    p.GPT167.gtber().modify(|_r, w| {
+        w.ccra()._01(); // シングルバッファ動作 (GTCCRA <--> GTCCRC)
+        w.ccrb()._01() // シングルバッファ動作 (GTCCRB <--> GTCCRE)
+    });
+
+    // 出力設定
+    // 周期の始まりでHigh, コンペアマッチでLow
+    p.GPT167.gtior().modify(|_r, w| {
+        if pin.is_gtioca() {
+            w.gtioa().set(0b01001);
+            w.oadflt()._0(); // カウント停止時はLow出力
+            w.oae()._1() // GTIOCA端子出力許可
+        } else {
+            w.gtiob().set(0b01001);
+            w.obdflt()._0(); // カウント停止時はLow出力
+            w.obe()._1() // GTIOCB端子出力許可
         }
-    })
+    });
+
+    // 中立位置で開始する
+    let compare = servo_pulse_count(period_count, 1500);
+    p.GPT167.gtccra().write(|w| unsafe { w.bits(compare) });
+    p.GPT167.gtccrb().write(|w| unsafe { w.bits(compare) });
+    p.GPT167.gtccrc().write(|w| unsafe { w.bits(compare) });
+    p.GPT167.gtccre().write(|w| unsafe { w.bits(compare) });
+
+    // I/Oポートの設定
+    // 端子機能選択(GPT)
+    const GPT_PSEL: u8 = 0b00011;
+    let (port, pin_number) = pin.port_pin();
+    pfs_configure(p, port, pin_number, PfsConfig::peripheral(GPT_PSEL, true));
+
+    // GPT167タイマーカウント動作を開始
+    p.GPT167.gtcr().modify(|_r, w| {
+        w.cst()._1();
+        w.md()._000(); // のこぎり波形PWMモード
+        match tpcs {
+            0b000 => w.tpcs()._000(), // プリスケーラ― (PCLKD/1)
+            0b001 => w.tpcs()._001(), // プリスケーラ― (PCLKD/4)
+            0b010 => w.tpcs()._010(), // プリスケーラ― (PCLKD/16)
+            _ => w.tpcs()._011(),     // プリスケーラ― (PCLKD/64)
+        }
+    });
+}
+
+// パルス幅(マイクロ秒)をカウント数に直す
+// 1周期(20ミリ秒)が period_count カウント
+const fn servo_pulse_count(period_count: u32, pulse_us: u32) -> u32 {
+    (period_count as u64 * pulse_us as u64 * SERVO_FRAME_HZ as u64 / 1_000_000) as u32
+}
+
+// 16分周の60000カウントで1500usは4500カウント
+const _: () = assert!(servo_pulse_count(60000, 1500) == 4500);
+
+// サーボモーターのパルス幅を pulse_us マイクロ秒にする
+// 500～2500usの範囲に切り詰める, 次の周期から有効になる
+fn servo_write_us(p: &pac::Peripherals, pulse_us: u32) {
+    let pulse_us = pulse_us.clamp(SERVO_PULSE_MIN_US, SERVO_PULSE_MAX_US);
+    let period_count = p.GPT167.gtpr().read().bits() + 1;
+    let compare = servo_pulse_count(period_count, pulse_us);
+
+    // 次の周期から有効になるバッファレジスタに書き込む
+    p.GPT167.gtccrc().write(|w| unsafe { w.bits(compare) });
+    p.GPT167.gtccre().write(|w| unsafe { w.bits(compare) });
+}
+
+// GPT321インプットキャプチャ完了フラグ
+static GPT321_CAPTURE_FLAG: Signal = Signal::new();
+
+// GPT321インプットキャプチャで測ったパルス幅(カウント数)
+static GPT321_CAPTURE_COUNT: AtomicU32 = AtomicU32::new(0);
+
+// インプットキャプチャB割り込み番号
+const GPT321_CAPTURE_B_IEL: pac::Interrupt = pac::Interrupt::IEL11;
+
+// インプットキャプチャB割り込みハンドラ
+// 立ち上がりでGTCCRA, 立ち下がりでGTCCRBにキャプチャしているので、その差がHighパルス幅
+#[cortex_m_rt::interrupt]
+fn IEL11() {
+    let p = unsafe { pac::Peripherals::steal() };
+
+    if p.GPT321.gtst().read().tcfb().is_1() {
+        let rising = p.GPT321.gtccra().read().bits();
+        let falling = p.GPT321.gtccrb().read().bits();
+        // カウンタは0xffff_ffffで一周するので、引き算は桁あふれを無視してよい
+        GPT321_CAPTURE_COUNT.store(falling.wrapping_sub(rising), Ordering::SeqCst);
+        GPT321_CAPTURE_FLAG.set();
+        // インプットキャプチャフラグクリア
+        p.GPT321
+            .gtst()
+            .modify(|_r, w| w.tcfa().clear_bit().tcfb().clear_bit());
+    }
+
+    // 割り込みステータスフラグクリア
+    p.ICU
+        .ielsr(GPT321_CAPTURE_B_IEL.number() as usize)
+        .modify(|_r, w| w.ir().clear_bit());
+}
+
+// GPT321をインプットキャプチャで動かして、指定の端子のHighパルス幅を測る
+// 立ち上がりエッジでGTCCRA, 立ち下がりエッジでGTCCRBにカウンタ値を取り込む
+fn gpt_capture_init(p: &pac::Peripherals, pin: Gpt321Pin) {
+    // GPT321~GPT320モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd5()._0());
+
+    // GPT321タイマーカウント動作を停止
+    p.GPT321.gtcr().modify(|_r, w| w.cst()._0());
+
+    // UPカウント設定
+    p.GPT321.gtuddtyc().modify(|_r, w| w.ud()._1());
+
+    // カウンタ最大値設定
+    // 32ビット全域を使う (48MHzで約89秒で一周)
+    p.GPT321.gtpr().write(|w| unsafe { w.bits(u32::MAX) });
+
+    // カウンタ初期値設定
+    p.GPT321.gtcnt().reset();
+
+    // 端子出力は禁止
+    p.GPT321.gtior().modify(|_r, w| w.oae()._0().obe()._0());
+
+    // バッファ動作はしない
+    p.GPT321.gtber().modify(|_r, w| w.ccra()._00().ccrb()._00());
+
+    // インプットキャプチャ要因設定
+    if pin.is_gtioca() {
+        // GTIOCA端子の立ち上がりでGTCCRA
+        p.GPT321
+            .gticasr()
+            .write(|w| w.ascarbl()._1().ascarbh()._1());
+        // GTIOCA端子の立ち下がりでGTCCRB
+        p.GPT321
+            .gticbsr()
+            .write(|w| w.bscafbl()._1().bscafbh()._1());
+    } else {
+        // GTIOCB端子の立ち上がりでGTCCRA
+        p.GPT321
+            .gticasr()
+            .write(|w| w.ascbral()._1().ascbrah()._1());
+        // GTIOCB端子の立ち下がりでGTCCRB
+        p.GPT321
+            .gticbsr()
+            .write(|w| w.bscbfal()._1().bscbfah()._1());
+    }
+
+    // キャプチャフラグクリア
+    p.GPT321
+        .gtst()
+        .modify(|_r, w| w.tcfa().clear_bit().tcfb().clear_bit());
+    GPT321_CAPTURE_FLAG.clear();
+
+    // I/Oポートの設定
+    // 端子機能選択(GPT)
+    const GPT_PSEL: u8 = 0b00011;
+    let (port, pin_number) = pin.port_pin();
+    pfs_configure(p, port, pin_number, PfsConfig::peripheral(GPT_PSEL, false));
+
+    // GPT321 インプットキャプチャB割り込み設定
+    p.ICU
+        .ielsr(GPT321_CAPTURE_B_IEL.number() as usize)
+        .modify(|_r, w| w.iels().set(GPT1_CAPTURE_COMPARE_B_EVENT));
+
+    // 割り込み有効
+    unmask_with_priority(GPT321_CAPTURE_B_IEL, IrqPriority::Timer);
+
+    // GPT321タイマーカウント動作を開始
+    p.GPT321.gtcr().modify(|_r, w| {
+        w.cst()._1();
+        w.md()._000(); // のこぎり波形PWMモード
+        w.tpcs()._000() // プリスケーラ― (PCLKD/1)
+    });
+}
+
+// 新しいパルス幅が測れていればマイクロ秒で返す
+fn gpt_capture_width_us(clocks: &ClockConfig) -> Option<u32> {
+    if GPT321_CAPTURE_FLAG.take() {
+        let count = GPT321_CAPTURE_COUNT.load(Ordering::SeqCst);
+        Some((count as u64 * 1_000_000 / clocks.pclkd as u64) as u32)
+    } else {
+        None
+    }
 }
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
     // 周辺機能
     let p = pac::Peripherals::take().unwrap();
-    let core = cortex_m::Peripherals::take().unwrap();
+    let mut core = cortex_m::Peripherals::take().unwrap();
 
     // 48MHzクロック設定
-    clock_init_hoco48(&p);
-    let mut delay = Delay::new(core.SYST, 48_000_000);
+    let clocks = clock_init_hoco48(&p).unwrap();
+    let mut delay = Delay::new(core.SYST, clocks.iclk);
 
-    // PORT 106 = D6(WS2812B)
-    // PORT 111 = D13(LED)
+    // WS2812Bのタイミングを計るDWTサイクルカウンタを有効にする
+    core.DCB.enable_trace();
+    core.DWT.enable_cycle_counter();
+    let ws2812b_timing = Ws2812bTiming::new(clocks.iclk);
+
+    // WS2812B(PORT 106 = D6)
+    // ユーザーLED(PORT 111 = D13)
     // 以上の入出力ポートを出力に設定
-    let led_pin_bit: u16 = 1 << 6 | 1 << 11;
-    p.PORT1
-        .pdr()
-        .modify(|r, w| unsafe { w.bits(r.bits() | led_pin_bit) });
+    // ws2812b_*()はWS2812BのPORTに書き込むので、ユーザーLEDも同じPORTにあること
+    const _: () = assert!(BOARD_PINS.ws2812b.port == BOARD_LEDS.onboard.port);
+    let led_pin_bit: u16 = 1 << BOARD_PINS.ws2812b.pin | 1 << BOARD_LEDS.onboard.pin;
+    let (ws2812b, led) = (BOARD_PINS.ws2812b, BOARD_LEDS.onboard);
+    for (port, pin) in [(ws2812b.port, ws2812b.pin), (led.port, led.pin)] {
+        pfs_configure(&p, port, pin, PfsConfig::output(false));
+    }
 
     //
     // 32ビットGPTタイマーの設定
@@ -355,7 +844,7 @@ fn main() -> ! {
     p.GPT320.gtuddtyc().modify(|_r, w| w.ud()._1());
 
     // カウンタ最大値設定
-    let period_count: u32 = 48_000_000; // 1秒周期
+    let period_count: u32 = clocks.pclkd / TICK_HZ; // 20ミリ秒周期
     p.GPT320
         .gtpr()
         .write(|w| unsafe { w.bits(period_count - 1) });
@@ -366,10 +855,10 @@ fn main() -> ! {
     // GPT320 オーバーフロー割り込み設定
     p.ICU
         .ielsr(GPT320_OVERFLOW_IEL.number() as usize)
-        .modify(|_r, w| w.iels().set(0x05d));
+        .modify(|_r, w| w.iels().set(GPT0_COUNTER_OVERFLOW_EVENT));
 
     // 割り込み有効
-    unsafe { cortex_m::peripheral::NVIC::unmask(GPT320_OVERFLOW_IEL) };
+    unmask_with_priority(GPT320_OVERFLOW_IEL, IrqPriority::Timer);
 
     // GPT320タイマーカウント動作を開始
     p.GPT320.gtcr().modify(|_r, w| {
@@ -379,14 +868,100 @@ fn main() -> ! {
     });
 
     // WS2812B消灯
-    ws2812b_reset(&p, &mut delay, led_pin_bit);
+    ws2812b_reset(&mut delay, &ws2812b_timing, led_pin_bit);
+
+    // 起動した時のメロディ
+    // GPT320のタイマー割り込みで長さを数えるので、GPT320を動かしてから鳴らす
+    if let Some((pin, notes)) = STARTUP_MELODY {
+        play_melody(&p, &clocks, pin, notes);
+    }
+
+    // PWM出力
+    // メロディを鳴らし終えてからGPT321を使う
+    if let Some((pin, freq_hz, duty)) = PWM_OUTPUT {
+        gpt_pwm_init(&p, &clocks, pin, freq_hz);
+        gpt_pwm_set_duty(&p, duty);
+    }
+
+    // アニメーションを始めるまで待つ
+    if let Some(ms) = STARTUP_DELAY_MS {
+        GptDelay::new(&p, &clocks).delay_ms(ms);
+    }
+
+    // アニメーション
+    let effects: [Effect; 3] = [rainbow_cycle, breathing, theater_chase];
+    let mut effect_index = 0;
+    let mut animator: Animator<WS2812B_NUM_LEDS> = Animator::new(effects[effect_index]);
+    let mut strip: Strip<WS2812B_NUM_LEDS> = Strip::new();
+
+    // PORT 107 = D7 とGNDの間につないだ押しボタン
+    // 3回(60ミリ秒)続けて同じ値なら確定する
+    let mut button = DebouncedInput::new(&p, 1, 7, 3);
+
+    // サーボモーター
+    if let Some(pin) = SERVO_SWEEP {
+        servo_init(&p, &clocks, pin);
+    }
+    let mut servo_pulse_us = 1500;
+    let mut servo_rising = true;
+
+    // パルス幅の測定
+    // メロディを鳴らし終えてからGPT321を使う
+    if let Some(pin) = PULSE_CAPTURE {
+        gpt_capture_init(&p, pin);
+    }
+    let mut brightness = WS2812B_BRIGHTNESS;
+
+    // ボタンで切り替えた後、アニメーションを止めている
+    let mut paused = false;
 
     // メインループ
-    let mut counter = 0;
     loop {
-        if GPT320_TIMER_OVERFLOW_FLAG.swap(false, Ordering::SeqCst) {
-            ws2812b_write(&p, led_pin_bit, RAINBOW_TABLE[counter]);
-            counter = (counter + 1) % RAINBOW_TABLE.len();
+        // タイマー割り込みまで眠って待つ
+        wait_for_tick();
+        // ボタンを押すごとにアニメーション効果を切り替える
+        button.update();
+        if button.was_pressed() {
+            effect_index = (effect_index + 1) % effects.len();
+            animator.set_effect(effects[effect_index]);
+            // 切り替えた効果の最初のフレームでしばらく止める
+            if let Some(micros) = BUTTON_PAUSE_US {
+                gpt_oneshot_us(&p, &clocks, micros);
+                paused = true;
+                animator.next_frame();
+            }
+        }
+        // サーボモーターを往復させる
+        if SERVO_SWEEP.is_some() {
+            (servo_pulse_us, servo_rising) = match (servo_rising, servo_pulse_us) {
+                (true, us) if us + SERVO_SWEEP_STEP_US >= SERVO_PULSE_MAX_US => {
+                    (SERVO_PULSE_MAX_US, false)
+                }
+                (true, us) => (us + SERVO_SWEEP_STEP_US, true),
+                (false, us) if us <= SERVO_PULSE_MIN_US + SERVO_SWEEP_STEP_US => {
+                    (SERVO_PULSE_MIN_US, true)
+                }
+                (false, us) => (us - SERVO_SWEEP_STEP_US, false),
+            };
+            servo_write_us(&p, servo_pulse_us);
+        }
+        // 測ったパルス幅で明るさを変える
+        if let Some(width_us) = gpt_capture_width_us(&clocks) {
+            brightness = pulse_to_brightness(width_us);
+        }
+        if paused && gpt_oneshot_expired() {
+            paused = false;
+        }
+        // フレームを描いてから全画素をまとめて送る
+        if !paused {
+            animator.next_frame();
+        }
+        for (i, &c) in animator.pixels().iter().enumerate() {
+            // 画素の数はどちらもWS2812B_NUM_LEDSなので範囲外にはならない
+            strip
+                .set(i, rgb_dimmed(c, brightness).gamma_correct())
+                .unwrap();
         }
+        strip.show(&mut delay, &ws2812b_timing, led_pin_bit);
     }
 }
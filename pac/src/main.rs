@@ -8,284 +8,41 @@
 #![no_main]
 
 use cortex_m::delay::Delay;
+use hello_ra4m1_common::board::{BOARD_LEDS, BOARD_PINS};
+use hello_ra4m1_common::clock::clock_init_hoco48;
+use hello_ra4m1_common::pfs::{PfsConfig, pfs_configure};
+use hello_ra4m1_common::rgb::Rgb;
+use hello_ra4m1_common::ws2812b::{Ws2812bTiming, ws2812b_reset, ws2812b_write};
 use panic_halt as _;
 use ra4m1_fsp_pac as pac;
-use scopeguard::defer;
-
-// クロック設定
-// 16MHz水晶発振子をメインクロックに設定する
-#[allow(dead_code)]
-fn clock_init_xtal(p: &pac::Peripherals) {
-    // 保護レジスタを操作して書込み許可を与える
-    p.SYSTEM.prcr().write(|w| {
-        w.prkey().set(0xa5);
-        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
-        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
-    });
-    // 関数脱出時に保護レジスタを元通りに復帰する
-    defer! {
-        p.SYSTEM.prcr().write(|w| {
-            w.prkey().set(0xa5);
-            w.prc0().clear_bit();
-            w.prc1().clear_bit()
-        });
-    }
-
-    // 消費電力モードはハイスピードモードに設定
-    p.SYSTEM.opccr().write(|w| w.opcm()._00());
-    while !p.SYSTEM.opccr().read().opcmtsf().bit_is_clear() {} // 確認
-
-    // サブクロックの停止
-    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
-    while !p.SYSTEM.sosccr().read().sostp().bit_is_set() {} // サブクロック停止確認
-
-    // メインクロック発振器(MOSC)の停止
-    p.SYSTEM.mosccr().write(|w| w.mostp()._1());
-    while !p.SYSTEM.mosccr().read().mostp().is_1() {} // 確認
-
-    // メインクロック発振器(MOSC)モードコントロールレジスタ
-    p.SYSTEM.momcr().write(|w| {
-        w.modrv1()._0(); // 10MHz ～ 20MHz
-        w.mosel()._0() // 外部水晶発振子
-    });
-
-    // メインクロック発振器(MOSC)待機時間
-    p.SYSTEM.moscwtcr().write(|w| w.msts()._1001()); // 32768us
-
-    // メインクロック発振器(MOSC)動作
-    p.SYSTEM.mosccr().write(|w| w.mostp()._0());
-    while !p.SYSTEM.mosccr().read().mostp().is_0() {} // 確認
-
-    // メインクロック発振器(MOSC)発振安定待ち
-    while !p.SYSTEM.oscsf().read().moscsf().bit_is_set() {}
-
-    // 分周器設定
-    p.SYSTEM.sckdivcr().write(|w| {
-        w.ick()._000(); // システムクロック(ICLK Div /1)
-        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
-        w.pckb()._000(); // 周辺モジュールクロックB(PCLKA Div /1)
-        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
-        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
-        w.fck()._000() // Flashインターフェースクロック(FCLK Div /1)
-    });
-
-    // システムクロックをメインクロックに切り替え
-    p.SYSTEM.sckscr().write(|w| w.cksel()._011()); // メインクロック発振器(MOSC)
-    while !p.SYSTEM.sckscr().read().cksel().is_011() {} // 確認
-
-    // フラッシュキャッシュ
-    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
-
-    while p.FCACHE.fcacheiv().read().fcacheiv().bit_is_set() {}
-    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
-}
-
-// クロック設定
-// 16MHz水晶発振子を12逓倍のち4分周した48MHzをクロックに設定する
-#[allow(dead_code)]
-fn clock_init_pll48(p: &pac::Peripherals) {
-    // 保護レジスタを操作して書込み許可を与える
-    p.SYSTEM.prcr().write(|w| {
-        w.prkey().set(0xa5);
-        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
-        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
-    });
-    // 関数脱出時に保護レジスタを元通りに復帰する
-    defer! {
-        p.SYSTEM.prcr().write(|w| {
-            w.prkey().set(0xa5);
-            w.prc0().clear_bit();
-            w.prc1().clear_bit()
-        });
-    }
-
-    // 消費電力モードはハイスピードモードに設定
-    p.SYSTEM.opccr().write(|w| w.opcm()._00());
-    while !p.SYSTEM.opccr().read().opcmtsf().bit_is_clear() {} // 確認
-
-    // サブクロックの停止
-    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
-    while !p.SYSTEM.sosccr().read().sostp().bit_is_set() {} // サブクロック停止確認
-
-    // メインクロック発振器(MOSC)の停止
-    p.SYSTEM.mosccr().write(|w| w.mostp()._1());
-    while !p.SYSTEM.mosccr().read().mostp().is_1() {} // 確認
-
-    //
-    // メインクロック発振器(MOSC)の入力は16MHz水晶発振子
-    //
-
-    // メインクロック発振器(MOSC)モードコントロールレジスタ
-    p.SYSTEM.momcr().write(|w| {
-        w.modrv1()._0(); // 10MHz ～ 20MHz
-        w.mosel()._0() // 外部水晶発振子
-    });
-
-    // メインクロック発振器(MOSC)待機時間
-    p.SYSTEM.moscwtcr().write(|w| w.msts()._1001()); // 32768us
-
-    // メインクロック発振器(MOSC)動作
-    p.SYSTEM.mosccr().write(|w| w.mostp()._0());
-    while !p.SYSTEM.mosccr().read().mostp().is_0() {} // 確認
-
-    // メインクロック発振器(MOSC)発振安定待ち
-    while !p.SYSTEM.oscsf().read().moscsf().bit_is_set() {}
-
-    // メインクロック発振器(MOSC)をPLLで逓倍する
-    // 逓倍率および分周比の設定
-    p.SYSTEM.pllccr2().write(|w| {
-        w.pllmul().set(12 - 1); // PLL Mul x12
-        w.plodiv()._10() // PLL Div /4
-    });
-
-    // PLL動作
-    p.SYSTEM.pllcr().write(|w| w.pllstp()._0());
-    while !p.SYSTEM.pllcr().read().pllstp().is_0() {} // 確認
-
-    // PLL発振安定待ち
-    while !p.SYSTEM.oscsf().read().pllsf().bit_is_set() {}
-
-    // 分周器設定
-    p.SYSTEM.sckdivcr().write(|w| {
-        w.ick()._000(); // システムクロック(ICLK Div /1)
-        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
-        w.pckb()._001(); // 周辺モジュールクロックB(PCLKA Div /2)
-        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
-        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
-        w.fck()._001() // Flashインターフェースクロック(FCLK Div /2)
-    });
-
-    // システムクロックをPLLに切り替え
-    p.SYSTEM.sckscr().write(|w| w.cksel()._101()); // PLL
-    while !p.SYSTEM.sckscr().read().cksel().is_101() {} // 確認
-
-    // フラッシュキャッシュ
-    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
-
-    while p.FCACHE.fcacheiv().read().fcacheiv().bit_is_set() {}
-    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
-}
-
-// クロック設定
-// 高速オンチップオシレータ(HOCO)を48MHzでメインクロックに設定する
-#[allow(dead_code)]
-fn clock_init_hoco48(p: &pac::Peripherals) {
-    // 保護レジスタを操作して書込み許可を与える
-    p.SYSTEM.prcr().write(|w| {
-        w.prkey().set(0xa5);
-        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
-        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
-    });
-    // 関数脱出時に保護レジスタを元通りに復帰する
-    defer! {
-        p.SYSTEM.prcr().write(|w| {
-            w.prkey().set(0xa5);
-            w.prc0().clear_bit();
-            w.prc1().clear_bit()
-        });
-    }
-
-    // 消費電力モードはハイスピードモードに設定
-    p.SYSTEM.opccr().write(|w| w.opcm()._00());
-    while !p.SYSTEM.opccr().read().opcmtsf().bit_is_clear() {} // 確認
-
-    // サブクロックの停止
-    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
-    while !p.SYSTEM.sosccr().read().sostp().bit_is_set() {} // サブクロック停止確認
-
-    // 高速オンチップオシレータ(HOCO)48MHz指定
-    // HOCOCR2レジスタのアドレス: 0x4001_e037
-    // HOCO48MHz指定: 0b0010_0000
-    unsafe {
-        core::ptr::write_volatile(0x4001_e037 as *mut u8, 0b0010_0000);
-    }
-    // 高速オンチップオシレータ(HOCO)クロック動作
-    p.SYSTEM.hococr().write(|w| w.hcstp()._0());
-    while !p.SYSTEM.hococr().read().hcstp().is_0() {} // 確認
-
-    // 高速オンチップオシレータ(HOCO)クロック発振安定待ち
-    while !p.SYSTEM.oscsf().read().hocosf().bit_is_set() {}
-
-    // 分周器設定
-    p.SYSTEM.sckdivcr().write(|w| {
-        w.ick()._000(); // システムクロック(ICLK Div /1)
-        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
-        w.pckb()._001(); // 周辺モジュールクロックB(PCLKA Div /2)
-        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
-        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
-        w.fck()._001() // Flashインターフェースクロック(FCLK Div /2)
-    });
-
-    // システムクロックを高速オンチップオシレータ(HOCO)クロックに切り替え
-    p.SYSTEM.sckscr().write(|w| w.cksel()._000()); // HOCOクロック
-    while !p.SYSTEM.sckscr().read().cksel().is_000() {} // 確認
-
-    // フラッシュキャッシュ
-    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
-
-    while p.FCACHE.fcacheiv().read().fcacheiv().bit_is_set() {}
-    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct Rgb<T> {
-    pub r: T,
-    pub g: T,
-    pub b: T,
-}
-
-fn ws2812b_reset(p: &pac::Peripherals, delay: &mut Delay, led_pin_bit: u16) {
-    // OUTPUT LOW LEVEL
-    p.PORT1
-        .podr()
-        .modify(|r, w| unsafe { w.bits(r.bits() & !led_pin_bit) });
-    delay.delay_us(280);
-}
-
-fn ws2812b_write(p: &pac::Peripherals, led_pin_bit: u16, value: Rgb<u8>) {
-    let grb = (value.g as u32) << 16 | (value.r as u32) << 8 | value.b as u32;
-    for bit_digit in (0..=23u8).rev() {
-        let flag = grb >> bit_digit & 1;
-        // OUTPUT HIGH LEVEL
-        p.PORT1
-            .podr()
-            .modify(|r, w| unsafe { w.bits(r.bits() | led_pin_bit) });
-        if flag == 0 {
-            cortex_m::asm::nop();
-        } else {
-            cortex_m::asm::nop();
-            cortex_m::asm::nop();
-            cortex_m::asm::nop();
-        }
-        // OUTPUT LOW LEVEL
-        p.PORT1
-            .podr()
-            .modify(|r, w| unsafe { w.bits(r.bits() & !led_pin_bit) });
-        cortex_m::asm::nop();
-        cortex_m::asm::nop();
-        cortex_m::asm::nop();
-    }
-}
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
     // 周辺機能
     let p = pac::Peripherals::take().unwrap();
-    let syst = cortex_m::Peripherals::take().unwrap().SYST;
+    let mut core = cortex_m::Peripherals::take().unwrap();
 
     // クロック設定
-    //clock_init_pll48(&p);
-    clock_init_hoco48(&p);
+    //let clocks = clock_init_pll48(&p).unwrap();
+    let clocks = clock_init_hoco48(&p).unwrap();
+
+    let mut delay = Delay::new(core.SYST, clocks.iclk);
 
-    let mut delay = Delay::new(syst, 48_000_000);
+    // WS2812Bのタイミングを計るDWTサイクルカウンタを有効にする
+    core.DCB.enable_trace();
+    core.DWT.enable_cycle_counter();
+    let ws2812b_timing = Ws2812bTiming::new(clocks.iclk);
 
-    // PORT 106 = D6(WS2812B)
-    // PORT 111 = D13(LED)
+    // WS2812B(PORT 106 = D6)
+    // ユーザーLED(PORT 111 = D13)
     // 以上の入出力ポートを出力に設定
-    let led_pin_bit: u16 = 1 << 6 | 1 << 11;
-    p.PORT1
-        .pdr()
-        .modify(|r, w| unsafe { w.bits(r.bits() | led_pin_bit) });
+    // ws2812b_*()はWS2812BのPORTに書き込むので、ユーザーLEDも同じPORTにあること
+    const _: () = assert!(BOARD_PINS.ws2812b.port == BOARD_LEDS.onboard.port);
+    let led_pin_bit: u16 = 1 << BOARD_PINS.ws2812b.pin | 1 << BOARD_LEDS.onboard.pin;
+    let (ws2812b, led) = (BOARD_PINS.ws2812b, BOARD_LEDS.onboard);
+    for (port, pin) in [(ws2812b.port, ws2812b.pin), (led.port, led.pin)] {
+        pfs_configure(&p, port, pin, PfsConfig::output(false));
+    }
 
     // 色
     let red = Rgb { r: 128, g: 0, b: 0 };
@@ -318,8 +75,8 @@ fn main() -> ! {
     // メインループ
     loop {
         for color in sequences {
-            ws2812b_reset(&p, &mut delay, led_pin_bit);
-            ws2812b_write(&p, led_pin_bit, color);
+            ws2812b_reset(&mut delay, &ws2812b_timing, led_pin_bit);
+            ws2812b_write(&ws2812b_timing, led_pin_bit, color);
             delay.delay_ms(1000);
         }
     }
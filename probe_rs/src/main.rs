@@ -7,140 +7,329 @@
 #![no_std]
 #![no_main]
 
-use cortex_m::delay::Delay;
-use defmt;
+use cortex_m::interrupt::InterruptNumber;
 use defmt_rtt as _;
-use heapless::{String, Vec};
+use embedded_hal::digital;
+use hello_ra4m1_common::board::BOARD_LEDS;
+use hello_ra4m1_common::clock::{clock_init_hoco48, current_iclk_hz};
+use hello_ra4m1_common::pfs::{PfsConfig, pfs_configure};
+use hello_ra4m1_common::port::{port_input, port_output, port_reset, port_set};
+use hello_ra4m1_common::signal::Signal;
+use hello_ra4m1_common::time::{Timeout, millis_tick, systick_init};
+use hello_ra4m1_common::unique_id::{read_product_part_number, read_unique_id};
+#[cfg(not(feature = "panic-sos"))]
 use panic_probe as _;
 use ra4m1_fsp_pac as pac;
-use scopeguard::defer;
-
-// クロック設定
-// 高速オンチップオシレータ(HOCO)を48MHzでメインクロックに設定する
-fn clock_init_hoco48(p: &pac::Peripherals) {
-    // 保護レジスタを操作して書込み許可を与える
-    p.SYSTEM.prcr().write(|w| {
-        w.prkey().set(0xa5);
-        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
-        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
-    });
-    // 関数脱出時に保護レジスタを元通りに復帰する
-    defer! {
-        p.SYSTEM.prcr().write(|w| {
-            w.prkey().set(0xa5);
-            w.prc0().clear_bit();
-            w.prc1().clear_bit()
-        });
-    }
-
-    // 消費電力モードはハイスピードモードに設定
-    p.SYSTEM.opccr().write(|w| w.opcm()._00());
-    while !p.SYSTEM.opccr().read().opcmtsf().bit_is_clear() {} // 確認
-
-    // サブクロックの停止
-    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
-    while !p.SYSTEM.sosccr().read().sostp().bit_is_set() {} // サブクロック停止確認
-
-    // 高速オンチップオシレータ(HOCO)48MHz指定
-    // HOCOCR2レジスタのアドレス: 0x4001_e037
-    // HOCO48MHz指定: 0b0010_0000
-    unsafe { core::ptr::write_volatile(0x4001_e037 as *mut u8, 0b0010_0000u8) };
-
-    // 高速オンチップオシレータ(HOCO)クロック動作
-    p.SYSTEM.hococr().write(|w| w.hcstp()._0());
-    while !p.SYSTEM.hococr().read().hcstp().is_0() {} // 確認
-
-    // 高速オンチップオシレータ(HOCO)クロック発振安定待ち
-    while !p.SYSTEM.oscsf().read().hocosf().bit_is_set() {}
-
-    // 分周器設定
-    p.SYSTEM.sckdivcr().write(|w| {
-        w.ick()._000(); // システムクロック(ICLK Div /1)
-        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
-        w.pckb()._001(); // 周辺モジュールクロックB(PCLKA Div /2)
-        w.pckc()._000(); // 周辺モジュールクロックC(PCLKA Div /1)
-        w.pckd()._000(); // 周辺モジュールクロックD(PCLKA Div /1)
-        w.fck()._001() // Flashインターフェースクロック(FCLK Div /2)
+use ra4m1_fsp_pac::interrupt;
+
+// 汎用出力端子
+pub struct OutputPin {
+    port: u8,
+    pin: u8,
+}
+
+impl OutputPin {
+    // PORT port の pin 番端子を汎用出力にする
+    pub fn new(p: &pac::Peripherals, port: u8, pin: u8) -> Self {
+        assert!(port <= 9 && pin <= 15);
+        pfs_configure(p, port, pin, PfsConfig::output(false));
+        OutputPin { port, pin }
+    }
+
+    // High出力
+    pub fn set_high(&mut self) {
+        port_set(self.port, 1 << self.pin);
+    }
+
+    // Low出力
+    pub fn set_low(&mut self) {
+        port_reset(self.port, 1 << self.pin);
+    }
+
+    // 出力を反転する
+    pub fn toggle(&mut self) {
+        if self.is_set_high() {
+            self.set_low();
+        } else {
+            self.set_high();
+        }
+    }
+
+    // High出力中ならtrue
+    pub fn is_set_high(&self) -> bool {
+        port_output(self.port) & (1 << self.pin) != 0
+    }
+}
+
+// embedded-hal の出力端子
+// 端子の操作は失敗しない
+impl digital::ErrorType for OutputPin {
+    type Error = core::convert::Infallible;
+}
+
+impl digital::OutputPin for OutputPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        OutputPin::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        OutputPin::set_high(self);
+        Ok(())
+    }
+}
+
+impl digital::StatefulOutputPin for OutputPin {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(OutputPin::is_set_high(self))
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!OutputPin::is_set_high(self))
+    }
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        OutputPin::toggle(self);
+        Ok(())
+    }
+}
+
+// 汎用入力端子
+#[allow(dead_code)]
+pub struct InputPin {
+    port: u8,
+    pin: u8,
+}
+
+#[allow(dead_code)]
+impl InputPin {
+    // PORT port の pin 番端子を汎用入力にする
+    // pull_up: trueなら入力プルアップを有効にする
+    pub fn new(p: &pac::Peripherals, port: u8, pin: u8, pull_up: bool) -> Self {
+        assert!(port <= 9 && pin <= 15);
+        pfs_configure(p, port, pin, PfsConfig::input(pull_up));
+        InputPin { port, pin }
+    }
+
+    // High入力ならtrue
+    pub fn is_high(&self) -> bool {
+        port_input(self.port) & (1 << self.pin) != 0
+    }
+
+    // Low入力ならtrue
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+// embedded-hal の入力端子
+impl digital::ErrorType for InputPin {
+    type Error = core::convert::Infallible;
+}
+
+impl digital::InputPin for InputPin {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(InputPin::is_high(self))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(InputPin::is_low(self))
+    }
+}
+
+// 外部端子割り込み(IRQn)に使える端子
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqPin {
+    P105, // D2  (IRQ0)
+    P104, // D3  (IRQ1)
+    P100, // A5  (IRQ2)
+    P110, // D12 (IRQ3)
+    P302, // D1  (IRQ5)
+    P301, // D0  (IRQ6)
+}
+
+impl IrqPin {
+    // (ポート番号, 端子番号)
+    const fn port_pin(self) -> (u8, u8) {
+        match self {
+            IrqPin::P105 => (1, 5),
+            IrqPin::P104 => (1, 4),
+            IrqPin::P100 => (1, 0),
+            IrqPin::P110 => (1, 10),
+            IrqPin::P302 => (3, 2),
+            IrqPin::P301 => (3, 1),
+        }
+    }
+
+    // IRQ番号
+    pub const fn irq(self) -> usize {
+        match self {
+            IrqPin::P105 => 0,
+            IrqPin::P104 => 1,
+            IrqPin::P100 => 2,
+            IrqPin::P110 => 3,
+            IrqPin::P302 => 5,
+            IrqPin::P301 => 6,
+        }
+    }
+}
+
+// 外部端子割り込みの検出条件
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+// 外部端子割り込み(IRQ0 ~ IRQ15)の検出フラグ
+// 割り込みハンドラで立てて、gpio_irq_take()で読んで下ろす
+static GPIO_IRQ_FLAGS: [Signal; 16] = [const { Signal::new() }; 16];
+
+// 外部端子割り込みに使えるICUの割り込み番号
+// 割り込みハンドラはIEL0 ~ IEL3を用意している
+const GPIO_IRQ_IELS: [pac::Interrupt; 4] = [
+    pac::Interrupt::IEL0,
+    pac::Interrupt::IEL1,
+    pac::Interrupt::IEL2,
+    pac::Interrupt::IEL3,
+];
+
+// 外部端子割り込みの設定
+// 端子の変化を検出すると割り込み番号 iel の割り込みハンドラが検出フラグを立てる
+pub fn gpio_irq_init(p: &pac::Peripherals, pin: IrqPin, edge: IrqEdge, iel: pac::Interrupt) {
+    assert!(GPIO_IRQ_IELS.contains(&iel));
+    let irq = pin.irq();
+    let (port, pin_number) = pin.port_pin();
+
+    // 設定中は割り込みを禁止する
+    cortex_m::peripheral::NVIC::mask(iel);
+
+    // 端子をIRQ入力端子にする
+    let config = PfsConfig {
+        irq: true,
+        ..PfsConfig::input(false)
+    };
+    pfs_configure(p, port, pin_number, config);
+
+    // 検出条件
+    // ノイズを除くためにデジタルフィルタ(PCLKB/64)を使う
+    p.ICU.irqcr(irq).write(|w| {
+        match edge {
+            IrqEdge::Falling => w.irqmd()._00(),
+            IrqEdge::Rising => w.irqmd()._01(),
+            IrqEdge::Both => w.irqmd()._10(),
+        };
+        w.fclksel()._11();
+        w.flten()._1()
     });
 
-    // システムクロックを高速オンチップオシレータ(HOCO)クロックに切り替え
-    p.SYSTEM.sckscr().write(|w| w.cksel()._000()); // HOCOクロック
-    while !p.SYSTEM.sckscr().read().cksel().is_000() {} // 確認
+    // 外部端子割り込みのイベント番号は IRQ番号 + 1
+    let n = iel.number() as usize;
+    GPIO_IRQ_FLAGS[irq].clear();
+    p.ICU.ielsr(n).write(|w| w.iels().set(irq as u8 + 1));
+    // 割り込みステータスフラグクリア
+    p.ICU.ielsr(n).modify(|_r, w| w.ir().clear_bit());
 
-    // フラッシュキャッシュ
-    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
+    // 割り込み有効
+    unsafe { cortex_m::peripheral::NVIC::unmask(iel) };
+}
 
-    while p.FCACHE.fcacheiv().read().fcacheiv().bit_is_set() {}
-    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
+// IRQ番号 irq の外部端子割り込みがあればtrue
+// 検出フラグは下ろす
+pub fn gpio_irq_take(irq: usize) -> bool {
+    GPIO_IRQ_FLAGS[irq].take()
 }
 
+// 外部端子割り込みの共通処理
+// IELSRnに設定したイベント番号からIRQ番号を求めて検出フラグを立てる
+fn gpio_irq_handler(n: usize) {
+    let p = unsafe { pac::Peripherals::steal() };
+    let event = p.ICU.ielsr(n).read().iels().bits() as usize;
+    if (1..=16).contains(&event) {
+        GPIO_IRQ_FLAGS[event - 1].set();
+    }
+    // 割り込みステータスフラグクリア
+    p.ICU.ielsr(n).modify(|_r, w| w.ir().clear_bit());
+}
+
+// 外部端子割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL0() {
+    gpio_irq_handler(0);
+}
+
+#[cortex_m_rt::interrupt]
+fn IEL1() {
+    gpio_irq_handler(1);
+}
+
+#[cortex_m_rt::interrupt]
+fn IEL2() {
+    gpio_irq_handler(2);
+}
+
+#[cortex_m_rt::interrupt]
+fn IEL3() {
+    gpio_irq_handler(3);
+}
+
+// SysTick割り込みハンドラ
+#[cortex_m_rt::exception]
+fn SysTick() {
+    millis_tick();
+}
+
+// LEDの点滅間隔(ミリ秒)
+const LED_BLINK_INTERVAL_MS: u32 = 1000;
+
 #[cortex_m_rt::entry]
 fn main() -> ! {
     // 型名
-    let product_part_number: String<16> = {
-        // ファクトリ MCU インフォメーションフラッシュルートテーブル (FMIFRT)
-        const FMIFRT: *const u32 = 0x407f_b19c as *const u32;
-
-        // ユニークIDのベースアドレス
-        let unique_id_base_address = unsafe { core::ptr::read_volatile(FMIFRT) } as *const u32;
-
-        //
-        let mut buf: Vec<u8, 16> = Vec::new();
-
-        // 型名レジスタ n（PNRn）（n = 0 ～ 3）
-        // ユニークIDのベースアドレスに対するオフセットは 24h, 28h, 2ch, 30h
-        for offset in [0x24, 0x28, 0x2c, 0x30] {
-            let pnr: u32 = unsafe {
-                core::ptr::read_volatile(unique_id_base_address.wrapping_byte_add(offset))
-            };
-            // バイトオーダーを変換
-            let bytes = pnr.to_ne_bytes();
-            //
-            for i in 0..4 {
-                buf.push(bytes[i]).unwrap();
-            }
-        }
-
-        // heapless::Stringに変換
-        String::from_utf8(buf).unwrap()
-    };
+    let product_part_number = read_product_part_number();
 
     // 挨拶
     defmt::info!(r#"Hello. I'm "{}""#, product_part_number.as_str());
+    defmt::info!("Unique ID: {=[u8; 16]:02x}", read_unique_id());
 
     // 周辺機能
     let p = pac::Peripherals::take().unwrap();
-    let cp = cortex_m::Peripherals::take().unwrap();
-    let mut delay = Delay::new(cp.SYST, 48_000_000);
+    let mut cp = cortex_m::Peripherals::take().unwrap();
 
     // クロック設定
-    clock_init_hoco48(&p);
+    clock_init_hoco48(&p).unwrap();
+    // 経過時間(ミリ秒)を数えるSysTickタイマーの設定
+    // 周波数はレジスタから読み出した値を使う
+    systick_init(&mut cp.SYST, current_iclk_hz(&p));
 
-    //
-    const LED: u16 = 1 << 11;
+    // ユーザーLED(UNO R4 MINIMAはPORT 111 = D13)の入出力ポートを出力に設定
+    let onboard = BOARD_LEDS.onboard;
+    let mut led = OutputPin::new(&p, onboard.port, onboard.pin);
 
-    // 書き込みプロテクトレジスタを操作して書込み許可を与える
-    p.PMISC.pwpr().write(|w| w.b0wi()._0());
-    p.PMISC.pwpr().write(|w| w.pfswe()._1());
-
-    // PORT 111 = D13(LED) の入出力ポートを出力に設定
-    p.PFS
-        .p111pfs()
-        .modify(|_r, w| w.pcr()._0().pdr()._1().ncodr()._0().pmr()._0());
-
-    // 書き込みプロテクトレジスタを復帰する
-    p.PMISC.pwpr().write(|w| w.pfswe()._0());
-    p.PMISC.pwpr().write(|w| w.b0wi()._1());
+    // PORT 105 = D2 の立ち上がりで割り込む(人感センサーなど)
+    gpio_irq_init(&p, IrqPin::P105, IrqEdge::Rising, pac::Interrupt::IEL0);
 
     // メインループ
+    // LEDは1秒ごとに切り替える
+    // delay_ms()で止まって待たないので、点滅させながら外部端子割り込みも見られる
+    let mut blink = Timeout::after(LED_BLINK_INTERVAL_MS);
+    // 点灯から始める(Lowで点灯するLEDならLow出力)
+    if onboard.active_low {
+        led.set_low();
+    } else {
+        led.set_high();
+    }
     loop {
-        p.PORT1
-            .podr()
-            .modify(|r, w| unsafe { w.bits(r.bits() | LED) });
-        delay.delay_ms(1000);
-        p.PORT1
-            .podr()
-            .modify(|r, w| unsafe { w.bits(r.bits() & !LED) });
-        delay.delay_ms(1000);
+        if gpio_irq_take(IrqPin::P105.irq()) {
+            defmt::info!("IRQ0 detected");
+        }
+        if blink.is_expired() {
+            led.toggle();
+            blink.advance(LED_BLINK_INTERVAL_MS);
+        }
+        // SysTick(1ミリ秒ごと)か外部端子割り込みまで眠って待つ
+        cortex_m::asm::wfi();
     }
 }
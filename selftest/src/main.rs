@@ -0,0 +1,388 @@
+// hello-ra4m1
+// 新しいボードの動作確認
+// クロック, 型名, リセット要因, LED, 温度センサ, シリアル通信を順に試してPASS/FAILを報告する
+//
+// SPDX-License-Identifier: MIT
+// SPDX-FileCopyrightText: 2026 Akihiro Yamamoto <github.com/ak1211>
+
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+use cortex_m::delay::Delay;
+use defmt_rtt as _;
+use heapless::String;
+use hello_ra4m1_common::board::{BOARD_LEDS, BOARD_PINS};
+use hello_ra4m1_common::clock::{ClockConfig, clock_init_hoco48, current_clock_config};
+use hello_ra4m1_common::pfs::{PfsConfig, PwprGuard, pfs_configure};
+use hello_ra4m1_common::reset::read_reset_cause;
+use hello_ra4m1_common::unique_id::read_product_part_number;
+use panic_probe as _;
+use ra4m1_fsp_pac as pac;
+
+// シリアル通信の通信速度
+const BAUD_RATE: u32 = 115200;
+
+// 1行受信を待つ時間(ミリ秒)
+const ECHO_TIMEOUT_MS: u32 = 10_000;
+
+// 1行の最大文字数
+const LINE_SIZE: usize = 64;
+
+// 温度センサの値として受け入れる範囲(℃)
+// RA4M1の動作温度範囲
+const TEMPERATURE_RANGE: core::ops::RangeInclusive<f32> = -40.0..=105.0;
+
+// A/D変換の高電位基準電圧(AVCC0, V)
+// Arduino UNO R4 MINIMAは5V, RA4M1-Zeroは3.3V
+#[cfg(feature = "board-uno-r4-minima")]
+const ADC_VREF_VOLTS: f32 = 5.0;
+#[cfg(feature = "board-ra4m1-zero")]
+const ADC_VREF_VOLTS: f32 = 3.3;
+
+// ユーザーズマニュアル(TSN 特性)より温度傾斜の標準値
+const TSN_TYPICAL_SLOPE: f32 = -3.65 / 1000.0; // V/℃
+
+// SCI1モジュールの設定
+// 割り込みを使わずにポーリングで送受信する
+fn sci_init(p: &pac::Peripherals, clocks: &ClockConfig) {
+    // SCI1モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrb().modify(|_r, w| w.mstpb30()._0());
+
+    // SCI動作を停止
+    p.SCI1.scr().reset();
+
+    // FIFO動作を禁止
+    p.SCI1.fcr().modify(|_r, w| w.fm()._0());
+
+    // 内蔵ボーレートジェネレータを選択
+    p.SCI1.scr().modify(|_r, w| w.cke()._00());
+
+    // 調歩同期
+    p.SCI1.simr1().modify(|_r, w| w.iicm()._0());
+
+    //
+    p.SCI1.spmr().modify(|_r, w| {
+        w.sse()._0(); // SSn端子機能は無効
+        w.ctse()._0(); // CTS機能は無効（RTS出力機能は有効）
+        w.mss()._0(); // TXDn端子は送信、RXDn端子は受信（マスタモード）
+        w.mff()._0(); // モードフォルトエラーなし
+        w.ckpol()._0(); // クロック極性反転なし
+        w.ckph()._0() // クロック遅延なし
+    });
+
+    //
+    p.SCI1.scmr().modify(|_r, w| {
+        w.smif()._0(); // 非スマートカードインタフェースモード
+        w.sinv()._0(); // TDRレジスタの内容をそのまま送信。受信データをそのままRDRレジスタに格納
+        w.sdir()._0(); // LSBファースト転送
+        w.chr1()._1() // データ長8ビットで送受信
+    });
+
+    //
+    p.SCI1.smr().modify(|_r, w| {
+        w.cks()._00(); // PCLKA /1 クロック (n = 0)
+        w.mp()._0(); // マルチプロセッサ通信機能は無効
+        w.stop()._0(); // STOP: 1bit
+        w.pe()._0(); // パリティビットを付加しない
+        w.chr()._0(); // データ長8ビットで送受信
+        w.cm()._0() // 調歩同期式モード
+    });
+
+    //
+    p.SCI1.semr().modify(|_r, w| {
+        w.bgdm()._0(); // ボーレートジェネレータから1倍の周波数のクロックを出力
+        w.brme()._0();
+        w.abcs()._0(); // 基本クロックの16サイクルを1ビット期間として選択
+        w.abcse()._0() // 1ビット期間のクロックサイクルは、SEMRレジスタのBGDMとABCS の組み合わせにより決定
+    });
+
+    // N = PCLKA / (64 * 2^(2n-1) * B) - 1 (n = 0)
+    let n = (clocks.pclka + 16 * BAUD_RATE) / (32 * BAUD_RATE) - 1; // 四捨五入
+    p.SCI1.brr().write(|w| unsafe { w.bits(n as u8) });
+
+    // 端子機能選択(SCI1)
+    const SCI1_PSEL: u8 = 0b00101;
+
+    // I/Oポートの設定
+    // 端子が割り当てられていないボードでは、送受信はするが端子には出ない
+    if let Some(sci1) = BOARD_PINS.sci1 {
+        let pwpr = PwprGuard::new(p);
+        let (txd, rxd) = (sci1.tx, sci1.rx);
+        pfs_configure(p, txd.port, txd.pin, PfsConfig::peripheral(SCI1_PSEL, true));
+        pfs_configure(
+            p,
+            rxd.port,
+            rxd.pin,
+            PfsConfig::peripheral(SCI1_PSEL, false),
+        );
+        drop(pwpr);
+    }
+
+    // シリアル送受信動作を許可
+    p.SCI1.scr().modify(|_r, w| {
+        w.te()._1(); // シリアル送信動作を許可
+        w.re()._1() // シリアル受信動作を許可
+    });
+}
+
+// 1行送信する
+fn uart_println(p: &pac::Peripherals, s: &str) {
+    for &txd in s.as_bytes().iter().chain(b"\r\n") {
+        // 送信データエンプティ待ち
+        while p.SCI1.ssr().read().tdre().is_0() {}
+        p.SCI1.tdr().write(|w| unsafe { w.bits(txd) });
+    }
+    // 送信終了待ち
+    while p.SCI1.ssr().read().tend().is_0() {}
+}
+
+// 1行受信する
+// timeout_msミリ秒以内に改行が来なければNone
+// 後退(BS, DEL)は最後の1文字を消す, 復帰(CR)は無視する
+fn uart_read_line(
+    p: &pac::Peripherals,
+    delay: &mut Delay,
+    timeout_ms: u32,
+) -> Option<String<LINE_SIZE>> {
+    let mut line = String::new();
+    // 1回の確認で10us待つ
+    for _ in 0..timeout_ms * 100 {
+        let ssr = p.SCI1.ssr().read();
+        if ssr.orer().is_1() || ssr.fer().is_1() || ssr.per().is_1() {
+            // シリアル通信エラーステータスフラグクリア
+            p.SCI1
+                .ssr()
+                .modify(|_r, w| w.per()._0().fer()._0().orer()._0());
+        }
+        if ssr.rdrf().is_1() {
+            match p.SCI1.rdr().read().bits() {
+                b'\n' => return Some(line),
+                b'\r' => {}
+                0x08 | 0x7f => {
+                    line.pop();
+                }
+                byte => {
+                    let _ = line.push(byte as char);
+                }
+            }
+        } else {
+            delay.delay_us(10);
+        }
+    }
+    None
+}
+
+// ADCで内蔵温度センサを1回読み取る(14ビット)
+fn read_tsn_raw(p: &pac::Peripherals) -> u16 {
+    // ADC14モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd16()._0());
+
+    // A/D変換を停止する
+    p.ADC140.adcsr().modify(|_r, w| w.adst()._0());
+    // アナログ入力チャネルは変換しない
+    p.ADC140.adansa0().reset();
+    p.ADC140.adansa1().reset();
+
+    // A/D変換設定
+    p.ADC140.adcer().modify(|_r, w| {
+        w.adprc()._11(); // 14ビット精度
+        w.adrfmt()._0() // A/Dデータレジスタのフォーマットを右詰めにする
+    });
+    // サンプリング時間設定
+    p.ADC140
+        .adsstrt()
+        .modify(|_r, w| unsafe { w.sst().bits(100) });
+    // 高電位基準電圧はAVCC0
+    p.ADC140.adhvrefcnt().modify(|_r, w| w.hvsel()._00());
+
+    p.ADC140.adexicr().modify(|_r, w| {
+        w.ocsa()._0(); // 内部基準電圧のA/D変換禁止
+        w.tssad()._0(); // 温度センサ出力A/D変換値加算／平均モード非選択
+        w.tssa()._1() // 温度センサ出力のA/D変換許可
+    });
+
+    // シングルスキャンモードでA/D変換開始
+    p.ADC140.adcsr().modify(|_r, w| {
+        w.adcs()._00(); // シングルスキャンモード
+        w.adst()._1() // A/D変換開始
+    });
+
+    // A/D変換待ち
+    while p.ADC140.adcsr().read().adst().is_1() {}
+
+    // 16384は2の14乗
+    p.ADC140.adtsdr().read().bits() & (16384 - 1)
+}
+
+// 温度センサ出力のA/D変換値を温度に変換する
+// T = (Vs - V1) / Slope + T1 (T1 = 125℃, V1はCAL125から求める)
+fn tsn_to_celsius(p: &pac::Peripherals, tsn: u16) -> f32 {
+    // 4096は2の12乗
+    let cal125 = {
+        let h = p.TSN.tscdrh().read().bits() as u16; // 上位4ビット
+        let l = p.TSN.tscdrl().read().bits() as u16; // 下位8ビット
+        ((h << 8) + l) & (4096 - 1)
+    };
+    let v1 = 3.3 * (cal125 as f32) / 4096.0;
+    let vs = ADC_VREF_VOLTS * (tsn as f32) / 16384.0;
+    (vs - v1) / TSN_TYPICAL_SLOPE + 125.0 // ℃
+}
+
+// 試験結果を数える
+struct Report {
+    passed: u32,
+    failed: u32,
+    uart: bool, // シリアル通信にも結果を送る
+}
+
+impl Report {
+    const fn new() -> Self {
+        Report {
+            passed: 0,
+            failed: 0,
+            uart: false,
+        }
+    }
+
+    // 1項目の結果をdefmtとシリアル通信で報告する
+    fn check(&mut self, p: &pac::Peripherals, name: &str, pass: bool, detail: &str) {
+        let verdict = if pass { "PASS" } else { "FAIL" };
+        if pass {
+            self.passed += 1;
+            defmt::info!("[{}] {}: {}", verdict, name, detail);
+        } else {
+            self.failed += 1;
+            defmt::error!("[{}] {}: {}", verdict, name, detail);
+        }
+        if self.uart {
+            let mut msg: String<128> = String::new();
+            let _ = write!(msg, "[{}] {}: {}", verdict, name, detail);
+            uart_println(p, msg.as_str());
+        }
+    }
+}
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    // 周辺機能
+    let p = pac::Peripherals::take().unwrap();
+    let cp = cortex_m::Peripherals::take().unwrap();
+
+    let mut report = Report::new();
+
+    // 前回のリセット要因
+    let reset_cause = read_reset_cause(&p);
+
+    // 48MHzクロック設定
+    // 失敗したら今のクロックのまま続ける
+    let clock_result = clock_init_hoco48(&p);
+    let clocks = clock_result.unwrap_or_else(|_| current_clock_config(&p));
+    let mut delay = Delay::new(cp.SYST, clocks.iclk);
+
+    // シリアル通信が使えるようになってから結果を報告する
+    // SCI1の端子が無いボードではdefmtだけで報告する
+    sci_init(&p, &clocks);
+    report.uart = BOARD_PINS.sci1.is_some();
+    if report.uart {
+        uart_println(&p, "selftest start");
+    }
+
+    // クロック
+    {
+        let mut detail: String<64> = String::new();
+        let _ = match clock_result {
+            Ok(c) => write!(detail, "{:?} ICLK={} Hz", c.source, c.iclk),
+            Err(e) => write!(detail, "{:?}", e),
+        };
+        report.check(&p, "clock", clock_result.is_ok(), detail.as_str());
+    }
+
+    // 型名
+    {
+        let part_number = read_product_part_number();
+        let part_number = part_number.trim_end();
+        report.check(
+            &p,
+            "part number",
+            part_number.starts_with("R7FA4M1"),
+            part_number,
+        );
+    }
+
+    // リセット要因
+    // どの要因でも動作には問題ないので報告するだけ
+    report.check(&p, "reset cause", true, reset_cause.as_str());
+
+    // LED
+    // 目で見て確かめる
+    {
+        let led = BOARD_LEDS.onboard;
+        led.init(&p);
+        for _ in 0..3 {
+            led.on();
+            delay.delay_ms(250);
+            led.off();
+            delay.delay_ms(250);
+        }
+        report.check(&p, "LED", true, "blinked 3 times (check by eye)");
+    }
+
+    // 温度センサ
+    {
+        let celsius = tsn_to_celsius(&p, read_tsn_raw(&p));
+        let mut detail: String<32> = String::new();
+        let _ = write!(detail, "{:.1} C", celsius);
+        report.check(
+            &p,
+            "temperature",
+            TEMPERATURE_RANGE.contains(&celsius),
+            detail.as_str(),
+        );
+    }
+
+    // シリアル通信
+    // 1行受け取って送り返す
+    if report.uart {
+        uart_println(&p, "type a line and press Enter:");
+        match uart_read_line(&p, &mut delay, ECHO_TIMEOUT_MS) {
+            Some(line) => {
+                let mut detail: String<{ LINE_SIZE + 8 }> = String::new();
+                let _ = write!(detail, "echo \"{}\"", line);
+                report.check(&p, "UART echo", true, detail.as_str());
+            }
+            None => report.check(&p, "UART echo", false, "timeout"),
+        }
+    } else {
+        defmt::warn!("UART echo skipped: no SCI1 pins on this board");
+    }
+
+    // まとめ
+    let mut summary: String<64> = String::new();
+    let _ = write!(
+        summary,
+        "selftest {}: {} passed, {} failed",
+        if report.failed == 0 { "PASS" } else { "FAIL" },
+        report.passed,
+        report.failed
+    );
+    defmt::info!("{}", summary.as_str());
+    if report.uart {
+        uart_println(&p, summary.as_str());
+    }
+
+    // 結果をLEDで示す
+    // 全部PASSなら点灯したまま, FAILがあれば速く点滅させる
+    let led = BOARD_LEDS.onboard;
+    loop {
+        if report.failed == 0 {
+            led.on();
+            cortex_m::asm::wfi();
+        } else {
+            led.on();
+            delay.delay_ms(100);
+            led.off();
+            delay.delay_ms(100);
+        }
+    }
+}
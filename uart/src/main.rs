@@ -8,125 +8,425 @@
 #![no_main]
 
 use bbqueue::nicknames::Jerk;
-use core::cell::Cell;
+use bbqueue::prod_cons::stream::StreamGrantW;
+use core::cell::{Cell, RefCell};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicUsize, Ordering};
 use cortex_m::interrupt::InterruptNumber;
 use critical_section::Mutex;
-use defmt;
+#[cfg(not(feature = "defmt-uart"))]
 use defmt_rtt as _;
-use heapless::{String, Vec, format};
+use heapless::{String, format};
+use hello_ra4m1_common::board::{BOARD_LEDS, BOARD_PINS};
+use hello_ra4m1_common::boot_count::{boot_count_increment, boot_count_read};
+use hello_ra4m1_common::can::{
+    CAN_RX_MAILBOXES, CanBitrate, CanFrame, can_init, can_receive_mailbox, can_send,
+};
+use hello_ra4m1_common::clock::{
+    ClockConfig, clock_init_hoco48, current_clock_config, current_iclk_hz,
+};
+use hello_ra4m1_common::cobs::{CobsError, cobs_decode, cobs_encode, cobs_max_encoded_len};
+use hello_ra4m1_common::crc::{CrcPolynomial, crc_compute, crc_init, crc_software};
+use hello_ra4m1_common::dataflash::{
+    DATAFLASH_BLOCK_SIZE, DataFlashError, TEMP_UNIT_BLOCK, dataflash_erase, dataflash_init,
+    dataflash_read, dataflash_write,
+};
+use hello_ra4m1_common::elc::{
+    ElcPeripheral, GPT0_COUNTER_OVERFLOW_EVENT, GPT1_COUNTER_OVERFLOW_EVENT, elc_link,
+};
+use hello_ra4m1_common::irq::{IrqPriority, unmask_with_priority};
+use hello_ra4m1_common::lvd::{LvdAction, LvdThreshold, lvd_init, lvd_nmi_clear, lvd_status};
+use hello_ra4m1_common::mstp::{Peripheral, module_stop};
+use hello_ra4m1_common::pfs::{PfsConfig, PwprGuard, pfs_configure};
+use hello_ra4m1_common::port::{port_reset, port_set};
+use hello_ra4m1_common::reset::{ResetCause, read_reset_cause};
+use hello_ra4m1_common::rtc::{RtcClockSource, rtc_init, rtc_now};
+use hello_ra4m1_common::signal::Signal;
+use hello_ra4m1_common::temp_log::TempLogger;
+use hello_ra4m1_common::time::{Timeout, busy_wait_ns, millis, millis_tick, systick_init};
+use hello_ra4m1_common::unique_id::{read_product_part_number, read_unique_id};
+#[cfg(not(feature = "panic-uart"))]
 use panic_probe as _;
 use ra4m1_fsp_pac as pac;
 use ra4m1_fsp_pac::interrupt;
-use scopeguard::defer;
 
-// クロック設定
-// 高速オンチップオシレータ(HOCO)を48MHzでメインクロックに設定する
-fn clock_init_hoco48(p: &pac::Peripherals) {
-    // 保護レジスタを操作して書込み許可を与える
-    p.SYSTEM.prcr().write(|w| {
-        w.prkey().set(0xa5);
-        w.prc0().set_bit(); // クロック発生回路関連レジスタに書込み許可を与える
-        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
-    });
-    // 関数脱出時に保護レジスタを元通りに復帰する
-    defer! {
-        p.SYSTEM.prcr().write(|w| {
-            w.prkey().set(0xa5);
-            w.prc0().clear_bit();
-            w.prc1().clear_bit()
-        });
-    }
-
-    // 消費電力モードはハイスピードモードに設定
-    p.SYSTEM.opccr().write(|w| w.opcm()._00());
-    while !p.SYSTEM.opccr().read().opcmtsf().bit_is_clear() {} // 確認
-
-    // サブクロックの停止
-    p.SYSTEM.sosccr().write(|w| w.sostp().set_bit()); // サブクロックの停止
-    while !p.SYSTEM.sosccr().read().sostp().bit_is_set() {} // サブクロック停止確認
-
-    // 高速オンチップオシレータ(HOCO)48MHz指定
-    // HOCOCR2レジスタのアドレス: 0x4001_e037
-    // HOCO48MHz指定: 0b0010_0000
-    unsafe { core::ptr::write_volatile(0x4001_e037 as *mut u8, 0b0010_0000) };
-
-    // 高速オンチップオシレータ(HOCO)クロック動作
-    p.SYSTEM.hococr().write(|w| w.hcstp()._0());
-    while !p.SYSTEM.hococr().read().hcstp().is_0() {} // 確認
-
-    // 高速オンチップオシレータ(HOCO)クロック発振安定待ち
-    while !p.SYSTEM.oscsf().read().hocosf().bit_is_set() {}
+// 32ビットGPTのチャネル
+// RA4M1の32ビットGPTはGPT320とGPT321の2チャネルだけ(GPT162~GPT167は16ビット)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Gpt32Channel {
+    Gpt320, // 1秒周期のタイマー割り込みと時刻
+    Gpt321, // DACの波形出力
+}
 
-    // 分周器設定
-    p.SYSTEM.sckdivcr().write(|w| {
-        w.ick()._000(); // システムクロック(ICLK Div /1)
-        w.pcka()._000(); // 周辺モジュールクロックA(PCLKA Div /1)
-        w.pckb()._001(); // 周辺モジュールクロックB(PCLKB Div /2)
-        w.pckc()._000(); // 周辺モジュールクロックC(PCLKC Div /1)
-        w.pckd()._000(); // 周辺モジュールクロックD(PCLKD Div /1)
-        w.fck()._001() // Flashインターフェースクロック(FCLK Div /2)
-    });
+impl Gpt32Channel {
+    // オーバーフロー割り込みハンドラで調べるチャネル
+    const ALL: [Gpt32Channel; 2] = [Gpt32Channel::Gpt320, Gpt32Channel::Gpt321];
 
-    // システムクロックを高速オンチップオシレータ(HOCO)クロックに切り替え
-    p.SYSTEM.sckscr().write(|w| w.cksel()._000()); // HOCOクロック
-    while !p.SYSTEM.sckscr().read().cksel().is_000() {} // 確認
+    // チャネルのレジスタ
+    // GPT320とGPT321は同じレジスタ構成
+    fn regs(self, p: &pac::Peripherals) -> &pac::gpt320::RegisterBlock {
+        match self {
+            Gpt32Channel::Gpt320 => &p.GPT320,
+            Gpt32Channel::Gpt321 => &p.GPT321,
+        }
+    }
 
-    // フラッシュキャッシュ
-    p.FCACHE.fcacheiv().write(|w| w.fcacheiv()._1()); // フラッシュキャッシュインバリデート
-    while !p.FCACHE.fcacheiv().read().fcacheiv().is_0() {} // 確認
+    // カウンタオーバーフロー(GPTn_COUNTER_OVERFLOW)のイベント番号
+    const fn overflow_event_number(self) -> u8 {
+        match self {
+            Gpt32Channel::Gpt320 => GPT0_COUNTER_OVERFLOW_EVENT,
+            Gpt32Channel::Gpt321 => GPT1_COUNTER_OVERFLOW_EVENT,
+        }
+    }
 
-    p.FCACHE.fcachee().write(|w| w.fcacheen().set_bit()); // フラッシュキャッシュ許可
+    // オーバーフロー割り込みに割り当てた割り込み番号
+    const fn overflow_iel(self) -> pac::Interrupt {
+        match self {
+            Gpt32Channel::Gpt320 => GPT320_OVERFLOW_IEL,
+            Gpt32Channel::Gpt321 => GPT321_OVERFLOW_IEL,
+        }
+    }
 }
 
 // GPTタイマーモジュール設定
-fn gpt_module_init(p: &pac::Peripherals) {
+// 指定のチャネルを period_count カウント周期のUPカウントにして、オーバーフロー割り込みを有効にする
+// カウント動作は gpt_start() で始める
+fn gpt_module_init(
+    p: &pac::Peripherals,
+    clocks: &ClockConfig,
+    channel: Gpt32Channel,
+    period_count: u32,
+) {
+    let gpt = channel.regs(p);
+    gpt_isr_register();
+
     // GPT321~GPT320モジュールのモジュールストップ状態の解除
     p.MSTP.mstpcrd().modify(|_r, w| w.mstpd5()._0());
 
-    // GPT320タイマーカウント動作を停止
-    p.GPT320.gtcr().modify(|_r, w| w.cst()._0());
+    // タイマーカウント動作を停止
+    gpt.gtcr().modify(|_r, w| w.cst()._0());
 
     // UPカウント設定
-    p.GPT320.gtuddtyc().modify(|_r, w| w.ud()._1());
+    gpt.gtuddtyc().modify(|_r, w| w.ud()._1());
 
     // カウンタ最大値設定
-    let period_count: u32 = 48_000_000; // 1秒周期
-    p.GPT320
-        .gtpr()
-        .write(|w| unsafe { w.bits(period_count - 1) });
+    if channel == Gpt32Channel::Gpt320 {
+        GPT320_CLOCK_HZ.store(clocks.pclkd, Ordering::Relaxed);
+    }
+    gpt.gtpr().write(|w| unsafe { w.bits(period_count - 1) });
 
     // カウンタ初期値設定
-    p.GPT320.gtcnt().reset();
+    gpt.gtcnt().reset();
 
-    // GPT320 オーバーフロー割り込み設定
+    // オーバーフロー割り込み設定
     p.ICU
-        .ielsr(GPT320_OVERFLOW_IEL.number() as usize)
-        .modify(|_r, w| w.iels().set(0x05d));
+        .ielsr(channel.overflow_iel().number() as usize)
+        .modify(|_r, w| w.iels().set(channel.overflow_event_number()));
+
+    // タイマーモジュール割り込み有効
+    // 時間のかかる処理をしてもシリアル受信を待たせないように、優先度は最も低くする
+    unmask_with_priority(channel.overflow_iel(), IrqPriority::Timer);
+}
+
+// タイマーカウント動作を開始
+fn gpt_start(p: &pac::Peripherals, channel: Gpt32Channel) {
+    channel.regs(p).gtcr().modify(|_r, w| {
+        w.cst()._1();
+        w.md()._000(); // のこぎり波形PWMモード
+        w.tpcs()._000() // プリスケーラ― (PCLKD/1)
+    });
+}
+
+// 割り込みハンドラが使う周辺機能の登録場所
+// 割り込みハンドラで全部の周辺機能をsteal()しないように、ハンドラごとに使うものだけを登録して with() で使う
+// 割り込みを許可する前に register() で登録しておく
+struct IsrResources<T>(Mutex<RefCell<Option<T>>>);
+
+impl<T> IsrResources<T> {
+    const fn new() -> Self {
+        IsrResources(Mutex::new(RefCell::new(None)))
+    }
+
+    // steal()した周辺機能を登録する
+    // 登録済みなら何もしないので、steal()するのは一度だけになる
+    fn register(&self, steal: impl FnOnce() -> T) {
+        critical_section::with(|cs| {
+            let mut resources = self.0.borrow_ref_mut(cs);
+            if resources.is_none() {
+                resources.replace(steal());
+            }
+        });
+    }
+
+    // 登録した周辺機能を割り込み禁止で使う, 登録前ならNone
+    fn with<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        critical_section::with(|cs| self.0.borrow_ref(cs).as_ref().map(f))
+    }
+}
+
+// GPTのオーバーフロー割り込みハンドラが使う周辺機能
+struct GptIsrResources {
+    gpt320: pac::GPT320,
+    gpt321: pac::GPT321,
+    dac12: pac::DAC12, // GPT321のオーバーフローごとに波形を出力する
+    icu: pac::ICU,     // 割り込みステータスフラグのクリア
+}
+
+impl GptIsrResources {
+    // チャネルのレジスタ
+    fn channel(&self, channel: Gpt32Channel) -> &pac::gpt320::RegisterBlock {
+        match channel {
+            Gpt32Channel::Gpt320 => &self.gpt320,
+            Gpt32Channel::Gpt321 => &self.gpt321,
+        }
+    }
+}
+
+// gpt_module_init()で登録する
+static GPT_ISR_RESOURCES: IsrResources<GptIsrResources> = IsrResources::new();
+
+// GPTのオーバーフロー割り込みハンドラが使う周辺機能を登録する
+fn gpt_isr_register() {
+    GPT_ISR_RESOURCES.register(|| unsafe {
+        GptIsrResources {
+            gpt320: pac::GPT320::steal(),
+            gpt321: pac::GPT321::steal(),
+            dac12: pac::DAC12::steal(),
+            icu: pac::ICU::steal(),
+        }
+    });
+}
+
+// 登録した周辺機能を割り込み禁止で使う, 登録前ならNone
+fn with_gpt<R>(f: impl FnOnce(&GptIsrResources) -> R) -> Option<R> {
+    GPT_ISR_RESOURCES.with(f)
+}
 
-    // GPT320 タイマーモジュール割り込み有効
-    unsafe { cortex_m::peripheral::NVIC::unmask(GPT320_OVERFLOW_IEL) };
+// GPTのオーバーフロー割り込みの振り分け
+// どの割り込み番号から呼んでも、GTSTでオーバーフローしたチャネルを調べて処理する
+fn gpt_overflow_dispatch(resources: &GptIsrResources) {
+    for channel in Gpt32Channel::ALL {
+        let gpt = resources.channel(channel);
+        if gpt.gtst().read().tcfpo().is_0() {
+            continue;
+        }
+        match channel {
+            Gpt32Channel::Gpt320 => {
+                // 優先度の高い割り込みハンドラの gpt_now_ticks() が上位とフラグの食い違いを見ないように
+                // 上位を増やすのとフラグを下ろすのは割り込み禁止でまとめて行う
+                cortex_m::interrupt::free(|_cs| {
+                    GPT320_OVERFLOW_COUNT.fetch_add(1, Ordering::SeqCst);
+                    // タイマオーバーフロー割り込みフラグクリア
+                    gpt.gtst().modify(|_r, w| w.tcfpo().clear_bit());
+                });
+                GPT320_TIMER_OVERFLOW_FLAG.set();
+            }
+            Gpt32Channel::Gpt321 => {
+                // タイマオーバーフロー割り込みフラグクリア
+                gpt.gtst().modify(|_r, w| w.tcfpo().clear_bit());
+                // 次のサンプルをDACに出力する
+                critical_section::with(|cs| {
+                    if let Some(wave_gen) = WAVE_GEN.borrow_ref_mut(cs).as_mut() {
+                        dac_write(&resources.dac12, wave_gen.next_sample());
+                    }
+                });
+            }
+        }
+    }
 }
 
 // GPT320タイマオーバーフロー検出フラグ
-static GPT320_TIMER_OVERFLOW_FLAG: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static GPT320_TIMER_OVERFLOW_FLAG: Signal = Signal::new();
+
+// GPT320のオーバーフロー回数(64ビットカウンタの上位)
+// 1秒周期なので一周するのは約136年後
+static GPT320_OVERFLOW_COUNT: AtomicU32 = AtomicU32::new(0);
+
+// GPT320の起動からの経過カウント数(PCLKD単位)
+// オーバーフロー回数 * カウンタ周期 + GTCNT
+// 割り込み禁止で読むので、どの割り込みハンドラからも呼べる
+fn gpt_now_ticks(gpt320: &pac::GPT320) -> u64 {
+    cortex_m::interrupt::free(|_cs| {
+        let period = gpt320.gtpr().read().bits() as u64 + 1;
+        let mut high = GPT320_OVERFLOW_COUNT.load(Ordering::SeqCst) as u64;
+        let mut count = gpt320.gtcnt().read().bits();
+        // オーバーフローしたのに割り込みハンドラがまだ動いていない(割り込み禁止中や
+        // 優先度の高い割り込みハンドラの中)ときは、オーバーフロー割り込みフラグを見て上位を補う
+        if gpt320.gtst().read().tcfpo().is_1() {
+            count = gpt320.gtcnt().read().bits(); // オーバーフロー後の値を読み直す
+            high += 1;
+        }
+        high * period + count as u64
+    })
+}
+
+// GPT320のカウントクロック(PCLKD)の周波数(Hz)
+// gpt_module_init()で設定するまでは0
+static GPT320_CLOCK_HZ: AtomicU32 = AtomicU32::new(0);
+
+// defmtのログに付ける時刻
+// GPT320の起動からの経過時間(マイクロ秒), GPT320を設定するまでは0
+defmt::timestamp!("{=u64:us}", {
+    // モジュールストップ中のGPT320は読まない
+    core::num::NonZeroU64::new(GPT320_CLOCK_HZ.load(Ordering::Relaxed) as u64)
+        .and_then(|hz| with_gpt(|gpt| gpt_now_ticks(&gpt.gpt320) * 1_000_000 / hz))
+        .unwrap_or(0)
+});
+
+// メインループを起こす理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wake {
+    Tick,     // タイマー割り込み(1秒周期)
+    Received, // シリアル通信でデータを受信した
+}
+
+// 次のタイマー割り込みか、シリアル通信の受信まで眠って待つ
+// WFIで眠っている間もシリアル通信の割り込み(RXI/TXI/TEI/ERI)で起こされて
+// 割り込みハンドラが動くので、送受信は止まらない
+// 受信でも起きるので、受信待ち行列はタイマー割り込みを待たずに取り出せる
+fn wait_for_wake() -> Wake {
+    loop {
+        // フラグを確認してからWFIに入るまでの間に割り込みが入ると
+        // 次の割り込みまで起きられないので、割り込み禁止の状態で確認する
+        cortex_m::interrupt::disable();
+        if GPT320_TIMER_OVERFLOW_FLAG.take() {
+            unsafe { cortex_m::interrupt::enable() };
+            return Wake::Tick;
+        }
+        if RXD_RECEIVED_FLAG.take() {
+            unsafe { cortex_m::interrupt::enable() };
+            return Wake::Received;
+        }
+        // 割り込み禁止中でも割り込み要求があればWFIから復帰する
+        // 割り込みハンドラはまだ動かないので、前後のカウントの差が眠っていた時間になる
+        // DWTサイクルカウンタはCPUのクロックで進むので、眠っている間の時間はGPT320で計る
+        let asleep = gpt320_now_ticks();
+        cortex_m::asm::wfi();
+        SLEEP_TICKS.fetch_add(
+            gpt320_now_ticks().saturating_sub(asleep) as u32,
+            Ordering::Relaxed,
+        );
+        // ここで割り込みハンドラが動く
+        unsafe { cortex_m::interrupt::enable() };
+    }
+}
+
+// GPT320の起動からの経過カウント数(PCLKD単位), GPT320を登録するまでは0
+fn gpt320_now_ticks() -> u64 {
+    with_gpt(|gpt| gpt_now_ticks(&gpt.gpt320)).unwrap_or(0)
+}
+
+// wait_for_wake()のWFIで眠っていた時間(GPT320のカウント数)
+// CpuLoad::tick()で読んだら0に戻す
+static SLEEP_TICKS: AtomicU32 = AtomicU32::new(0);
+
+// シリアル通信送信データエンプティ割り込みの回数と、処理にかかったサイクル数(DWTサイクルカウンタ)
+// "load" で送ったら0に戻す
+static UART_TXI_COUNT: AtomicU32 = AtomicU32::new(0);
+static UART_TXI_CYCLES: AtomicU32 = AtomicU32::new(0);
+
+// CPU負荷の測定
+// 1秒ごとにGPT320のカウントの進みとWFIで眠っていた時間から、眠っていなかった割合を求める
+struct CpuLoad {
+    last_ticks: u64,    // 前回tick()を呼んだ時のGPT320のカウント数
+    load_permille: u32, // 直前の1秒間の負荷(‰)
+    peak_permille: u32, // 前回report()からの最大の負荷(‰)
+}
+
+impl CpuLoad {
+    fn new() -> Self {
+        SLEEP_TICKS.store(0, Ordering::Relaxed);
+        CpuLoad {
+            last_ticks: gpt320_now_ticks(),
+            load_permille: 0,
+            peak_permille: 0,
+        }
+    }
+
+    // 1秒ごとに呼ぶ
+    fn tick(&mut self) {
+        let now = gpt320_now_ticks();
+        let elapsed = now.saturating_sub(self.last_ticks);
+        self.last_ticks = now;
+        let asleep = (SLEEP_TICKS.swap(0, Ordering::Relaxed) as u64).min(elapsed);
+        if let Some(load) = ((elapsed - asleep) * 1000).checked_div(elapsed) {
+            self.load_permille = load as u32;
+            self.peak_permille = self.peak_permille.max(self.load_permille);
+        }
+    }
+
+    // シリアル通信で "load" を受け取ったら負荷と送信データエンプティ割り込みの回数とサイクル数を送る
+    // 最大の負荷と割り込みの回数は0に戻す
+    fn report(&mut self) {
+        let count = UART_TXI_COUNT.swap(0, Ordering::Relaxed);
+        let cycles = UART_TXI_CYCLES.swap(0, Ordering::Relaxed);
+        defmt::info!(
+            "CPU load {} permille (peak {}), TXI {} irqs {} cycles",
+            self.load_permille,
+            self.peak_permille,
+            count,
+            cycles
+        );
+        if let Some(mut line) = uart_line(64) {
+            let _ = write!(
+                line,
+                "CPU load {}.{}% (peak {}.{}%), TXI {} irqs {} cycles",
+                self.load_permille / 10,
+                self.load_permille % 10,
+                self.peak_permille / 10,
+                self.peak_permille % 10,
+                count,
+                cycles
+            );
+            let _ = line.commit();
+        }
+        self.peak_permille = self.load_permille;
+    }
+}
+
+// SysTick割り込みハンドラ
+// 起動からの経過時間(millis)を1ミリ秒進める
+#[cortex_m_rt::exception]
+fn SysTick() {
+    millis_tick();
+}
+
+// 電源電圧低下を知らせる検出電圧
+// Noneなら電圧検出回路を使わない
+// Arduino UNO R4 MINIMAのVCCは5V, RA4M1-Zeroは3.3V
+#[cfg(feature = "board-uno-r4-minima")]
+const LVD_THRESHOLD: Option<LvdThreshold> = Some(LvdThreshold::V4_02);
+#[cfg(feature = "board-ra4m1-zero")]
+const LVD_THRESHOLD: Option<LvdThreshold> = Some(LvdThreshold::V2_90);
+
+// 電源電圧低下を検出するとNMIハンドラで立てるフラグ
+static LVD_WARNING_FLAG: Signal = Signal::new();
+
+// ノンマスカブル割り込み(NMI)ハンドラ
+// NMIは割り込み禁止でも入るので、IsrResourcesのように割り込み禁止で守る登録場所は使えずsteal()する
+#[cortex_m_rt::exception]
+unsafe fn NonMaskableInt() {
+    let p = unsafe { pac::Peripherals::steal() };
+    if lvd_nmi_clear(&p) {
+        LVD_WARNING_FLAG.set();
+    }
+}
 
 // タイマオーバーフロー割り込み番号
 const GPT320_OVERFLOW_IEL: pac::Interrupt = pac::Interrupt::IEL10;
 
-// タイマオーバーフロー割り込みハンドラ
+// GPT320 タイマオーバーフロー割り込みハンドラ
 #[cortex_m_rt::interrupt]
 fn IEL10() {
-    let p = unsafe { pac::Peripherals::steal() };
-
-    if p.GPT320.gtst().read().tcfpo().is_1() {
-        // タイマオーバーフロー割り込み
-        critical_section::with(|cs| GPT320_TIMER_OVERFLOW_FLAG.borrow(cs).replace(true));
-        // タイマオーバーフロー割り込みフラグクリア
-        p.GPT320.gtst().modify(|_r, w| w.tcfpo().clear_bit());
-    }
+    // gpt_module_init()が割り込みを許可する前に登録するので、ここで登録前(None)にはならない
+    with_gpt(|gpt| {
+        gpt_overflow_dispatch(gpt);
 
-    // 割り込みステータスフラグクリア
-    p.ICU.ielsr(10).modify(|_r, w| w.ir().clear_bit());
+        // 割り込みステータスフラグクリア
+        gpt.icu
+            .ielsr(GPT320_OVERFLOW_IEL.number() as usize)
+            .modify(|_r, w| w.ir().clear_bit());
+    });
 }
 
 const QUEUE_SIZE: usize = 64;
@@ -134,11 +434,219 @@ const QUEUE_SIZE: usize = 64;
 // シリアル通信受信待ち行列
 static RXD_QUEUE: Jerk<QUEUE_SIZE> = Jerk::new();
 
+// シリアル通信でデータを受信したフラグ
+// 受信データ割り込みで立てて、メインループを起こす
+static RXD_RECEIVED_FLAG: Signal = Signal::new();
+
+// シリアル通信受信待ち行列に溜まっているバイト数
+// bbqueueには格納されているバイト数を調べる方法が無いので別に数える
+// 受信データ割り込みで増やし、受信待ち行列から取り出したら減らす
+static RXD_QUEUE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+// シリアル通信受信待ち行列に溜まっているバイト数
+fn uart_rx_len() -> usize {
+    RXD_QUEUE_LEN.load(Ordering::SeqCst)
+}
+
+// 受信待ち行列が溢れる前にXOFFを送って相手の送信を止め、空いたらXONで再開させる(ソフトウェアフロー制御)ならtrue
+// 端末側もXON/XOFFのフロー制御を有効にすること
+// メインループは受信のたびに受信待ち行列を取り出すので、普段はXOFFを送らない
+// 行の処理(dump tempなど)で取り出しが遅れた時に相手を待たせる
+// バイナリのフレームを送受信する時は、XON/XOFFのバイトがフレームに混ざるので使わない
+const UART_XON_XOFF: bool = TELEMETRY_TEXT && !UART_RX_COBS_FRAMES && !SBUS_RECEIVE;
+
+// 受信待ち行列がこのバイト数になったらXOFFを送る
+// XOFFが届くまでに相手が送ってしまう分を残しておく
+const RXD_XOFF_THRESHOLD: usize = QUEUE_SIZE / 2;
+
+// XOFFを送った後、受信待ち行列がこのバイト数以下になったらXONを送る
+const RXD_XON_THRESHOLD: usize = QUEUE_SIZE / 4;
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+// XOFFを送って相手の送信を止めている
+static RXD_XOFF_SENT: AtomicBool = AtomicBool::new(false);
+
+// 送信待ち行列より先に送るフロー制御の文字, 0なら無し
+static TXD_FLOW_CONTROL: AtomicU8 = AtomicU8::new(0);
+
+// フロー制御の文字を送信待ち行列の先頭に割り込ませて送る
+fn uart_send_flow_control(sci1: &pac::SCI1, byte: u8) {
+    TXD_FLOW_CONTROL.store(byte, Ordering::SeqCst);
+    sci1_start_transmit(sci1);
+}
+
+// 受信待ち行列が空いたらXONを送って相手の送信を再開させる
+// メインループで受信待ち行列から取り出した後に呼ぶ
+fn uart_rx_flow_resume(p: &pac::Peripherals) {
+    if UART_XON_XOFF
+        && uart_rx_len() <= RXD_XON_THRESHOLD
+        && RXD_XOFF_SENT.swap(false, Ordering::SeqCst)
+    {
+        uart_send_flow_control(&p.SCI1, XON);
+    }
+}
+
+// シリアル通信受信待ち行列が一杯で捨てた受信データのバイト数
+// 増えていたらメインループの取り出しが受信に追いついていない
+static RXD_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+// 起動してから捨てた受信データのバイト数
+fn uart_rx_dropped() -> u32 {
+    RXD_DROPPED.load(Ordering::Relaxed)
+}
+
+// シリアル通信受信待ち行列を空にする
+// 端末を(再)接続した後などに、それまでに届いた受信データを捨てる
+fn uart_rx_clear() {
+    // 読み出し位置が末尾で折り返すと2回に分かれるので空になるまで繰り返す
+    let rxd_cons = RXD_QUEUE.stream_consumer();
+    while let Ok(rgr) = rxd_cons.read() {
+        let len = rgr.len();
+        rgr.release(len);
+        RXD_QUEUE_LEN.fetch_sub(len, Ordering::SeqCst);
+    }
+}
+
 // シリアル通信送信待ち行列
 static TXD_QUEUE: Jerk<QUEUE_SIZE> = Jerk::new();
 
+// データ長
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DataBits {
+    Seven, // 7ビット
+    Eight, // 8ビット
+}
+
+// パリティ
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Parity {
+    None, // パリティビットを付加しない
+    Even, // 偶数パリティ
+    Odd,  // 奇数パリティ
+}
+
+// ストップビット
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StopBits {
+    One, // 1ビット
+    Two, // 2ビット
+}
+
+// データのビット順(SCMR.SDIR)
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BitOrder {
+    LsbFirst, // LSBファースト(調歩同期式の普通の順)
+    MsbFirst, // MSBファースト(SPIや一部のスマートカードなど)
+}
+
+// 調歩同期式の通信速度とフレーム形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SerialFormat {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub bit_order: BitOrder,
+}
+
+impl SerialFormat {
+    // 8ビット, パリティなし, ストップビット1 (8N1)
+    pub const fn n81(baud: u32) -> Self {
+        SerialFormat {
+            baud,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            bit_order: BitOrder::LsbFirst,
+        }
+    }
+
+    pub const fn with(self, data_bits: DataBits, parity: Parity, stop_bits: StopBits) -> Self {
+        SerialFormat {
+            data_bits,
+            parity,
+            stop_bits,
+            ..self
+        }
+    }
+
+    // ビット順を変える(初期値はLSBファースト)
+    #[allow(dead_code)]
+    pub const fn with_bit_order(self, bit_order: BitOrder) -> Self {
+        SerialFormat { bit_order, ..self }
+    }
+}
+
+// SCI1の通信速度とフレーム形式
+// SBUSは 100000bps, 8ビット, 偶数パリティ, ストップビット2
+const UART_FORMAT: SerialFormat = if SBUS_RECEIVE {
+    SerialFormat::n81(100_000).with(DataBits::Eight, Parity::Even, StopBits::Two)
+} else {
+    SerialFormat::n81(115200)
+};
+
+// SCI1の通信速度とフレーム形式を設定する
+// SMRとBRRは送受信動作を禁止(SCR.TE = 0, SCR.RE = 0)している時にしか書き換えられない
+fn sci1_set_format(p: &pac::Peripherals, clocks: &ClockConfig, format: &SerialFormat) {
+    // 通信速度
+    // PCLKA = 48MHz
+    // n = 0
+    // B = 115200 bps
+    // 2^(2n-1) = 2^(-1) = 1/2
+
+    //       48 * 10^6
+    // N = --------------------- - 1 = 13 - 1 = 12
+    //       64 * 1/2 * 115200
+
+    // Nが8ビットに収まる最小のnを選ぶ
+    let (cks, n) = (0..=3u32)
+        .map(|cks| {
+            let div = 32 * (1 << (2 * cks)) * format.baud;
+            (cks, ((clocks.pclka + div / 2) / div).max(1) - 1) // 四捨五入
+        })
+        .find(|&(_cks, n)| n <= 255)
+        .unwrap_or((3, 255));
+
+    //
+    p.SCI1.scmr().modify(|_r, w| {
+        w.smif()._0(); // 非スマートカードインタフェースモード
+        w.sinv()._0(); // TDRレジスタの内容をそのまま送信。受信データをそのままRDRレジスタに格納
+        match format.bit_order {
+            BitOrder::LsbFirst => w.sdir()._0(), // LSBファースト転送
+            BitOrder::MsbFirst => w.sdir()._1(), // MSBファースト転送
+        };
+        w.chr1()._1() // データ長7ビットか8ビット(SMR.CHRで選ぶ)
+    });
+
+    //
+    p.SCI1.smr().modify(|_r, w| {
+        w.cks().set(cks as u8); // PCLKA / 4^n クロック
+        w.mp()._0(); // マルチプロセッサ通信機能は無効
+        match format.stop_bits {
+            StopBits::One => w.stop()._0(),
+            StopBits::Two => w.stop()._1(),
+        };
+        match format.parity {
+            Parity::None => w.pe()._0(), // パリティビットを付加しない
+            Parity::Even => w.pe()._1().pm()._0(),
+            Parity::Odd => w.pe()._1().pm()._1(),
+        };
+        match format.data_bits {
+            DataBits::Seven => w.chr()._1(),
+            DataBits::Eight => w.chr()._0(),
+        };
+        w.cm()._0() // 調歩同期式モード
+    });
+
+    p.SCI1.brr().write(|w| unsafe { w.bits(n as u8) });
+}
+
 // シリアルコミュニケーションインタフェース(SCI)モジュール設定
-fn sci_module_init(p: &pac::Peripherals) {
+fn sci_module_init(p: &pac::Peripherals, clocks: &ClockConfig) {
     // SCI1モジュールのモジュールストップ状態の解除
     p.MSTP.mstpcrb().modify(|_r, w| w.mstpb30()._0());
 
@@ -164,24 +672,6 @@ fn sci_module_init(p: &pac::Peripherals) {
         w.ckph()._0() // クロック遅延なし
     });
 
-    //
-    p.SCI1.scmr().modify(|_r, w| {
-        w.smif()._0(); // 非スマートカードインタフェースモード
-        w.sinv()._0(); // TDRレジスタの内容をそのまま送信。受信データをそのままRDRレジスタに格納
-        w.sdir()._0(); // LSBファースト転送
-        w.chr1()._1() // データ長8ビットで送受信
-    });
-
-    //
-    p.SCI1.smr().modify(|_r, w| {
-        w.cks()._00(); // PCLKA /1 クロック (n = 0)
-        w.mp()._0(); // マルチプロセッサ通信機能は無効
-        w.stop()._0(); // STOP: 1bit
-        w.pe()._0(); // パリティビットを付加しない
-        w.chr()._0(); // データ長8ビットで送受信
-        w.cm()._0() // 調歩同期式モード
-    });
-
     //
     p.SCI1.semr().modify(|_r, w| {
         w.bgdm()._0(); // ボーレートジェネレータから1倍の周波数のクロックを出力
@@ -190,16 +680,8 @@ fn sci_module_init(p: &pac::Peripherals) {
         w.abcse()._0() // 1ビット期間のクロックサイクルは、SEMRレジスタのBGDMとABCS の組み合わせにより決定
     });
 
-    // PCLKA = 48MHz
-    // n = 0
-    // B = 115200 bps
-    // 2^(2n-1) = 2^(-1) = 1/2
-
-    //       48 * 10^6
-    // N = --------------------- - 1 = 13 - 1 = 12
-    //       64 * 1/2 * 115200
-
-    p.SCI1.brr().write(|w| unsafe { w.bits(12) });
+    // 通信速度とフレーム形式
+    sci1_set_format(p, clocks, &UART_FORMAT);
 
     // イベント番号
     const SCI1_RXI_EVENT_NUMBER: u8 = 0x09e;
@@ -207,6 +689,9 @@ fn sci_module_init(p: &pac::Peripherals) {
     const SCI1_TEI_EVENT_NUMBER: u8 = 0x0a0;
     const SCI1_ERI_EVENT_NUMBER: u8 = 0x0a1;
 
+    // 端子機能選択(SCI1)
+    const SCI1_PSEL: u8 = 0b00101;
+
     // シリアル通信受信データ割り込み設定
     p.ICU
         .ielsr(SCI1_RXI_IEL.number() as usize)
@@ -227,123 +712,51 @@ fn sci_module_init(p: &pac::Peripherals) {
         .ielsr(SCI1_ERI_IEL.number() as usize)
         .modify(|_r, w| w.iels().set(SCI1_ERI_EVENT_NUMBER));
 
+    // 割り込みハンドラが使う周辺機能を登録する
+    SCI1_ISR_RESOURCES.register(|| unsafe {
+        Sci1IsrResources {
+            sci1: pac::SCI1::steal(),
+            icu: pac::ICU::steal(),
+        }
+    });
+
     // SCI1モジュール割り込み有効
-    unsafe {
-        cortex_m::peripheral::NVIC::unmask(SCI1_RXI_IEL);
-        cortex_m::peripheral::NVIC::unmask(SCI1_TXI_IEL);
-        cortex_m::peripheral::NVIC::unmask(SCI1_TEI_IEL);
-        cortex_m::peripheral::NVIC::unmask(SCI1_ERI_IEL);
-    }
+    // 受信は他の割り込みハンドラの実行中でも読み取れるように優先度を最も高くする
+    unmask_with_priority(SCI1_RXI_IEL, IrqPriority::SciReceive);
+    unmask_with_priority(SCI1_TXI_IEL, IrqPriority::SciTransmit);
+    unmask_with_priority(SCI1_TEI_IEL, IrqPriority::SciTransmit);
+    unmask_with_priority(SCI1_ERI_IEL, IrqPriority::SciReceive);
 
     // I/Oポートの設定
-    let _ = {
-        // 書き込みプロテクトレジスタを操作してPmnPFS レジスタに書き込み許可を与える
-        p.PMISC.pwpr().write(|w| w.b0wi()._0());
-        p.PMISC.pwpr().write(|w| w.pfswe()._1());
-
-        // 離脱時に書き込みプロテクトレジスタを元通りに復帰する
-        defer! {
-        p.PMISC.pwpr().write(|w| w.pfswe()._0());
-        p.PMISC.pwpr().write(|w| w.b0wi()._1());
-        }
-
-        if false {
-            // PORT 012 = TX_LED
-            p.PFS
-                .p012pfs()
-                .modify(|_r, w| w.pcr()._0().pdr()._1().podr()._1().ncodr()._0());
-            // PORT 013 = RX_LED
-            p.PFS
-                .p013pfs()
-                .modify(|_r, w| w.pcr()._0().pdr()._1().podr()._1().ncodr()._0());
-            // PORT 501 = SCI1_TXD
-            p.PFS.p501pfs().reset();
-            p.PFS.p501pfs().modify(|_r, w| {
-                unsafe { w.psel().bits(0b00101) };
-                w.pcr()._0().pdr()._1().ncodr()._0()
-            });
-            p.PFS.p502pfs().modify(|_r, w| w.pmr()._1());
-            // PORT 502 = SCI1_RXD
-            p.PFS.p502pfs().reset();
-            p.PFS.p502pfs().modify(|_r, w| {
-                unsafe { w.psel().bits(0b00101) };
-                w.pcr()._0().pdr()._0().ncodr()._0()
-            });
-            p.PFS.p502pfs().modify(|_r, w| w.pmr()._1());
-        } else {
-            //
-            // このあたりアドレスが変なので、ユーザーズマニュアルの値でwrite_volatileしてみる
-            //
-            let ptr = p.PFS.p110pfs().as_ptr();
-            defmt::debug!("P110pfs is {:X}", ptr);
-            let ptr = p.PFS.p111pfs().as_ptr();
-            defmt::debug!("P111pfs is {:X}", ptr);
-            let ptr = p.PFS.p112pfs().as_ptr();
-            defmt::debug!("P112pfs is {:X}", ptr);
-            let ptr = p.PFS.p113pfs().as_ptr();
-            defmt::debug!("P113pfs is {:X}", ptr);
-            let ptr = p.PFS.p114pfs().as_ptr();
-            defmt::debug!("P114pfs is {:X}", ptr);
-            let ptr = p.PFS.p115pfs().as_ptr();
-            defmt::debug!("P115pfs is {:X}", ptr);
-            let ptr = p.PFS.p010pfs().as_ptr();
-            defmt::debug!("P010pfs is {:X}", ptr);
-            let ptr = p.PFS.p011pfs().as_ptr();
-            defmt::debug!("P011pfs is {:X}", ptr);
-            let ptr = p.PFS.p012pfs().as_ptr();
-            defmt::debug!("P012pfs is {:X}", ptr);
-            let ptr = p.PFS.p013pfs().as_ptr();
-            defmt::debug!("P013pfs is {:X}", ptr);
-            let ptr = p.PFS.p014pfs().as_ptr();
-            defmt::debug!("P014pfs is {:X}", ptr);
-            let ptr = p.PFS.p015pfs().as_ptr();
-            defmt::debug!("P015pfs is {:X}", ptr);
-            let ptr = p.PFS.p500pfs().as_ptr();
-            defmt::debug!("P500pfs is {:X}", ptr);
-            let ptr = p.PFS.p501pfs().as_ptr();
-            defmt::debug!("P501pfs is {:X}", ptr);
-            let ptr = p.PFS.p502pfs().as_ptr();
-            defmt::debug!("P502pfs is {:X}", ptr);
-            let ptr = p.PFS.p503pfs().as_ptr();
-            defmt::debug!("P503pfs is {:X}", ptr);
-            let ptr = p.PFS.p504pfs().as_ptr();
-            defmt::debug!("P504pfs is {:X}", ptr);
-            let ptr = p.PFS.p505pfs().as_ptr();
-            defmt::debug!("P505pfs is {:X}", ptr);
-            //
-            unsafe {
-                // P010PFS  = 0x4004_0828
-                // P011PFS  = 0x4004_0828 +  4 = 0x4004_082c
-                // P012PFS  = 0x4004_0828 +  8 = 0x4004_0830
-                // P013PFS  = 0x4004_0828 + 12 = 0x4004_0834
-                // P014PFS  = 0x4004_0828 + 16 = 0x4004_0838
-                // P015PFS  = 0x4004_0828 + 16 = 0x4004_083c
-                const P012PFS_ADDR: *mut u32 = 0x4004_0830 as *mut u32;
-                const P013PFS_ADDR: *mut u32 = 0x4004_0834 as *mut u32;
-                let podr_bit: u32 = 1 << 0;
-                let pdr_bit: u32 = 1 << 2;
-                // PORT 012 = TX_LED
-                core::ptr::write_volatile(P012PFS_ADDR, pdr_bit | podr_bit);
-                // PORT 013 = RX_LED
-                core::ptr::write_volatile(P013PFS_ADDR, pdr_bit | podr_bit);
-                // P500PFS  = 0x4004_0940
-                // P501PFS  = 0x4004_0940 +  4 = 0x4004_0944
-                // P502PFS  = 0x4004_0940 +  8 = 0x4004_0948
-                // P503PFS  = 0x4004_0940 + 12 = 0x4004_094c
-                // P504PFS  = 0x4004_0940 + 16 = 0x4004_0950
-                // P505PFS  = 0x4004_0940 + 20 = 0x4004_0954
-                const P501PFS_ADDR: *mut u32 = 0x4004_0944 as *mut u32;
-                const P502PFS_ADDR: *mut u32 = 0x4004_0948 as *mut u32;
-                let psel_bit: u32 = 0b00101 << 24;
-                let pmr_bit: u32 = 1 << 16;
-                let pdr_bit: u32 = 1 << 2;
-                // PORT 501 = SCI1_TXD
-                core::ptr::write_volatile(P501PFS_ADDR, psel_bit | pmr_bit | pdr_bit);
-                // PORT 502 = SCI1_RXD
-                core::ptr::write_volatile(P502PFS_ADDR, psel_bit | pmr_bit);
-            }
-        };
-    };
+    // 端子をまとめて設定する間は書き込み許可を与えたままにする
+    let pwpr = PwprGuard::new(p);
+    // TX_LED, RX_LED
+    for led in [BOARD_LEDS.tx, BOARD_LEDS.rx].into_iter().flatten() {
+        led.init(p);
+    }
+    // SCI1_TXD (UNO R4 MINIMAはPORT 501), SCI1_RXD (UNO R4 MINIMAはPORT 502)
+    // 端子が割り当てられていないボードでは、送受信はするが端子には出ない
+    if let Some(sci1) = BOARD_PINS.sci1 {
+        let (txd, rxd) = (sci1.tx, sci1.rx);
+        pfs_configure(p, txd.port, txd.pin, PfsConfig::peripheral(SCI1_PSEL, true));
+        pfs_configure(
+            p,
+            rxd.port,
+            rxd.pin,
+            PfsConfig::peripheral(SCI1_PSEL, false),
+        );
+    } else {
+        defmt::warn!("no SCI1 pins on this board");
+    }
+    drop(pwpr);
+
+    // RS-485トランシーバーのDE/RE端子
+    rs485_de_init(p);
+
+    // DTCで送信する
+    if UART_DTC_TRANSMIT {
+        uart_dtc_init(p);
+    }
 
     // シリアル送信が動作していない時は1を出力
     p.SCI1.sptr().write(|w| w.spb2dt()._1().spb2io()._1());
@@ -363,337 +776,3795 @@ fn sci_module_init(p: &pac::Peripherals) {
     });
 }
 
-// シリアル通信受信データ割り込み番号
-const SCI1_RXI_IEL: pac::Interrupt = pac::Interrupt::IEL6;
-
-// シリアル通信受信データ割り込みハンドラ
-#[cortex_m_rt::interrupt]
-fn IEL6() {
-    let p = unsafe { pac::Peripherals::steal() };
-    // RX_LED (PORT 013) を点灯
-    p.PORT0
-        .podr()
-        .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 13)) });
-
-    //
-    let rxd_prod = RXD_QUEUE.stream_producer();
-    // 受信データーをシリアル受信待ち行列に追加する
-    let mut wgrant = rxd_prod.grant_exact(1).unwrap();
-    wgrant[0] = p.SCI1.rdr().read().bits();
-    wgrant.commit(1);
+// レジスタの内容を1行ずつdefmtのログとシリアル通信に出力する
+fn dump_line(line: &str) {
+    defmt::info!("{=str}", line);
+    uart_println(line.as_bytes());
+}
 
-    // RX_LED (PORT 013) を消灯
-    p.PORT0
-        .podr()
-        .modify(|r, w| unsafe { w.bits(r.bits() | (1 << 13)) });
+// クロックの設定(SCKSCR, SCKDIVCR)を出力する
+// 分周比は 1/2^n の n ではなく分周の値(1, 2, 4, ...)で出す
+fn dump_clock_state(p: &pac::Peripherals) {
+    let cksel = p.SYSTEM.sckscr().read().cksel().bits();
+    let source = match cksel {
+        0 => "HOCO",
+        1 => "MOCO",
+        2 => "LOCO",
+        3 => "MOSC",
+        4 => "SOSC",
+        5 => "PLL",
+        _ => "?",
+    };
+    let _ = format!("SCKSCR={:02X} CKSEL={}", cksel, source).map(|s: String<48>| dump_line(&s));
 
-    // 割り込みステータスフラグクリア
-    p.ICU.ielsr(6).modify(|_r, w| w.ir().clear_bit());
+    let div = p.SYSTEM.sckdivcr().read();
+    let _ = format!(
+        "SCKDIVCR={:08X} ICK=/{} FCK=/{} PCKA=/{} PCKB=/{} PCKC=/{} PCKD=/{}",
+        div.bits(),
+        1u32 << div.ick().bits(),
+        1u32 << div.fck().bits(),
+        1u32 << div.pcka().bits(),
+        1u32 << div.pckb().bits(),
+        1u32 << div.pckc().bits(),
+        1u32 << div.pckd().bits(),
+    )
+    .map(|s: String<80>| dump_line(&s));
 }
 
-// シリアル送信バッファに送る
-fn uart_println(input: &[u8]) {
-    let txd_prod = TXD_QUEUE.stream_producer();
-    let mut wgrant = txd_prod.grant_exact(input.len() + 2).unwrap();
+// SCI1の設定と状態(SMR, BRR, SCR, SSR)を出力する
+fn dump_sci_state(p: &pac::Peripherals) {
+    let smr = p.SCI1.smr().read();
+    let _ = format!(
+        "SCI1 SMR={:02X} CKS={} CHR={} PE={} PM={} STOP={} CM={} BRR={}",
+        smr.bits(),
+        smr.cks().bits(),
+        smr.chr().bit() as u8,
+        smr.pe().bit() as u8,
+        smr.pm().bit() as u8,
+        smr.stop().bit() as u8,
+        smr.cm().bit() as u8,
+        p.SCI1.brr().read().bits(),
+    )
+    .map(|s: String<80>| dump_line(&s));
 
-    wgrant[0..input.len()].copy_from_slice(input);
-    wgrant[input.len()..].copy_from_slice(b"\r\n");
-    wgrant.commit(input.len() + 2);
+    let scr = p.SCI1.scr().read();
+    let _ = format!(
+        "SCI1 SCR={:02X} TIE={} RIE={} TE={} RE={} TEIE={} CKE={}",
+        scr.bits(),
+        scr.tie().bit() as u8,
+        scr.rie().bit() as u8,
+        scr.te().bit() as u8,
+        scr.re().bit() as u8,
+        scr.teie().bit() as u8,
+        scr.cke().bits(),
+    )
+    .map(|s: String<80>| dump_line(&s));
 
-    //
-    let p = unsafe { pac::Peripherals::steal() };
+    let ssr = p.SCI1.ssr().read();
+    let _ = format!(
+        "SCI1 SSR={:02X} TDRE={} RDRF={} ORER={} FER={} PER={} TEND={}",
+        ssr.bits(),
+        ssr.tdre().bit() as u8,
+        ssr.rdrf().bit() as u8,
+        ssr.orer().bit() as u8,
+        ssr.fer().bit() as u8,
+        ssr.per().bit() as u8,
+        ssr.tend().bit() as u8,
+    )
+    .map(|s: String<80>| dump_line(&s));
+}
 
-    // シリアル送信動作を許可
+// SCIモジュールの停止
+// 送受信と割り込みを止めて送受信待ち行列を空にし、端子を汎用入力に戻してからモジュールストップ状態にする
+// この後にsci_module_init()を呼ぶと最初から設定し直せる
+fn sci_deinit(p: &pac::Peripherals) {
+    // 送受信動作と割り込み要求を禁止
     p.SCI1.scr().modify(|_r, w| {
-        w.tie()._1(); // SCIn_TXI割り込み要求を許可
+        w.rie()._0(); // SCIn_RXI割り込み要求を禁止
+        w.tie()._0(); // SCIn_TXI割り込み要求を禁止
         w.teie()._0(); // SCIn_TEI割り込み要求を禁止
-        w.te()._1() // シリアル送信動作を許可
+        w.re()._0(); // シリアル受信動作を禁止
+        w.te()._0() // シリアル送信動作を禁止
     });
-}
 
-// シリアル通信送信データエンプティ割り込み番号
-const SCI1_TXI_IEL: pac::Interrupt = pac::Interrupt::IEL7;
+    // SCI1モジュール割り込み無効
+    for iel in [SCI1_RXI_IEL, SCI1_TXI_IEL, SCI1_TEI_IEL, SCI1_ERI_IEL] {
+        cortex_m::peripheral::NVIC::mask(iel);
+        cortex_m::peripheral::NVIC::unpend(iel);
+        // 割り込みステータスフラグクリア
+        p.ICU
+            .ielsr(iel.number() as usize)
+            .modify(|_r, w| w.ir().clear_bit());
+    }
 
-// シリアル通信送信データエンプティ割り込みハンドラ
-#[cortex_m_rt::interrupt]
-fn IEL7() {
-    let p = unsafe { pac::Peripherals::steal() };
+    // エラーステータスフラグクリア
+    p.SCI1
+        .ssr()
+        .modify(|_r, w| w.per()._0().fer()._0().orer()._0());
+    SCI1_CONSECUTIVE_ERRORS.store(0, Ordering::Relaxed);
 
+    // DTCによる送信を止める
+    uart_dtc_cancel(p);
+
+    // 送受信待ち行列を空にする
+    // 読み出し位置が末尾で折り返すと2回に分かれるので空になるまで繰り返す
     let txd_cons = TXD_QUEUE.stream_consumer();
+    while let Ok(rgr) = txd_cons.read() {
+        let len = rgr.len();
+        rgr.release(len);
+    }
+    uart_rx_clear();
 
-    // 送信
-    if let Ok(rgr) = txd_cons.read() {
-        let txd = rgr[0];
-        rgr.release(1);
+    // I/Oポートの設定
+    // SCI1_TXD, SCI1_RXD = 汎用入力
+    for pin in BOARD_PINS.sci1.iter().flat_map(|sci1| [sci1.tx, sci1.rx]) {
+        pfs_configure(p, pin.port, pin.pin, PfsConfig::input(false));
+    }
 
-        // TX_LED (PORT 012) を点灯
-        p.PORT0
-            .podr()
-            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 12)) });
+    // SCI1モジュールへのクロックの供給を止める
+    module_stop(p, Peripheral::Sci1);
+}
 
-        p.SCI1.tdr().write(|w| unsafe { w.bits(txd) });
+// 形式の分からない相手の通信速度とフレーム形式を探す
+// (送るバイト列, 返ってくるはずのバイト列), Noneなら探さない
+// 見つかればSCI1をその形式にして、見つからなければUART_FORMATのままにする
+const SCI_AUTOBAUD_PROBE: Option<(&[u8], &[u8])> = None;
 
-        //
-        p.SCI1.scr().modify(|_r, w| {
-            w.tie()._1(); // SCIn_TXI割り込み要求を許可
-            w.teie()._0() // SCIn_TEI割り込み要求を禁止
-        });
-    } else {
-        p.SCI1.scr().modify(|_r, w| {
-            w.tie()._0(); // SCIn_TXI割り込み要求を禁止
-            w.teie()._1() // SCIn_TEI割り込み要求を許可
+// 探す通信速度
+const AUTOBAUD_BAUDS: [u32; 5] = [9600, 19200, 38400, 57600, 115200];
+
+// 探すフレーム形式(8N1, 8E1, 7E1)
+const AUTOBAUD_FRAMINGS: [(DataBits, Parity, StopBits); 3] = [
+    (DataBits::Eight, Parity::None, StopBits::One),
+    (DataBits::Eight, Parity::Even, StopBits::One),
+    (DataBits::Seven, Parity::Even, StopBits::One),
+];
+
+// 1つの形式で返事を待つ時間(ミリ秒)
+const AUTOBAUD_TIMEOUT_MS: u32 = 200;
+
+// AUTOBAUD_BAUDS と AUTOBAUD_FRAMINGS の組み合わせを順に試して、
+// probe を送ってから受信エラー(オーバーラン, フレーミング, パリティ)無しで expected を受信できた形式を返す
+// 試している間は割り込みを使わずにポーリングで送受信するので、受信待ち行列には何も入らない
+// 終わったら見つかった形式(見つからなければUART_FORMAT)に戻して割り込みでの受信を再開する
+fn sci_autobaud(p: &pac::Peripherals, probe: &[u8], expected: &[u8]) -> Option<SerialFormat> {
+    let clocks = current_clock_config(p);
+
+    // 送信待ち行列に残っているデータを今の形式で送り切る
+    uart_flush_blocking(p);
+
+    let found = AUTOBAUD_BAUDS
+        .iter()
+        .flat_map(|&baud| {
+            AUTOBAUD_FRAMINGS
+                .iter()
+                .map(move |&(data_bits, parity, stop_bits)| {
+                    SerialFormat::n81(baud).with(data_bits, parity, stop_bits)
+                })
+        })
+        .find(|format| {
+            // 全部試すとIWDTのタイムアウトより長くかかる
+            iwdt_refresh(p);
+            sci1_try_format(p, &clocks, format, probe, expected)
+        });
+
+    // 送受信動作を禁止してから形式を書き換える
+    p.SCI1.scr().modify(|_r, w| w.re()._0().te()._0());
+    sci1_set_format(p, &clocks, &found.unwrap_or(UART_FORMAT));
+    p.SCI1
+        .ssr()
+        .modify(|_r, w| w.per()._0().fer()._0().orer()._0());
+    uart_rx_clear();
+    p.SCI1.scr().modify(|_r, w| {
+        w.rie()._1(); // SCIn_RXI割り込み要求を許可
+        w.re()._1() // シリアル受信動作を許可
+    });
+    found
+}
+
+// format で probe を送って、AUTOBAUD_TIMEOUT_MS 以内に受信エラー無しで expected を受信できたらtrue
+fn sci1_try_format(
+    p: &pac::Peripherals,
+    clocks: &ClockConfig,
+    format: &SerialFormat,
+    probe: &[u8],
+    expected: &[u8],
+) -> bool {
+    // 送受信動作と割り込み要求を禁止してから形式を書き換える
+    p.SCI1.scr().modify(|_r, w| {
+        w.rie()._0(); // SCIn_RXI割り込み要求を禁止
+        w.tie()._0(); // SCIn_TXI割り込み要求を禁止
+        w.teie()._0(); // SCIn_TEI割り込み要求を禁止
+        w.re()._0(); // シリアル受信動作を禁止
+        w.te()._0() // シリアル送信動作を禁止
+    });
+    sci1_set_format(p, clocks, format);
+    p.SCI1
+        .ssr()
+        .modify(|_r, w| w.per()._0().fer()._0().orer()._0());
+    p.SCI1.scr().modify(|_r, w| w.re()._1().te()._1());
+
+    // 送信
+    rs485_set_de(true);
+    for &byte in probe {
+        while p.SCI1.ssr().read().tdre().is_0() {}
+        p.SCI1.tdr().write(|w| unsafe { w.bits(byte) });
+    }
+    while p.SCI1.ssr().read().tend().is_0() {}
+    rs485_set_de(false);
+
+    // 受信
+    // 7ビットの時はRDRの最上位ビットが0になるので比べる相手もそろえる
+    let mask = match format.data_bits {
+        DataBits::Seven => 0x7f,
+        DataBits::Eight => 0xff,
+    };
+    let timeout = Timeout::after(AUTOBAUD_TIMEOUT_MS);
+    let mut matched = 0;
+    while matched < expected.len() && !timeout.is_expired() {
+        let ssr = p.SCI1.ssr().read();
+        if ssr.orer().is_1() || ssr.fer().is_1() || ssr.per().is_1() {
+            return false;
+        }
+        if ssr.rdrf().is_1() {
+            let byte = p.SCI1.rdr().read().bits();
+            // 食い違ったら、そのバイトが expected の先頭かどうかから数え直す
+            matched = if byte == expected[matched] & mask {
+                matched + 1
+            } else {
+                usize::from(byte == expected[0] & mask)
+            };
+        }
+    }
+    matched == expected.len()
+}
+
+// シリアル通信受信データ割り込み番号
+const SCI1_RXI_IEL: pac::Interrupt = pac::Interrupt::IEL6;
+
+// SCI1の割り込みハンドラ(受信データ, 送信データエンプティ, 送信終了, エラー)が使う周辺機能
+struct Sci1IsrResources {
+    sci1: pac::SCI1,
+    icu: pac::ICU, // 割り込みステータスフラグのクリアとDTCの起動
+}
+
+// sci_module_init()で登録する
+static SCI1_ISR_RESOURCES: IsrResources<Sci1IsrResources> = IsrResources::new();
+
+// シリアル通信受信データ割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL6() {
+    // sci_module_init()が割り込みを許可する前に登録するので、ここで登録前(None)にはならない
+    SCI1_ISR_RESOURCES.with(|sci| {
+        // RX_LED を点灯
+        if let Some(led) = BOARD_LEDS.rx {
+            led.on();
+        }
+
+        // 正常に受信できたので連続エラー回数をリセット
+        SCI1_CONSECUTIVE_ERRORS.store(0, Ordering::Relaxed);
+
+        // 受信データを読むと割り込み要因が消えるので、待ち行列の空きに関係なく必ず読む
+        let data = sci.sci1.rdr().read().bits();
+        let rxd_prod = RXD_QUEUE.stream_producer();
+        // 受信データーをシリアル受信待ち行列に追加する
+        // 待ち行列が一杯なら捨てて数えておく
+        if let Ok(mut wgrant) = rxd_prod.grant_exact(1) {
+            wgrant[0] = data;
+            wgrant.commit(1);
+            let len = RXD_QUEUE_LEN.fetch_add(1, Ordering::SeqCst) + 1;
+            // 溢れる前に相手の送信を止める
+            if UART_XON_XOFF
+                && len >= RXD_XOFF_THRESHOLD
+                && !RXD_XOFF_SENT.swap(true, Ordering::SeqCst)
+            {
+                uart_send_flow_control(&sci.sci1, XOFF);
+            }
+        } else {
+            RXD_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        RXD_RECEIVED_FLAG.set();
+
+        // RX_LED を消灯
+        if let Some(led) = BOARD_LEDS.rx {
+            led.off();
+        }
+
+        // 割り込みステータスフラグクリア
+        sci.icu.ielsr(6).modify(|_r, w| w.ir().clear_bit());
+    });
+}
+
+// RS-485トランシーバーのDE/RE端子(ポート番号, 端子番号)
+// 送信中だけHighにしてトランシーバーを送信方向にする
+// Noneなら使わない(RS-485モードにしない)
+const RS485_DE_PIN: Option<(u8, u8)> = None;
+
+// RS-485トランシーバーのDE/RE端子を出力(Low = 受信方向)にする
+fn rs485_de_init(p: &pac::Peripherals) {
+    let Some((port, pin)) = RS485_DE_PIN else {
+        return;
+    };
+    rs485_set_de(false);
+    pfs_configure(p, port, pin, PfsConfig::output(false));
+}
+
+// RS-485トランシーバーの送信方向(DE)を切り替える
+// 送信終了割り込みとメインループの両方から呼ぶので、同じPORTの他の端子を壊さないPOSR/PORRに書く
+fn rs485_set_de(enable: bool) {
+    let Some((port, pin)) = RS485_DE_PIN else {
+        return;
+    };
+    if enable {
+        port_set(port, 1 << pin);
+    } else {
+        port_reset(port, 1 << pin);
+    }
+}
+
+// 送信割り込みを許可して送信待ち行列からの送信を始める
+// RS-485モードなら最初のバイトを送る前にトランシーバーを送信方向にする
+fn sci1_start_transmit(sci1: &pac::SCI1) {
+    cortex_m::interrupt::free(|_cs| {
+        rs485_set_de(true);
+        sci1.scr().modify(|_r, w| {
+            w.tie()._1(); // SCIn_TXI割り込み要求を許可
+            w.teie()._0(); // SCIn_TEI割り込み要求を禁止
+            w.te()._1() // シリアル送信動作を許可
         });
+    });
+}
+
+// シリアル送信バッファに1行送る
+// 行末に "*" と CRC-16-CCITT の16進数4桁を付けて受信側で誤りを検出できるようにする
+// 文字列用なので、バイナリデータは uart_write で送る
+// 送信待ち行列が空くまで待ち続けるので失敗は無く、戻り値も無い
+// 相手が受け取らなくなると止まったままになるので、その恐れがある時は uart_println_timeout を使う
+fn uart_println(input: &[u8]) {
+    let p = unsafe { pac::Peripherals::steal() };
+
+    // 行末に付けるCRC
+    let trailer = uart_line_trailer(&p, input);
+
+    uart_write(input);
+    uart_write(&trailer);
+}
+
+// シリアル通信の送信エラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UartError {
+    Timeout, // 期限までに送信待ち行列が空かなかった(相手が受け取っていない)
+}
+
+// uart_printlnと同じように1行送るが、送信待ち行列が空くのを ms ミリ秒までしか待たない
+// 相手が受け取らなくなっても止まり続けずに、期限切れならErr(Timeout)を返す
+// Resultを返す1行送信はこちらだけ
+// 期限までに送信待ち行列に入れた分は送られるので、行の途中で切れると行末(CRCと改行)は付かない
+fn uart_println_timeout(input: &[u8], ms: u32) -> Result<(), UartError> {
+    let p = unsafe { pac::Peripherals::steal() };
+
+    // 行末に付けるCRC
+    let trailer = uart_line_trailer(&p, input);
+
+    let deadline = Timeout::after(ms);
+    uart_write_until(input, Some(&deadline))?;
+    uart_write_until(&trailer, Some(&deadline))
+}
+
+// バイト列をそのまま送信バッファに送る
+// uart_printlnと違って行末(CRCと改行)を付けないので、バイナリデータを送れる
+// 送信待ち行列に入りきらない分は、送信割り込みで空くのを待って送る
+// 割り込みハンドラの中から呼ぶと空かないので、メインループから呼ぶこと
+fn uart_write(input: &[u8]) {
+    let Ok(()) = embedded_io::Write::write_all(&mut Uart, input);
+}
+
+// 送信待ち行列に入りきらない分は、送信割り込みで空くのを deadline まで待って送る
+// deadline がNoneなら空くまで待ち続ける
+fn uart_write_until(input: &[u8], deadline: Option<&Timeout>) -> Result<(), UartError> {
+    let p = unsafe { pac::Peripherals::steal() };
+    let txd_prod = TXD_QUEUE.stream_producer();
+
+    let mut rest = input;
+    while !rest.is_empty() {
+        if let Ok(mut wgrant) = txd_prod.grant_max_remaining(rest.len()) {
+            let len = wgrant.len();
+            wgrant.copy_from_slice(&rest[..len]);
+            wgrant.commit(len);
+            rest = &rest[len..];
+
+            // シリアル送信動作を許可
+            sci1_start_transmit(&p.SCI1);
+        } else if deadline.is_some_and(|deadline| deadline.is_expired()) {
+            return Err(UartError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+// 行末に付ける "*" と CRC-16-CCITT の16進数4桁と改行
+const UART_LINE_TRAILER_LEN: usize = 7;
+
+fn uart_line_trailer(p: &pac::Peripherals, input: &[u8]) -> [u8; UART_LINE_TRAILER_LEN] {
+    let crc = crc_compute(p, input);
+    let mut trailer = *b"*0000\r\n";
+    for (i, c) in trailer[1..5].iter_mut().enumerate() {
+        let nibble = (crc >> (12 - 4 * i)) & 0x0f;
+        *c = b"0123456789ABCDEF"[nibble as usize];
+    }
+    trailer
+}
+
+// 送信待ち行列の領域に write! で直接書き込む1行
+// heapless::Stringで組み立ててから送信待ち行列に写す二度手間を省く
+// uart_line()で領域を確保して、書き終わったらcommit()で書いた分だけを行末と一緒に送る
+// 書き込みが確保した長さを超えたら、その行はcommit()しても送らない
+struct UartLine {
+    wgrant: StreamGrantW<&'static Jerk<QUEUE_SIZE>>,
+    len: usize,     // 書き込んだバイト数
+    overflow: bool, // 確保した長さを超えて書き込もうとした
+}
+
+// 最大max_lenバイトの1行を書き込む領域を送信待ち行列に確保する
+// 行末の分も含めて連続した空きが無ければNone
+fn uart_line(max_len: usize) -> Option<UartLine> {
+    let wgrant = TXD_QUEUE
+        .stream_producer()
+        .grant_exact(max_len + UART_LINE_TRAILER_LEN)
+        .ok()?;
+    Some(UartLine {
+        wgrant,
+        len: 0,
+        overflow: false,
+    })
+}
+
+impl UartLine {
+    // 行末を付けて書き込んだ分を送る
+    // 確保した長さを超えていたら何も送らずにErr
+    fn commit(mut self) -> Result<usize, core::fmt::Error> {
+        if self.overflow {
+            // 書き込み許可を返すだけで何も送らない
+            self.wgrant.commit(0);
+            return Err(core::fmt::Error);
+        }
+        let p = unsafe { pac::Peripherals::steal() };
+        let len = self.len;
+        let trailer = uart_line_trailer(&p, &self.wgrant[..len]);
+        self.wgrant[len..len + UART_LINE_TRAILER_LEN].copy_from_slice(&trailer);
+        self.wgrant.commit(len + UART_LINE_TRAILER_LEN);
+
+        // シリアル送信動作を許可
+        sci1_start_transmit(&p.SCI1);
+        Ok(len)
+    }
+}
+
+impl core::fmt::Write for UartLine {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if self.overflow || end + UART_LINE_TRAILER_LEN > self.wgrant.len() {
+            self.overflow = true;
+            return Err(core::fmt::Error);
+        }
+        self.wgrant[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// COBSで符号化して区切りの0x00を付けたフレームを送信バッファに送る
+// 送信待ち行列に入りきらない時はCobsError::BufferTooSmall
+fn uart_send_frame(input: &[u8]) -> Result<(), CobsError> {
+    let mut encoded = [0u8; QUEUE_SIZE];
+    let len = cobs_encode(input, &mut encoded)?;
+    if len >= encoded.len() {
+        return Err(CobsError::BufferTooSmall);
+    }
+    // 区切り
+    encoded[len] = 0x00;
+    let frame = &encoded[..=len];
+
+    let txd_prod = TXD_QUEUE.stream_producer();
+    let mut wgrant = txd_prod
+        .grant_exact(frame.len())
+        .map_err(|_| CobsError::BufferTooSmall)?;
+    wgrant.copy_from_slice(frame);
+    wgrant.commit(frame.len());
+
+    //
+    let p = unsafe { pac::Peripherals::steal() };
+
+    // シリアル送信動作を許可
+    sci1_start_transmit(&p.SCI1);
+    Ok(())
+}
+
+// 温度の計測結果をシリアル通信で送る形式
+// trueなら人が読める文字列, falseならsend_telemetry()の固定長バイナリフレーム
+const TELEMETRY_TEXT: bool = true;
+
+// 内蔵温度センサーのA/D変換の開始方法
+// trueならELCでGPT320のオーバーフローから変換を始める, falseならメインループでソフトウェアから始める
+const TSN_ELC_TRIGGER: bool = true;
+
+// テレメトリフレームの通し番号
+static TELEMETRY_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+// 内蔵温度センサーの値をテレメトリフレームで送る
+// フレームの中身(6バイト, リトルエンディアン)
+//   0..2 通し番号(u16)
+//   2..4 温度センサーの14ビットA/D変換値(u16)
+//   4..6 0..4のCRC-16-CCITT(u16)
+// これをCOBSで符号化して区切りの0x00を付けて送る
+fn send_telemetry(p: &pac::Peripherals, tsn_raw: u16) {
+    let sequence = TELEMETRY_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+    let mut frame = [0u8; 6];
+    frame[0..2].copy_from_slice(&sequence.to_le_bytes());
+    frame[2..4].copy_from_slice(&tsn_raw.to_le_bytes());
+    let crc = crc_compute(p, &frame[0..4]) as u16;
+    frame[4..6].copy_from_slice(&crc.to_le_bytes());
+
+    if uart_send_frame(&frame).is_err() {
+        defmt::warn!("telemetry frame dropped");
+    }
+}
+
+// 受信フレームの最大の長さ(復号後)
+const FRAME_SIZE: usize = 32;
+
+// 受信したバイト列を区切りの0x00まで溜めて、COBSを復号したフレームにする
+// 長すぎるフレームや復号できないフレームは次の区切りまで捨てる
+struct FrameReader {
+    buf: heapless::Vec<u8, { cobs_max_encoded_len(FRAME_SIZE) }>,
+    overflow: bool,
+}
+
+impl FrameReader {
+    const fn new() -> Self {
+        FrameReader {
+            buf: heapless::Vec::new(),
+            overflow: false,
+        }
+    }
+
+    // 受信した1バイトを渡す
+    // フレームがそろったら on_frame を呼ぶ
+    fn feed(&mut self, byte: u8, on_frame: impl FnOnce(&[u8])) {
+        if byte != 0x00 {
+            if self.buf.push(byte).is_err() {
+                self.overflow = true;
+            }
+            return;
+        }
+        let mut frame = [0u8; FRAME_SIZE];
+        if !self.overflow
+            && !self.buf.is_empty()
+            && let Ok(len) = cobs_decode(&self.buf, &mut frame)
+        {
+            on_frame(&frame[..len]);
+        }
+        self.buf.clear();
+        self.overflow = false;
+    }
+}
+
+// 受信データをCOBSのフレームとして扱うならtrue, 改行区切りの行として扱うならfalse
+const UART_RX_COBS_FRAMES: bool = false;
+
+// SCI1でラジコン受信機のSBUS出力を受信するならtrue
+// 100000bps, 8ビット, 偶数パリティ, ストップビット2の設定にして、受信データをSBUSのフレームとして扱う
+// SBUSは信号の論理が反転しているので、受信機とRXD1の間に外付けのインバーター(トランジスタなど)が必要
+// SCMR.SINVはデータビットだけを反転してスタートビットとストップビットは反転しないのでSBUSには使えない
+// 送信も同じ設定になるので、シリアル通信の端末には読めない
+const SBUS_RECEIVE: bool = false;
+
+// SBUSのフレームの長さ
+const SBUS_FRAME_LEN: usize = 25;
+// SBUSのフレームの先頭
+const SBUS_START_BYTE: u8 = 0x0f;
+
+// SBUSのフレーム
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+struct SbusFrame {
+    channels: [u16; 16], // 1～16チャンネルの値(11ビット, 0～2047)
+    ch17: bool,          // デジタルチャンネル17
+    ch18: bool,          // デジタルチャンネル18
+    frame_lost: bool,    // 送信機からのフレームを取りこぼした
+    failsafe: bool,      // 送信機からの電波が途切れてフェイルセーフになっている
+}
+
+// 25バイトのフレームを復号する
+// 1～22バイト目に11ビットのチャンネル値がLSBから詰めてあり、23バイト目がフラグ
+const fn sbus_decode(buf: &[u8; SBUS_FRAME_LEN]) -> SbusFrame {
+    let mut channels = [0u16; 16];
+    let mut ch = 0;
+    while ch < 16 {
+        let mut value = 0u16;
+        let mut bit = 0;
+        while bit < 11 {
+            let pos = ch * 11 + bit;
+            if buf[1 + pos / 8] >> (pos % 8) & 1 != 0 {
+                value |= 1 << bit;
+            }
+            bit += 1;
+        }
+        channels[ch] = value;
+        ch += 1;
+    }
+    let flags = buf[23];
+    SbusFrame {
+        channels,
+        ch17: flags & 0x01 != 0,
+        ch18: flags & 0x02 != 0,
+        frame_lost: flags & 0x04 != 0,
+        failsafe: flags & 0x08 != 0,
+    }
+}
+
+// 1チャンネル目が全て1, 2チャンネル目の最下位ビットが1, フェイルセーフ
+const _: () = {
+    let mut buf = [0u8; SBUS_FRAME_LEN];
+    buf[0] = SBUS_START_BYTE;
+    buf[1] = 0xff;
+    buf[2] = 0x0f;
+    buf[23] = 0x08;
+    let frame = sbus_decode(&buf);
+    assert!(frame.channels[0] == 0x7ff);
+    assert!(frame.channels[1] == 1);
+    assert!(frame.channels[2] == 0);
+    assert!(frame.failsafe && !frame.frame_lost);
+};
+
+// 受信したバイト列から先頭0x0Fで始まる25バイトのSBUSフレームを取り出す
+// 最後のバイトが終端(SBUSは0x00, SBUS2は下位4ビットが0x4)でなければ同期がずれているので、
+// 溜めたバイト列の中の次の0x0Fからやり直す
+struct SbusReader {
+    buf: [u8; SBUS_FRAME_LEN],
+    len: usize,
+}
+
+impl SbusReader {
+    const fn new() -> Self {
+        SbusReader {
+            buf: [0; SBUS_FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    // 受信した1バイトを渡す
+    // フレームがそろったら on_frame を呼ぶ
+    fn feed(&mut self, byte: u8, on_frame: impl FnOnce(SbusFrame)) {
+        if self.len == 0 && byte != SBUS_START_BYTE {
+            return;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if self.len < SBUS_FRAME_LEN {
+            return;
+        }
+        let end = self.buf[SBUS_FRAME_LEN - 1];
+        if end == 0x00 || end & 0x0f == 0x04 {
+            on_frame(sbus_decode(&self.buf));
+            self.len = 0;
+        } else {
+            // 次の0x0Fを先頭にして詰め直す
+            let next = self.buf[1..]
+                .iter()
+                .position(|&b| b == SBUS_START_BYTE)
+                .map_or(SBUS_FRAME_LEN, |i| i + 1);
+            self.buf.copy_within(next.., 0);
+            self.len = SBUS_FRAME_LEN - next;
+        }
+    }
+}
+
+// 受信した文字を送り返す(エコーバック)ならtrue
+static UART_ECHO: AtomicBool = AtomicBool::new(false);
+
+// エコーバックの有効/無効を切り替える
+fn uart_set_echo(enable: bool) {
+    UART_ECHO.store(enable, Ordering::Relaxed);
+}
+
+// エコーバックが有効なら受信データを送信待ち行列に入れて送り返す
+// 送信待ち行列に入りきらない分は送り返さずに捨てる(受信データの処理は止めない)
+fn uart_echo(input: &[u8]) {
+    if !UART_ECHO.load(Ordering::Relaxed) || input.is_empty() {
+        return;
+    }
+    let txd_prod = TXD_QUEUE.stream_producer();
+    let Ok(mut wgrant) = txd_prod.grant_max_remaining(input.len()) else {
+        return;
+    };
+    let len = wgrant.len();
+    wgrant.copy_from_slice(&input[..len]);
+    wgrant.commit(len);
+
+    //
+    let p = unsafe { pac::Peripherals::steal() };
+
+    // シリアル送信動作を許可
+    sci1_start_transmit(&p.SCI1);
+}
+
+// 1行の最大文字数
+const LINE_SIZE: usize = 64;
+
+// 行の途中で受信が途切れてから、入力中の行を捨てるまでの時間(ミリ秒)
+// Noneなら捨てずに改行を待ち続ける
+// メインループで調べるので、実際に捨てるのは最大1秒(タイマー割り込みの周期)遅れることがある
+const LINE_IDLE_TIMEOUT_MS: Option<u32> = Some(10_000);
+
+// 端末から貼り付けた複数行を受け取れる行入力ならtrue
+// 端末のエスケープシーケンス(ブラケットペーストの ESC[200~ と ESC[201~ や矢印キーなど)を取り除き、
+// CR, LF, CRLF のどれも1つの改行として扱い、その他の制御文字は捨てる
+// falseなら改行はLFだけで、CRは無視する
+const LINE_PASTE_SAFE: bool = true;
+
+// 受信中のエスケープシーケンス
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None, // エスケープシーケンスの外
+    Esc,  // ESCを受信した
+    Csi,  // ESC [ を受信した, 0x40~0x7Eの終端文字まで捨てる
+    Ss3,  // ESC O を受信した, 次の1文字まで捨てる
+}
+
+// 受信した文字を改行(LF)まで溜めて1行にする
+// 後退(BS, DEL)は最後の1文字を消す, 復帰(CR)は無視する
+// 1行の最大文字数を超えた分は捨てる
+// 入力中の行があるまま LINE_IDLE_TIMEOUT_MS の間受信が無ければ、poll_idle()でその行を捨てる
+// LINE_PASTE_SAFE なら改行と制御文字の扱いが変わる
+struct LineReader {
+    line: String<LINE_SIZE>,
+    idle: Option<Timeout>, // 入力中の行を捨てる期限
+    escape: Escape,        // 受信中のエスケープシーケンス
+    after_cr: bool,        // 直前にCRで行を終えた(続くLFは同じ改行)
+}
+
+impl LineReader {
+    const fn new() -> Self {
+        LineReader {
+            line: String::new(),
+            idle: None,
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    // 受信が途切れたまま期限を過ぎていたら、入力中の行を on_timeout に渡して捨てる
+    // メインループで受信の有無にかかわらず呼ぶこと
+    fn poll_idle(&mut self, on_timeout: impl FnOnce(&str)) {
+        if self.idle.is_some_and(|idle| idle.is_expired()) {
+            on_timeout(self.line.as_str());
+            self.line.clear();
+            self.idle = None;
+        }
+    }
+
+    // 受信した1文字を渡す
+    // 1行そろったら on_line を呼ぶ
+    fn feed(&mut self, byte: u8, on_line: impl FnOnce(&str)) {
+        if LINE_PASTE_SAFE {
+            self.feed_paste_safe(byte, on_line);
+        } else {
+            match byte {
+                b'\n' => {
+                    on_line(self.line.as_str());
+                    self.line.clear();
+                }
+                b'\r' => {}
+                0x08 | 0x7f => {
+                    self.line.pop();
+                }
+                _ => {
+                    let _ = self.line.push(byte as char);
+                }
+            }
+        }
+        // 受信するたびに期限を延ばす, 入力中の行が無ければ期限も無い
+        self.idle = LINE_IDLE_TIMEOUT_MS
+            .filter(|_| !self.line.is_empty())
+            .map(Timeout::after);
+    }
+
+    // 貼り付けに強い行入力(LINE_PASTE_SAFE)の1文字
+    fn feed_paste_safe(&mut self, byte: u8, on_line: impl FnOnce(&str)) {
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+        let mut line_end = false;
+        match self.escape {
+            Escape::Esc => {
+                self.escape = match byte {
+                    b'[' => Escape::Csi,
+                    b'O' => Escape::Ss3,
+                    _ => Escape::None, // ESCと1文字の組
+                };
+            }
+            Escape::Csi => {
+                if (0x40..=0x7e).contains(&byte) {
+                    self.escape = Escape::None;
+                }
+            }
+            Escape::Ss3 => self.escape = Escape::None,
+            Escape::None => match byte {
+                0x1b => self.escape = Escape::Esc,
+                b'\r' => {
+                    self.after_cr = true;
+                    line_end = true;
+                }
+                b'\n' => line_end = !after_cr,
+                0x08 | 0x7f => {
+                    self.line.pop();
+                }
+                b'\t' => {
+                    let _ = self.line.push(' ');
+                }
+                0x00..=0x1f => {} // その他の制御文字は捨てる
+                _ => {
+                    let _ = self.line.push(byte as char);
+                }
+            },
+        }
+        if line_end {
+            on_line(self.line.as_str());
+            self.line.clear();
+        }
+    }
+}
+
+// SCI1のシリアル通信をembedded-ioのRead/Writeで使う
+// 送受信は割り込みハンドラと送受信待ち行列(TXD_QUEUE/RXD_QUEUE)を介して行う
+// uart_write()もこれで送る
+pub struct Uart;
+
+impl embedded_io::ErrorType for Uart {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io::Write for Uart {
+    // 送信待ち行列に空きができるまで待って、入るだけ書き込む
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let txd_prod = TXD_QUEUE.stream_producer();
+        let mut wgrant = loop {
+            if let Ok(wgrant) = txd_prod.grant_max_remaining(buf.len()) {
+                break wgrant;
+            }
+        };
+        let len = wgrant.len();
+        wgrant.copy_from_slice(&buf[..len]);
+        wgrant.commit(len);
+
+        //
+        let p = unsafe { pac::Peripherals::steal() };
+
+        // シリアル送信動作を許可
+        sci1_start_transmit(&p.SCI1);
+        Ok(len)
+    }
+
+    // 送信終了割り込みでシリアル送信動作が禁止されるまで待つ
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        while !tx_idle() {}
+        Ok(())
+    }
+}
+
+impl embedded_io::Read for Uart {
+    // 受信待ち行列にデーターが届くまで待って、届いた分を読み込む
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let rxd_cons = RXD_QUEUE.stream_consumer();
+        let rgr = loop {
+            if let Ok(rgr) = rxd_cons.read() {
+                break rgr;
+            }
+        };
+        let len = rgr.len().min(buf.len());
+        buf[..len].copy_from_slice(&rgr[..len]);
+        rgr.release(len);
+        RXD_QUEUE_LEN.fetch_sub(len, Ordering::SeqCst);
+        Ok(len)
+    }
+}
+
+// シリアル通信送信データエンプティ割り込み番号
+const SCI1_TXI_IEL: pac::Interrupt = pac::Interrupt::IEL7;
+
+// 送信待ち行列の連続した領域をデータトランスファコントローラ(DTC)で送る
+// trueなら送信データエンプティ割り込みごとにDTCがTDRレジスタへ書き込むので、CPUは1バイトごとに割り込まれない
+// falseなら1バイトごとに割り込みハンドラで書き込む
+const UART_DTC_TRANSMIT: bool = true;
+
+// DTCで送る最小のバイト数
+// これより短い時は転送情報を書く手間の方が大きいので、1バイトごとに割り込みハンドラで送る
+const UART_DTC_MIN_LEN: usize = 8;
+
+// DTCの転送情報(ノーマル転送モード)
+#[repr(C, align(4))]
+struct DtcTransferInfo {
+    mode: u32,  // MRA(b31-b24), MRB(b23-b16)
+    sar: u32,   // 転送元アドレス
+    dar: u32,   // 転送先アドレス
+    count: u32, // CRA(b31-b16) 転送回数, CRB(b15-b0) ノーマル転送モードでは使わない
+}
+
+// 転送モード
+// MRA: MD = 00 ノーマル転送, SZ = 00 バイト転送, SM = 10 転送元アドレスを加算
+// MRB: CHNE = 0 チェーン転送禁止, DISEL = 0 転送が終わった時にCPUへ割り込む, DM = 00 転送先アドレス固定
+const DTC_MODE_BYTE_SRC_INCREMENT: u32 = 0b0000_1000 << 24;
+
+// DTCベクタテーブル
+// 割り込み番号(IELn)ごとに転送情報のアドレスを置く
+// DTCVBRレジスタの下位10ビットは0固定なので1KB境界に置く
+#[repr(C, align(1024))]
+struct DtcVectorTable([u32; 32]);
+
+static mut DTC_VECTOR_TABLE: DtcVectorTable = DtcVectorTable([0; 32]);
+static mut UART_DTC_TRANSFER_INFO: DtcTransferInfo = DtcTransferInfo {
+    mode: 0,
+    sar: 0,
+    dar: 0,
+    count: 0,
+};
+
+// DTCで送信中のバイト数(送信待ち行列からまだ取り除いていない)
+// 0ならDTCで送信していない
+static UART_DTC_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+// DTCの設定
+fn uart_dtc_init(p: &pac::Peripherals) {
+    // 保護レジスタを操作して書込み許可を与える
+    p.SYSTEM.prcr().write(|w| {
+        w.prkey().set(0xa5);
+        w.prc1().set_bit() // 低消費電力モード関連レジスタに書込み許可を与える
+    });
+    // DTCモジュールのモジュールストップ状態の解除
+    p.SYSTEM.mstpcra().modify(|_r, w| w.mstpa22()._0());
+    // 保護レジスタを元通りに復帰する
+    p.SYSTEM.prcr().write(|w| {
+        w.prkey().set(0xa5);
+        w.prc1().clear_bit()
+    });
+
+    // 送信データエンプティ割り込みで転送情報を読み出す
+    unsafe {
+        let table = &raw mut DTC_VECTOR_TABLE;
+        (*table).0[SCI1_TXI_IEL.number() as usize] = (&raw const UART_DTC_TRANSFER_INFO) as u32;
+    }
+
+    // DTCの動作を開始
+    p.DTC.dtcst().write(|w| w.dtcst()._0());
+    p.DTC.dtccr().write(|w| w.rrs()._0()); // 転送情報を毎回読み出す
+    p.DTC
+        .dtcvbr()
+        .write(|w| unsafe { w.dtcvbr().bits((&raw const DTC_VECTOR_TABLE) as u32) });
+    p.DTC.dtcst().write(|w| w.dtcst()._1());
+}
+
+// data の先頭の1バイトをTDRレジスタに書き込んで、残りは送信データエンプティ割り込みごとにDTCで送る
+// 送り終わると送信データエンプティ割り込みがCPUに入る
+fn uart_dtc_start(sci1: &pac::SCI1, icu: &pac::ICU, data: &[u8]) {
+    // 転送回数0は65536回の意味になるので、2バイト以上あること
+    debug_assert!(data.len() >= 2 && data.len() <= u16::MAX as usize);
+    unsafe {
+        let info = &raw mut UART_DTC_TRANSFER_INFO;
+        (*info).mode = DTC_MODE_BYTE_SRC_INCREMENT;
+        (*info).sar = data[1..].as_ptr() as u32;
+        (*info).dar = sci1.tdr() as *const _ as u32;
+        (*info).count = ((data.len() - 1) as u32) << 16;
+    }
+    // DTCが読み出す前に転送情報を書き終える
+    cortex_m::asm::dsb();
+    UART_DTC_IN_FLIGHT.store(data.len(), Ordering::SeqCst);
+
+    // 送信データエンプティ割り込みでDTCを起動する
+    icu.ielsr(SCI1_TXI_IEL.number() as usize)
+        .modify(|_r, w| w.dtce()._1());
+
+    // 次の送信データエンプティ割り込みはこの1バイトがシフトレジスタに移った時に入る
+    sci1.tdr().write(|w| unsafe { w.bits(data[0]) });
+}
+
+// DTCによる送信を止めて、送った分だけ送信待ち行列から取り除く
+// 送っていない分は送信待ち行列に残る
+fn uart_dtc_cancel(p: &pac::Peripherals) {
+    p.ICU
+        .ielsr(SCI1_TXI_IEL.number() as usize)
+        .modify(|_r, w| w.dtce()._0());
+    let in_flight = UART_DTC_IN_FLIGHT.swap(0, Ordering::SeqCst);
+    if in_flight > 0 {
+        // DTCは転送ごとに残りの転送回数を転送情報に書き戻す
+        let count = unsafe { core::ptr::read_volatile(&raw const UART_DTC_TRANSFER_INFO.count) };
+        let remaining = (count >> 16) as usize;
+        if let Ok(rgr) = TXD_QUEUE.stream_consumer().read() {
+            rgr.release(in_flight - remaining);
+        }
+    }
+}
+
+// シリアル通信送信データエンプティ割り込みハンドラ
+// CPU負荷の測定のために、割り込みの回数と処理にかかったサイクル数を数える
+#[cortex_m_rt::interrupt]
+fn IEL7() {
+    let start = cortex_m::peripheral::DWT::cycle_count();
+
+    // sci_module_init()が割り込みを許可する前に登録するので、ここで登録前(None)にはならない
+    SCI1_ISR_RESOURCES.with(|sci| {
+        sci1_transmit(sci);
+
+        // 割り込みステータスフラグクリア
+        sci.icu.ielsr(7).modify(|_r, w| w.ir().clear_bit());
+    });
+
+    UART_TXI_COUNT.fetch_add(1, Ordering::Relaxed);
+    UART_TXI_CYCLES.fetch_add(
+        cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start),
+        Ordering::Relaxed,
+    );
+}
+
+// 送信データエンプティ割り込みで次のバイト(DTCで送る時は次のまとまり)を送る
+fn sci1_transmit(sci: &Sci1IsrResources) {
+    let txd_cons = TXD_QUEUE.stream_consumer();
+
+    // DTCで送り終わった
+    // 最後のバイトをTDRレジスタに書き込んだところなので、次の送信データエンプティ割り込みで続きを送る
+    let in_flight = UART_DTC_IN_FLIGHT.swap(0, Ordering::SeqCst);
+    if in_flight > 0 {
+        if let Ok(rgr) = txd_cons.read() {
+            rgr.release(in_flight);
+        }
+        return;
+    }
+
+    // フロー制御の文字は送信待ち行列より先に送る
+    let flow_control = TXD_FLOW_CONTROL.swap(0, Ordering::SeqCst);
+    if flow_control != 0 {
+        sci.sci1.tdr().write(|w| unsafe { w.bits(flow_control) });
+        sci.sci1.scr().modify(|_r, w| {
+            w.tie()._1(); // SCIn_TXI割り込み要求を許可
+            w.teie()._0() // SCIn_TEI割り込み要求を禁止
+        });
+        return;
+    }
+
+    // 送信
+    if let Ok(rgr) = txd_cons.read() {
+        // TX_LED を点灯
+        if let Some(led) = BOARD_LEDS.tx {
+            led.on();
+        }
+
+        if UART_DTC_TRANSMIT && rgr.len() >= UART_DTC_MIN_LEN {
+            // 送り終わるまで送信待ち行列から取り除かない
+            uart_dtc_start(&sci.sci1, &sci.icu, &rgr);
+            rgr.release(0);
+        } else {
+            let txd = rgr[0];
+            rgr.release(1);
+            sci.sci1.tdr().write(|w| unsafe { w.bits(txd) });
+        }
+
+        //
+        sci.sci1.scr().modify(|_r, w| {
+            w.tie()._1(); // SCIn_TXI割り込み要求を許可
+            w.teie()._0() // SCIn_TEI割り込み要求を禁止
+        });
+    } else {
+        sci.sci1.scr().modify(|_r, w| {
+            w.tie()._0(); // SCIn_TXI割り込み要求を禁止
+            w.teie()._1() // SCIn_TEI割り込み要求を許可
+        });
+    }
+}
+
+// シリアル通信送信終了割り込み番号
+const SCI1_TEI_IEL: pac::Interrupt = pac::Interrupt::IEL8;
+
+// 送信終了割り込みで立つフラグ
+// 送信待ち行列が空になり、最後のバイトのストップビットまで送り終わったことを示す
+static SCI1_TX_COMPLETE_FLAG: Signal = Signal::new();
+
+// 送信を終えていればtrue
+// 送信終了割り込みは送信待ち行列が空になってから許可され、そこでシリアル送信動作を禁止するので
+// TEビットが0なら最後のバイトのストップビットまで送り終わっている
+fn tx_idle() -> bool {
+    let p = unsafe { pac::Peripherals::steal() };
+    p.SCI1.scr().read().te().is_0()
+}
+
+// 前回呼んでから送信終了割り込みがあればtrue
+// フラグは下ろす
+#[allow(dead_code)]
+fn tx_complete_take() -> bool {
+    SCI1_TX_COMPLETE_FLAG.take()
+}
+
+// シリアル通信送信終了割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL8() {
+    // sci_module_init()が割り込みを許可する前に登録するので、ここで登録前(None)にはならない
+    SCI1_ISR_RESOURCES.with(|sci| {
+        // 送信終了割り込みを許可した後に送信待ち行列にデータが入っていたら送信を続ける
+        // RS-485モードではここでトランシーバーを受信方向に戻すと送信中のデータが途切れる
+        if TXD_QUEUE.stream_consumer().read().is_ok() {
+            sci.sci1.scr().modify(|_r, w| {
+                w.tie()._1(); // SCIn_TXI割り込み要求を許可
+                w.teie()._0() // SCIn_TEI割り込み要求を禁止
+            });
+            // 割り込みステータスフラグクリア
+            sci.icu.ielsr(8).modify(|_r, w| w.ir().clear_bit());
+            return;
+        }
+
+        // シフトレジスタまで送り終わったのでRS-485トランシーバーを受信方向に戻す
+        rs485_set_de(false);
+
+        // シリアル送信動作を禁止
+        sci.sci1.scr().modify(|_r, w| {
+            w.tie()._0(); // SCIn_TXI割り込み要求を禁止
+            w.teie()._0(); // SCIn_TEI割り込み要求を禁止
+            w.te()._0() // シリアル送信動作を禁止
+        });
+
+        // TX_LED を消灯
+        if let Some(led) = BOARD_LEDS.tx {
+            led.off();
+        }
+
+        // 送信終了を知らせる
+        SCI1_TX_COMPLETE_FLAG.set();
+
+        // 割り込みステータスフラグクリア
+        sci.icu.ielsr(8).modify(|_r, w| w.ir().clear_bit());
+    });
+}
+
+// シリアル通信エラー割り込み番号
+const SCI1_ERI_IEL: pac::Interrupt = pac::Interrupt::IEL9;
+
+// この回数だけシリアル通信エラーが連続したらリセットする
+// Noneならリセットしない
+const SCI1_RESET_AFTER_ERRORS: Option<u32> = None;
+
+// シリアル通信エラーの連続回数
+static SCI1_CONSECUTIVE_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+// シリアル通信エラー割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL9() {
+    // sci_module_init()が割り込みを許可する前に登録するので、ここで登録前(None)にはならない
+    SCI1_ISR_RESOURCES.with(|sci| {
+        // シリアル通信エラーステータス
+        let ssr = sci.sci1.ssr().read().bits();
+        defmt::error!("{:X}", ssr);
+
+        // シリアル通信エラーステータスフラグクリア
+        sci.sci1
+            .ssr()
+            .modify(|_r, w| w.per()._0().fer()._0().orer()._0());
+
+        // エラーが続くようならリセットする
+        let errors = SCI1_CONSECUTIVE_ERRORS.fetch_add(1, Ordering::Relaxed) + 1;
+        if SCI1_RESET_AFTER_ERRORS.is_some_and(|limit| errors >= limit) {
+            defmt::error!("{} consecutive SCI errors, resetting", errors);
+            system_reset();
+        }
+
+        // 割り込みステータスフラグクリア
+        sci.icu.ielsr(9).modify(|_r, w| w.ir().clear_bit());
+    });
+}
+
+// シリアル送信待ち行列に残っているデータを送り切る
+// 割り込みハンドラの中からも呼べるように、割り込みを使わずにポーリングで送信する
+fn uart_flush_blocking(p: &pac::Peripherals) {
+    // DTCで送っていない分はここで送る
+    uart_dtc_cancel(p);
+
+    let txd_cons = TXD_QUEUE.stream_consumer();
+
+    // RS-485トランシーバーを送信方向にする
+    rs485_set_de(true);
+
+    // 送信割り込みを止めてシリアル送信動作を許可
+    p.SCI1.scr().modify(|_r, w| {
+        w.tie()._0(); // SCIn_TXI割り込み要求を禁止
+        w.teie()._0(); // SCIn_TEI割り込み要求を禁止
+        w.te()._1() // シリアル送信動作を許可
+    });
+
+    while let Ok(rgr) = txd_cons.read() {
+        let len = rgr.len();
+        for &txd in rgr.iter() {
+            // 送信データエンプティ待ち
+            while p.SCI1.ssr().read().tdre().is_0() {}
+            p.SCI1.tdr().write(|w| unsafe { w.bits(txd) });
+        }
+        rgr.release(len);
+    }
+
+    // 送信終了待ち
+    while p.SCI1.ssr().read().tend().is_0() {}
+
+    // RS-485トランシーバーを受信方向に戻す
+    rs485_set_de(false);
+}
+
+// defmtのログをSCI1から送るロガー
+// ログの1フレームはrzCOBSで符号化して0x00で区切るので、ホスト側はdefmt-printで復号する
+// 割り込みハンドラの中からもログを出せるように、送信待ち行列に空きが無い分は待たずに捨てる
+// (割り込み禁止のまま送信割り込みを待つとデッドロックする)
+// 捨てたフレームは壊れるが、次の区切り(0x00)から復号し直せる
+#[cfg(feature = "defmt-uart")]
+#[defmt::global_logger]
+struct UartLogger;
+
+#[cfg(feature = "defmt-uart")]
+struct UartLoggerState {
+    taken: AtomicBool,
+    cs_restore: core::cell::UnsafeCell<critical_section::RestoreState>,
+    encoder: core::cell::UnsafeCell<defmt::Encoder>,
+    // 今のフレームの書き込み状態
+    frame: core::cell::Cell<UartLogFrame>,
+}
+
+// ログの1フレームの書き込み状態
+// ログのレベルは最初に書かれる書式文字列の番号で分かるので、それまでフレームを始めない
+#[cfg(feature = "defmt-uart")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum UartLogFrame {
+    Pending,  // acquire()の後でまだ何も書いていない
+    Started,  // 送っている
+    Filtered, // しきい値より低いレベルなので捨てている
+}
+
+// ログのレベル
+#[cfg(feature = "defmt-uart")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+#[repr(u8)]
+enum LogLevel {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+}
+
+#[cfg(feature = "defmt-uart")]
+impl LogLevel {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            0 => LogLevel::Trace,
+            1 => LogLevel::Debug,
+            2 => LogLevel::Info,
+            3 => LogLevel::Warn,
+            _ => LogLevel::Error,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "trace" => Some(LogLevel::Trace),
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+
+    // このレベルの書式文字列の番号の始まり
+    // defmtのリンカースクリプト(defmt.x)は書式文字列をレベル順に並べて、境目に印のシンボルを置く
+    fn first_index(self) -> u16 {
+        unsafe extern "C" {
+            static __DEFMT_MARKER_TRACE_START: u8;
+            static __DEFMT_MARKER_DEBUG_START: u8;
+            static __DEFMT_MARKER_INFO_START: u8;
+            static __DEFMT_MARKER_WARN_START: u8;
+            static __DEFMT_MARKER_ERROR_START: u8;
+        }
+        let marker = match self {
+            LogLevel::Trace => &raw const __DEFMT_MARKER_TRACE_START,
+            LogLevel::Debug => &raw const __DEFMT_MARKER_DEBUG_START,
+            LogLevel::Info => &raw const __DEFMT_MARKER_INFO_START,
+            LogLevel::Warn => &raw const __DEFMT_MARKER_WARN_START,
+            LogLevel::Error => &raw const __DEFMT_MARKER_ERROR_START,
+        };
+        marker as usize as u16
+    }
+}
+
+// シリアル通信に送るログのレベルのしきい値
+// これより低いレベルのログは符号化する前に捨てる
+#[cfg(feature = "defmt-uart")]
+static UART_LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+// しきい値より低いレベルなので捨てたログの数
+#[cfg(feature = "defmt-uart")]
+static UART_LOG_FILTERED: AtomicU32 = AtomicU32::new(0);
+
+// 送信待ち行列に入りきらずに途中から捨てたログの数
+#[cfg(feature = "defmt-uart")]
+static UART_LOG_OVERFLOWED: AtomicU32 = AtomicU32::new(0);
+
+// 送信待ち行列に入りきらなかったらtrue (フレームごとに下ろす)
+#[cfg(feature = "defmt-uart")]
+static UART_LOG_TRUNCATED: AtomicBool = AtomicBool::new(false);
+
+// シリアル通信に送るログのレベルのしきい値を変える
+#[cfg(feature = "defmt-uart")]
+fn uart_log_set_level(level: LogLevel) {
+    UART_LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+#[cfg(feature = "defmt-uart")]
+fn uart_log_level() -> LogLevel {
+    LogLevel::from_u8(UART_LOG_LEVEL.load(Ordering::Relaxed))
+}
+
+// シリアル通信で "log" を受け取ったらしきい値と捨てたログの数を送る
+// "log debug" のようにレベルを付けるとしきい値を変える
+#[cfg(feature = "defmt-uart")]
+fn uart_log_command(args: &str) {
+    if !args.is_empty() {
+        match LogLevel::from_name(args) {
+            Some(level) => uart_log_set_level(level),
+            None => {
+                uart_println(b"usage: log [trace|debug|info|warn|error]");
+                return;
+            }
+        }
+    }
+    if let Some(mut line) = uart_line(64) {
+        let _ = write!(
+            line,
+            "log level {}, filtered {}, overflowed {}",
+            uart_log_level().as_str(),
+            UART_LOG_FILTERED.load(Ordering::Relaxed),
+            UART_LOG_OVERFLOWED.load(Ordering::Relaxed)
+        );
+        let _ = line.commit();
+    }
+}
+
+// acquire()からrelease()までクリティカルセクションの中でしか触らない
+#[cfg(feature = "defmt-uart")]
+unsafe impl Sync for UartLoggerState {}
+
+#[cfg(feature = "defmt-uart")]
+static UART_LOGGER_STATE: UartLoggerState = UartLoggerState {
+    taken: AtomicBool::new(false),
+    cs_restore: core::cell::UnsafeCell::new(critical_section::RestoreState::invalid()),
+    encoder: core::cell::UnsafeCell::new(defmt::Encoder::new()),
+    frame: core::cell::Cell::new(UartLogFrame::Pending),
+};
+
+// 符号化したログを送信待ち行列に入るだけ入れる
+#[cfg(feature = "defmt-uart")]
+fn uart_logger_write(bytes: &[u8]) {
+    let txd_prod = TXD_QUEUE.stream_producer();
+    let mut rest = bytes;
+    // 待ち行列の末尾で折り返すと2回に分かれる
+    while !rest.is_empty() {
+        let Ok(mut wgrant) = txd_prod.grant_max_remaining(rest.len()) else {
+            UART_LOG_TRUNCATED.store(true, Ordering::Relaxed);
+            break;
+        };
+        let len = wgrant.len();
+        wgrant.copy_from_slice(&rest[..len]);
+        wgrant.commit(len);
+        rest = &rest[len..];
+    }
+}
+
+#[cfg(feature = "defmt-uart")]
+unsafe impl defmt::Logger for UartLogger {
+    fn acquire() {
+        let state = &UART_LOGGER_STATE;
+        let restore = unsafe { critical_section::acquire() };
+        if state.taken.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        state.taken.store(true, Ordering::Relaxed);
+        unsafe { state.cs_restore.get().write(restore) };
+        // フレームは最初の write() でレベルを見てから始める
+        state.frame.set(UartLogFrame::Pending);
+        UART_LOG_TRUNCATED.store(false, Ordering::Relaxed);
+    }
+
+    unsafe fn flush() {
+        // 割り込み禁止中なのでポーリングで送り切る
+        let p = unsafe { pac::Peripherals::steal() };
+        uart_flush_blocking(&p);
+    }
+
+    unsafe fn release() {
+        let state = &UART_LOGGER_STATE;
+        if state.frame.get() == UartLogFrame::Started {
+            unsafe { (*state.encoder.get()).end_frame(uart_logger_write) };
+            if UART_LOG_TRUNCATED.load(Ordering::Relaxed) {
+                UART_LOG_OVERFLOWED.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // 送信を始める
+            let p = unsafe { pac::Peripherals::steal() };
+            sci1_start_transmit(&p.SCI1);
+        }
+
+        state.taken.store(false, Ordering::Relaxed);
+        unsafe { critical_section::release(state.cs_restore.get().read()) };
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        let state = &UART_LOGGER_STATE;
+        if state.frame.get() == UartLogFrame::Pending {
+            // フレームの最初に書かれるのは書式文字列の番号(u16)
+            // ログのレベルの範囲にあって、しきい値のレベルより前なら捨てる
+            let filtered = match bytes {
+                [lo, hi, ..] => {
+                    let index = u16::from_le_bytes([*lo, *hi]);
+                    (LogLevel::Trace.first_index()..uart_log_level().first_index()).contains(&index)
+                }
+                _ => false,
+            };
+            if filtered {
+                UART_LOG_FILTERED.fetch_add(1, Ordering::Relaxed);
+                state.frame.set(UartLogFrame::Filtered);
+            } else {
+                unsafe { (*state.encoder.get()).start_frame(uart_logger_write) };
+                state.frame.set(UartLogFrame::Started);
+            }
+        }
+        if state.frame.get() == UartLogFrame::Started {
+            unsafe { (*state.encoder.get()).write(bytes, uart_logger_write) };
+        }
+    }
+}
+
+// 書き込めるだけ書き込んで、あふれた分は捨てる文字列
+#[cfg(feature = "panic-uart")]
+struct TruncatingWriter<const N: usize>(String<N>);
+
+#[cfg(feature = "panic-uart")]
+impl<const N: usize> core::fmt::Write for TruncatingWriter<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// パニックの発生場所とメッセージをシリアル通信で送って停止する
+// パニック中は割り込みを使えないので、ポーリングで1バイトずつ送信する
+// 送った後はリセットせずに止まったままにする(IWDTをリフレッシュし続ける)
+// 送信待ち行列の大きさ(QUEUE_SIZE)に収まらない部分は切り捨てる
+#[cfg(feature = "panic-uart")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    let p = unsafe { pac::Peripherals::steal() };
+
+    // 送信待ち行列に残っているデータを先に送り切る
+    uart_flush_blocking(&p);
+
+    // 改行(CR LF)の分を空けておく
+    let mut msg: TruncatingWriter<{ QUEUE_SIZE - 2 }> = TruncatingWriter(String::new());
+    if let Some(location) = info.location() {
+        let _ = write!(msg, "PANIC at {}:{}: ", location.file(), location.line());
+    } else {
+        let _ = write!(msg, "PANIC: ");
+    }
+    let _ = write!(msg, "{}", info.message());
+
+    // ポーリングで送信する
+    rs485_set_de(true);
+    for &txd in msg.0.as_bytes().iter().chain(b"\r\n") {
+        // 送信データエンプティ待ち
+        while p.SCI1.ssr().read().tdre().is_0() {}
+        p.SCI1.tdr().write(|w| unsafe { w.bits(txd) });
+    }
+    // 送信終了待ち
+    while p.SCI1.ssr().read().tend().is_0() {}
+    rs485_set_de(false);
+
+    // パニックの表示を読めるように、リセットしないで止まる
+    // IWDTはスリープ中もカウントを続けるので、WFIで眠るとIWDTのタイムアウトでリセットされてしまう
+    // (割り込み禁止なのでWFIで眠ったまま起きられないこともある)
+    // ウィンドウは設定していないので、いつリフレッシュしてもよい
+    loop {
+        iwdt_refresh(&p);
+    }
+}
+
+// ソフトウェアリセット
+// 送信待ち行列に残っているデータは送り切ってからリセットする
+fn system_reset() -> ! {
+    let p = unsafe { pac::Peripherals::steal() };
+
+    // 割り込みを禁止して送信待ち行列を送り切る
+    cortex_m::interrupt::disable();
+    uart_flush_blocking(&p);
+
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+// 独立ウォッチドッグタイマ(IWDT)のタイムアウト時間
+// IWDTはレジスタで起動できないので、オプション設定メモリ(OFS0)で起動方法を決める
+// IWDT専用クロック(IWDTCLK)は約15kHz
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum IwdtTimeout {
+    Ms1092,  // 1024サイクル, IWDTCLK/16
+    Ms2185,  // 1024サイクル, IWDTCLK/32
+    Ms4369,  // 1024サイクル, IWDTCLK/64
+    Ms17476, // 2048サイクル, IWDTCLK/128
+    Ms34953, // 2048サイクル, IWDTCLK/256
+}
+
+// IWDTのタイムアウト時間
+// Noneなら使わない
+const IWDT_TIMEOUT: Option<IwdtTimeout> = Some(IwdtTimeout::Ms2185);
+
+// オプション設定メモリ OFS0 の値
+// リセット後にIWDTを自動起動する(オートスタートモード)
+const fn iwdt_ofs0(timeout: Option<IwdtTimeout>) -> u32 {
+    let Some(timeout) = timeout else {
+        return 0xffff_ffff; // IWDTは停止状態
+    };
+    // (IWDTTOPS, IWDTCKS)
+    let (tops, cks) = match timeout {
+        IwdtTimeout::Ms1092 => (0b10, 0b0010),
+        IwdtTimeout::Ms2185 => (0b10, 0b0011),
+        IwdtTimeout::Ms4369 => (0b10, 0b0100),
+        IwdtTimeout::Ms17476 => (0b11, 0b1111),
+        IwdtTimeout::Ms34953 => (0b11, 0b0101),
+    };
+    let mut ofs0: u32 = 0xffff_ffff;
+    ofs0 &= !(1 << 1); // IWDTSTRT: オートスタートモードでIWDTを起動
+    ofs0 &= !(0b11 << 2);
+    ofs0 |= tops << 2; // IWDTTOPS: タイムアウト期間
+    ofs0 &= !(0b1111 << 4);
+    ofs0 |= cks << 4; // IWDTCKS: クロック分周比
+    // IWDTRPES, IWDTRPSS: ウィンドウなし (リセット値のまま)
+    // IWDTRSTIRQS: リセット出力 (リセット値のまま)
+    ofs0 &= !(1 << 14); // IWDTSTPCTL: スリープモード中もカウントを続ける
+    ofs0
+}
+
+// オプション設定メモリ
+// OFS0 (0x0000_0400), OFS1 (0x0000_0404)
+#[unsafe(link_section = ".option_setting")]
+#[used]
+static OPTION_SETTING: [u32; 2] = [iwdt_ofs0(IWDT_TIMEOUT), 0xffff_ffff];
+
+// IWDTのカウンタをリフレッシュする
+// タイムアウト時間内に呼ばれなければリセットがかかる
+fn iwdt_refresh(p: &pac::Peripherals) {
+    // 00h, FFhの順に書き込む
+    p.IWDT.iwdtrr().write(|w| unsafe { w.iwdtrr().bits(0x00) });
+    p.IWDT.iwdtrr().write(|w| unsafe { w.iwdtrr().bits(0xff) });
+}
+
+// A/D変換の精度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum AdcResolution {
+    Bits12 = 12, // 12ビット(14ビットより変換が速い)
+    Bits14 = 14, // 14ビット
+}
+
+impl AdcResolution {
+    // 右詰め値の有効ビット
+    const fn mask(self) -> u16 {
+        (1 << self as u16) - 1
+    }
+
+    const fn from_bits(bits: u8) -> Self {
+        if bits == AdcResolution::Bits12 as u8 {
+            AdcResolution::Bits12
+        } else {
+            AdcResolution::Bits14
+        }
+    }
+}
+
+// 16384は2の14乗, 4096は2の12乗
+const _: () = assert!(AdcResolution::Bits14.mask() == 16384 - 1);
+const _: () = assert!(AdcResolution::Bits12.mask() == 4096 - 1);
+
+// アナログ入力端子のA/D変換の精度
+// 温度センサ出力はtsn_to_celsius()が14ビットを前提にしているので、この設定にかかわらず14ビットで変換する
+static ADC_PIN_RESOLUTION: AtomicU8 = AtomicU8::new(AdcResolution::Bits14 as u8);
+
+// アナログ入力端子のA/D変換の精度を変える
+// 次に始めるスキャン(adc_read_channel, adc_scan, adc_continuous_start)から使う
+fn adc_set_resolution(resolution: AdcResolution) {
+    ADC_PIN_RESOLUTION.store(resolution as u8, Ordering::Relaxed);
+}
+
+// アナログ入力端子のA/D変換の精度
+fn adc_pin_resolution() -> AdcResolution {
+    AdcResolution::from_bits(ADC_PIN_RESOLUTION.load(Ordering::Relaxed))
+}
+
+// スキャンを始める前にA/D変換の精度を選ぶ
+// A/D変換を停止した状態で呼ぶこと
+fn adc_select_resolution(p: &pac::Peripherals, resolution: AdcResolution) {
+    p.ADC140.adcer().modify(|_r, w| match resolution {
+        AdcResolution::Bits12 => w.adprc()._00(), // 12ビット精度
+        AdcResolution::Bits14 => w.adprc()._11(), // 14ビット精度
+    });
+}
+
+// 今のスキャンのA/D変換の精度
+fn adc_selected_resolution(adc140: &pac::ADC140) -> AdcResolution {
+    if adc140.adcer().read().adprc().is_00() {
+        AdcResolution::Bits12
+    } else {
+        AdcResolution::Bits14
+    }
+}
+
+// ADCモジュール設定
+fn adc_module_init(p: &pac::Peripherals) {
+    // ADC14モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd16()._0());
+
+    // A/D変換を停止する
+    p.ADC140.adcsr().modify(|_r, w| w.adst()._0());
+    p.ADC140.adansa0().reset();
+    p.ADC140.adansa1().reset();
+    p.ADC140.adansb0().reset();
+    p.ADC140.adansb1().reset();
+
+    // A/D変換設定
+    p.ADC140.adcer().modify(|_r, w| {
+        w.adprc()._11(); // 14ビット精度
+        w.adrfmt()._0() // A/Dデータレジスタのフォーマットを右詰めにする
+    });
+
+    // サンプリング時間設定
+    // 周辺モジュールクロックC(PCLKC Div /1) = 48MHz
+    p.ADC140
+        .adsstrt()
+        .modify(|_r, w| unsafe { w.sst().bits(100) });
+
+    // 高電位基準電圧
+    adc_set_reference(p, ADC_REFERENCE);
+
+    // A/Dスキャン終了割り込みハンドラが使う周辺機能を登録する
+    ADC_ISR_RESOURCES.register(|| unsafe {
+        AdcIsrResources {
+            adc140: pac::ADC140::steal(),
+            icu: pac::ICU::steal(),
+        }
+    });
+}
+
+// A/D変換の高電位基準電圧
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VRef {
+    Avcc(u16),     // AVCC0 (電圧 mV)
+    Internal,      // 内部基準電圧
+    External(u16), // VREFH0端子 (電圧 mV)
+}
+
+impl VRef {
+    // 基準電圧(mV)
+    const fn millivolts(self) -> u16 {
+        match self {
+            VRef::Avcc(mv) | VRef::External(mv) => mv,
+            VRef::Internal => ADC_INTERNAL_VREF_MILLIVOLTS,
+        }
+    }
+}
+
+// 内部基準電圧(mV, ユーザーズマニュアルの電気的特性の標準値)
+const ADC_INTERNAL_VREF_MILLIVOLTS: u16 = 1450;
+
+// 起動時の高電位基準電圧(AVCC0)
+// Arduino UNO R4 MINIMAは5V, RA4M1-Zeroは3.3V
+#[cfg(feature = "board-uno-r4-minima")]
+const ADC_REFERENCE: VRef = VRef::Avcc(5000);
+#[cfg(feature = "board-ra4m1-zero")]
+const ADC_REFERENCE: VRef = VRef::Avcc(3300);
+
+// 今の高電位基準電圧(mV)
+// A/D変換値を電圧に直す時に使う
+static ADC_VREF_MILLIVOLTS: AtomicU16 = AtomicU16::new(ADC_REFERENCE.millivolts());
+
+// 高電位基準電圧の選択(ADHVREFCNT.HVSEL)を書き換えた後の待ち時間(ナノ秒)
+const ADC_HVSEL_SETTLE_NS: u32 = 200;
+
+// A/D変換の高電位基準電圧を切り替える
+// A/D変換を停止した状態で呼ぶこと
+fn adc_set_reference(p: &pac::Peripherals, vref: VRef) {
+    // 内部ノードディスチャージ（基準電圧端子を選択しない）
+    p.ADC140.adhvrefcnt().modify(|_r, w| {
+        w.hvsel()._11(); // 内部ノードディスチャージ（基準電圧端子を選択しない）
+        w.adslp()._0() // 通常動作
+    });
+    busy_wait_ns(ADC_HVSEL_SETTLE_NS);
+
+    // 高電位基準電圧を選択
+    p.ADC140.adhvrefcnt().modify(|_r, w| match vref {
+        VRef::Avcc(_) => w.hvsel()._00(),     // AVCC0
+        VRef::External(_) => w.hvsel()._01(), // VREFH0
+        VRef::Internal => w.hvsel()._10(),    // 内部基準電圧
+    });
+    busy_wait_ns(ADC_HVSEL_SETTLE_NS);
+
+    ADC_VREF_MILLIVOLTS.store(vref.millivolts(), Ordering::Relaxed);
+}
+
+// 今の高電位基準電圧(V)
+fn adc_vref_volts() -> f32 {
+    ADC_VREF_MILLIVOLTS.load(Ordering::Relaxed) as f32 / 1000.0
+}
+
+// 温度センサ出力のA/D変換値を読み取る
+// average_countはハードウェアで平均する回数
+// 14ビット精度で平均モードを使えるのは2回と4回の変換だけなので、それ以外は1回
+// A/D変換が終わるまで止まって待つ
+fn read_tsn_raw(p: &pac::Peripherals, average_count: u8) -> u16 {
+    tsn_scan_start(p, average_count);
+
+    // A/D変換待ち
+    while !adc_is_done(p) {}
+
+    adc_result(p)
+}
+
+// 止まらずに読む時の温度センサ出力をハードウェアで平均する回数
+const ADC_TSN_AVERAGE_COUNT: u8 = 4;
+
+// 温度センサ出力のA/D変換を始めて、終わるのを待たずに戻る
+// adc_is_done() がtrueになってから adc_result() で結果を受け取る
+fn adc_start(p: &pac::Peripherals) {
+    tsn_scan_start(p, ADC_TSN_AVERAGE_COUNT);
+}
+
+// adc_start() で始めたA/D変換が終わっていればtrue
+fn adc_is_done(p: &pac::Peripherals) -> bool {
+    p.ADC140.adcsr().read().adst().is_0()
+}
+
+// 温度センサ出力のA/D変換値
+// adc_is_done() がtrueになってから読むこと
+fn adc_result(p: &pac::Peripherals) -> u16 {
+    // A/D 温度センサデータレジスタの値を読み取る
+    // 14ビット右詰め値
+    p.ADC140.adtsdr().read().bits() & AdcResolution::Bits14.mask()
+}
+
+// 温度センサ出力だけをシングルスキャンモードでA/D変換を始める
+fn tsn_scan_start(p: &pac::Peripherals, average_count: u8) {
+    let average = matches!(average_count, 2 | 4);
+
+    // A/D変換を停止する(連続スキャン中なら止める)
+    adc_continuous_stop(p);
+    adc_select_resolution(p, AdcResolution::Bits14);
+    // アナログ入力チャネルは変換しない
+    p.ADC140.adansa0().reset();
+    p.ADC140.adansa1().reset();
+    p.ADC140.adexicr().modify(|_r, w| {
+        w.ocsa()._0(); // 内部基準電圧のA/D変換禁止
+        if average {
+            w.tssad()._1(); // 温度センサ出力A/D変換値加算／平均モード選択
+        } else {
+            w.tssad()._0(); // 温度センサ出力A/D変換値加算／平均モード非選択
+        }
+        w.tssa()._1() // 温度センサ出力のA/D変換許可
+    });
+    p.ADC140.adadc().modify(|_r, w| match average_count {
+        2 => {
+            w.adc()._001(); // 2回変換
+            w.avee()._1() // 平均モード
+        }
+        4 => {
+            w.adc()._011(); // 4回変換
+            w.avee()._1() // 平均モード
+        }
+        _ => {
+            w.adc()._000(); // 1回変換
+            w.avee()._0() // 加算モード
+        }
+    });
+
+    // シングルスキャンモードでA/D変換開始
+    p.ADC140.adcsr().modify(|_r, w| {
+        w.adcs()._00(); // シングルスキャンモード
+        w.adst()._1() // A/D変換開始
+    });
+}
+
+// 温度センサ出力のA/D変換値を温度に変換する
+fn tsn_to_celsius(p: &pac::Peripherals, tsn: f32) -> f32 {
+    // ユーザーズマニュアルにおける TSNの章より計算式
+    //
+    // 温度（T）はセンサの電圧出力（Vs）と比例関係にあるため、以下の式で温度を求められます。
+    // T = (Vs - V1) / Slope + T1
+    // T：測定温度（℃）
+    // Vs：温度測定時の温度センサの出力電圧（V）
+    // T1：1 点目の試行測定時の温度（℃）
+    // V1：T1 測定時の温度センサの出力電圧（V）
+    // T2：2 点目の試行測定時の温度（℃）
+    // V2：T2 測定時の温度センサの出力電圧（V）
+    // Slope：温度センサの温度傾斜（V/ ℃）、Slope = (V2 - V1) / (T2 - T1)
+
+    // Ta = Tj = 125 ℃および AVCC0 = 3.3V の条件で、
+    // 温度センサが出力した電圧を、
+    // FFh を書き込む ADC16 によって変換した温度センサの温度値（CAL125）
+    // 4096は2の12乗
+    let cal125 = {
+        let h = p.TSN.tscdrh().read().bits() as u16; // 上位4ビット
+        let l = p.TSN.tscdrl().read().bits() as u16; // 下位8ビット
+        ((h << 8) + l) & (4096 - 1)
+    };
+
+    // V1：T1 測定時の温度センサの出力電圧（V）
+    let v1 = 3.3 * (cal125 as f32) / 4096.0;
+
+    // Vs：温度測定時の温度センサの出力電圧（V）
+    // 14ビットのA/D変換値を高電位基準電圧で電圧に直す
+    let vref = adc_vref_volts();
+    let vs = vref * tsn / 16384.0;
+
+    // 温度傾斜
+    let slope = match TSN_CALIBRATION_POINT {
+        // 2点目の校正値があればチップごとの温度傾斜を求める
+        // Slope = (V2 - V1) / (T2 - T1)
+        Some((t2, tsn2)) => {
+            let v2 = vref * (tsn2 as f32) / 16384.0;
+            (v2 - v1) / (t2 - 125.0)
+        }
+        // 無ければユーザーズマニュアル(TSN 特性)の標準値
+        None => TSN_TYPICAL_SLOPE,
+    };
+
+    // 内蔵温度センサの値
+    (vs - v1) / slope + 125.0 // ℃
+}
+
+// ユーザーズマニュアル(TSN 特性)より温度傾斜の標準値
+const TSN_TYPICAL_SLOPE: f32 = -3.65 / 1000.0; // V/℃
+
+// 温度センサの2点目の校正値 (T2 ℃, そのときのA/D変換値(14ビット))
+// RA4M1の工場出荷時の校正値はCAL125(125℃)の1点だけなので、温度傾斜は標準値になる。
+// 温度傾斜のばらつきで数℃ずれるので、既知の温度で(同じ基準電圧で)読んだ read_tsn_raw の値を
+// ここに設定すると、その温度とCAL125の2点を結ぶチップごとの温度傾斜を使う。
+// 例: Some((25.0, 4060))
+const TSN_CALIBRATION_POINT: Option<(f32, u16)> = None;
+
+// Arduino UNO R4 MINIMAのアナログ入力端子
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum AnalogPin {
+    A0, // P014 = AN009
+    A1, // P000 = AN000
+    A2, // P001 = AN001
+    A3, // P002 = AN002
+    A4, // P101 = AN021
+    A5, // P100 = AN022
+}
+
+impl AnalogPin {
+    // アナログ入力チャネル番号(ANxxx)
+    const fn channel(self) -> usize {
+        match self {
+            AnalogPin::A0 => 9,
+            AnalogPin::A1 => 0,
+            AnalogPin::A2 => 1,
+            AnalogPin::A3 => 2,
+            AnalogPin::A4 => 21,
+            AnalogPin::A5 => 22,
+        }
+    }
+
+    // (ポート番号, 端子番号)
+    const fn port_pin(self) -> (u8, u8) {
+        match self {
+            AnalogPin::A0 => (0, 14),
+            AnalogPin::A1 => (0, 0),
+            AnalogPin::A2 => (0, 1),
+            AnalogPin::A3 => (0, 2),
+            AnalogPin::A4 => (1, 1),
+            AnalogPin::A5 => (1, 0),
+        }
+    }
+}
+
+// I/Oポートをアナログ入力に設定
+fn adc_pin_init(p: &pac::Peripherals, pin: AnalogPin) {
+    let (port, pin_number) = pin.port_pin();
+    let config = PfsConfig {
+        analog: true, // アナログ端子として使用する
+        ..PfsConfig::input(false)
+    };
+    pfs_configure(p, port, pin_number, config);
+}
+
+// アナログ入力チャネルのA/Dデータレジスタの値を読み取る
+// 今のスキャンの精度(12ビットか14ビット)の右詰め値
+fn adc_channel_data(adc140: &pac::ADC140, channel: usize) -> u16 {
+    let value = match channel {
+        21 => adc140.addr21().read().bits(),
+        22 => adc140.addr22().read().bits(),
+        n => adc140.addr(n).read().bits(),
+    };
+    value & adc_selected_resolution(adc140).mask()
+}
+
+// アナログ入力端子の電圧をA/D変換する
+// adc_set_resolution()で選んだ精度の右詰め値を返す
+#[allow(dead_code)]
+fn adc_read_channel(p: &pac::Peripherals, pin: AnalogPin) -> u16 {
+    // I/Oポートをアナログ入力に設定
+    adc_pin_init(p, pin);
+
+    // A/D変換を停止する(連続スキャン中なら止める)
+    adc_continuous_stop(p);
+    adc_select_resolution(p, adc_pin_resolution());
+    p.ADC140.adexicr().modify(|_r, w| {
+        w.ocsa()._0(); // 内部基準電圧のA/D変換禁止
+        w.tssa()._0() // 温度センサ出力のA/D変換禁止
+    });
+    p.ADC140.adadc().modify(|_r, w| {
+        w.adc()._000(); // 1回変換
+        w.avee()._0() // 加算モード
+    });
+
+    // 変換するチャネルを選択する
+    let channel = pin.channel();
+    if channel < 16 {
+        p.ADC140
+            .adansa0()
+            .write(|w| unsafe { w.bits(1 << channel) });
+        p.ADC140.adansa1().reset();
+    } else {
+        p.ADC140.adansa0().reset();
+        p.ADC140
+            .adansa1()
+            .write(|w| unsafe { w.bits(1 << (channel - 16)) });
+    }
+
+    // シングルスキャンモードでA/D変換開始
+    p.ADC140.adcsr().modify(|_r, w| {
+        w.adcs()._00(); // シングルスキャンモード
+        w.adst()._1() // A/D変換開始
+    });
+
+    // A/D変換待ち
+    while p.ADC140.adcsr().read().adst().is_1() {}
+
+    // A/Dデータレジスタの値を読み取る
+    adc_channel_data(&p.ADC140, channel)
+}
+
+// 1回のスキャンで変換するチャネル
+#[derive(Debug, Clone, Copy)]
+pub enum AdcChannel {
+    Pin(AnalogPin), // アナログ入力端子
+    Temperature,    // 内蔵温度センサ出力
+}
+
+// channels のチャネルを1回のシングルスキャンでまとめてA/D変換する
+// 右詰め値を channels と同じ順に返す
+// 精度はadc_set_resolution()で選んだものだが、温度センサ出力を含む時はスキャン全体を14ビットにする
+// チャネルごとにA/D変換を開始し直すより速い
+fn adc_scan<const N: usize>(
+    p: &pac::Peripherals,
+    channels: &[AdcChannel],
+) -> heapless::Vec<u16, N> {
+    assert!(channels.len() <= N);
+
+    // A/D変換を停止する(連続スキャン中なら止める)
+    adc_continuous_stop(p);
+
+    // 変換するチャネルを選択する
+    let mut ansa: u32 = 0;
+    let mut temperature = false;
+    for &channel in channels {
+        match channel {
+            AdcChannel::Pin(pin) => {
+                adc_pin_init(p, pin);
+                ansa |= 1 << pin.channel();
+            }
+            AdcChannel::Temperature => temperature = true,
+        }
+    }
+    adc_select_resolution(
+        p,
+        if temperature {
+            AdcResolution::Bits14
+        } else {
+            adc_pin_resolution()
+        },
+    );
+    p.ADC140.adansa0().write(|w| unsafe { w.bits(ansa as u16) });
+    p.ADC140
+        .adansa1()
+        .write(|w| unsafe { w.bits((ansa >> 16) as u16) });
+    p.ADC140.adexicr().modify(|_r, w| {
+        w.ocsa()._0(); // 内部基準電圧のA/D変換禁止
+        w.tssad()._0(); // 温度センサ出力A/D変換値加算／平均モード非選択
+        if temperature {
+            w.tssa()._1() // 温度センサ出力のA/D変換許可
+        } else {
+            w.tssa()._0() // 温度センサ出力のA/D変換禁止
+        }
+    });
+    p.ADC140.adadc().modify(|_r, w| {
+        w.adc()._000(); // 1回変換
+        w.avee()._0() // 加算モード
+    });
+
+    // シングルスキャンモードでA/D変換開始
+    p.ADC140.adcsr().modify(|_r, w| {
+        w.adcs()._00(); // シングルスキャンモード
+        w.adst()._1() // A/D変換開始
+    });
+
+    // A/D変換待ち
+    while p.ADC140.adcsr().read().adst().is_1() {}
+
+    // A/Dデータレジスタを1回ずつ読み取る
+    channels
+        .iter()
+        .map(|&channel| match channel {
+            AdcChannel::Pin(pin) => adc_channel_data(&p.ADC140, pin.channel()),
+            AdcChannel::Temperature => {
+                p.ADC140.adtsdr().read().bits() & AdcResolution::Bits14.mask()
+            }
+        })
+        .collect()
+}
+
+// シリアル通信で "adc" を受け取ったらA0~A3と温度センサ出力を1回のスキャンでA/D変換して右詰め値を送る
+// A4, A5はI2C(SDA1, SCL1)と兼用なので変換しない
+// 温度センサーのA/D変換は止まるので、呼んだ後に始め直すこと
+fn send_adc_scan(p: &pac::Peripherals) {
+    const CHANNELS: [AdcChannel; 5] = [
+        AdcChannel::Pin(AnalogPin::A0),
+        AdcChannel::Pin(AnalogPin::A1),
+        AdcChannel::Pin(AnalogPin::A2),
+        AdcChannel::Pin(AnalogPin::A3),
+        AdcChannel::Temperature,
+    ];
+    let values = adc_scan::<{ CHANNELS.len() }>(p, &CHANNELS);
+    defmt::info!("ADC scan: {}", values.as_slice());
+    if let Some(mut line) = uart_line(56) {
+        let _ = write!(
+            line,
+            "A0 {} A1 {} A2 {} A3 {} TSN {}",
+            values[0], values[1], values[2], values[3], values[4]
+        );
+        let _ = line.commit();
+    }
+}
+
+// シリアル通信で "adc cont" を受け取ったらA0~A3を連続スキャンで変換して、
+// A/Dスキャン終了割り込みで受け取った ADC_CONTINUOUS_SCANS 回分の平均を送る
+// 温度センサーのA/D変換は止まるので、呼んだ後に始め直すこと
+fn send_adc_continuous(p: &pac::Peripherals) {
+    const PINS: [AnalogPin; 4] = [AnalogPin::A0, AnalogPin::A1, AnalogPin::A2, AnalogPin::A3];
+    let mut sums = [0u32; PINS.len()];
+
+    // GPT320のオーバーフローによるA/D変換開始を止めてから始める
+    adc_continuous_stop(p);
+    adc_continuous_start(p, &PINS);
+    let mut scans = 0;
+    while scans < ADC_CONTINUOUS_SCANS {
+        // A/Dスキャン終了割り込みで起こされるまで待つ
+        match adc_continuous_poll() {
+            Some(samples) => {
+                for (sum, &pin) in sums.iter_mut().zip(PINS.iter()) {
+                    *sum += samples.get(pin) as u32;
+                }
+                scans += 1;
+            }
+            None => cortex_m::asm::wfi(),
+        }
+    }
+    adc_continuous_stop(p);
+
+    let averages = sums.map(|sum| sum / ADC_CONTINUOUS_SCANS);
+    defmt::info!("ADC continuous: {}", averages);
+    if let Some(mut line) = uart_line(56) {
+        let _ = write!(
+            line,
+            "A0 {} A1 {} A2 {} A3 {} (avg of {})",
+            averages[0], averages[1], averages[2], averages[3], ADC_CONTINUOUS_SCANS
+        );
+        let _ = line.commit();
+    }
+}
+
+// "adc cont" で平均するスキャンの回数
+const ADC_CONTINUOUS_SCANS: u32 = 16;
+
+// 連続スキャンで得たアナログ入力端子A0~A5の値
+#[derive(Debug, Clone, Copy)]
+pub struct AdcSamples {
+    values: [u16; 6],
+}
+
+impl AdcSamples {
+    // 端子の右詰め値(連続スキャンを始めた時のadc_set_resolution()の精度)
+    // 連続スキャンで選択していない端子の値は不定
+    pub const fn get(&self, pin: AnalogPin) -> u16 {
+        self.values[pin as usize]
+    }
+}
+
+// 連続スキャンの最新の結果
+static ADC_SCAN_RESULT: Mutex<Cell<Option<AdcSamples>>> = Mutex::new(Cell::new(None));
+
+// GPT320のオーバーフローで開始した温度センサ出力のA/D変換値
+static ADC_TSN_RESULT: Mutex<Cell<Option<u16>>> = Mutex::new(Cell::new(None));
+
+// A/Dスキャン終了割り込みを許可するADIEビット(ビット12)
+// PACに無いので直接書き込む
+const ADCSR_ADIE_BIT: u16 = 1 << 12;
+
+// A/Dスキャン終了割り込み番号
+const ADC140_ADI_IEL: pac::Interrupt = pac::Interrupt::IEL12;
+
+// A/Dスキャン終了割り込みハンドラが使う周辺機能
+struct AdcIsrResources {
+    adc140: pac::ADC140,
+    icu: pac::ICU, // 割り込みステータスフラグのクリア
+}
+
+// adc_module_init()で登録する
+static ADC_ISR_RESOURCES: IsrResources<AdcIsrResources> = IsrResources::new();
+
+// A/Dスキャン終了割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL12() {
+    // adc_module_init()で登録するので、割り込みを許可する時には登録済み
+    ADC_ISR_RESOURCES.with(|adc| {
+        // A/Dデータレジスタを結果バッファにコピーする
+        const PINS: [AnalogPin; 6] = [
+            AnalogPin::A0,
+            AnalogPin::A1,
+            AnalogPin::A2,
+            AnalogPin::A3,
+            AnalogPin::A4,
+            AnalogPin::A5,
+        ];
+        let samples = AdcSamples {
+            values: PINS.map(|pin| adc_channel_data(&adc.adc140, pin.channel())),
+        };
+        critical_section::with(|cs| ADC_SCAN_RESULT.borrow(cs).set(Some(samples)));
+        // 温度センサ出力を変換していればその値も(adc_gpt_triggered_start()の場合)
+        if adc.adc140.adexicr().read().tssa().is_1() {
+            let tsn = adc.adc140.adtsdr().read().bits() & AdcResolution::Bits14.mask();
+            critical_section::with(|cs| ADC_TSN_RESULT.borrow(cs).set(Some(tsn)));
+        }
+
+        // 割り込みステータスフラグクリア
+        adc.icu.ielsr(12).modify(|_r, w| w.ir().clear_bit());
+    });
+}
+
+// 連続スキャンモードでA/D変換を開始する
+// スキャンが終わるたびにA/Dスキャン終了割り込みで結果バッファを更新する
+// 連続スキャン中にread_tsnやadc_read_channelを呼ぶと連続スキャンは止まる
+// スキャンごとに割り込みが入るので、チャネル数が少ないと割り込みの頻度が高くなる
+fn adc_continuous_start(p: &pac::Peripherals, pins: &[AnalogPin]) {
+    // A/D変換を停止する
+    p.ADC140.adcsr().modify(|_r, w| w.adst()._0());
+    adc_select_resolution(p, adc_pin_resolution());
+    p.ADC140.adexicr().modify(|_r, w| {
+        w.ocsa()._0(); // 内部基準電圧のA/D変換禁止
+        w.tssa()._0() // 温度センサ出力のA/D変換禁止
+    });
+    p.ADC140.adadc().modify(|_r, w| {
+        w.adc()._000(); // 1回変換
+        w.avee()._0() // 加算モード
+    });
+
+    // 変換するチャネルを選択する
+    let mut ansa: u32 = 0;
+    for &pin in pins {
+        adc_pin_init(p, pin);
+        ansa |= 1 << pin.channel();
+    }
+    p.ADC140.adansa0().write(|w| unsafe { w.bits(ansa as u16) });
+    p.ADC140
+        .adansa1()
+        .write(|w| unsafe { w.bits((ansa >> 16) as u16) });
+
+    // A/Dスキャン終了割り込み設定
+    const ADC140_ADI_EVENT_NUMBER: u8 = 0x032;
+    p.ICU
+        .ielsr(ADC140_ADI_IEL.number() as usize)
+        .modify(|_r, w| w.iels().set(ADC140_ADI_EVENT_NUMBER));
+    critical_section::with(|cs| ADC_SCAN_RESULT.borrow(cs).set(None));
+    unmask_with_priority(ADC140_ADI_IEL, IrqPriority::Adc);
+
+    // 連続スキャンモードでA/D変換開始
+    p.ADC140
+        .adcsr()
+        .modify(|r, w| unsafe { w.bits(r.bits() | ADCSR_ADIE_BIT) });
+    p.ADC140.adcsr().modify(|_r, w| {
+        w.adcs()._10(); // 連続スキャンモード
+        w.adst()._1() // A/D変換開始
+    });
+}
+
+// 連続スキャンを停止する
+// GPT320のオーバーフローによるA/D変換開始も止める
+fn adc_continuous_stop(p: &pac::Peripherals) {
+    p.ADC140.adcsr().modify(|_r, w| {
+        w.trge()._0(); // 同期トリガによるA/D変換開始を禁止
+        w.adst()._0()
+    });
+    // スキャン終了割り込みを禁止する
+    p.ADC140
+        .adcsr()
+        .modify(|r, w| unsafe { w.bits(r.bits() & !ADCSR_ADIE_BIT) });
+    cortex_m::peripheral::NVIC::mask(ADC140_ADI_IEL);
+}
+
+// GPT320のオーバーフローでA/D変換を開始する
+// ELCでGPT320のオーバーフローイベントをADC140につなぐので、ソフトウェアの遅れに関係なく一定の周期で変換できる
+// 温度センサ出力を4回変換して平均し、A/Dスキャン終了割り込みでadc_triggered_poll()に渡す
+// この後にread_tsnやadc_read_channelを呼ぶと止まる
+fn adc_gpt_triggered_start(p: &pac::Peripherals) {
+    // A/D変換を停止する
+    adc_continuous_stop(p);
+    adc_select_resolution(p, AdcResolution::Bits14);
+    // アナログ入力チャネルは変換しない
+    p.ADC140.adansa0().reset();
+    p.ADC140.adansa1().reset();
+    p.ADC140.adexicr().modify(|_r, w| {
+        w.ocsa()._0(); // 内部基準電圧のA/D変換禁止
+        w.tssad()._1(); // 温度センサ出力A/D変換値加算／平均モード選択
+        w.tssa()._1() // 温度センサ出力のA/D変換許可
+    });
+    p.ADC140.adadc().modify(|_r, w| {
+        w.adc()._011(); // 4回変換
+        w.avee()._1() // 平均モード
+    });
+
+    // A/D変換開始トリガにELCのイベント(ELC_AD00)を選ぶ
+    const TRSA_ELC_AD00: u8 = 0x09;
+    p.ADC140
+        .adstrgr()
+        .modify(|_r, w| unsafe { w.trsa().bits(TRSA_ELC_AD00) });
+    elc_link(p, GPT0_COUNTER_OVERFLOW_EVENT, ElcPeripheral::Adc140);
+
+    // A/Dスキャン終了割り込み設定
+    const ADC140_ADI_EVENT_NUMBER: u8 = 0x032;
+    p.ICU
+        .ielsr(ADC140_ADI_IEL.number() as usize)
+        .modify(|_r, w| w.iels().set(ADC140_ADI_EVENT_NUMBER));
+    critical_section::with(|cs| ADC_TSN_RESULT.borrow(cs).set(None));
+    unmask_with_priority(ADC140_ADI_IEL, IrqPriority::Adc);
+
+    // シングルスキャンモードで同期トリガによるA/D変換開始を許可する
+    p.ADC140
+        .adcsr()
+        .modify(|r, w| unsafe { w.bits(r.bits() | ADCSR_ADIE_BIT) });
+    p.ADC140.adcsr().modify(|_r, w| {
+        w.adcs()._00(); // シングルスキャンモード
+        w.extrg()._0(); // 同期トリガ(ELC)
+        w.trge()._1() // トリガによるA/D変換開始を許可
+    });
+}
+
+// 前回から更新されたGPT320のオーバーフローで変換した温度センサ出力のA/D変換値を取り出す
+// 更新されていなければNone
+fn adc_triggered_poll() -> Option<u16> {
+    critical_section::with(|cs| ADC_TSN_RESULT.borrow(cs).take())
+}
+
+// 前回から更新された連続スキャンの結果を取り出す
+// 更新されていなければNone
+fn adc_continuous_poll() -> Option<AdcSamples> {
+    critical_section::with(|cs| ADC_SCAN_RESULT.borrow(cs).take())
+}
+
+// 内蔵温度センサの値を読み取る
+#[allow(dead_code)]
+fn read_tsn(p: &pac::Peripherals) -> f32 {
+    let tsn = read_tsn_raw(p, 1);
+    tsn_to_celsius(p, tsn as f32)
+}
+
+// 温度
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Temperature(f32); // ℃
+
+impl Temperature {
+    pub const fn from_celsius(celsius: f32) -> Self {
+        Temperature(celsius)
+    }
+
+    // 摂氏(℃)
+    pub const fn celsius(self) -> f32 {
+        self.0
+    }
+
+    // 絶対温度(K)
+    pub fn kelvin(self) -> f32 {
+        self.0 + 273.15
+    }
+
+    // 華氏(℉)
+    pub fn fahrenheit(self) -> f32 {
+        self.0 * 9.0 / 5.0 + 32.0
+    }
+}
+
+// 温度の表示単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum TempUnit {
+    Celsius,    // 摂氏(℃)
+    Fahrenheit, // 華氏(℉)
+    Kelvin,     // 絶対温度(K)
+}
+
+impl TempUnit {
+    // "C", "F", "K" のどれか (大文字小文字を区別しない)
+    fn from_name(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("C") {
+            Some(TempUnit::Celsius)
+        } else if name.eq_ignore_ascii_case("F") {
+            Some(TempUnit::Fahrenheit)
+        } else if name.eq_ignore_ascii_case("K") {
+            Some(TempUnit::Kelvin)
+        } else {
+            None
+        }
+    }
+
+    // 温度の表示に付ける単位記号
+    const fn suffix(self) -> &'static str {
+        match self {
+            TempUnit::Celsius => "C",
+            TempUnit::Fahrenheit => "F",
+            TempUnit::Kelvin => "K",
+        }
+    }
+
+    // 摂氏 celsius をこの単位の値にする
+    fn convert(self, celsius: f32) -> f32 {
+        let t = Temperature::from_celsius(celsius);
+        match self {
+            TempUnit::Celsius => t.celsius(),
+            TempUnit::Fahrenheit => t.fahrenheit(),
+            TempUnit::Kelvin => t.kelvin(),
+        }
+    }
+}
+
+// 温度の表示単位を記録する位置(データフラッシュの先頭から)
+const TEMP_UNIT_OFFSET: usize = TEMP_UNIT_BLOCK * DATAFLASH_BLOCK_SIZE;
+
+// 記録されている温度の表示単位
+// 単位記号とそのビット反転を書くので、消去後の不定な値や書きかけはNoneになる
+fn temp_unit_read() -> Option<TempUnit> {
+    let mut buf = [0u8; 2];
+    dataflash_read(TEMP_UNIT_OFFSET, &mut buf).ok()?;
+    if buf[0] != !buf[1] {
+        return None;
+    }
+    TempUnit::from_name(core::str::from_utf8(&buf[..1]).ok()?)
+}
+
+// 温度の表示単位をデータフラッシュに記録して、読み直して確かめる
+// 単位を変えた時だけ書くので、毎回ブロックを消去してから先頭に書く
+fn temp_unit_save(clocks: &ClockConfig, unit: TempUnit) -> Result<(), DataFlashError> {
+    dataflash_erase(clocks, TEMP_UNIT_BLOCK)?;
+    let code = unit.suffix().as_bytes()[0];
+    dataflash_write(clocks, TEMP_UNIT_OFFSET, &[code, !code])?;
+    if temp_unit_read() == Some(unit) {
+        Ok(())
+    } else {
+        Err(DataFlashError::ProgramFailed)
+    }
+}
+
+// シリアル通信で "unit C", "unit F", "unit K" を受け取ったら温度の表示単位を変えてデータフラッシュに記録する
+// "unit" だけなら今の単位を返す
+fn temp_unit_command(clocks: &ClockConfig, unit: &mut TempUnit, args: &str) {
+    if !args.is_empty() {
+        let Some(new_unit) = TempUnit::from_name(args) else {
+            uart_println(b"usage: unit [C|F|K]");
+            return;
+        };
+        if new_unit != *unit {
+            *unit = new_unit;
+            if let Err(e) = temp_unit_save(clocks, new_unit) {
+                defmt::warn!("temperature unit not saved: {}", defmt::Debug2Format(&e));
+                uart_println(b"WARNING: temperature unit not saved");
+            }
+        }
+    }
+    let _ = format!("temperature unit {}", unit.suffix())
+        .map(|s: String<24>| uart_println(s.as_bytes()));
+}
+
+// 温度の変化の向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum TempTrend {
+    Rising,  // 上がっている
+    Falling, // 下がっている
+    Stable,  // 変わらない
+}
+
+// 設定温度を横切った向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum TempCrossing {
+    Above, // 設定温度以上になった
+    Below, // 設定温度 - 不感帯以下になった
+}
+
+// 温度の監視
+// 設定温度の前後で行ったり来たりしても通知が続かないように不感帯(ヒステリシス)を持つ
+// 設定温度以上で上, (設定温度 - 不感帯)以下で下と判定して、その間では前の判定を保つ
+// 変化の向きも、前に向きを決めた温度から不感帯以上変わるまでStableとする
+struct TempMonitor {
+    setpoint: f32,           // 設定温度(℃)
+    deadband: f32,           // 不感帯(℃)
+    above: Option<bool>,     // 設定温度より上ならtrue, まだ判定していなければNone
+    trend_base: Option<f32>, // 前に変化の向きを決めた温度
+}
+
+impl TempMonitor {
+    const fn new(setpoint: f32, deadband: f32) -> Self {
+        TempMonitor {
+            setpoint,
+            deadband,
+            above: None,
+            trend_base: None,
+        }
+    }
+
+    // 温度 celsius を受け取って、変化の向きと設定温度を横切ったかを返す
+    // 最初の値では横切ったことにしないが、設定温度以上ならAboveを返す
+    fn update(&mut self, celsius: f32) -> (TempTrend, Option<TempCrossing>) {
+        let trend = match self.trend_base {
+            Some(base) if celsius >= base + self.deadband => TempTrend::Rising,
+            Some(base) if celsius <= base - self.deadband => TempTrend::Falling,
+            _ => TempTrend::Stable,
+        };
+        if trend != TempTrend::Stable || self.trend_base.is_none() {
+            self.trend_base = Some(celsius);
+        }
+
+        let above = if celsius >= self.setpoint {
+            true
+        } else if celsius <= self.setpoint - self.deadband {
+            false
+        } else {
+            // 不感帯の中では前の判定を保つ
+            self.above.unwrap_or(false)
+        };
+        let crossing = match (self.above, above) {
+            (Some(false) | None, true) => Some(TempCrossing::Above),
+            (Some(true), false) => Some(TempCrossing::Below),
+            _ => None,
+        };
+        self.above = Some(above);
+        (trend, crossing)
+    }
+}
+
+// 直近N個の温度の記録
+// 古いものから上書きするリングバッファで、窓の中の最小, 最大, 平均を求める
+// 0.01℃単位の整数で持って整数で合計するので、f32にするのは出し入れの時だけ
+struct History<const N: usize> {
+    samples: [i16; N], // 温度(0.01℃単位)
+    next: usize,       // 次に書き込む位置
+    len: usize,        // 記録した個数(最大N)
+}
+
+impl<const N: usize> History<N> {
+    const fn new() -> Self {
+        History {
+            samples: [0; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    // 温度 celsius を記録する
+    // 一杯なら一番古い記録を上書きする
+    fn push(&mut self, celsius: f32) {
+        let centi = (celsius * 100.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        self.samples[self.next] = centi;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    // 記録した温度
+    fn window(&self) -> &[i16] {
+        &self.samples[..self.len]
+    }
+
+    fn min(&self) -> Option<f32> {
+        self.window().iter().min().map(|&c| c as f32 / 100.0)
+    }
+
+    fn max(&self) -> Option<f32> {
+        self.window().iter().max().map(|&c| c as f32 / 100.0)
+    }
+
+    fn average(&self) -> Option<f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let sum: i32 = self.window().iter().map(|&c| c as i32).sum();
+        Some(sum as f32 / self.len as f32 / 100.0)
+    }
+}
+
+// 温度の記録の個数
+// GPT320のオーバーフロー(1秒)ごとに1つ記録するので60秒分
+const TEMP_HISTORY_SIZE: usize = 60;
+
+// シリアル通信で "stats" を受け取ったら温度の記録の最小, 平均, 最大を unit の単位で送る
+fn send_temp_stats(history: &History<TEMP_HISTORY_SIZE>, unit: TempUnit) {
+    if let Some(mut line) = uart_line(48) {
+        let _ = match (history.min(), history.average(), history.max()) {
+            (Some(min), Some(avg), Some(max)) => write!(
+                line,
+                "min/avg/max over last {}s: {:.1}/{:.1}/{:.1} {}",
+                history.len,
+                unit.convert(min),
+                unit.convert(avg),
+                unit.convert(max),
+                unit.suffix()
+            ),
+            _ => write!(line, "no temperature samples yet"),
+        };
+        let _ = line.commit();
+    }
+}
+
+// 温度の警告
+// (設定温度, 不感帯)の℃, Noneなら警告しない
+// 設定温度以上になるとD13(LED)を点灯してシリアル通信で知らせ、(設定温度 - 不感帯)以下で消灯する
+const TEMP_ALERT: Option<(f32, f32)> = Some((30.0, 1.0));
+
+// 内蔵温度センサーの値をデータフラッシュに記録する間隔(秒)
+// Noneなら記録しない
+// 記録はデータフラッシュの先頭5ブロックに256件から320件残り、一杯になると古いものから上書きする
+const TEMP_LOG_INTERVAL_S: Option<u32> = Some(60);
+
+// "dump temp" で1行送るのを待つ最長の時間(ミリ秒)
+// 相手が受け取らなくなったらここで送るのをやめる(IWDTのタイムアウトより短くする)
+const TEMP_LOG_LINE_TIMEOUT_MS: u32 = 500;
+
+// シリアル通信で "dump temp" を受け取ったらデータフラッシュの温度の記録を古い順に全部送る
+// ("dump" だけならレジスタの表示)
+// 送信待ち行列が空くのを待ちながら送るので、その間もIWDTをリフレッシュする
+fn send_temp_log(p: &pac::Peripherals, logger: &TempLogger, unit: TempUnit) {
+    let mut count = 0;
+    for record in logger.records() {
+        iwdt_refresh(p);
+        let Ok::<String<48>, _>(line) = format!(
+            "{} boot {} +{} s {:.2} {}",
+            record.seq,
+            record.boot,
+            record.uptime_s,
+            unit.convert(record.celsius()),
+            unit.suffix()
+        ) else {
+            continue;
+        };
+        if let Err(e) = uart_println_timeout(line.as_bytes(), TEMP_LOG_LINE_TIMEOUT_MS) {
+            defmt::warn!("temperature record dump stopped: {}", e);
+            return;
+        }
+        count += 1;
+    }
+    let _ =
+        format!("{} temperature records", count).map(|s: String<32>| uart_println(s.as_bytes()));
+}
+
+// 内蔵温度センサの値を平均して読み取る
+// samples回のA/D変換値の平均から温度を求める
+// 4回まではハードウェアの平均モード(2回か4回)で、それ以上は4回平均の値をさらに平均する
+#[allow(dead_code)]
+fn read_tsn_averaged(p: &pac::Peripherals, samples: u16) -> f32 {
+    let tsn = match samples {
+        0 | 1 => read_tsn_raw(p, 1) as f32,
+        2 | 3 => read_tsn_raw(p, 2) as f32,
+        4 => read_tsn_raw(p, 4) as f32,
+        _ => {
+            let n = samples.div_ceil(4);
+            let sum: u32 = (0..n).map(|_| read_tsn_raw(p, 4) as u32).sum();
+            sum as f32 / n as f32
+        }
+    };
+    tsn_to_celsius(p, tsn)
+}
+
+// 12ビットD/Aコンバーターの最大値
+const DAC_MAX_VALUE: u16 = 4095;
+
+// 三角波の1タイマー割り込みごとの増減量
+const DAC_TRIANGLE_STEP: u16 = 256;
+
+// D/Aコンバーター(DAC12)モジュール設定
+// DA0出力はP014 = Arduino A0端子に出る
+// A0端子はアナログ入力(AN009)と共用なので、DACを使うときはA0をA/D変換に使わない
+fn dac_init(p: &pac::Peripherals) {
+    // DAC12モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd20()._0());
+
+    // P014をアナログ端子にする
+    adc_pin_init(p, AnalogPin::A0);
+
+    // D/A変換設定
+    p.DAC12.davrefcr().write(|w| w.ref_()._001()); // 基準電圧にAVCC0/AVSS0を選択
+    p.DAC12.dadpr().write(|w| w.dpsel()._0()); // D/Aデータレジスタのフォーマットを右詰めにする
+    p.DAC12.dadr0().write(|w| unsafe { w.dadr().bits(0) });
+
+    // D/A変換出力を許可
+    p.DAC12.dacr().modify(|_r, w| w.daoe0()._1());
+}
+
+// D/Aコンバーターに出力する
+// 範囲外の値は最大値(4095)に丸める
+fn dac_write(dac12: &pac::DAC12, value_0_4095: u16) {
+    let value = value_0_4095.min(DAC_MAX_VALUE);
+    dac12.dadr0().write(|w| unsafe { w.dadr().bits(value) });
+}
+
+// 波形
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,     // 正弦波
+    Triangle, // 三角波
+    Sawtooth, // のこぎり波
+}
+
+// 波形テーブルの長さ(1周期のサンプル数)
+const WAVE_TABLE_LEN: usize = 256;
+
+// 1周期分の波形テーブル(0～DAC_MAX_VALUE)をコンパイル時に作る
+const fn wave_table(waveform: Waveform) -> [u16; WAVE_TABLE_LEN] {
+    // [-π/2, π/2]の正弦をテイラー展開(x^13まで)で求める
+    const fn sin(x: f64) -> f64 {
+        let x2 = x * x;
+        let mut term = x;
+        let mut sum = x;
+        let mut n = 1;
+        while n <= 6 {
+            term = -term * x2 / ((2 * n) * (2 * n + 1)) as f64;
+            sum += term;
+            n += 1;
+        }
+        sum
+    }
+    const PI: f64 = core::f64::consts::PI;
+    const MAX: f64 = DAC_MAX_VALUE as f64;
+    let mut table = [0u16; WAVE_TABLE_LEN];
+    let mut i = 0;
+    while i < WAVE_TABLE_LEN {
+        let value = match waveform {
+            Waveform::Sine => {
+                // sin(θ) = sin(π - θ) で[-π/2, π/2]に畳む
+                let theta = 2.0 * PI * i as f64 / WAVE_TABLE_LEN as f64;
+                let x = if theta <= PI / 2.0 {
+                    theta
+                } else if theta <= 3.0 * PI / 2.0 {
+                    PI - theta
+                } else {
+                    theta - 2.0 * PI
+                };
+                (MAX / 2.0) * (1.0 + sin(x))
+            }
+            Waveform::Triangle => {
+                let half = (WAVE_TABLE_LEN / 2) as f64;
+                if i < WAVE_TABLE_LEN / 2 {
+                    MAX * i as f64 / half
+                } else {
+                    MAX * (WAVE_TABLE_LEN - i) as f64 / half
+                }
+            }
+            Waveform::Sawtooth => MAX * i as f64 / (WAVE_TABLE_LEN - 1) as f64,
+        };
+        // 四捨五入
+        table[i] = (value + 0.5) as u16;
+        i += 1;
+    }
+    table
+}
+
+static SINE_TABLE: [u16; WAVE_TABLE_LEN] = wave_table(Waveform::Sine);
+static TRIANGLE_TABLE: [u16; WAVE_TABLE_LEN] = wave_table(Waveform::Triangle);
+static SAWTOOTH_TABLE: [u16; WAVE_TABLE_LEN] = wave_table(Waveform::Sawtooth);
+
+// 正弦波は中央(2048)から始まって1/4周期で最大, 3/4周期で最小
+const _: () = {
+    let sine = wave_table(Waveform::Sine);
+    assert!(sine[0] == 2048 && sine[64] == DAC_MAX_VALUE && sine[128] == 2048 && sine[192] == 0);
+    let triangle = wave_table(Waveform::Triangle);
+    assert!(triangle[0] == 0 && triangle[128] == DAC_MAX_VALUE && triangle[255] == 32);
+    let sawtooth = wave_table(Waveform::Sawtooth);
+    assert!(sawtooth[0] == 0 && sawtooth[255] == DAC_MAX_VALUE);
+};
+
+// 波形テーブルを位相アキュムレータで読み出して、1サンプルずつD/Aコンバーターの値を作る
+// 位相(32ビット)の上位8ビットがテーブルの位置で、1サンプルごとに step だけ進める
+pub struct WaveGen {
+    table: &'static [u16; WAVE_TABLE_LEN],
+    phase: u32,
+    step: u32,
+}
+
+impl WaveGen {
+    // サンプリング周波数 sample_hz で周波数 freq_hz の波形を作る
+    pub fn new(waveform: Waveform, freq_hz: u32, sample_hz: u32) -> Self {
+        let table = match waveform {
+            Waveform::Sine => &SINE_TABLE,
+            Waveform::Triangle => &TRIANGLE_TABLE,
+            Waveform::Sawtooth => &SAWTOOTH_TABLE,
+        };
+        WaveGen {
+            table,
+            phase: 0,
+            step: ((freq_hz as u64) << 32).div_ceil(sample_hz as u64) as u32,
+        }
+    }
+
+    // 次のサンプル
+    pub fn next_sample(&mut self) -> u16 {
+        let value = self.table[(self.phase >> 24) as usize];
+        self.phase = self.phase.wrapping_add(self.step);
+        value
+    }
+}
+
+// DACから出力する波形と周波数(Hz)
+// Noneならメインループで1タイマー割り込みごとに三角波を出力する
+// Someなら GPT321 のオーバーフロー割り込みごとに1サンプル出力する
+const DAC_WAVE: Option<(Waveform, u32)> = None;
+
+// 波形のサンプリング周波数(Hz)
+// 出力できる周波数はこの半分まで, 波形テーブルを1サンプルずつ全部出すのはこの1/256の周波数
+const DAC_WAVE_SAMPLE_HZ: u32 = 20_000;
+
+// 割り込みハンドラで使う波形発生器
+static WAVE_GEN: Mutex<RefCell<Option<WaveGen>>> = Mutex::new(RefCell::new(None));
+
+// GPT321 タイマオーバーフロー割り込み番号
+const GPT321_OVERFLOW_IEL: pac::Interrupt = pac::Interrupt::IEL16;
+
+// GPT321をサンプリング周期で回して、オーバーフロー割り込みごとにDACへ波形を出力する
+// dac_init()の後で呼ぶこと
+fn wave_gen_init(p: &pac::Peripherals, clocks: &ClockConfig, waveform: Waveform, freq_hz: u32) {
+    critical_section::with(|cs| {
+        WAVE_GEN
+            .borrow_ref_mut(cs)
+            .replace(WaveGen::new(waveform, freq_hz, DAC_WAVE_SAMPLE_HZ));
+    });
+
+    // サンプリング周期でGPT321を回す
+    let channel = Gpt32Channel::Gpt321;
+    gpt_module_init(p, clocks, channel, clocks.pclkd / DAC_WAVE_SAMPLE_HZ);
+    gpt_start(p, channel);
+}
+
+// GPT321 タイマオーバーフロー割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL16() {
+    with_gpt(|gpt| {
+        gpt_overflow_dispatch(gpt);
+
+        // 割り込みステータスフラグクリア
+        gpt.icu
+            .ielsr(GPT321_OVERFLOW_IEL.number() as usize)
+            .modify(|_r, w| w.ir().clear_bit());
+    });
+}
+
+// I2Cの通信速度(Hz)
+const I2C_FREQ: u32 = 100_000;
+
+// 起動時にI2Cバス(A4/SDA, A5/SCL)につながっている機器を探す
+// プルアップ抵抗をつけてから true にする
+const I2C_BUS_SCAN: bool = false;
+
+// I2Cの通信エラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cError {
+    Nack,                 // 相手からNACKが返ってきた
+    ArbitrationLost,      // アービトレーションロスト
+    Timeout,              // バスやフラグが待っても変わらない(バスが固まった, スレーブが無いなど)
+    BitrateNotAchievable, // PCLKBから I2C_FREQ を作れない
+}
+
+// 状態の確認を何回繰り返したら諦めるか
+const I2C_SPIN_LIMIT: u32 = 1_000_000;
+
+// check()がSomeを返すまで繰り返す
+// I2C_SPIN_LIMIT回確かめても変わらなければIIC1を内部リセットしてErr(I2cError::Timeout)を返す
+fn i2c_spin_until(
+    p: &pac::Peripherals,
+    mut check: impl FnMut() -> Option<Result<(), I2cError>>,
+) -> Result<(), I2cError> {
+    for _ in 0..I2C_SPIN_LIMIT {
+        if let Some(result) = check() {
+            return result;
+        }
     }
+    // 内部リセットで送受信の途中の状態を捨てる(通信速度などの設定は残る)
+    p.IIC1.iccr1().modify(|_r, w| w.iicrst()._1());
+    p.IIC1.icmr3().modify(|_r, w| w.ackwp()._1());
+    p.IIC1.icmr3().modify(|_r, w| w.ackbt()._0());
+    p.IIC1.icmr3().modify(|_r, w| w.ackwp()._0());
+    p.IIC1.icmr3().modify(|_r, w| w.wait()._0());
+    p.IIC1.iccr1().modify(|_r, w| w.iicrst()._0());
+    Err(I2cError::Timeout)
+}
 
-    // 割り込みステータスフラグクリア
-    p.ICU.ielsr(7).modify(|_r, w| w.ir().clear_bit());
+// cond()が成り立つまで待つ
+fn i2c_wait(p: &pac::Peripherals, mut cond: impl FnMut() -> bool) -> Result<(), I2cError> {
+    i2c_spin_until(p, || cond().then_some(Ok(())))
 }
 
-// シリアル通信送信終了割り込み番号
-const SCI1_TEI_IEL: pac::Interrupt = pac::Interrupt::IEL8;
+// I2Cモジュール(IIC1)設定
+// PORT 101 = SDA1 (Arduino A4/SDA)
+// PORT 100 = SCL1 (Arduino A5/SCL)
+// PCLKBから I2C_FREQ を作れなければErr(I2cError::BitrateNotAchievable)
+fn i2c_init(p: &pac::Peripherals, clocks: &ClockConfig) -> Result<(), I2cError> {
+    // IIC1モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrb().modify(|_r, w| w.mstpb8()._0());
 
-// シリアル通信送信終了割り込みハンドラ
-#[cortex_m_rt::interrupt]
-fn IEL8() {
-    let p = unsafe { pac::Peripherals::steal() };
+    // I/Oポートの設定
+    // NMOSオープンドレイン
+    const IIC1_PSEL: u8 = 0b00111; // IIC
+    let config = PfsConfig {
+        open_drain: true,
+        ..PfsConfig::peripheral(IIC1_PSEL, false)
+    };
+    // PORT 100 = SCL1
+    pfs_configure(p, 1, 0, config);
+    // PORT 101 = SDA1
+    pfs_configure(p, 1, 1, config);
 
-    // シリアル送信動作を禁止
-    p.SCI1.scr().modify(|_r, w| {
-        w.tie()._0(); // SCIn_TXI割り込み要求を禁止
-        w.teie()._0(); // SCIn_TEI割り込み要求を禁止
-        w.te()._0() // シリアル送信動作を禁止
+    // 通信速度設定
+    // SCLの周期から立ち上がりと立ち下がりの時間(約1300ns)を引いた残りを
+    // Low期間が少し長くなるように分ける
+    // ICBRL, ICBRHは5ビットなので、収まるように内部基準クロック fIIC = PCLKB / 2^CKS を選ぶ
+    let (cks, total) = (0..=7u8)
+        .map(|cks| {
+            let fiic = clocks.pclkb >> cks;
+            let rise_fall = fiic / 100_000 * 13 / 100;
+            (cks, (fiic / I2C_FREQ).saturating_sub(rise_fall))
+        })
+        .find(|&(_cks, total)| {
+            total >= 2 && total * 55 / 100 <= 32 && total - total * 55 / 100 <= 32
+        })
+        .ok_or(I2cError::BitrateNotAchievable)?;
+    let low = total * 55 / 100;
+    let high = total - low;
+
+    // IIC1をリセットする
+    p.IIC1.iccr1().modify(|_r, w| w.ice()._0()); // SCL, SDA端子を非駆動
+    p.IIC1.iccr1().modify(|_r, w| w.iicrst()._1()); // IIC1リセット
+    p.IIC1.iccr1().modify(|_r, w| w.ice()._1()); // 内部リセット
+
+    // 通信速度設定
+    p.IIC1.icmr1().modify(|_r, w| w.cks().set(cks));
+    p.IIC1
+        .icbrl()
+        .write(|w| unsafe { w.bits(0xe0 | (low - 1) as u8) }); // 上位3ビットは1
+    p.IIC1
+        .icbrh()
+        .write(|w| unsafe { w.bits(0xe0 | (high - 1) as u8) }); // 上位3ビットは1
+
+    // NACKを受信したら転送を中断する
+    p.IIC1.icfer().modify(|_r, w| {
+        w.nacke()._1(); // NACK受信転送中断許可
+        w.male()._1() // マスタアービトレーションロスト検出許可
     });
 
-    // TX_LED (PORT 012) を消灯
-    p.PORT0
-        .podr()
-        .modify(|r, w| unsafe { w.bits(r.bits() | (1 << 12)) });
+    // 内部リセット解除
+    p.IIC1.iccr1().modify(|_r, w| w.iicrst()._0());
+    Ok(())
+}
 
-    // 割り込みステータスフラグクリア
-    p.ICU.ielsr(8).modify(|_r, w| w.ir().clear_bit());
+// ストップコンディションを発行して、バスが解放されるのを待つ
+fn i2c_stop(p: &pac::Peripherals) -> Result<(), I2cError> {
+    p.IIC1.icsr2().modify(|_r, w| w.stop().clear_bit());
+    p.IIC1.iccr2().modify(|_r, w| w.sp()._1());
+    i2c_wait(p, || p.IIC1.icsr2().read().stop().is_1())?;
+    p.IIC1.icsr2().modify(|_r, w| {
+        w.nackf().clear_bit();
+        w.stop().clear_bit()
+    });
+    Ok(())
 }
 
-// シリアル通信エラー割り込み番号
-const SCI1_ERI_IEL: pac::Interrupt = pac::Interrupt::IEL9;
+// スタートコンディションを発行して、スレーブアドレスを送信する
+// read: trueなら読み込み、falseなら書き込み
+fn i2c_start(p: &pac::Peripherals, addr: u8, read: bool) -> Result<(), I2cError> {
+    // バスが解放されるのを待つ
+    i2c_wait(p, || p.IIC1.iccr2().read().bbsy().is_0())?;
 
-// シリアル通信エラー割り込みハンドラ
-#[cortex_m_rt::interrupt]
-fn IEL9() {
-    let p = unsafe { pac::Peripherals::steal() };
+    // スタートコンディション発行
+    p.IIC1.iccr2().modify(|_r, w| w.st()._1());
+    i2c_spin_until(p, || {
+        let icsr2 = p.IIC1.icsr2().read();
+        if icsr2.al().is_1() {
+            p.IIC1.icsr2().modify(|_r, w| w.al().clear_bit());
+            Some(Err(I2cError::ArbitrationLost))
+        } else {
+            icsr2.tdre().is_1().then_some(Ok(()))
+        }
+    })?;
+
+    // スレーブアドレスを送信する
+    let rw_bit = if read { 1 } else { 0 };
+    p.IIC1
+        .icdrt()
+        .write(|w| unsafe { w.bits(addr << 1 | rw_bit) });
+    Ok(())
+}
 
-    // シリアル通信エラーステータス
-    let ssr = p.SCI1.ssr().read().bits();
-    defmt::error!("{:X}", ssr);
+// I2Cスレーブにデーターを書き込む
+fn i2c_write(p: &pac::Peripherals, addr: u8, bytes: &[u8]) -> Result<(), I2cError> {
+    i2c_start(p, addr, false)?;
 
-    // シリアル通信エラーステータスフラグクリア
-    p.SCI1
-        .ssr()
-        .modify(|_r, w| w.per()._0().fer()._0().orer()._0());
+    // NACKを受信したらストップコンディションを発行して諦める
+    let wait_unless_nack = |done: fn(&pac::iic1::icsr2::R) -> bool| {
+        i2c_spin_until(p, || {
+            let icsr2 = p.IIC1.icsr2().read();
+            if icsr2.nackf().is_1() {
+                Some(i2c_stop(p).and(Err(I2cError::Nack)))
+            } else {
+                done(&icsr2).then_some(Ok(()))
+            }
+        })
+    };
+
+    for &byte in bytes {
+        // 送信データエンプティを待つ
+        wait_unless_nack(|icsr2| icsr2.tdre().is_1())?;
+        p.IIC1.icdrt().write(|w| unsafe { w.bits(byte) });
+    }
 
-    // 割り込みステータスフラグクリア
-    p.ICU.ielsr(9).modify(|_r, w| w.ir().clear_bit());
+    // 送信終了を待つ
+    wait_unless_nack(|icsr2| icsr2.tend().is_1())?;
+
+    i2c_stop(p)
 }
 
-// ADCモジュール設定
-fn adc_module_init(p: &pac::Peripherals) {
-    // ADC14モジュールのモジュールストップ状態の解除
-    p.MSTP.mstpcrd().modify(|_r, w| w.mstpd16()._0());
+// I2Cスレーブからデーターを読み込む
+fn i2c_read(p: &pac::Peripherals, addr: u8, buf: &mut [u8]) -> Result<(), I2cError> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    i2c_start(p, addr, true)?;
 
-    // A/D変換を停止する
-    p.ADC140.adcsr().modify(|_r, w| w.adst()._0());
-    p.ADC140.adansa0().reset();
-    p.ADC140.adansa1().reset();
-    p.ADC140.adansb0().reset();
-    p.ADC140.adansb1().reset();
+    // スレーブアドレスの送信完了を待つ
+    i2c_spin_until(p, || {
+        let icsr2 = p.IIC1.icsr2().read();
+        if icsr2.nackf().is_1() {
+            // スレーブが応答しない
+            p.IIC1.icsr2().modify(|_r, w| w.stop().clear_bit());
+            p.IIC1.iccr2().modify(|_r, w| w.sp()._1());
+            let _ = p.IIC1.icdrr().read().bits(); // ダミーリード
+            let stopped = i2c_wait(p, || p.IIC1.icsr2().read().stop().is_1());
+            p.IIC1.icsr2().modify(|_r, w| {
+                w.nackf().clear_bit();
+                w.stop().clear_bit()
+            });
+            Some(stopped.and(Err(I2cError::Nack)))
+        } else {
+            icsr2.rdrf().is_1().then_some(Ok(()))
+        }
+    })?;
 
-    // A/D変換設定
-    p.ADC140.adcer().modify(|_r, w| {
-        w.adprc()._11(); // 14ビット精度
-        w.adrfmt()._0() // A/Dデータレジスタのフォーマットを右詰めにする
+    // 1バイトずつICDRRを読むまでSCLをLowに保持して、ACK/NACKの設定を間に合わせる
+    p.IIC1.icmr3().modify(|_r, w| w.wait()._1());
+    if buf.len() == 1 {
+        // 最後のバイトにはNACKを返す
+        p.IIC1.icmr3().modify(|_r, w| w.ackwp()._1());
+        p.IIC1.icmr3().modify(|_r, w| w.ackbt()._1());
+    }
+    // ダミーリードで受信を開始する
+    let _ = p.IIC1.icdrr().read().bits();
+
+    let len = buf.len();
+    for (i, byte) in buf.iter_mut().enumerate() {
+        // 受信データフルを待つ
+        i2c_wait(p, || p.IIC1.icsr2().read().rdrf().is_1())?;
+        if i + 1 == len {
+            // 最後のバイト
+            p.IIC1.icsr2().modify(|_r, w| w.stop().clear_bit());
+            p.IIC1.iccr2().modify(|_r, w| w.sp()._1());
+            *byte = p.IIC1.icdrr().read().bits();
+            p.IIC1.icmr3().modify(|_r, w| w.wait()._0());
+        } else {
+            if i + 2 == len {
+                // 次の最後のバイトにはNACKを返す
+                p.IIC1.icmr3().modify(|_r, w| w.ackwp()._1());
+                p.IIC1.icmr3().modify(|_r, w| w.ackbt()._1());
+            }
+            *byte = p.IIC1.icdrr().read().bits();
+        }
+    }
+
+    // ストップコンディションの検出を待つ
+    i2c_wait(p, || p.IIC1.icsr2().read().stop().is_1())?;
+    p.IIC1.icsr2().modify(|_r, w| {
+        w.nackf().clear_bit();
+        w.stop().clear_bit()
     });
+    // 次の受信のためにACKに戻す
+    p.IIC1.icmr3().modify(|_r, w| w.ackbt()._0());
+    p.IIC1.icmr3().modify(|_r, w| w.ackwp()._0());
+    p.IIC1.icmr3().modify(|_r, w| w.wait()._0());
+    Ok(())
+}
 
-    // サンプリング時間設定
-    // 周辺モジュールクロックC(PCLKC Div /1) = 48MHz
-    p.ADC140
-        .adsstrt()
-        .modify(|_r, w| unsafe { w.sst().bits(100) });
+// I2Cバスにつながっている機器を探す
+// 7ビットアドレスの一般用の範囲(0x08～0x77)にデーター無しの書き込みを送って、ACKを返したアドレスを出力する
+// 見つけた機器からは1バイト読み込んで一緒に出力する
+fn i2c_bus_scan(p: &pac::Peripherals) -> Result<(), I2cError> {
+    let mut found = 0;
+    for addr in 0x08..=0x77 {
+        match i2c_write(p, addr, &[]) {
+            Ok(()) => {}
+            Err(I2cError::Nack) => continue,
+            Err(e) => return Err(e),
+        }
+        found += 1;
+        let mut byte = [0u8; 1];
+        let read = i2c_read(p, addr, &mut byte);
+        defmt::info!(
+            "I2C {=u8:02x}: {}",
+            addr,
+            defmt::Debug2Format(&read.map(|()| byte[0]))
+        );
+        let _ = match read {
+            Ok(()) => format!("I2C {:02x}: {:02x}", addr, byte[0]),
+            Err(e) => format!("I2C {:02x}: {:?}", addr, e),
+        }
+        .map(|s: String<32>| uart_println(s.as_bytes()));
+    }
+    defmt::info!("I2C scan: {} devices", found);
+    let _ = format!("I2C scan: {} devices", found).map(|s: String<32>| uart_println(s.as_bytes()));
+    Ok(())
+}
 
-    // 内部ノードディスチャージ（基準電圧端子を選択しない）
-    p.ADC140.adhvrefcnt().modify(|_r, w| {
-        w.hvsel()._11(); // 内部ノードディスチャージ（基準電圧端子を選択しない）
-        w.adslp()._0() // 通常動作
+// SPIモード (クロック極性CPOL, クロック位相CPHA)
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum SpiMode {
+    Mode0, // CPOL = 0, CPHA = 0
+    Mode1, // CPOL = 0, CPHA = 1
+    Mode2, // CPOL = 1, CPHA = 0
+    Mode3, // CPOL = 1, CPHA = 1
+}
+
+// SCI0をクロック同期式モードにしてSPIマスタとして使う
+// PORT 411 = MOSI0 (Arduino D11)
+// PORT 410 = MISO0 (Arduino D12)
+// PORT 412 = SCK0
+// SCK0はArduinoのD13(P111)ではないので注意
+// ほとんどのSPIデバイスはMSBファーストなので bit_order は普通 BitOrder::MsbFirst にする
+fn spi_init(
+    p: &pac::Peripherals,
+    clocks: &ClockConfig,
+    mode: SpiMode,
+    bit_order: BitOrder,
+    freq: u32,
+) {
+    // SCI0モジュールのモジュールストップ状態の解除
+    p.MSTP.mstpcrb().modify(|_r, w| w.mstpb31()._0());
+
+    // SCI動作を停止
+    p.SCI0.scr().reset();
+
+    // FIFO動作を禁止
+    p.SCI0.fcr().modify(|_r, w| w.fm()._0());
+
+    // 内蔵ボーレートジェネレータを選択してSCKn端子からクロックを出力する
+    p.SCI0.scr().modify(|_r, w| w.cke()._00());
+
+    // 簡易I2Cモードではない
+    p.SCI0.simr1().modify(|_r, w| w.iicm()._0());
+
+    // SCIのクロック同期式モードは、極性反転なし・遅延なしでSPIモード3になる
+    // (アイドル時High, 立ち下がりで出力, 立ち上がりで取り込み)
+    p.SCI0.spmr().modify(|_r, w| {
+        w.sse()._0(); // SSn端子機能は無効
+        w.ctse()._0(); // CTS機能は無効
+        w.mss()._0(); // マスタモード
+        w.mff()._0(); // モードフォルトエラーなし
+        match mode {
+            SpiMode::Mode0 => w.ckpol()._1().ckph()._1(),
+            SpiMode::Mode1 => w.ckpol()._1().ckph()._0(),
+            SpiMode::Mode2 => w.ckpol()._0().ckph()._1(),
+            SpiMode::Mode3 => w.ckpol()._0().ckph()._0(),
+        }
     });
-    cortex_m::asm::nop();
-    cortex_m::asm::nop();
-    cortex_m::asm::nop();
-    cortex_m::asm::nop();
-    cortex_m::asm::nop();
 
-    // 高電位基準電圧にAVCC0を選択
-    // Arduino UNO R4 MINIMAの場合 5V
-    p.ADC140.adhvrefcnt().modify(|_r, w| {
-        w.hvsel()._00() // 高電位基準電圧にAVCC0を選択
+    //
+    p.SCI0.scmr().modify(|_r, w| {
+        w.smif()._0(); // 非スマートカードインタフェースモード
+        w.sinv()._0(); // TDRレジスタの内容をそのまま送信。受信データをそのままRDRレジスタに格納
+        match bit_order {
+            BitOrder::LsbFirst => w.sdir()._0(), // LSBファースト転送
+            BitOrder::MsbFirst => w.sdir()._1(), // MSBファースト転送
+        };
+        w.chr1()._1() // データ長8ビットで送受信
     });
-    cortex_m::asm::nop();
-    cortex_m::asm::nop();
-    cortex_m::asm::nop();
-    cortex_m::asm::nop();
-    cortex_m::asm::nop();
-}
 
-// 内蔵温度センサの値を読み取る
-fn read_tsn(p: &pac::Peripherals) -> f32 {
-    // ユーザーズマニュアルにおける TSNの章より計算式
+    // クロック同期式モードのビットレート
+    //               PCLKA
+    // N = ------------------------ - 1
+    //       8 * 2^(2n-1) * B
+    // Nが8ビットに収まる最小のnを選ぶ
+    // 指定の周波数を超えないようにNは切り上げる
+    let (cks, n) = (0..=3u32)
+        .map(|cks| {
+            let div = 4 * (1 << (2 * cks)) * freq;
+            (cks, clocks.pclka.div_ceil(div).max(1) - 1)
+        })
+        .find(|&(_cks, n)| n <= 255)
+        .unwrap_or((3, 255));
+
     //
-    // 温度（T）はセンサの電圧出力（Vs）と比例関係にあるため、以下の式で温度を求められます。
-    // T = (Vs - V1) / Slope + T1
-    // T：測定温度（℃）
-    // Vs：温度測定時の温度センサの出力電圧（V）
-    // T1：1 点目の試行測定時の温度（℃）
-    // V1：T1 測定時の温度センサの出力電圧（V）
-    // T2：2 点目の試行測定時の温度（℃）
-    // V2：T2 測定時の温度センサの出力電圧（V）
-    // Slope：温度センサの温度傾斜（V/ ℃）、Slope = (V2 - V1) / (T2 - T1)
+    p.SCI0.smr().modify(|_r, w| {
+        w.cks().set(cks as u8); // PCLKA / 4^n クロック
+        w.cm()._1() // クロック同期式モード
+    });
+    p.SCI0.brr().write(|w| unsafe { w.bits(n as u8) });
 
-    // A/D変換を停止する
-    p.ADC140.adcsr().modify(|_r, w| w.adst()._0());
-    p.ADC140.adexicr().modify(|_r, w| {
-        w.ocsa()._0(); // 内部基準電圧のA/D変換禁止
-        w.tssad()._0(); // 温度センサ出力A/D変換値加算／平均モード非選択
-        w.tssa()._1() // 温度センサ出力のA/D変換許可
+    // イベント番号
+    const SCI0_TXI_EVENT_NUMBER: u8 = 0x099;
+    const SCI0_TEI_EVENT_NUMBER: u8 = 0x09a;
+
+    // 送信データエンプティ割り込みと送信終了割り込みの設定(spi_write_async()で使う)
+    p.ICU
+        .ielsr(SCI0_TXI_IEL.number() as usize)
+        .modify(|_r, w| w.iels().set(SCI0_TXI_EVENT_NUMBER));
+    p.ICU
+        .ielsr(SCI0_TEI_IEL.number() as usize)
+        .modify(|_r, w| w.iels().set(SCI0_TEI_EVENT_NUMBER));
+    // 割り込みハンドラが使う周辺機能を登録する
+    SCI0_ISR_RESOURCES.register(|| unsafe {
+        Sci0IsrResources {
+            sci0: pac::SCI0::steal(),
+            icu: pac::ICU::steal(),
+        }
     });
+    unmask_with_priority(SCI0_TXI_IEL, IrqPriority::SciTransmit);
+    unmask_with_priority(SCI0_TEI_IEL, IrqPriority::SciTransmit);
 
-    // シングルスキャンモードでA/D変換開始
-    p.ADC140.adcsr().modify(|_r, w| {
-        w.adcs()._00(); // シングルスキャンモード
-        w.adst()._1() // A/D変換開始
+    // I/Oポートの設定
+    const SCI0_PSEL: u8 = 0b00100; // SCI0
+    // PORT 410 = MISO0
+    pfs_configure(p, 4, 10, PfsConfig::peripheral(SCI0_PSEL, false));
+    // PORT 411 = MOSI0
+    pfs_configure(p, 4, 11, PfsConfig::peripheral(SCI0_PSEL, true));
+    // PORT 412 = SCK0
+    pfs_configure(p, 4, 12, PfsConfig::peripheral(SCI0_PSEL, true));
+
+    // 送受信を同時に許可する
+    p.SCI0.scr().modify(|_r, w| {
+        w.te()._1(); // シリアル送信動作を許可
+        w.re()._1() // シリアル受信動作を許可
     });
+}
 
-    // Ta = Tj = 125 ℃および AVCC0 = 3.3V の条件で、
-    // 温度センサが出力した電圧を、
-    // FFh を書き込む ADC16 によって変換した温度センサの温度値（CAL125）
-    // 4096は2の12乗
-    let cal125 = {
-        let h = p.TSN.tscdrh().read().bits() as u16; // 上位4ビット
-        let l = p.TSN.tscdrl().read().bits() as u16; // 下位8ビット
-        ((h << 8) + l) & (4096 - 1)
+// SPIで全二重通信する
+// bufの内容を送信して、同時に受信したデーターでbufを上書きする
+// spi_write_async()の送信中は使えないので、spi_write_idle()で送り終わるのを待つこと
+fn spi_transfer(p: &pac::Peripherals, buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        // 送信データエンプティを待つ
+        while p.SCI0.ssr().read().tdre().is_0() {}
+        p.SCI0.tdr().write(|w| unsafe { w.bits(*byte) });
+        // 受信データフルを待つ
+        while p.SCI0.ssr().read().rdrf().is_0() {
+            if p.SCI0.ssr().read().orer().is_1() {
+                // オーバーランエラーフラグをクリア
+                p.SCI0.ssr().modify(|_r, w| w.orer().clear_bit());
+            }
+        }
+        *byte = p.SCI0.rdr().read().bits();
+    }
+}
+
+// SPI送信待ち行列
+// 表示器に画素データをまとめて送るので、シリアル通信の待ち行列より大きくする
+const SPI_QUEUE_SIZE: usize = 512;
+static SPI_TXD_QUEUE: Jerk<SPI_QUEUE_SIZE> = Jerk::new();
+
+// SPI送信データエンプティ割り込み番号
+const SCI0_TXI_IEL: pac::Interrupt = pac::Interrupt::IEL13;
+
+// SPI送信終了割り込み番号
+const SCI0_TEI_IEL: pac::Interrupt = pac::Interrupt::IEL14;
+
+// SPI送信待ち行列を最後のバイトまで送り終わると立つフラグ
+static SPI_TX_COMPLETE_FLAG: Signal = Signal::new();
+
+// SPIで送信だけする(受信データは捨てる)
+// data を送信待ち行列に入れて、送信は送信データエンプティ割り込みで行う
+// 待ち行列に入れたバイト数を返す(空きが足りなければ入った分だけ)
+// クロック同期式モードは受信を許可したままだとオーバーランエラーで送信が止まるので、
+// 送信中は送信だけを許可して、送り終わったら送受信を同時に許可した状態に戻す
+fn spi_write_async(p: &pac::Peripherals, data: &[u8]) -> usize {
+    let txd_prod = SPI_TXD_QUEUE.stream_producer();
+    let Ok(mut wgrant) = txd_prod.grant_max_remaining(data.len()) else {
+        return 0;
     };
+    let len = wgrant.len();
+    wgrant.copy_from_slice(&data[..len]);
+    wgrant.commit(len);
 
-    // V1：T1 測定時の温度センサの出力電圧（V）
-    let v1 = 3.3 * (cal125 as f32) / 4096.0;
+    cortex_m::interrupt::free(|_cs| {
+        // 送信中でなければ送信を始める
+        // 送信中なら割り込みハンドラが続けて送る
+        if spi_write_idle(p) {
+            // TEビットとREビットは同時に0にしてから切り替える
+            p.SCI0.scr().modify(|_r, w| {
+                w.te()._0();
+                w.re()._0()
+            });
+            p.SCI0.scr().modify(|_r, w| {
+                w.tie()._1(); // SCIn_TXI割り込み要求を許可
+                w.teie()._0(); // SCIn_TEI割り込み要求を禁止
+                w.te()._1() // シリアル送信動作を許可
+            });
+        }
+    });
+    len
+}
 
-    // A/D変換待ち
-    while p.ADC140.adcsr().read().adst().is_1() {}
+// spi_write_async()で送信中でなければtrue
+// 送信中は受信動作を禁止しているので、REビットが1なら送り終わっている
+fn spi_write_idle(p: &pac::Peripherals) -> bool {
+    p.SCI0.scr().read().re().is_1()
+}
 
-    // A/D 温度センサデータレジスタの値を読み取る
-    // 14ビット右詰め値
-    // 16384は2の14乗
-    let tsn = p.ADC140.adtsdr().read().bits() & (16384 - 1);
+// 前回呼んでからspi_write_async()の送信を送り終わっていればtrue
+// フラグは下ろす
+fn spi_write_complete_take() -> bool {
+    SPI_TX_COMPLETE_FLAG.take()
+}
 
-    // Vs：温度測定時の温度センサの出力電圧（V）
-    let vs = 5.0 * (tsn as f32) / 16384.0;
+// SPIの折り返し試験
+// D11(MOSI0)とD12(MISO0)をつないで Some((SpiMode::Mode0, 1_000_000)) のように(SPIモード, 周波数Hz)にする
+const SPI_LOOPBACK_TEST: Option<(SpiMode, u32)> = None;
 
-    // ユーザーズマニュアル(TSN 特性)より温度傾斜
-    const SLOPE: f32 = -3.65 / 1000.0; // V/℃
+// SPIの折り返し試験
+// spi_transfer()で送ったデーターがそのまま返ってくるか確かめて、
+// spi_write_async()で送り終わった後にもう一度確かめる
+fn spi_loopback_test(p: &pac::Peripherals) -> bool {
+    const PATTERN: [u8; 6] = [0x00, 0x55, 0xaa, 0xff, 0x12, 0x34];
 
-    // 内蔵温度センサの値
-    (vs - v1) / SLOPE + 125.0 // ℃
+    let mut buf = PATTERN;
+    spi_transfer(p, &mut buf);
+    let before = buf == PATTERN;
+
+    // 送信終了割り込みで起こされるまで待つ
+    let queued = spi_write_async(p, &PATTERN);
+    while !spi_write_complete_take() {
+        cortex_m::asm::wfi();
+    }
+    let written = queued == PATTERN.len() && spi_write_idle(p);
+
+    let mut buf = PATTERN;
+    spi_transfer(p, &mut buf);
+    let after = buf == PATTERN;
+
+    defmt::info!(
+        "SPI loopback: transfer {}, write_async {}, transfer after write {}",
+        before,
+        written,
+        after
+    );
+    before && written && after
 }
 
-#[cortex_m_rt::entry]
-fn main() -> ! {
-    let _ = {
-        // ファクトリ MCU インフォメーションフラッシュルートテーブル (FMIFRT)
-        const FMIFRT: *const u32 = 0x407f_b19c as *const u32;
+// SPI(SCI0)の割り込みハンドラ(送信データエンプティ, 送信終了)が使う周辺機能
+struct Sci0IsrResources {
+    sci0: pac::SCI0,
+    icu: pac::ICU, // 割り込みステータスフラグのクリア
+}
 
-        // ユニークIDのベースアドレス
-        let unique_id_base_address = unsafe { core::ptr::read_volatile(FMIFRT) } as *const u32;
+// spi_init()で登録する
+static SCI0_ISR_RESOURCES: IsrResources<Sci0IsrResources> = IsrResources::new();
 
-        //
-        let mut buf: Vec<u8, 16> = Vec::new();
+// SPI送信データエンプティ割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL13() {
+    // spi_init()が割り込みを許可する前に登録するので、ここで登録前(None)にはならない
+    SCI0_ISR_RESOURCES.with(|spi| {
+        let txd_cons = SPI_TXD_QUEUE.stream_consumer();
 
-        // 型名レジスタ n（PNRn）（n = 0 ～ 3）
-        // ユニークIDのベースアドレスに対するオフセットは 24h, 28h, 2ch, 30h
-        for offset in [0x24, 0x28, 0x2c, 0x30] {
-            let pnr: u32 = unsafe {
-                core::ptr::read_volatile(unique_id_base_address.wrapping_byte_add(offset))
-            };
-            // バイトオーダーを変換
-            let bytes = pnr.to_ne_bytes();
-            //
-            for i in 0..4 {
-                buf.push(bytes[i]).unwrap();
-            }
+        // 送信
+        if let Ok(rgr) = txd_cons.read() {
+            let txd = rgr[0];
+            rgr.release(1);
+            spi.sci0.tdr().write(|w| unsafe { w.bits(txd) });
+        } else {
+            spi.sci0.scr().modify(|_r, w| {
+                w.tie()._0(); // SCIn_TXI割り込み要求を禁止
+                w.teie()._1() // SCIn_TEI割り込み要求を許可
+            });
+        }
+
+        // 割り込みステータスフラグクリア
+        spi.icu
+            .ielsr(SCI0_TXI_IEL.number() as usize)
+            .modify(|_r, w| w.ir().clear_bit());
+    });
+}
+
+// SPI送信終了割り込みハンドラ
+#[cortex_m_rt::interrupt]
+fn IEL14() {
+    // spi_init()が割り込みを許可する前に登録するので、ここで登録前(None)にはならない
+    SCI0_ISR_RESOURCES.with(|spi| {
+        // 送信終了割り込みを許可した後に送信待ち行列にデータが入っていたら送信を続ける
+        if SPI_TXD_QUEUE.stream_consumer().read().is_ok() {
+            spi.sci0.scr().modify(|_r, w| {
+                w.tie()._1(); // SCIn_TXI割り込み要求を許可
+                w.teie()._0() // SCIn_TEI割り込み要求を禁止
+            });
+        } else {
+            // 送信動作を禁止してから、spi_transfer()のために送受信を同時に許可する
+            spi.sci0.scr().modify(|_r, w| {
+                w.tie()._0(); // SCIn_TXI割り込み要求を禁止
+                w.teie()._0(); // SCIn_TEI割り込み要求を禁止
+                w.te()._0() // シリアル送信動作を禁止
+            });
+            spi.sci0.scr().modify(|_r, w| {
+                w.te()._1(); // シリアル送信動作を許可
+                w.re()._1() // シリアル受信動作を許可
+            });
+
+            // 送信終了を知らせる
+            SPI_TX_COMPLETE_FLAG.set();
+        }
+
+        // 割り込みステータスフラグクリア
+        spi.icu
+            .ielsr(SCI0_TEI_IEL.number() as usize)
+            .modify(|_r, w| w.ir().clear_bit());
+    });
+}
+
+// CANの通信速度
+// CANトランシーバーをD4(CTX0)とD5(CRX0)につないだら Some(CanBitrate::Kbps500) のようにする
+const CAN_BITRATE: Option<CanBitrate> = None;
+
+// CANモジュールの設定
+// 設定できたらtrue
+// 受信はメインループで受信メールボックスを見に行く(受信割り込みは使わない)
+fn can_module_init(p: &pac::Peripherals, clocks: &ClockConfig, bitrate: CanBitrate) -> bool {
+    match can_init(p, clocks, bitrate) {
+        Ok(timing) => {
+            defmt::info!(
+                "CAN {} bps (prescaler {}, tseg1 {}, tseg2 {})",
+                bitrate.bps(),
+                timing.prescaler,
+                timing.tseg1,
+                timing.tseg2
+            );
+            true
         }
+        Err(e) => {
+            defmt::error!("CAN init failed: {}", defmt::Debug2Format(&e));
+            false
+        }
+    }
+}
+
+// 受信メールボックスから新しいフレームを1つ取り出す
+fn can_receive(p: &pac::Peripherals) -> Option<CanFrame> {
+    CAN_RX_MAILBOXES
+        .clone()
+        .find_map(|mb| can_receive_mailbox(p, mb))
+}
 
-        // heapless::Stringに変換
-        let product_part_number: String<16> = String::from_utf8(buf).unwrap();
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    {
+        // 型名
+        let product_part_number = read_product_part_number();
 
         // 挨拶
         defmt::info!(r#"Hello. I'm "{}""#, product_part_number.as_str());
-    };
+        defmt::info!("Unique ID: {=[u8; 16]:02x}", read_unique_id());
+    }
 
     // 周辺機能
     let p = pac::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
 
-    let _ = {
-        // 書き込みプロテクトレジスタを操作してPmnPFS レジスタに書き込み許可を与える
-        p.PMISC.pwpr().write(|w| w.b0wi()._0());
-        p.PMISC.pwpr().write(|w| w.pfswe()._1());
+    // 割り込みハンドラの処理のサイクル数を計るDWTサイクルカウンタを有効にする
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
 
-        // 離脱時に書き込みプロテクトレジスタを元通りに復帰する
-        defer! {
-        p.PMISC.pwpr().write(|w| w.pfswe()._0());
-        p.PMISC.pwpr().write(|w| w.b0wi()._1());
-        }
+    // 前回のリセット要因
+    // 次のリセットと区別できるように、読み出したら検出フラグを下ろす
+    let reset_cause = read_reset_cause(&p);
+    defmt::info!("reset cause: {}", reset_cause.as_str());
 
-        // PORT 111 = D13(LED)
-        // 以上の入出力ポートを出力に設定
-        p.PFS.p111pfs().reset();
-        p.PFS.p111pfs().modify(|_r, w| w.pdr()._1());
-    };
+    // ユーザーLED(UNO R4 MINIMAはD13)の入出力ポートを出力に設定
+    BOARD_LEDS.onboard.init(&p);
 
     // ADCモジュール設定
     adc_module_init(&p);
 
     // 48MHzクロック設定
-    clock_init_hoco48(&p);
+    let clocks = clock_init_hoco48(&p).unwrap();
+    // レジスタから読み出したシステムクロックの周波数と一致するか確かめる
+    defmt::assert_eq!(current_iclk_hz(&p), clocks.iclk);
 
     // GPTタイマーモジュールの設定
-    gpt_module_init(&p);
+    gpt_module_init(&p, &clocks, Gpt32Channel::Gpt320, clocks.pclkd); // 1秒周期
+
+    // 経過時間(ミリ秒)を数えるSysTickタイマーの設定
+    systick_init(&mut cp.SYST, clocks.iclk);
 
     // SCIモジュールの設定
-    sci_module_init(&p);
+    sci_module_init(&p, &clocks);
 
-    // GPT320タイマーカウント動作を開始
-    p.GPT320.gtcr().modify(|_r, w| {
-        w.cst()._1();
-        w.md()._000(); // のこぎり波形PWMモード
-        w.tpcs()._000() // プリスケーラ― (PCLKD/1)
+    // CRC演算器の設定
+    // 行末のCRCはCRC演算器で計算するので、シリアル通信で最初の1行を送る前に設定する
+    crc_init(&p, CrcPolynomial::Crc16Ccitt);
+    // CRC演算器の結果をソフトウェアの計算と比べる
+    defmt::assert_eq!(
+        crc_compute(&p, b"123456789"),
+        crc_software(CrcPolynomial::Crc16Ccitt, b"123456789")
+    );
+
+    // つないだ相手の通信速度とフレーム形式を探す
+    if let Some((probe, expected)) = SCI_AUTOBAUD_PROBE {
+        match sci_autobaud(&p, probe, expected) {
+            Some(format) => defmt::info!("serial format detected: {}", format),
+            None => defmt::warn!("serial format not detected"),
+        }
+    }
+
+    // 起動回数をデータフラッシュに記録する
+    dataflash_init(&clocks);
+    let boot_count = boot_count_increment(&clocks).unwrap_or_else(|e| {
+        defmt::warn!("boot count not recorded: {}", defmt::Debug2Format(&e));
+        boot_count_read()
     });
 
+    // 挨拶とリセット要因と起動回数をシリアル通信で送る
+    let _ = format!(
+        r#"Hello. I'm "{}" (reset: {}, boot: {})"#,
+        read_product_part_number().trim_end(),
+        reset_cause.as_str(),
+        boot_count
+    )
+    .map(|s: String<80>| uart_println(s.as_bytes()));
+
+    // DACモジュールの設定
+    dac_init(&p);
+    if let Some((waveform, freq_hz)) = DAC_WAVE {
+        wave_gen_init(&p, &clocks, waveform, freq_hz);
+    }
+
+    // RTCの設定
+    // Arduino UNO R4 MINIMAにはサブクロック用の水晶振動子が無いのでLOCOを使う
+    if let Err(e) = rtc_init(&p, &clocks, RtcClockSource::Loco) {
+        defmt::warn!("RTC init failed: {}", defmt::Debug2Format(&e));
+        uart_println(b"WARNING: RTC init failed");
+    }
+
+    // CANモジュールの設定
+    let can_ready = CAN_BITRATE.is_some_and(|bitrate| can_module_init(&p, &clocks, bitrate));
+    if can_ready {
+        // 起動したことを知らせる
+        if let Err(e) = can_send(&p, 0x100, b"hello") {
+            defmt::warn!("CAN send failed: {}", defmt::Debug2Format(&e));
+        }
+    }
+
+    // I2Cバスにつながっている機器を探す
+    if I2C_BUS_SCAN && let Err(e) = i2c_init(&p, &clocks).and_then(|()| i2c_bus_scan(&p)) {
+        defmt::warn!("I2C scan failed: {}", defmt::Debug2Format(&e));
+        uart_println(b"I2C scan failed");
+    }
+
+    // SPIの折り返し試験
+    if let Some((mode, freq)) = SPI_LOOPBACK_TEST {
+        spi_init(&p, &clocks, mode, BitOrder::MsbFirst, freq);
+        if spi_loopback_test(&p) {
+            uart_println(b"SPI loopback OK");
+        } else {
+            defmt::warn!("SPI loopback failed");
+            uart_println(b"SPI loopback failed");
+        }
+    }
+
+    // 電源電圧低下の検出
+    if let Some(threshold) = LVD_THRESHOLD {
+        lvd_init(&p, &clocks, threshold, LvdAction::Interrupt);
+        if lvd_status(&p).below_threshold {
+            LVD_WARNING_FLAG.set();
+        }
+    }
+
+    // 前回ウォッチドッグでリセットされていたら知らせる
+    if reset_cause == ResetCause::IndependentWatchdog {
+        defmt::warn!("reset by IWDT");
+        uart_println(b"reset by IWDT");
+    }
+
+    // GPT320のオーバーフローで温度センサーのA/D変換を始める
+    if TSN_ELC_TRIGGER {
+        adc_gpt_triggered_start(&p);
+    }
+
+    // GPT320タイマーカウント動作を開始
+    gpt_start(&p, Gpt32Channel::Gpt320);
+
     //
     // メインループ
     //
+    // 起動するまでに届いていた受信データを捨てて、まっさらな状態から始める
+    uart_rx_clear();
     let rxd_cons = RXD_QUEUE.stream_consumer();
+    let mut line_reader = LineReader::new();
+    let mut frame_reader = FrameReader::new();
+    let mut sbus_reader = SbusReader::new();
+    // SBUSの受信中は送り返しても読む相手がいない
+    uart_set_echo(!SBUS_RECEIVE);
+    let mut dac_value: u16 = 0;
+    let mut dac_rising = true;
+    let mut temp_monitor =
+        TEMP_ALERT.map(|(setpoint, deadband)| TempMonitor::new(setpoint, deadband));
+    let mut temp_history = History::<TEMP_HISTORY_SIZE>::new();
+    // データフラッシュへの温度の記録と次に記録する期限
+    let mut temp_logger = TEMP_LOG_INTERVAL_S
+        .map(|interval_s| (TempLogger::open(), Timeout::after(interval_s * 1000)));
+    // 温度の表示単位(データフラッシュに記録した単位, 記録が無ければ摂氏)
+    let mut temp_unit = temp_unit_read().unwrap_or(TempUnit::Celsius);
+    let mut rx_dropped = uart_rx_dropped();
+    // adc_start() で温度センサ出力のA/D変換を始めたらtrue
+    let mut adc_started = false;
+    let mut cpu_load = CpuLoad::new();
     loop {
-        // タイマー割り込みがあったか？
-        let flag =
-            critical_section::with(|cs| GPT320_TIMER_OVERFLOW_FLAG.borrow(cs).replace(false));
-        // タイマー割り込みがあれば
-        if flag {
+        // タイマー割り込みか受信まで眠って待つ
+        let wake = wait_for_wake();
+        // ウォッチドッグタイマーをリフレッシュ
+        iwdt_refresh(&p);
+        // 1秒ごとの処理
+        if wake == Wake::Tick {
+            cpu_load.tick();
+            // DACから三角波を出力する
+            // DAC_WAVEを設定した時はGPT321の割り込みハンドラが出力する
+            if DAC_WAVE.is_none() {
+                dac_write(&p.DAC12, dac_value);
+            }
+            (dac_value, dac_rising) = match (dac_rising, dac_value) {
+                (true, v) if v >= DAC_MAX_VALUE - DAC_TRIANGLE_STEP => (DAC_MAX_VALUE, false),
+                (true, v) => (v + DAC_TRIANGLE_STEP, true),
+                (false, v) if v <= DAC_TRIANGLE_STEP => (0, true),
+                (false, v) => (v - DAC_TRIANGLE_STEP, false),
+            };
+            // 電源電圧が下がっていたら知らせる
+            if LVD_WARNING_FLAG.take()
+                && let Some(threshold) = LVD_THRESHOLD
+            {
+                let mv = threshold.millivolts();
+                defmt::warn!("VCC below {} mV", mv);
+                let _ = format!("WARNING: VCC below {} mV", mv)
+                    .map(|s: String<40>| uart_println(s.as_bytes()));
+            }
+            // 受信データを捨てていたら知らせる
+            let dropped = uart_rx_dropped();
+            if dropped != rx_dropped {
+                let n = dropped.wrapping_sub(rx_dropped);
+                rx_dropped = dropped;
+                defmt::warn!("{} received bytes dropped", n);
+                let _ = format!("WARNING: {} received bytes dropped", n)
+                    .map(|s: String<40>| uart_println(s.as_bytes()));
+            }
             // 内蔵温度センサーの値を読む
-            let t = read_tsn(&p);
-            // 内蔵温度センサーの値をシリアル通信で出力する
-            let _ = format!("{:>8.04} C", t).map(|s: String<20>| uart_println(s.as_bytes()));
-            //
-            if let Ok(rgr) = rxd_cons.read() {
-                // シリアル通信でデーターを受信した
-                let len = rgr.len();
-                let text: String<QUEUE_SIZE> = rgr.iter().map(|&u| u as char).collect();
-                defmt::info!("RXD: {}", text.as_str());
-                rgr.release(len);
+            // ELCで変換を始めた場合はA/Dスキャン終了割り込みで受け取った値を使う
+            // そうでなければ前回始めたA/D変換の結果を受け取って次の変換を始める(変換の終わりを待たない)
+            let tsn_raw = if TSN_ELC_TRIGGER {
+                adc_triggered_poll()
+            } else {
+                let raw = (adc_started && adc_is_done(&p)).then(|| adc_result(&p));
+                if !adc_started || raw.is_some() {
+                    adc_start(&p);
+                    adc_started = true;
+                }
+                raw
+            };
+            // 内蔵温度センサーの値を一定の間隔でデータフラッシュに記録する
+            if let (Some((logger, due)), Some(interval_s), Some(tsn)) =
+                (temp_logger.as_mut(), TEMP_LOG_INTERVAL_S, tsn_raw)
+                && due.is_expired()
+            {
+                due.advance(interval_s * 1000);
+                let celsius = tsn_to_celsius(&p, tsn as f32);
+                let uptime_s = (millis() / 1000) as u32;
+                if let Err(e) = logger.append(&clocks, boot_count, uptime_s, celsius) {
+                    defmt::warn!("temperature not logged: {}", defmt::Debug2Format(&e));
+                }
+            }
+            if TELEMETRY_TEXT {
+                if let Some(t) = tsn_raw.map(|tsn| tsn_to_celsius(&p, tsn as f32)) {
+                    temp_history.push(t);
+                    // 日時を付けて内蔵温度センサーの値をシリアル通信で出力する
+                    // 送信待ち行列に直接書き込む
+                    let now = rtc_now(&p);
+                    if let Some(mut line) = uart_line(40) {
+                        let _ = write!(
+                            line,
+                            "{} {:>8.04} {}",
+                            now,
+                            temp_unit.convert(t),
+                            temp_unit.suffix()
+                        );
+                        let _ = line.commit();
+                    }
+                    // 設定温度を横切ったらLEDとシリアル通信で知らせる
+                    if let Some(monitor) = temp_monitor.as_mut() {
+                        let (trend, crossing) = monitor.update(t);
+                        if trend != TempTrend::Stable {
+                            defmt::info!("temperature {}", trend);
+                        }
+                        if let Some(crossing) = crossing {
+                            let led_on = crossing == TempCrossing::Above;
+                            BOARD_LEDS.onboard.set(led_on);
+                            let setpoint = monitor.setpoint;
+                            let _ = if led_on {
+                                format!(
+                                    "ALERT: temperature above {:.1} {}",
+                                    temp_unit.convert(setpoint),
+                                    temp_unit.suffix()
+                                )
+                            } else {
+                                format!(
+                                    "temperature back below {:.1} {}",
+                                    temp_unit.convert(setpoint - monitor.deadband),
+                                    temp_unit.suffix()
+                                )
+                            }
+                            .map(|s: String<40>| uart_println(s.as_bytes()));
+                        }
+                    }
+                }
+            } else if let Some(tsn_raw) = tsn_raw {
+                // 内蔵温度センサーの値をテレメトリフレームで出力する
+                send_telemetry(&p, tsn_raw);
+            }
+        }
+        // CANで受信したフレームをシリアル通信で出力する
+        while let Some(frame) = can_ready.then(|| can_receive(&p)).flatten() {
+            defmt::info!("CAN RX {:03x}: {=[u8]:02x}", frame.id, frame.data());
+            if let Some(mut line) = uart_line(40) {
+                let _ = write!(line, "CAN {:03X} [{}]", frame.id, frame.len);
+                for byte in frame.data() {
+                    let _ = write!(line, " {:02X}", byte);
+                }
+                let _ = line.commit();
             }
         }
+        // "reinit" を受け取ったらSCI1を設定し直す
+        let mut sci_reinit = false;
+        // 受信待ち行列が空になるまで取り出す
+        // 待ち行列の終わりで折り返している時は、1回の読み出しでは前半しか取り出せない
+        while let Ok(rgr) = rxd_cons.read() {
+            // シリアル通信でデーターを受信した
+            let len = rgr.len();
+            // 端末に入力した文字が見えるように送り返す
+            uart_echo(&rgr);
+            for &rxd in rgr.iter() {
+                if SBUS_RECEIVE {
+                    sbus_reader.feed(rxd, |frame| defmt::debug!("SBUS: {}", frame));
+                } else if UART_RX_COBS_FRAMES {
+                    frame_reader.feed(rxd, |frame| defmt::info!("RXD frame: {=[u8]:02x}", frame));
+                } else {
+                    line_reader.feed(rxd, |line| {
+                        defmt::info!("{} ms RXD: {}", millis(), line);
+                        match line.trim() {
+                            "stats" => send_temp_stats(&temp_history, temp_unit),
+                            cmd if cmd == "unit" || cmd.starts_with("unit ") => {
+                                temp_unit_command(&clocks, &mut temp_unit, cmd[4..].trim())
+                            }
+                            "dump" => {
+                                dump_clock_state(&p);
+                                dump_sci_state(&p);
+                            }
+                            "dump temp" => match temp_logger.as_ref() {
+                                Some((logger, _due)) => send_temp_log(&p, logger, temp_unit),
+                                None => uart_println(b"temperature logging is off"),
+                            },
+                            "reinit" => sci_reinit = true,
+                            "load" => cpu_load.report(),
+                            cmd @ ("adc 12" | "adc 14") => {
+                                let resolution = if cmd == "adc 12" {
+                                    AdcResolution::Bits12
+                                } else {
+                                    AdcResolution::Bits14
+                                };
+                                adc_set_resolution(resolution);
+                                defmt::info!("ADC resolution {}", resolution);
+                                let _ = format!("ADC resolution {} bits", resolution as u8)
+                                    .map(|s: String<32>| uart_println(s.as_bytes()));
+                            }
+                            cmd @ ("adc" | "adc cont") => {
+                                if cmd == "adc" {
+                                    send_adc_scan(&p);
+                                } else {
+                                    send_adc_continuous(&p);
+                                }
+                                // 止まった温度センサーのA/D変換を始め直す
+                                if TSN_ELC_TRIGGER {
+                                    adc_gpt_triggered_start(&p);
+                                } else {
+                                    adc_started = false;
+                                }
+                            }
+                            #[cfg(feature = "defmt-uart")]
+                            cmd if cmd == "log" || cmd.starts_with("log ") => {
+                                uart_log_command(cmd[3..].trim())
+                            }
+                            _ => {}
+                        }
+                    });
+                }
+            }
+            rgr.release(len);
+            RXD_QUEUE_LEN.fetch_sub(len, Ordering::SeqCst);
+        }
+        // 受信待ち行列を読み終わってから止める(sci_deinit()は受信待ち行列を空にする)
+        // 自動判別した形式は使わず、UART_FORMATで設定し直す
+        if sci_reinit {
+            uart_println(b"reinit SCI1");
+            uart_flush_blocking(&p);
+            sci_deinit(&p);
+            sci_module_init(&p, &clocks);
+            defmt::info!("SCI1 reinitialized");
+        }
+        // 受信待ち行列が空いたので相手の送信を再開させる
+        uart_rx_flow_resume(&p);
+        // 打ちかけのまま放っておかれた行を捨てる
+        line_reader.poll_idle(|line| {
+            defmt::info!("RXD line timed out: {}", line);
+            uart_println(b"\r\nline timed out");
+        });
     }
 }